@@ -8,10 +8,10 @@ use std::time::{Duration, Instant};
 
 use criterion::{criterion_group, criterion_main, BatchSize, Bencher, Criterion};
 
-use http_test_server::TestServer;
 use remove_dir_all::remove_dir_all;
 use separator::Separatable;
 
+use ::addon_proxy::testing::MockOrigin;
 use ::addon_proxy::{default_client, on_request, Proxy};
 
 #[derive(Default)]
@@ -86,7 +86,9 @@ fn start_proxy(config_path: &'static str) -> impl FnOnce() {
                         .send(controller)
                         .expect("send proxy controller")
                 })
-                .set_on_server_stop(move || stop_signal_sender.send(()).expect("send stop signal"))
+                .set_on_server_stop(move |_summary| {
+                    stop_signal_sender.send(()).expect("send stop signal")
+                })
                 .start()
                 .await
         };
@@ -108,16 +110,16 @@ fn start_proxy(config_path: &'static str) -> impl FnOnce() {
 }
 
 #[must_use = "Mock server is stopped on drop"]
-fn start_mock_server() -> TestServer {
-    let mock_server = TestServer::new_with_port(5005).unwrap();
+fn start_mock_server() -> MockOrigin {
+    let mock_server = MockOrigin::start(5005).unwrap();
 
     mock_server
-        .create_resource("/manifest.json")
+        .resource("/manifest.json")
         .header("Content-Type", "application/json")
         .body(include_str!("../bench_data/manifest.json"));
 
     mock_server
-        .create_resource("/catalog/movie/top.json")
+        .resource("/catalog/movie/top.json")
         .header("Content-Type", "application/json")
         .body(include_str!("../bench_data/top.json"));
 
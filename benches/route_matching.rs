@@ -0,0 +1,59 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use ::addon_proxy::{test_route, ProxyConfig};
+
+/// Compare the trie-based `ProxyConfig::find_route` with the linear scan of all routes.
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("route_matching");
+    for &route_count in &[10, 100, 1_000] {
+        let proxy_config = proxy_config(route_count);
+        // The most specific route is the last one.
+        let url = format!(
+            "addon-{}.example.com/v1/catalog/movie/top.json",
+            route_count - 1
+        );
+        assert!(proxy_config.find_route(&[&url]).is_some());
+
+        group.bench_with_input(BenchmarkId::new("trie", route_count), &url, |b, url| {
+            b.iter(|| proxy_config.find_route(&[url]))
+        });
+        group.bench_with_input(BenchmarkId::new("linear", route_count), &url, |b, url| {
+            b.iter(|| {
+                proxy_config
+                    .routes
+                    .iter()
+                    .filter(|route| url.starts_with(&route.from))
+                    .max_by_key(|route| route.from.len())
+            })
+        });
+        // Rules, routes, request validation and the cache key.
+        let url = format!("http://{}", url);
+        group.bench_with_input(
+            BenchmarkId::new("test_route", route_count),
+            &url,
+            |b, url| b.iter(|| test_route(url, &proxy_config)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
+
+/// The bench config with `route_count` additional routes -
+/// a host route and a path route for every other addon.
+fn proxy_config(route_count: usize) -> ProxyConfig {
+    let config = include_str!("../bench_data/proxy_cfg_no_cache.toml");
+    let routes = (0..route_count)
+        .map(|index| {
+            let path = if index % 2 == 0 { "" } else { "/v1" };
+            format!(
+                "[[routes]]\nfrom = \"addon-{}.example.com{}\"\nto = \"http://localhost:{}\"\n",
+                index,
+                path,
+                8000 + index
+            )
+        })
+        .collect::<String>();
+    toml::from_str(&format!("{}\n{}", config, routes)).expect("valid proxy config")
+}
@@ -0,0 +1,94 @@
+//! Start the demo addon and the proxy in front of it and show routing, validation and caching.
+//!
+//! ```bash
+//! cargo run --example demo
+//! ```
+//!
+//! The proxy keeps running after the walkthrough, so you can try your own requests
+//! (e.g. `curl http://127.0.0.1:7000/demo/catalog/movie/top.json`) or open the admin dashboard
+//! on http://127.0.0.1:7000/admin/ui.
+
+use std::{io, thread};
+
+use hyper::{Client, Uri};
+use tokio::sync::oneshot;
+
+use ::addon_proxy::{default_client, on_request, Proxy, ProxyController};
+
+mod demo_addon;
+
+const CONFIG_PATH: &str = "examples/demo_proxy_config.toml";
+const PROXY_URL: &str = "http://127.0.0.1:7000";
+
+/// Description and URL path of requests sent through the proxy.
+const WALKTHROUGH: &[(&str, &str)] = &[
+    ("Clear the cache from the previous demo run", "/clear-cache"),
+    ("Manifest - routed to the demo addon", "/demo/manifest.json"),
+    (
+        "Catalog - the addon is called and the response is cached",
+        "/demo/catalog/movie/top.json",
+    ),
+    (
+        "The same catalog - served from the cache, the addon isn't called",
+        "/demo/catalog/movie/top.json",
+    ),
+    (
+        "Not a Stremio resource - rejected by the validation",
+        "/demo/not-a-resource",
+    ),
+    ("Unknown route - 404", "/unknown/manifest.json"),
+];
+
+#[tokio::main]
+async fn main() {
+    tokio::spawn(async {
+        if let Err(error) = demo_addon::serve().await {
+            eprintln!("demo addon failed: {}", error);
+        }
+    });
+
+    let (controller_sender, controller_receiver) = oneshot::channel::<ProxyController>();
+    tokio::spawn(async {
+        // The proxy is stopped when the controller is dropped.
+        if let Ok(controller) = controller_receiver.await {
+            walkthrough().await;
+            wait_for_enter().await;
+            controller.stop();
+        }
+    });
+
+    Proxy::new(default_client, on_request)
+        .set_config_path(CONFIG_PATH)
+        .set_on_server_start(move |controller| {
+            let _ = controller_sender.send(controller);
+        })
+        .start()
+        .await;
+}
+
+async fn wait_for_enter() {
+    let (enter_sender, enter_receiver) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = io::stdin().read_line(&mut String::new());
+        let _ = enter_sender.send(());
+    });
+    let _ = enter_receiver.await;
+}
+
+async fn walkthrough() {
+    let client = Client::new();
+    for (description, path) in WALKTHROUGH {
+        println!("\n{}", description);
+        let url = format!("{}{}", PROXY_URL, path)
+            .parse::<Uri>()
+            .expect("walkthrough URL");
+        match client.get(url.clone()).await {
+            Ok(response) => println!("  GET {} -> {}", url, response.status()),
+            Err(error) => println!("  GET {} failed: {}", url, error),
+        }
+    }
+    println!(
+        "\nThe proxy is running on {}/demo/ (the admin dashboard on {}/admin/ui), press Enter to stop it.",
+        PROXY_URL, PROXY_URL
+    );
+}
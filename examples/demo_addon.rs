@@ -0,0 +1,89 @@
+//! Minimal Stremio addon with a manifest and one catalog.
+//!
+//! Run it alone by `cargo run --example demo_addon` or together with the proxy
+//! by `cargo run --example demo` (see `examples/demo.rs`).
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Request, Response, Server, StatusCode};
+
+/// The addon listens on this port - see the route in `examples/demo_proxy_config.toml`.
+pub const PORT: u16 = 7001;
+
+const MANIFEST: &str = r#"{
+    "id": "org.addon_proxy.demo",
+    "version": "1.0.0",
+    "name": "Addon Proxy Demo",
+    "description": "Demo addon for Addon Proxy",
+    "resources": ["catalog"],
+    "types": ["movie"],
+    "catalogs": [{ "type": "movie", "id": "top" }],
+    "idPrefixes": ["tt"]
+}"#;
+
+const TOP_CATALOG: &str = r#"{
+    "metas": [
+        {
+            "id": "tt0032138",
+            "type": "movie",
+            "name": "The Wizard of Oz",
+            "poster": "https://images.metahub.space/poster/medium/tt0032138/img"
+        },
+        {
+            "id": "tt0017136",
+            "type": "movie",
+            "name": "Metropolis",
+            "poster": "https://images.metahub.space/poster/medium/tt0017136/img"
+        }
+    ]
+}"#;
+
+// ------ serve ------
+
+/// Serve the addon on `127.0.0.1:<PORT>` until the future is dropped.
+///
+/// # Errors
+///
+/// Returns error when the port cannot be bound.
+pub async fn serve() -> Result<(), hyper::Error> {
+    let address = SocketAddr::from(([127, 0, 0, 1], PORT));
+    let make_service =
+        make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle_request)) });
+    Server::try_bind(&address)?.serve(make_service).await
+}
+
+async fn handle_request(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    // The log shows which requests have reached the origin (i.e. they haven't been cached).
+    println!("[demo addon] {} {}", req.method(), req.uri());
+
+    let (body, cache_control) = match req.uri().path() {
+        "/manifest.json" => (MANIFEST, "max-age=3600"),
+        "/catalog/movie/top.json" => (TOP_CATALOG, "max-age=60"),
+        _ => {
+            let mut response = Response::new(Body::from("Not found."));
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(response);
+        }
+    };
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .body(Body::from(body))
+        .expect("build demo addon response");
+    Ok(response)
+}
+
+// ------ main ------
+
+// `main` is unused when this file is included as a module into `examples/demo.rs`.
+#[allow(dead_code)]
+#[tokio::main]
+async fn main() {
+    println!("Demo addon is listening on http://127.0.0.1:{}", PORT);
+    if let Err(error) = serve().await {
+        eprintln!("demo addon failed: {}", error);
+    }
+}
@@ -0,0 +1,18 @@
+//! Decode arbitrary bytes as a cached response and turn it into a response like the proxy does.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use addon_proxy::CacheValueForDeserialization;
+use hyper::{Body, Response};
+
+fuzz_target!(|data: &[u8]| {
+    let cached_response = match bincode::deserialize::<CacheValueForDeserialization>(data) {
+        Ok(cached_response) => cached_response,
+        Err(_) => return,
+    };
+    cached_response.is_fresh();
+
+    let mut response = Response::new(Body::from(cached_response.body));
+    *response.status_mut() = cached_response.status;
+    *response.headers_mut() = cached_response.headers;
+});
@@ -0,0 +1,51 @@
+//! Pass arbitrary URLs through rules and routes, incl. a route with arbitrary `from` and `to`.
+#![no_main]
+use std::collections::BTreeMap;
+
+use libfuzzer_sys::fuzz_target;
+
+use addon_proxy::{test_route, ProxyConfig, ProxyRoute};
+use once_cell::sync::Lazy;
+
+static PROXY_CONFIG: Lazy<ProxyConfig> = Lazy::new(|| {
+    toml::from_str(include_str!("../../proxy_config.toml")).expect("valid proxy_config.toml")
+});
+
+fuzz_target!(|input: (String, String, String)| {
+    let (url, from, to) = input;
+
+    let _ = test_route(&url, &PROXY_CONFIG);
+
+    let to = match to.parse() {
+        Ok(to) => to,
+        Err(_) => return,
+    };
+    let mut proxy_config = PROXY_CONFIG.clone();
+    proxy_config.routes.insert(
+        0,
+        ProxyRoute {
+            from,
+            to,
+            validate: None,
+            negotiate_manifest: None,
+            cache_partition: None,
+            body_replacements: BTreeMap::new(),
+            json_filter: None,
+            verbose: None,
+            allowed_countries: None,
+            blocked_countries: None,
+            to_by_region: BTreeMap::new(),
+            head_revalidation: None,
+            cache_private: None,
+            group: None,
+            signing_profile: None,
+            fix_content_type: None,
+            transcode_to_utf8: None,
+            latency_slo: None,
+            answer_preflight: None,
+            strategy: None,
+            cors: None,
+        },
+    );
+    let _ = test_route(&url, &proxy_config);
+});
@@ -0,0 +1,680 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+use http::Uri;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use serde_json::Value;
+
+use crate::proxy::cache::{self, CacheValueForDeserialization};
+use crate::proxy::{test_route, Db, ProxyConfig, ADMIN_TOKEN_HEADER, DEFAULT_CONFIG_PATH};
+
+mod bench;
+
+pub use bench::{bench, BenchOptions};
+
+// ------ Command ------
+
+/// Command parsed from the command-line arguments.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Start the proxy server (no subcommand).
+    Serve { config_path: PathBuf },
+    /// `check` - Load the config and report problems without starting the server.
+    Check { config_path: PathBuf },
+    /// `route-test <url>` - Show how the proxy would handle a GET request with the URL.
+    RouteTest { config_path: PathBuf, url: String },
+    /// `cache ls|get|rm|stats|migrate` - Inspect or modify the cache of the stopped proxy
+    /// (`ls`, `get <url>` and `rm <url>` work with the running proxy through its admin API).
+    Cache {
+        config_path: PathBuf,
+        action: CacheAction,
+    },
+    /// `bench` - Send requests to a running proxy and print its throughput and latencies.
+    Bench {
+        config_path: PathBuf,
+        options: BenchOptions,
+    },
+    /// `schema` - Print JSON Schema of the config for editors and validators.
+    Schema,
+}
+
+/// See `Command::Cache`.
+#[derive(Debug, PartialEq)]
+pub enum CacheAction {
+    /// List all cached responses.
+    Ls,
+    /// Print the cached response identified by a cache key or all responses cached for the URL.
+    Get(String),
+    /// Remove the cached response identified by a cache key or all responses cached for the URL.
+    Rm(String),
+    /// Print cache statistics.
+    Stats,
+    /// Re-key cached responses from the old origin to the new one (see `cache::migrate`).
+    Migrate {
+        old_origin: String,
+        new_origin: String,
+    },
+}
+
+impl Command {
+    /// Parse arguments without the program name.
+    ///
+    /// # Errors
+    ///
+    /// Returns `String` error with the usage when the arguments are invalid.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let command = Command::parse(std::env::args().skip(1))?;
+    /// ```
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut positional_args = Vec::new();
+        // `--name value` pairs.
+        let mut options = BTreeMap::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg.starts_with("--") {
+                match args.next() {
+                    Some(value) => options.insert(arg, value),
+                    None => return Err(usage(&format!("missing value of '{}'", arg))),
+                };
+            } else {
+                positional_args.push(arg);
+            }
+        }
+        let config_path = options
+            .remove("--config")
+            .map_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH), PathBuf::from);
+
+        let positional_args = positional_args
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        let cache = |action| Self::Cache {
+            config_path: config_path.clone(),
+            action,
+        };
+        let command = match positional_args.as_slice() {
+            [] => Self::Serve { config_path },
+            ["check"] => Self::Check { config_path },
+            ["route-test", url] => Self::RouteTest {
+                config_path,
+                url: (*url).to_owned(),
+            },
+            ["cache", "ls"] => cache(CacheAction::Ls),
+            ["cache", "get", key] => cache(CacheAction::Get((*key).to_owned())),
+            ["cache", "rm", key] => cache(CacheAction::Rm((*key).to_owned())),
+            ["cache", "stats"] => cache(CacheAction::Stats),
+            ["cache", "migrate", old_origin, new_origin] => cache(CacheAction::Migrate {
+                old_origin: (*old_origin).to_owned(),
+                new_origin: (*new_origin).to_owned(),
+            }),
+            ["bench"] => Self::Bench {
+                config_path,
+                options: BenchOptions::from_options(&mut options).map_err(|error| usage(&error))?,
+            },
+            ["schema"] => Self::Schema,
+            _ => {
+                return Err(usage(&format!(
+                    "invalid arguments '{}'",
+                    positional_args.join(" ")
+                )))
+            }
+        };
+        if let Some(option) = options.keys().next() {
+            return Err(usage(&format!("unexpected option '{}'", option)));
+        }
+        Ok(command)
+    }
+}
+
+fn usage(error: &str) -> String {
+    format!(
+        "{}\n\nUSAGE:\n    addon_proxy [--config <path>]\n    addon_proxy check [--config <path>]\n    addon_proxy route-test <url> [--config <path>]\n    addon_proxy cache ls|get <key|url>|rm <key|url>|stats|migrate <old_origin> <new_origin> [--config <path>]\n    addon_proxy bench [--requests <count>] [--concurrency <count>] [--path <path>] [--url <proxy_url>] [--config <path>]\n    addon_proxy schema",
+        error
+    )
+}
+
+// ------ check ------
+
+/// Load and print the config (secrets are redacted) and its problems.
+///
+/// # Errors
+///
+/// Returns `String` error when the config cannot be loaded or it has problems.
+pub async fn check(config_path: PathBuf) -> Result<(), String> {
+    let proxy_config = ProxyConfig::load(&config_path)
+        .await
+        .map_err(|error| format!("cannot load '{}': {}", config_path.display(), error))?;
+
+    let config_json =
+        serde_json::to_string_pretty(&proxy_config).map_err(|error| error.to_string())?;
+    println!("{}", config_json);
+    println!(
+        "Listening address: {}:{}",
+        proxy_config.ip,
+        proxy_config.port()
+    );
+
+    let errors = match proxy_config.validate() {
+        Ok(()) => {
+            println!("Config '{}' is valid.", config_path.display());
+            return Ok(());
+        }
+        Err(errors) => errors,
+    };
+    Err(errors
+        .iter()
+        .map(|problem| format!("- {}", problem))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+// ------ schema ------
+
+/// Print JSON Schema of `ProxyConfig`.
+///
+/// # Errors
+///
+/// Returns `String` error when the schema serialization fails.
+pub fn schema() -> Result<(), String> {
+    let schema = schemars::schema_for!(ProxyConfig);
+    let schema_json = serde_json::to_string_pretty(&schema).map_err(|error| error.to_string())?;
+    println!("{}", schema_json);
+    Ok(())
+}
+
+// ------ route_test ------
+
+/// Print how the proxy would handle a GET request with the URL.
+///
+/// _Note:_ Requests to routes with `negotiate_manifest` are validated
+/// by the origin manifest only in the running proxy.
+///
+/// # Errors
+///
+/// Returns `String` error when the config cannot be loaded or the URL is invalid.
+pub async fn route_test(config_path: PathBuf, url: &str) -> Result<(), String> {
+    let proxy_config = ProxyConfig::load(&config_path)
+        .await
+        .map_err(|error| format!("cannot load '{}': {}", config_path.display(), error))?;
+    let report = test_route(url, &proxy_config)?;
+
+    let or_none = |value: Option<String>| value.unwrap_or_else(|| "-".to_owned());
+    println!("Route:        {}", or_none(report.route));
+    println!("Rule routing: {}", report.routed_by_rule);
+    println!(
+        "Upstream URI: {}",
+        or_none(report.upstream_uri.map(|uri| uri.to_string()))
+    );
+    println!(
+        "Validation:   {}",
+        match report.validation_passed {
+            Some(true) => "passed",
+            Some(false) => "failed",
+            None => "skipped",
+        }
+    );
+    println!(
+        "Rejected:     {}",
+        or_none(report.rejected_with.map(|status| status.to_string()))
+    );
+    println!("Cache key:    {}", or_none(report.cache_key));
+    Ok(())
+}
+
+// ------ cache ------
+
+/// Run the cache subcommand.
+///
+/// _Note:_ The database is locked by the running proxy, so the subcommand is sent
+/// to its admin API instead (see `cache_via_admin_api`).
+///
+/// # Errors
+///
+/// Returns `String` error when the config or the database cannot be loaded,
+/// or when the cached response doesn't exist.
+pub async fn cache(config_path: PathBuf, action: CacheAction) -> Result<(), String> {
+    let proxy_config = ProxyConfig::load(&config_path)
+        .await
+        .map_err(|error| format!("cannot load '{}': {}", config_path.display(), error))?;
+    let db = match sled::open(&proxy_config.db_directory) {
+        Ok(db) => db,
+        Err(db_error) => {
+            return cache_via_admin_api(action, &proxy_config)
+                .await
+                .map_err(|error| {
+                    format!(
+                        "cannot open database '{}': {}; admin API of the running proxy: {}",
+                        proxy_config.db_directory.display(),
+                        db_error,
+                        error
+                    )
+                });
+        }
+    };
+
+    match action {
+        CacheAction::Ls => {
+            for entry in db.iter() {
+                let (key, value) = entry.map_err(|error| error.to_string())?;
+                println!("{}  {}", hex(&key), describe_cached_response(&value));
+            }
+        }
+        CacheAction::Get(key_or_url) => {
+            let keys = cache_keys(&key_or_url, &proxy_config, &db)?
+                .into_iter()
+                .filter(|key| db.contains_key(key).unwrap_or_default())
+                .collect::<Vec<_>>();
+            if keys.is_empty() {
+                return Err(format!("no cached response of '{}' found", key_or_url));
+            }
+            for (index, key) in keys.into_iter().enumerate() {
+                if index > 0 {
+                    println!();
+                }
+                print_cached_response(&db, key, &proxy_config)?;
+            }
+        }
+        CacheAction::Rm(key_or_url) => {
+            let mut removed = 0;
+            for key in cache_keys(&key_or_url, &proxy_config, &db)? {
+                if cache::remove(&db, key)? {
+                    println!("Cached response {} removed.", hex(&key));
+                    removed += 1;
+                }
+            }
+            if removed == 0 {
+                return Err(format!("no cached response of '{}' found", key_or_url));
+            }
+            db.flush().map_err(|error| error.to_string())?;
+        }
+        CacheAction::Stats => {
+            let (mut responses, mut fresh, mut invalid, mut body_size) = (0, 0, 0, 0);
+            for entry in db.iter() {
+                let (_, value) = entry.map_err(|error| error.to_string())?;
+                responses += 1;
+                match bincode::deserialize::<CacheValueForDeserialization>(&value) {
+                    Ok(value) => {
+                        if value.is_fresh() {
+                            fresh += 1;
+                        }
+                        body_size += value.body.len();
+                    }
+                    Err(_) => invalid += 1,
+                }
+            }
+            println!("Responses:    {}", responses);
+            println!("Fresh:        {}", fresh);
+            println!("Stale:        {}", responses - fresh - invalid);
+            println!("Invalid:      {}", invalid);
+            println!("Body size:    {} B", body_size);
+            println!(
+                "Size on disk: {} B",
+                db.size_on_disk().map_err(|error| error.to_string())?
+            );
+        }
+        CacheAction::Migrate {
+            old_origin,
+            new_origin,
+        } => {
+            let parse_origin = |origin: &str| {
+                origin
+                    .parse::<Uri>()
+                    .map_err(|error| format!("invalid origin '{}': {}", origin, error))
+            };
+            let old_origin = parse_origin(&old_origin)?;
+            let new_origin = parse_origin(&new_origin)?;
+            // Catch typos - migrated responses would be unreachable otherwise.
+            let is_routed = proxy_config.routes.iter().any(|route| {
+                route.to.iter().any(|to| to == &new_origin)
+                    || route.to_by_region.values().any(|to| to == &new_origin)
+            }) || proxy_config
+                .rules
+                .iter()
+                .any(|rule| rule.route_to.as_ref() == Some(&new_origin));
+            if !is_routed {
+                return Err(format!(
+                    "no route or rule points to the new origin '{}'",
+                    new_origin
+                ));
+            }
+            let migration = cache::migrate(&db, &old_origin, &new_origin)?;
+            db.flush().map_err(|error| error.to_string())?;
+            println!(
+                "Migrated {} cached responses, skipped {} (cached per user or already cached for the new origin).",
+                migration.migrated, migration.skipped
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Run `ls`, `get <url>` or `rm <url>` through the admin API of the proxy running
+/// on the local machine (see `ProxyConfig::cache_inspect_url_path`,
+/// `ProxyConfig::cache_entry_url_path` and `ProxyConfig::admin_token`).
+///
+/// # Errors
+///
+/// Returns `String` error when the action or the admin endpoint isn't available,
+/// or when the request fails.
+async fn cache_via_admin_api(
+    action: CacheAction,
+    proxy_config: &ProxyConfig,
+) -> Result<(), String> {
+    let endpoint = |url_path: &Option<String>, field: &str| {
+        url_path
+            .clone()
+            .ok_or_else(|| format!("'{}' isn't configured", field))
+    };
+    let (method, path) = match &action {
+        CacheAction::Ls => (
+            Method::GET,
+            endpoint(
+                &proxy_config.cache_inspect_url_path,
+                "cache_inspect_url_path",
+            )?,
+        ),
+        CacheAction::Get(url) | CacheAction::Rm(url) if url.contains("://") => {
+            let method = match action {
+                CacheAction::Rm(_) => Method::DELETE,
+                _ => Method::GET,
+            };
+            let path = endpoint(&proxy_config.cache_entry_url_path, "cache_entry_url_path")?;
+            (method, format!("{}?uri={}", path, url))
+        }
+        _ => return Err("stop the proxy or use the URL instead of the cache key".to_owned()),
+    };
+
+    // NOTE: DNS can be slow, use rather IP.
+    let mut request = Request::builder().method(method).uri(format!(
+        "http://127.0.0.1:{}{}",
+        proxy_config.port(),
+        path
+    ));
+    if let Some(admin_token) = &proxy_config.admin_token {
+        request = request.header(ADMIN_TOKEN_HEADER, admin_token.as_str());
+    }
+    let request = request
+        .body(Body::empty())
+        .map_err(|error| error.to_string())?;
+    let response = Client::new()
+        .request(request)
+        .await
+        .map_err(|error| error.to_string())?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|error| error.to_string())?;
+    match status {
+        StatusCode::OK => (),
+        StatusCode::NOT_FOUND => return Err("no cached response found".to_owned()),
+        status => {
+            return Err(format!(
+                "{}: {}",
+                status,
+                String::from_utf8_lossy(&body).trim()
+            ))
+        }
+    }
+    let report = serde_json::from_slice::<Value>(&body).map_err(|error| error.to_string())?;
+    let entries = report["entries"].as_array().cloned().unwrap_or_default();
+
+    match action {
+        CacheAction::Ls => {
+            for entry in entries {
+                println!(
+                    "{}  {} {}  {}  {:>8} B{}",
+                    entry["key"].as_str().unwrap_or_default(),
+                    entry["method"].as_str().unwrap_or_default(),
+                    entry["uri"].as_str().unwrap_or_default(),
+                    entry["status"],
+                    entry["size"],
+                    if entry["fresh"] == true {
+                        ""
+                    } else {
+                        "  (stale)"
+                    }
+                );
+            }
+            if report["truncated"] == true {
+                println!("(truncated, stop the proxy to list all cached responses)");
+            }
+        }
+        CacheAction::Rm(_) => println!("Removed {} cached response(s).", entries.len()),
+        _ => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).map_err(|error| error.to_string())?
+        ),
+    }
+    Ok(())
+}
+
+/// Print the cached response with the key.
+fn print_cached_response(db: &Db, key: [u8; 8], proxy_config: &ProxyConfig) -> Result<(), String> {
+    let value = get_cached_response(db, key)?;
+    let value = bincode::deserialize::<CacheValueForDeserialization>(&value)
+        .map_err(|error| format!("invalid cached response: {}", error))?;
+    println!("Key:      {}", hex(&key));
+    println!("Request:  {} {}", value.request.method, value.request.uri);
+    println!("Status:   {}", value.status);
+    println!("Age:      {} s", value.age());
+    println!("Validity: {} s", value.validity);
+    println!("Cached:   {}", value.timestamp);
+    println!("Reason:   {:?}", value.metadata.reason);
+    if let Some(route) = &value.metadata.route {
+        println!("Route:    {}", route);
+    }
+    if !value.metadata.original_uri.is_empty() {
+        println!("Original: {}", value.metadata.original_uri);
+    }
+    if !value.metadata.tags.is_empty() {
+        println!("Tags:     {}", value.metadata.tags.join(", "));
+    }
+    for (name, header_value) in &value.headers {
+        println!(
+            "{}: {}",
+            name,
+            proxy_config.loggable_header_value(name.as_str(), header_value.as_bytes())
+        );
+    }
+    println!();
+    println!("{}", String::from_utf8_lossy(&value.body));
+    Ok(())
+}
+
+fn get_cached_response(db: &Db, key: [u8; 8]) -> Result<sled::IVec, String> {
+    db.get(key)
+        .map_err(|error| error.to_string())?
+        .ok_or_else(|| format!("cached response {} not found", hex(&key)))
+}
+
+/// Parse the hex key printed by `cache ls` or find keys of all responses cached for the URL
+/// (incl. `Vary` variants - see `cache::keys_of_uri`) and the key of a GET request with the URL.
+fn cache_keys(
+    key_or_url: &str,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+) -> Result<Vec<[u8; 8]>, String> {
+    if !key_or_url.contains("://") {
+        return parse_cache_key(key_or_url).map(|key| vec![key]);
+    }
+    let report = test_route(key_or_url, proxy_config)?;
+    let (hex_key, upstream_uri) = match (report.cache_key, report.upstream_uri) {
+        (Some(hex_key), Some(upstream_uri)) => (hex_key, upstream_uri),
+        _ => return Err(format!("URL '{}' isn't routed to any origin", key_or_url)),
+    };
+    let mut keys = cache::keys_of_uri(
+        db,
+        &cache::cached_uri(&upstream_uri, proxy_config.cache_key.as_ref()),
+    )?;
+    // `VaryHeaders` names are stored under the key of the request.
+    let key = parse_cache_key(&hex_key)?;
+    if !keys.contains(&key) {
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+fn parse_cache_key(hex_key: &str) -> Result<[u8; 8], String> {
+    let bytes = (0..hex_key.len())
+        .step_by(2)
+        .map(|index| {
+            hex_key
+                .get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<_>>>();
+    bytes
+        .and_then(|bytes| <[u8; 8]>::try_from(bytes.as_slice()).ok())
+        .ok_or_else(|| format!("invalid cache key '{}'", hex_key))
+}
+
+fn describe_cached_response(value: &[u8]) -> String {
+    match bincode::deserialize::<CacheValueForDeserialization>(value) {
+        Ok(value) => format!(
+            "{} {}  {}  {:>8} B  age {} s / {} s{}{}",
+            value.request.method,
+            value.request.uri,
+            value.status.as_u16(),
+            value.body.len(),
+            value.age(),
+            value.validity,
+            if value.is_fresh() { "" } else { "  (stale)" },
+            if value.is_negative() {
+                "  (origin failure)"
+            } else {
+                ""
+            }
+        ),
+        Err(_) => "invalid".to_owned(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| (*arg).to_owned()).collect()
+    }
+
+    #[test]
+    fn parse_serve() {
+        assert_eq!(
+            Command::parse(args(&[])),
+            Ok(Command::Serve {
+                config_path: PathBuf::from(DEFAULT_CONFIG_PATH)
+            })
+        );
+    }
+
+    #[test]
+    fn parse_check() {
+        assert_eq!(
+            Command::parse(args(&["check", "--config", "my_config.toml"])),
+            Ok(Command::Check {
+                config_path: PathBuf::from("my_config.toml")
+            })
+        );
+        assert!(Command::parse(args(&["check", "--config"])).is_err());
+        assert!(Command::parse(args(&["check", "check"])).is_err());
+    }
+
+    #[test]
+    fn parse_route_test() {
+        assert_eq!(
+            Command::parse(args(&["route-test", "http://example.com/manifest.json"])),
+            Ok(Command::RouteTest {
+                config_path: PathBuf::from(DEFAULT_CONFIG_PATH),
+                url: "http://example.com/manifest.json".to_owned()
+            })
+        );
+        assert!(Command::parse(args(&["route-test"])).is_err());
+    }
+
+    #[test]
+    fn parse_cache() {
+        assert_eq!(
+            Command::parse(args(&[
+                "cache",
+                "rm",
+                "0011223344556677",
+                "--config",
+                "a.toml"
+            ])),
+            Ok(Command::Cache {
+                config_path: PathBuf::from("a.toml"),
+                action: CacheAction::Rm("0011223344556677".to_owned())
+            })
+        );
+        assert_eq!(
+            Command::parse(args(&[
+                "cache",
+                "migrate",
+                "http://old.com",
+                "http://new.com"
+            ])),
+            Ok(Command::Cache {
+                config_path: PathBuf::from(DEFAULT_CONFIG_PATH),
+                action: CacheAction::Migrate {
+                    old_origin: "http://old.com".to_owned(),
+                    new_origin: "http://new.com".to_owned()
+                }
+            })
+        );
+        assert!(Command::parse(args(&["cache", "get"])).is_err());
+        assert!(Command::parse(args(&["cache", "ls", "--requests", "10"])).is_err());
+    }
+
+    #[test]
+    fn parse_bench() {
+        assert_eq!(
+            Command::parse(args(&["bench", "--requests", "100", "--concurrency", "10"])),
+            Ok(Command::Bench {
+                config_path: PathBuf::from(DEFAULT_CONFIG_PATH),
+                options: BenchOptions {
+                    requests: 100,
+                    concurrency: 10,
+                    path: "/status".to_owned(),
+                    url: None,
+                }
+            })
+        );
+        assert!(Command::parse(args(&["bench", "--concurrency", "0"])).is_err());
+        assert!(Command::parse(args(&["bench", "--requests", "many"])).is_err());
+    }
+
+    #[test]
+    fn parse_schema() {
+        assert_eq!(Command::parse(args(&["schema"])), Ok(Command::Schema));
+        assert!(Command::parse(args(&["schema", "ProxyRoute"])).is_err());
+    }
+
+    #[test]
+    fn config_schema() {
+        let schema = serde_json::to_value(schemars::schema_for!(ProxyConfig)).unwrap();
+        assert!(schema["required"]
+            .as_array()
+            .unwrap()
+            .contains(&"routes".into()));
+        // `version` is set by `ProxyConfig::load`.
+        assert!(schema["properties"].get("version").is_none());
+        let rule = &schema["definitions"]["ProxyRule"]["properties"];
+        assert_eq!(rule["path"]["type"], serde_json::json!(["string", "null"]));
+        assert_eq!(rule["deny"]["format"], "uint16");
+        // One origin or a list of origin replicas.
+        let to = &schema["definitions"]["Upstreams"]["anyOf"];
+        assert_eq!(to[0]["type"], "string");
+        assert_eq!(to[1]["type"], "array");
+    }
+}
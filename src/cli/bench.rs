@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use hyper::{Client, StatusCode, Uri};
+
+use crate::proxy::ProxyConfig;
+
+// ------ BenchOptions ------
+
+/// See `Command::Bench`.
+#[derive(Debug, PartialEq)]
+pub struct BenchOptions {
+    /// The number of all requests (default is 10 000).
+    pub requests: usize,
+    /// The number of concurrent clients (default is 100).
+    pub concurrency: usize,
+    /// Requested path (default is `/status`).
+    pub path: String,
+    /// Proxy URL (default is `http://127.0.0.1:<port>` with the port from the config).
+    pub url: Option<String>,
+}
+
+impl BenchOptions {
+    /// Take bench options from `--name value` pairs.
+    ///
+    /// # Errors
+    ///
+    /// Returns `String` error when an option value is invalid.
+    pub(super) fn from_options(options: &mut BTreeMap<String, String>) -> Result<Self, String> {
+        let bench_options = Self {
+            requests: parse_option(options, "--requests")?.unwrap_or(10_000),
+            concurrency: parse_option(options, "--concurrency")?.unwrap_or(100),
+            path: options
+                .remove("--path")
+                .unwrap_or_else(|| "/status".to_owned()),
+            url: options.remove("--url"),
+        };
+        if bench_options.concurrency == 0 || bench_options.requests < bench_options.concurrency {
+            return Err("'--concurrency' has to be between 1 and '--requests'".to_owned());
+        }
+        Ok(bench_options)
+    }
+}
+
+fn parse_option<T: FromStr>(
+    options: &mut BTreeMap<String, String>,
+    name: &str,
+) -> Result<Option<T>, String> {
+    options
+        .remove(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| format!("invalid value '{}' of '{}'", value, name))
+        })
+        .transpose()
+}
+
+// ------ bench ------
+
+/// Send GET requests to the running proxy and print requests per second and latency percentiles.
+///
+/// Each concurrent client sends its requests sequentially -
+/// it's the same scenario as in `benches/proxy_benchmark.rs`.
+///
+/// # Errors
+///
+/// Returns `String` error when the config cannot be loaded or the URL is invalid.
+pub async fn bench(config_path: PathBuf, options: BenchOptions) -> Result<(), String> {
+    let proxy_url = match options.url.clone() {
+        Some(url) => url,
+        None => {
+            let proxy_config = ProxyConfig::load(&config_path)
+                .await
+                .map_err(|error| format!("cannot load '{}': {}", config_path.display(), error))?;
+            // NOTE: DNS can be slow, use rather IP.
+            format!("http://127.0.0.1:{}", proxy_config.port())
+        }
+    };
+    let url = format!("{}{}", proxy_url.trim_end_matches('/'), options.path)
+        .parse::<Uri>()
+        .map_err(|error| format!("invalid URL: {}", error))?;
+
+    let client = Client::new();
+    let sequence_length = options.requests / options.concurrency;
+
+    let bench_start = Instant::now();
+    let results = join_all((0..options.concurrency).map(|_| {
+        let client = &client;
+        let url = &url;
+        async move {
+            let mut latencies = Vec::with_capacity(sequence_length);
+            let mut failures = 0;
+            for _ in 0..sequence_length {
+                let now = Instant::now();
+                let response = match client.get(url.clone()).await {
+                    Ok(response) => response,
+                    Err(_) => {
+                        failures += 1;
+                        continue;
+                    }
+                };
+                let status = response.status();
+                // Read response body until the end.
+                if hyper::body::to_bytes(response.into_body()).await.is_err()
+                    || status != StatusCode::OK
+                {
+                    failures += 1;
+                    continue;
+                }
+                latencies.push(now.elapsed());
+            }
+            (latencies, failures)
+        }
+    }))
+    .await;
+    let bench_time = bench_start.elapsed();
+
+    let mut latencies = Vec::with_capacity(options.requests);
+    let mut failures = 0;
+    for (client_latencies, client_failures) in results {
+        latencies.extend(client_latencies);
+        failures += client_failures;
+    }
+    latencies.sort();
+
+    let all_requests = sequence_length * options.concurrency;
+    println!("URL ...................................... {}", url);
+    println!(
+        "Number of all requests ................... {}",
+        all_requests
+    );
+    println!(
+        "Number of concurrent clients ............. {}",
+        options.concurrency
+    );
+    println!("Failed requests (incl. non-200) .......... {}", failures);
+    println!(
+        "Bench time ............................... {:#?}",
+        bench_time
+    );
+    println!(
+        "Requests per second ...................... {:.0}",
+        all_requests as f64 / bench_time.as_secs_f64()
+    );
+    for &percentile in &[50, 90, 99, 100] {
+        if let Some(latency) = latency_percentile(&latencies, percentile) {
+            println!(
+                "Latency p{:<3} ............................ {:#?}",
+                percentile, latency
+            );
+        }
+    }
+    Ok(())
+}
+
+/// `sorted_latencies` have to be sorted in ascending order.
+fn latency_percentile(sorted_latencies: &[Duration], percentile: usize) -> Option<Duration> {
+    if sorted_latencies.is_empty() {
+        return None;
+    }
+    let index = (sorted_latencies.len() - 1) * percentile / 100;
+    sorted_latencies.get(index).copied()
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let latencies = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(
+            latency_percentile(&latencies, 50),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            latency_percentile(&latencies, 99),
+            Some(Duration::from_millis(99))
+        );
+        assert_eq!(
+            latency_percentile(&latencies, 100),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(latency_percentile(&[], 50), None);
+    }
+}
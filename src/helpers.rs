@@ -1,22 +1,195 @@
-use chrono::Utc;
+use chrono::{TimeZone, Utc};
 use once_cell::sync::Lazy;
+use std::future::Future;
 use std::sync::{Arc, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
 
 // ------ now_timestamp ------
 
-static NOW_GETTER: Lazy<RwLock<Arc<dyn Fn() -> i64 + Send + Sync>>> =
-    Lazy::new(|| RwLock::new(Arc::new(|| Utc::now().timestamp())));
+/// A function that returns the current timestamp.
+pub type NowGetter = Arc<dyn Fn() -> i64 + Send + Sync>;
 
+// `None` means the system clock.
+static NOW_GETTER: Lazy<RwLock<Option<NowGetter>>> = Lazy::new(|| RwLock::new(None));
+
+tokio::task_local! {
+    static SCOPED_NOW_GETTER: NowGetter;
+}
+
+/// Set the global now getter.
+///
+/// _Note:_ It affects all tasks without their own scoped now getter (see `with_now_getter`).
 pub fn set_now_getter(now: impl Fn() -> i64 + Send + Sync + 'static) {
-    *NOW_GETTER.write().unwrap() = Arc::new(now);
+    *NOW_GETTER.write().unwrap() = Some(Arc::new(now));
+}
+
+/// Run the `future` with its own now getter.
+///
+/// It allows to manipulate with time in parallel tests
+/// or to provide a custom clock without affecting other tasks.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let timestamp = with_now_getter(Arc::new(|| 0), async { now_timestamp() }).await;
+/// assert_eq!(timestamp, 0);
+/// ```
+pub async fn with_now_getter<F: Future>(now_getter: NowGetter, future: F) -> F::Output {
+    SCOPED_NOW_GETTER.scope(now_getter, future).await
+}
+
+/// Run the `future` with the given now getter or with the global one when it's `None`.
+pub async fn with_optional_now_getter<F: Future>(
+    now_getter: Option<NowGetter>,
+    future: F,
+) -> F::Output {
+    match now_getter {
+        Some(now_getter) => with_now_getter(now_getter, future).await,
+        None => future.await,
+    }
+}
+
+/// Get the now getter of the current task set by `with_now_getter`.
+pub fn scoped_now_getter() -> Option<NowGetter> {
+    SCOPED_NOW_GETTER.try_with(Arc::clone).ok()
+}
+
+/// Spawn a new task with the given now getter (see `with_optional_now_getter`).
+///
+/// Spawned tasks don't inherit the scoped now getter of their parent task,
+/// pass `scoped_now_getter()` to keep it.
+pub fn spawn_with_now_getter<F>(now_getter: Option<NowGetter>, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    // Boxed to not copy big futures on the stack while they are wrapped.
+    tokio::spawn(with_optional_now_getter(now_getter, Box::pin(future)))
 }
 
 /// Get the current timestamp.
 ///
 /// It should be used instead of `chrono::Utc::now()` to allow time manipulation in tests.
 ///
+/// The scoped now getter (see `with_now_getter`) has a higher priority than the global one.
+///
 /// There shouldn't be any noticeable performance penalties according to the benchmarks.
 #[allow(clippy::must_use_candidate)]
 pub fn now_timestamp() -> i64 {
-    NOW_GETTER.read().unwrap()()
+    custom_now_timestamp().unwrap_or_else(|| Utc::now().timestamp())
+}
+
+/// Get the timestamp from the scoped or global now getter, `None` when the system clock is used.
+fn custom_now_timestamp() -> Option<i64> {
+    SCOPED_NOW_GETTER
+        .try_with(|now_getter| now_getter())
+        .ok()
+        .or_else(|| {
+            NOW_GETTER
+                .read()
+                .unwrap()
+                .as_ref()
+                .map(|now_getter| now_getter())
+        })
+}
+
+// ------ http_date ------
+
+/// Format the timestamp as an HTTP date (e.g. `Sun, 13 Sep 2020 12:26:40 GMT`)
+/// for headers like `Date` or `Expires`.
+#[allow(clippy::must_use_candidate)]
+pub fn http_date(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap())
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+// ------ monotonic_timestamp ------
+
+static PROCESS_START: Lazy<Instant> = Lazy::new(Instant::now);
+
+static PROCESS_START_TIMESTAMP: Lazy<i64> = Lazy::new(|| Utc::now().timestamp());
+
+static BOOT_ID: Lazy<u64> = Lazy::new(|| {
+    #[allow(clippy::cast_possible_truncation)]
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or_default();
+    nanos ^ u64::from(std::process::id()).rotate_left(32)
+});
+
+/// Get the number of seconds elapsed since the process start.
+///
+/// Unlike `now_timestamp`, it isn't affected by system clock changes,
+/// but it's meaningful only within one process - see `boot_id`.
+///
+/// A custom now getter (see `set_now_getter` and `with_now_getter`) drives it instead
+/// of the process uptime, so time manipulation affects both clocks.
+#[allow(clippy::must_use_candidate, clippy::cast_possible_wrap)]
+pub fn monotonic_timestamp() -> i64 {
+    match custom_now_timestamp() {
+        Some(now) => now.saturating_sub(*PROCESS_START_TIMESTAMP),
+        None => PROCESS_START.elapsed().as_secs() as i64,
+    }
+}
+
+/// Get the identifier of the current process run.
+///
+/// Monotonic timestamps with different boot ids can't be compared.
+#[allow(clippy::must_use_candidate)]
+pub fn boot_id() -> u64 {
+    *BOOT_ID
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::join;
+
+    #[tokio::test]
+    async fn scoped_now_getters() {
+        let (first, second) = join(
+            with_now_getter(Arc::new(|| 10), async { now_timestamp() }),
+            with_now_getter(Arc::new(|| 20), async { now_timestamp() }),
+        )
+        .await;
+        assert_eq!(first, 10);
+        assert_eq!(second, 20);
+        assert!(now_timestamp() > 20);
+    }
+
+    #[tokio::test]
+    async fn spawned_task_keeps_now_getter() {
+        let timestamp = with_now_getter(Arc::new(|| 10), async {
+            let spawned = spawn_with_now_getter(scoped_now_getter(), async { now_timestamp() });
+            spawned.await.unwrap()
+        })
+        .await;
+        assert_eq!(timestamp, 10);
+        assert!(scoped_now_getter().is_none());
+    }
+
+    #[test]
+    fn http_dates() {
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(1_600_000_000), "Sun, 13 Sep 2020 12:26:40 GMT");
+    }
+
+    #[tokio::test]
+    async fn monotonic_timestamp_follows_now_getter() {
+        let (before, after) = join(
+            with_now_getter(Arc::new(|| 1_000_000), async { monotonic_timestamp() }),
+            with_now_getter(Arc::new(|| 1_000_600), async { monotonic_timestamp() }),
+        )
+        .await;
+        assert_eq!(after - before, 600);
+        assert!(monotonic_timestamp() >= 0);
+        assert_eq!(boot_id(), boot_id());
+    }
 }
@@ -1,6 +1,12 @@
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
 use futures_util::future::Future;
+use futures_util::stream::{self, StreamExt};
 use hyper::body::Bytes;
 use hyper::{Body, Request, Response};
+use tokio::time;
 
 /// Convert `Request/Response` body from `Body` to `Bytes`.
 ///
@@ -16,6 +22,13 @@ pub async fn bytes_to_body(bytes: Bytes) -> Result<Body, hyper::Error> {
     Ok(Body::from(bytes))
 }
 
+/// Response extension of responses whose body is streamed to the client
+/// without buffering in memory (e.g. see `ProxyConfig::max_cacheable_body_size`).
+///
+/// _Note:_ Responses created from buffered bodies (e.g. by `clone_response`) don't have it.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamedBody;
+
 /// Map `Request` body.
 ///
 /// Standard `Body` is a `Stream` so this function is `async` to allow to aggregate `Stream` to vectors.
@@ -48,18 +61,6 @@ where
     Ok(Response::from_parts(parts, mapped_body))
 }
 
-/// Clone `Request`.
-///
-/// _Warning:_: Extensions cannot be cloned.
-pub fn clone_request<T: Clone>(req: &Request<T>) -> Request<T> {
-    let mut new_req = Request::new(req.body().clone());
-    *new_req.method_mut() = req.method().clone();
-    *new_req.uri_mut() = req.uri().clone();
-    *new_req.version_mut() = req.version();
-    *new_req.headers_mut() = req.headers().clone();
-    new_req
-}
-
 /// Clone `Response`.
 ///
 /// _Warning:_: Extensions cannot be cloned.
@@ -84,3 +85,110 @@ pub async fn fork_response(
         map_response_body(clone_response(&response_with_byte_body), bytes_to_body).await?;
     Ok((response, response_with_byte_body))
 }
+
+/// Read `Body` into `Bytes` if it isn't bigger than `max_size` (in bytes).
+///
+/// Returns `Err` with the equivalent `Body` when the body is too big, so the rest of the body
+/// can be streamed without buffering.
+pub async fn body_to_bytes_with_limit(
+    mut body: Body,
+    max_size: usize,
+) -> Result<Result<Bytes, Body>, hyper::Error> {
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        size += chunk.len();
+        chunks.push(chunk);
+        if size > max_size {
+            return Ok(Err(prepend_chunks(chunks, body)));
+        }
+    }
+    Ok(Ok(chunks.concat().into()))
+}
+
+/// Put already read `chunks` back before the rest of the `body`.
+pub fn prepend_chunks(chunks: Vec<Bytes>, body: Body) -> Body {
+    let read_chunks = stream::iter(chunks.into_iter().map(Ok::<_, hyper::Error>));
+    Body::wrap_stream(read_chunks.chain(body))
+}
+
+/// The error of bodies not read completely before their deadline (see `with_body_deadline`).
+#[derive(Debug)]
+pub struct BodyDeadlineExceeded;
+
+impl fmt::Display for BodyDeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "body deadline exceeded")
+    }
+}
+
+impl Error for BodyDeadlineExceeded {}
+
+/// Fail the `Body` with `BodyDeadlineExceeded` when it isn't read completely before `deadline`
+/// (e.g. when the origin sends the body too slowly).
+pub fn with_body_deadline(body: Body, deadline: Instant) -> Body {
+    let deadline = time::Instant::from_std(deadline);
+    let chunks = stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match time::timeout_at(deadline, body.next()).await {
+            Ok(Some(chunk)) => Some((
+                chunk.map_err(|error| Box::new(error) as Box<dyn Error + Send + Sync>),
+                Some(body),
+            )),
+            Ok(None) => None,
+            Err(_) => Some((Err(Box::new(BodyDeadlineExceeded) as _), None)),
+        }
+    });
+    Body::wrap_stream(chunks)
+}
+
+/// The error of bodies transferred too slowly (see `with_min_transfer_rate`).
+#[derive(Debug)]
+pub struct BodyTooSlow;
+
+impl fmt::Display for BodyTooSlow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "body transferred too slowly")
+    }
+}
+
+impl Error for BodyTooSlow {}
+
+/// Fail the `Body` with `BodyTooSlow` when its average transfer rate falls below
+/// `bytes_per_second` after `grace_period` (e.g. when the origin stalls mid-body).
+pub fn with_min_transfer_rate(body: Body, bytes_per_second: u64, grace_period: Duration) -> Body {
+    let start = time::Instant::now();
+    let chunks = stream::unfold(Some((body, 0_u64)), move |state| async move {
+        let (mut body, transferred) = state?;
+        // The rate falls below the minimum when the next chunk doesn't arrive before the deadline.
+        let transfer_time =
+            Duration::from_millis(transferred.saturating_mul(1000) / bytes_per_second.max(1));
+        let deadline = start + grace_period.max(transfer_time);
+        match time::timeout_at(deadline, body.next()).await {
+            Ok(Some(Ok(chunk))) => {
+                let transferred = transferred.saturating_add(chunk.len() as u64);
+                Some((Ok(chunk), Some((body, transferred))))
+            }
+            Ok(Some(Err(error))) => Some((
+                Err(Box::new(error) as Box<dyn Error + Send + Sync>),
+                Some((body, transferred)),
+            )),
+            Ok(None) => None,
+            Err(_) => Some((Err(Box::new(BodyTooSlow) as _), None)),
+        }
+    });
+    Body::wrap_stream(chunks)
+}
+
+/// The error has been caused by the error `E` (e.g. `BodyDeadlineExceeded`).
+pub fn is_caused_by<E: Error + 'static>(error: &hyper::Error) -> bool {
+    let mut source = error.source();
+    while let Some(error) = source {
+        if error.is::<E>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
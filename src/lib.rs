@@ -1,5 +1,8 @@
+pub mod cli;
 pub mod helpers;
 pub mod proxy;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub use proxy::*;
 
 mod hyper_helpers;
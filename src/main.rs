@@ -1,6 +1,37 @@
+use std::{env, process};
+
+use ::addon_proxy::cli::{self, Command};
 use ::addon_proxy::{default_client, on_request, Proxy};
 
 #[tokio::main]
 async fn main() {
-    Proxy::new(default_client, on_request).start().await
+    let command = Command::parse(env::args().skip(1)).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        process::exit(2)
+    });
+    let result = match command {
+        Command::Serve { config_path } => {
+            Proxy::new(default_client, on_request)
+                .set_config_path(config_path)
+                .set_handle_signals(true)
+                .start()
+                .await;
+            Ok(())
+        }
+        Command::Check { config_path } => cli::check(config_path).await,
+        Command::RouteTest { config_path, url } => cli::route_test(config_path, &url).await,
+        Command::Cache {
+            config_path,
+            action,
+        } => cli::cache(config_path, action).await,
+        Command::Bench {
+            config_path,
+            options,
+        } => cli::bench(config_path, options).await,
+        Command::Schema => cli::schema(),
+    };
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(1)
+    }
 }
@@ -1,36 +1,116 @@
+use std::any::Any;
 use std::convert::Infallible;
-use std::env;
 use std::future::Future;
 use std::marker::PhantomData;
 use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
+use futures_util::future::FutureExt;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Client, Request, Response, Server};
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode, Uri};
 
 use tokio::sync::{mpsc, oneshot, watch};
-use tokio::task;
+use tokio::{task, time};
+use tracing::{error, info, warn};
 
 use shadow_clone::shadow_clone;
 
+use crate::helpers::{self, NowGetter};
+
+mod addon_collection;
+mod body_transformers;
+pub(crate) mod cache;
+mod cache_seed;
+mod cache_sweeper;
+mod compression;
 mod config;
 mod controller;
 mod default_client;
+mod dns_failover;
+mod error_budget;
+mod error_kind;
+mod fair_queue;
+#[cfg(feature = "geoip")]
+mod geoip;
+mod hardening;
+mod health_check;
+mod hooks;
+mod idempotency;
+mod latency_slo;
+mod load_balancer;
+mod logging;
+mod manifest;
+mod metrics;
 mod on_request;
+mod prefetch;
+mod profiler;
+mod revalidation;
+mod route_trie;
+mod rules;
+#[cfg(feature = "scripting")]
+mod scripting;
+mod signing;
+mod state;
+mod stats;
 mod validations;
+mod virtual_endpoints;
 
-pub use config::{ProxyConfig, ProxyRoute};
-pub use controller::ProxyController;
+pub use addon_collection::CollectionRoutes;
+pub use body_transformers::{
+    transform_body, transform_response, BodyTransformer, JsonFilter, Replace,
+};
+pub use cache::{CacheHits, CacheValueForDeserialization};
+pub use cache_seed::CacheSeed;
+pub use config::{
+    AddonCollectionConfig, CacheBypassConfig, CacheKeyConfig, CachePartitionConfig,
+    CompressionConfig, ConfigVersion, CorsConfig, DnsFailoverConfig, ErrorBudgetConfig,
+    FairQueueConfig, GeoIpConfig, HappyEyeballsConfig, HeaderLimitPolicy, HealthChecksConfig,
+    IdempotencyConfig, JsonFilterConfig, LatencySlo, LatencySloAlertsConfig, LibraryModeConfig,
+    LoadBalancingStrategy, LogFormat, LogLevel, MetricsConfig, MetricsPushProtocol,
+    MinTransferRateConfig, PrefetchConfig, PreflightConfig, ProfilingConfig, ProxyConfig,
+    ProxyRoute, ProxyRule, RequestDeadlineConfig, ResourceTtlsConfig, ResponseHeaderLimitsConfig,
+    ResponseSigningConfig, RevalidationConfig, RouteGroup, ScriptConfig, SigningProfile,
+    TrustedProxy, Upstreams, VirtualEndpoint,
+};
+pub use controller::{ProxyController, ShutdownReason, ShutdownSummary};
 pub use default_client::default_client;
-pub use on_request::on_request;
+pub use dns_failover::FailoverConnector;
+pub use error_budget::ErrorBudgets;
+pub use error_kind::ProxyErrorKind;
+pub use fair_queue::{FairQueue, FairQueuePermit, QueueFull};
+pub use health_check::{UpstreamHealth, UpstreamStatus};
+pub use hooks::{
+    CacheEvent, OnCacheEvent, OnUpstreamConnect, OnUpstreamError, ProxyHooks, UpstreamError,
+    UpstreamRequest,
+};
+pub use latency_slo::{LatencySlos, SloEvaluation};
+pub use load_balancer::{LoadBalancer, UpstreamConnection};
+pub use manifest::{
+    AddonCapabilities, AddonProtocol, CatalogCapability, ManifestRegistry, ResourceCapability,
+};
+pub use on_request::{on_request, test_route, RouteTestReport, ADMIN_TOKEN_HEADER};
+pub use profiler::{Profiler, RequestProfile, StageProfile};
+pub use revalidation::RevalidationQueue;
+pub use route_trie::{IndexedRoutes, RouteIndex, RouteTrie};
+pub use state::ProxyState;
+pub use stats::{InFlightGuard, Stats, StatsSnapshot};
 
 pub const DEFAULT_CONFIG_PATH: &str = "proxy_config.toml";
 
 // ------ Proxy ------
 
 /// See documentation for `Proxy` field `on_request`.
-pub type ScheduleConfigReload = Arc<dyn Fn() + Send + Sync>;
+pub type ScheduleConfigReload = Arc<dyn Fn() -> ConfigReload + Send + Sync>;
+/// Resolves to the epoch of the reloaded config or to the reasons why the config
+/// hasn't been applied (see `ProxyConfig::validate`).
+///
+/// The reload continues when it's dropped.
+pub type ConfigReload = oneshot::Receiver<Result<u64, Vec<String>>>;
 pub type Db = sled::Db;
 
 /// Represents a proxy server.
@@ -80,24 +160,27 @@ pub struct Proxy<C, B, CC, OR, ORO> {
     ///
     /// # Arguments
     ///
-    /// - `req` - The original request.
+    /// - `req` - The original request. The client's address is stored
+    ///    in its extensions (see `RemoteAddr`).
     ///
     /// - `client` - The client set in the `Proxy` instance.
     ///
     /// - `proxy_config` - A configuration loaded from `proxy_config.toml`.
     ///
     /// - `schedule_config_reload` - The configuration will be reloaded and passed
-    ///    to new requests after the call.
+    ///    to new requests after the call, unless it's invalid (see `ConfigReload`).
     ///
     /// - `db` - Persistent storage to support features like caching.
     ///
+    /// - `state` - Runtime state shared by all requests (e.g. the fair queue).
+    ///
     /// # Example
     ///
     /// ```rust,ignore
     /// use std::sync::Arc;
     /// use hyper::{Body, Client, Request, Response};
     /// use hyper::client::HttpConnector;
-    /// use proxy::{ProxyConfig, ScheduleConfigReload, Db};
+    /// use proxy::{ProxyConfig, ProxyState, ScheduleConfigReload, Db};
     ///
     /// pub async fn on_request(
     ///     req: Request<Body>,
@@ -105,6 +188,7 @@ pub struct Proxy<C, B, CC, OR, ORO> {
     ///     proxy_config: Arc<ProxyConfig>,
     ///     schedule_config_reload: ScheduleConfigReload,
     ///     db: Db,
+    ///     state: Arc<ProxyState>,
     /// ) -> Result<Response<Body>, hyper::Error> {
     ///     println!("original req: {:#?}", req);
     ///     let req = try_map_request(req, &proxy_config, schedule_config_reload, &db);
@@ -128,7 +212,22 @@ pub struct Proxy<C, B, CC, OR, ORO> {
 
     // Callback `on_server_stop` is invoked when the server has been stopped
     /// and all resources have been freed.
-    pub on_server_stop: Option<Box<dyn FnOnce() + Send>>,
+    pub on_server_stop: Option<Box<dyn FnOnce(ShutdownSummary) + Send>>,
+    /// A custom clock used by all requests processed by this `Proxy` and by its background tasks.
+    /// A custom clock used by all requests processed by this `Proxy`.
+    ///
+    /// The global now getter (see `helpers::set_now_getter`) is used when it's `None`.
+    pub now_getter: Option<NowGetter>,
+
+    /// Callbacks invoked while requests are handled - see `ProxyHooks`.
+    ///
+    /// They're passed to `on_request` in `ProxyState::hooks`.
+    pub hooks: ProxyHooks,
+
+    /// Stop the server gracefully on SIGTERM or SIGINT (Ctrl+C on Windows) like `ProxyController::stop`.
+    ///
+    /// In-flight requests are finished during `ProxyConfig::shutdown_grace_period`. Default is `false`.
+    pub handle_signals: bool,
 
     _phantom: (PhantomData<C>, PhantomData<B>, PhantomData<ORO>),
 }
@@ -139,7 +238,14 @@ where
     B: Send + 'static,
     CC: Send + Fn(&ProxyConfig) -> Client<C, B>,
     ORO: Future<Output = Result<Response<Body>, hyper::Error>> + Send,
-    OR: Fn(Request<Body>, Arc<Client<C, B>>, Arc<ProxyConfig>, ScheduleConfigReload, Db) -> ORO
+    OR: Fn(
+            Request<Body>,
+            Arc<Client<C, B>>,
+            Arc<ProxyConfig>,
+            ScheduleConfigReload,
+            Db,
+            Arc<ProxyState>,
+        ) -> ORO
         + Send
         + Sync
         + Copy
@@ -169,6 +275,9 @@ where
             on_request,
             on_server_start: None,
             on_server_stop: None,
+            now_getter: None,
+            hooks: ProxyHooks::default(),
+            handle_signals: false,
             _phantom: (PhantomData, PhantomData, PhantomData),
         }
     }
@@ -236,19 +345,143 @@ where
     /// #[tokio::main]
     /// async fn main() {
     ///     Proxy::new(Client::new(), on_request)
-    ///         .set_on_server_stop(|| println!("Server has been stopped!"))
+    ///         .set_on_server_stop(|summary| println!("Server has been stopped! {:#?}", summary))
     ///         .start()
     ///         .await
     /// }
     /// ```
     pub fn set_on_server_stop(
         &mut self,
-        on_server_stop: impl FnOnce() + 'static + Send,
+        on_server_stop: impl FnOnce(ShutdownSummary) + 'static + Send,
     ) -> &mut Self {
         self.on_server_stop = Some(Box::new(on_server_stop));
         self
     }
 
+    /// Set a custom clock for all requests processed by this `Proxy`.
+    ///
+    /// It's useful when you need to manipulate with time in tests running in parallel
+    /// or when you want to provide your own time source.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// use ::addon_proxy::{proxy::Proxy, on_request};
+    /// use hyper::Client;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     Proxy::new(Client::new(), on_request)
+    ///         .set_now_getter(|| chrono::Utc::now().timestamp() + 60)
+    ///         .start()
+    ///         .await
+    /// }
+    /// ```
+    pub fn set_now_getter(&mut self, now: impl Fn() -> i64 + Send + Sync + 'static) -> &mut Self {
+        self.now_getter = Some(Arc::new(now));
+        self
+    }
+
+    /// Provided callback is invoked just before a request is sent to the origin.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// use ::addon_proxy::{proxy::Proxy, on_request};
+    /// use hyper::Client;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     Proxy::new(Client::new(), on_request)
+    ///         .set_on_upstream_connect(|request| println!("Sending to {}", request.uri))
+    ///         .start()
+    ///         .await
+    /// }
+    /// ```
+    pub fn set_on_upstream_connect(
+        &mut self,
+        on_upstream_connect: impl Fn(&UpstreamRequest) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.hooks.on_upstream_connect = Some(Arc::new(on_upstream_connect));
+        self
+    }
+
+    /// Provided callback is invoked when the origin fails - see `UpstreamError`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// use ::addon_proxy::{proxy::Proxy, on_request};
+    /// use hyper::Client;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     Proxy::new(Client::new(), on_request)
+    ///         .set_on_upstream_error(|request, error| println!("{} failed: {:?}", request.uri, error))
+    ///         .start()
+    ///         .await
+    /// }
+    /// ```
+    pub fn set_on_upstream_error(
+        &mut self,
+        on_upstream_error: impl Fn(&UpstreamRequest, &UpstreamError) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.hooks.on_upstream_error = Some(Arc::new(on_upstream_error));
+        self
+    }
+
+    /// Provided callback is invoked on each cache lookup - see `CacheEvent`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// use ::addon_proxy::{proxy::{CacheEvent, Proxy}, on_request};
+    /// use hyper::Client;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     Proxy::new(Client::new(), on_request)
+    ///         .set_on_cache_event(|event| {
+    ///             if let CacheEvent::Miss { uri } = event {
+    ///                 println!("Cache miss: {}", uri)
+    ///             }
+    ///         })
+    ///         .start()
+    ///         .await
+    /// }
+    /// ```
+    pub fn set_on_cache_event(
+        &mut self,
+        on_cache_event: impl Fn(&CacheEvent) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.hooks.on_cache_event = Some(Arc::new(on_cache_event));
+        self
+    }
+
+    /// Enable or disable the graceful shutdown on SIGTERM or SIGINT.
+    ///
+    /// Default is `false` so a library user can handle signals itself
+    /// and stop the proxy by `ProxyController::stop`. The `addon_proxy` binary enables it.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// use ::addon_proxy::{proxy::Proxy, on_request};
+    /// use hyper::Client;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     Proxy::new(Client::new(), on_request)
+    ///         .set_handle_signals(true)
+    ///         .start()
+    ///         .await
+    /// }
+    /// ```
+    pub fn set_handle_signals(&mut self, handle_signals: bool) -> &mut Self {
+        self.handle_signals = handle_signals;
+        self
+    }
+
     /// Start the `Proxy` server.
     ///
     /// # Example
@@ -270,110 +503,359 @@ where
     ///    - If the database opening failed (e.g. the storage directory cannot be created).
     /// - While the server is running and it's not possible to send items through a channel
     /// (this shouldn't happen in practice).
+    #[allow(clippy::too_many_lines)]
     pub async fn start(&mut self) {
         let on_request = self.on_request;
+        let now_getter = self.now_getter.clone();
         let config_path = self.config_path.clone();
-        let proxy_config = ProxyConfig::load(&config_path)
+        let mut proxy_config = ProxyConfig::load(&config_path)
             .await
             .expect("load proxy config");
+        proxy_config.version.epoch = 1;
+        logging::init(&proxy_config);
         let client = Arc::new((&self.client_creator)(&proxy_config));
-        let addr = SocketAddr::new(
-            proxy_config.ip,
-            env::var("PORT")
-                .ok()
-                .and_then(|port| port.parse().ok())
-                .unwrap_or(proxy_config.default_port),
-        );
+        let addr = SocketAddr::new(proxy_config.ip, proxy_config.port());
         // All operations in sled are thread-safe.
         // The Db may be cloned and shared across threads without needing to use Arc or Mutex etc…
         let db = sled::open(&proxy_config.db_directory).expect("open database");
+        // `state` is shared by all requests and it survives config reloads.
+        let mut state = ProxyState::default();
+        state.hooks = self.hooks.clone();
+        let state = Arc::new(state);
+        if let Err(e) = cache::ensure_format(&db) {
+            error!("cannot check the cache format: {}", e);
+        }
+        if let Err(e) = state.stats.restore(&db) {
+            error!("cannot restore stats: {}", e);
+        }
+        let shutdown_grace_period =
+            Duration::from_secs(u64::from(proxy_config.shutdown_grace_period));
+        let shutdown_drain_delay =
+            Duration::from_secs(u64::from(proxy_config.shutdown_drain_delay));
 
         // `config_reload_sender` will be used to schedule proxy config reload from `on_request` callbacks.
         // `config_reload_receiver` will be used in the standalone task to listen for `schedule_config_reload` calls.
-        let (config_reload_sender, mut config_reload_receiver) = mpsc::unbounded_channel();
-        // `config_sender` will be used to send a (re)loaded config to the request service.
+        let (config_reload_sender, mut config_reload_receiver) =
+            mpsc::unbounded_channel::<oneshot::Sender<Result<u64, Vec<String>>>>();
+        let proxy_config = Arc::new(proxy_config);
+        // `current_config` is loaded without waiting just before the `on_request` callback is called.
+        let current_config = Arc::new(ArcSwap::new(Arc::clone(&proxy_config)));
+        // `config_sender` will be used to notify standalone tasks about (re)loaded configs.
         // `config_receiver` will be used to accept the sent config.
-        let (config_sender, config_receiver) = watch::channel(Arc::new(proxy_config));
-
-        // Spawn a new task that broadcasts (re)loaded configs.
-        // These configs are picked just before the `on_request` callback is called.
-        task::spawn(async move {
-            while config_reload_receiver.recv().await.is_some() {
-                match ProxyConfig::load(&config_path).await {
-                    Ok(proxy_config) => {
-                        config_sender
-                            .broadcast(Arc::new(proxy_config))
-                            .expect("broadcast reloaded config");
-                        println!("proxy config reloaded");
-                    }
-                    Err(err) => eprintln!("cannot reload proxy config: {}", err),
+        let (config_sender, config_receiver) = watch::channel(proxy_config);
+
+        // Spawn a new task that replaces `current_config` with reloaded configs and broadcasts them.
+        task::spawn({
+            shadow_clone!(current_config);
+            async move {
+                let mut epoch = 1;
+                while let Some(result_sender) = config_reload_receiver.recv().await {
+                    // The running config is kept when the new one is invalid.
+                    let proxy_config = ProxyConfig::load(&config_path)
+                        .await
+                        .map_err(|err| vec![err])
+                        .and_then(|proxy_config| {
+                            proxy_config.validate()?;
+                            Ok(proxy_config)
+                        });
+                    let result = match proxy_config {
+                        Ok(mut proxy_config) => {
+                            epoch += 1;
+                            // The epoch is set before the config is visible to requests.
+                            proxy_config.version.epoch = epoch;
+                            let proxy_config = Arc::new(proxy_config);
+                            current_config.store(Arc::clone(&proxy_config));
+                            config_sender
+                                .broadcast(proxy_config)
+                                .expect("broadcast reloaded config");
+                            info!(config_epoch = epoch, "proxy config reloaded");
+                            Ok(epoch)
+                        }
+                        Err(errors) => {
+                            error!("cannot reload proxy config: {}", errors.join("; "));
+                            Err(errors)
+                        }
+                    };
+                    // The requester may be gone.
+                    result_sender.send(result).ok();
                 }
             }
         });
 
+        // Push metrics in a standalone task - it's stopped when `config_sender` is dropped.
+        helpers::spawn_with_now_getter(
+            now_getter.clone(),
+            metrics::push_metrics(config_receiver.clone(), Arc::clone(&state)),
+        );
+
+        // Probe origins in a standalone task - it's stopped when `config_sender` is dropped.
+        helpers::spawn_with_now_getter(
+            now_getter.clone(),
+            health_check::check_upstreams(config_receiver.clone(), Arc::clone(&state)),
+        );
+
+        // Remove expired cached responses in a standalone task - it's stopped when `config_sender` is dropped.
+        helpers::spawn_with_now_getter(
+            now_getter.clone(),
+            cache_sweeper::sweep_cache(config_receiver.clone(), db.clone(), Arc::clone(&state)),
+        );
+
+        // Sync routes with the addon collection in a standalone task - it's stopped when `config_sender` is dropped.
+        helpers::spawn_with_now_getter(
+            now_getter.clone(),
+            addon_collection::sync_routes(config_receiver.clone(), Arc::clone(&state)),
+        );
+
         // `schedule_config_reload` will be passed to all `on_request` callbacks.
         let schedule_config_reload = Arc::new(move || {
+            let (result_sender, result_receiver) = oneshot::channel();
             config_reload_sender
                 .clone()
-                .send(())
+                .send(result_sender)
                 .expect("schedule proxy config reload");
+            result_receiver
         });
 
-        // The request service. It's usually bound to a single connection.
         // The callback will be executed for each request.
-        let service = service_fn({
-            shadow_clone!(db);
+        let handle_request = {
+            shadow_clone!(db, state, now_getter);
             move |req: Request<Body>| {
-                shadow_clone!(mut config_receiver, client, schedule_config_reload, db);
+                shadow_clone!(
+                    current_config,
+                    client,
+                    schedule_config_reload,
+                    db,
+                    state,
+                    now_getter
+                );
                 async move {
-                    on_request(
+                    let _in_flight = state.stats.track_request();
+                    let (method, uri) = (req.method().clone(), req.uri().clone());
+                    let response = on_request(
                         req,
                         client,
-                        config_receiver.recv().await.expect("receive proxy config"),
+                        current_config.load_full(),
                         schedule_config_reload,
                         db,
-                    )
-                    .await
+                        Arc::clone(&state),
+                    );
+                    let response = isolate_panics(response, method, uri, &state.stats);
+                    helpers::with_optional_now_getter(now_getter, response).await
                 }
             }
-        });
+        };
+
+        // Warm the cache in a standalone task - it's stopped when `config_sender` is dropped.
+        // The proxy isn't ready until the first seeding is finished.
+        if config_receiver.borrow().cache_seed_file.is_some() {
+            state.cache_seed.schedule();
+        }
+        helpers::spawn_with_now_getter(
+            now_getter.clone(),
+            cache_seed::seed_cache(config_receiver, Arc::clone(&state), handle_request.clone()),
+        );
 
         // Since a request service is bound to a single connection,
         // a server needs a way to make them as it accepts connections.
         // This is what a `make_service_fn` does.
-        let make_service = make_service_fn(move |_| {
-            shadow_clone!(service);
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let remote_addr = RemoteAddr(conn.remote_addr());
+            shadow_clone!(handle_request);
+            // The request service bound to the connection.
+            let service = service_fn(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(remote_addr);
+                handle_request(req)
+            });
             async move { Ok::<_, Infallible>(service) }
         });
 
         let server = Server::bind(&addr).serve(make_service);
-        println!("Listening on http://{}", addr);
+        info!("Listening on http://{}", addr);
 
         // Prepare controller with ability to gracefully shutdown the server.
         let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
-        let server = server.with_graceful_shutdown(async {
-            shutdown_receiver.await.ok();
+        // Signals have the same effect as `ProxyController::stop`.
+        let shutdown_signal = {
+            let signal = if self.handle_signals {
+                Some(controller::shutdown_signal())
+            } else {
+                None
+            };
+            async move {
+                match signal {
+                    Some(signal) => signal.await,
+                    None => futures_util::future::pending().await,
+                }
+            }
+        };
+        // `drain_sender` notifies the grace period timer below.
+        let (drain_sender, drain_receiver) = oneshot::channel::<()>();
+        let server = server.with_graceful_shutdown({
+            shadow_clone!(state);
+            async move {
+                tokio::select! {
+                    _ = shutdown_receiver => (),
+                    signal = shutdown_signal => info!(signal, "shutdown signal received"),
+                }
+                // Announce drain - the status endpoint starts to respond with 503.
+                state.start_draining();
+                // Keep accepting connections until load balancers notice the drain.
+                time::delay_for(shutdown_drain_delay).await;
+                // The listener is closed when this future is resolved.
+                drain_sender.send(()).ok();
+            }
         });
 
+        let started_at = Instant::now();
         if let Some(on_server_start) = self.on_server_start.take() {
             on_server_start(ProxyController { shutdown_sender });
         }
 
-        // Block until the server is stopped.
-        if let Err(e) = server.await {
-            eprintln!("server error: {}", e);
+        // Block until the server is stopped
+        // or until the grace period for in-flight requests has elapsed.
+        tokio::pin!(server);
+        let reason = tokio::select! {
+            result = &mut server => {
+                match result {
+                    Ok(()) => ShutdownReason::Requested,
+                    Err(e) => {
+                        error!("server error: {}", e);
+                        ShutdownReason::ServerError(e.to_string())
+                    }
+                }
+            }
+            _ = async {
+                if drain_receiver.await.is_ok() {
+                    time::delay_for(shutdown_grace_period).await
+                } else {
+                    // The server has been stopped without the shutdown signal.
+                    futures_util::future::pending::<()>().await
+                }
+            } => {
+                warn!(
+                    "shutdown grace period elapsed with {} in-flight request(s)",
+                    state.stats.snapshot().in_flight
+                );
+                ShutdownReason::GracePeriodElapsed
+            }
+        };
+
+        // Persist counters.
+        if let Err(e) = state.stats.persist(&db) {
+            error!("cannot persist stats: {}", e);
         }
+        if let Err(e) = state.cache_hits.flush(&db) {
+            error!("cannot save cache hits: {}", e);
+        }
+        let stats = state.stats.snapshot();
+        let summary = ShutdownSummary {
+            reason,
+            uptime: started_at.elapsed(),
+            pending_requests: stats.in_flight,
+            stats,
+        };
 
         // Save dirty data.
         if let Err(e) = db.flush_async().await {
-            eprintln!("database flush error: {}", e);
+            error!("database flush error: {}", e);
         }
         // Close db & release file locks.
         drop(db);
 
         // Notify subscriber that server has been stopped and resources have beed freed.
         if let Some(on_server_stop) = self.on_server_stop.take() {
-            on_server_stop();
+            on_server_stop(summary);
         }
     }
 }
+
+/// Convert a panic during the request processing to `INTERNAL_SERVER_ERROR` response,
+/// so the panic doesn't tear down the connection task.
+async fn isolate_panics(
+    response: impl Future<Output = Result<Response<Body>, hyper::Error>>,
+    method: Method,
+    uri: Uri,
+    stats: &Stats,
+) -> Result<Response<Body>, hyper::Error> {
+    match AssertUnwindSafe(response).catch_unwind().await {
+        Ok(response) => response,
+        Err(panic) => {
+            Stats::increment(&stats.panics);
+            error!(
+                %method,
+                %uri,
+                message = panic_message(&*panic),
+                "request panicked"
+            );
+            let mut response = Response::new(Body::from("Internal proxy error."));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            Ok(response)
+        }
+    }
+}
+
+/// Try to get a message passed to `panic!`.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+// ------ RemoteAddr ------
+
+/// The address of the client connected to the proxy.
+/// It's stored in the request extensions by `Proxy::start`.
+///
+/// _Note:_ It's the address of the load balancer when the proxy is behind one
+/// (the client's IP is read from the header `X-Forwarded-For` then
+/// - see `ProxyConfig::trusted_proxies`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RemoteAddr(pub SocketAddr);
+
+impl RemoteAddr {
+    /// Get the address stored in the request extensions.
+    pub fn of<T>(req: &Request<T>) -> Option<SocketAddr> {
+        req.extensions()
+            .get::<Self>()
+            .map(|remote_addr| remote_addr.0)
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyper_helpers::body_to_bytes;
+
+    async fn panicking_on_request() -> Result<Response<Body>, hyper::Error> {
+        panic!("middleware failed")
+    }
+
+    #[tokio::test]
+    async fn isolate_panics_internal_server_error() {
+        let stats = Stats::default();
+
+        let response = isolate_panics(
+            panicking_on_request(),
+            Method::GET,
+            Uri::from_static("/manifest.json"),
+            &stats,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(stats.snapshot().panics, 1);
+
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Internal proxy error.");
+    }
+
+    #[test]
+    fn panic_message_string() {
+        let panic: Box<dyn Any + Send> = Box::new(format!("{} failed", "middleware"));
+        assert_eq!(panic_message(&*panic), "middleware failed");
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures_util::future;
+use hyper::client::HttpConnector;
+use hyper::{body, Client, Uri};
+use hyper_tls::HttpsConnector;
+use serde_json::Value;
+use tokio::sync::watch;
+use tokio::time;
+use tracing::{error, warn};
+
+use super::{
+    AddonCollectionConfig, IndexedRoutes, ProxyConfig, ProxyRoute, ProxyState, RouteGroup,
+};
+
+type CollectionClient = Client<HttpsConnector<HttpConnector>>;
+
+const MANIFEST_PATH: &str = "/manifest.json";
+
+// ------ CollectionRoutes ------
+
+/// Routes generated from the addon collection - see `ProxyConfig::addon_collection`.
+#[derive(Default)]
+pub struct CollectionRoutes {
+    routes: RwLock<Arc<IndexedRoutes>>,
+}
+
+impl CollectionRoutes {
+    /// The routes from the last successful collection fetch.
+    pub fn get(&self) -> Arc<IndexedRoutes> {
+        Arc::clone(&self.routes.read().expect("read collection routes"))
+    }
+
+    fn set(&self, routes: Vec<ProxyRoute>) {
+        *self.routes.write().expect("write collection routes") =
+            Arc::new(IndexedRoutes::new(routes));
+    }
+}
+
+// ------ sync_routes ------
+
+/// Regenerate routes according to the current `ProxyConfig::addon_collection`
+/// until the config sender is dropped.
+///
+/// Routes are kept when the collection cannot be fetched.
+pub async fn sync_routes(
+    mut config_receiver: watch::Receiver<Arc<ProxyConfig>>,
+    state: Arc<ProxyState>,
+) {
+    let client = Client::builder().build(HttpsConnector::new());
+    let mut proxy_config: Option<Arc<ProxyConfig>> = None;
+
+    loop {
+        let poll_interval = proxy_config
+            .as_ref()
+            .and_then(|proxy_config| proxy_config.addon_collection.as_ref())
+            .map(|collection_config| {
+                Duration::from_secs(u64::from(collection_config.poll_interval.max(1)))
+            });
+        let poll_time = async {
+            match poll_interval {
+                Some(poll_interval) => time::delay_for(poll_interval).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            new_proxy_config = config_receiver.recv() => {
+                match new_proxy_config {
+                    Some(new_proxy_config) => proxy_config = Some(new_proxy_config),
+                    None => return,
+                }
+            }
+            _ = poll_time => ()
+        }
+        let proxy_config = match &proxy_config {
+            Some(proxy_config) => proxy_config,
+            None => continue,
+        };
+        let collection_config = match &proxy_config.addon_collection {
+            Some(collection_config) => collection_config,
+            None => {
+                state.collection_routes.set(Vec::new());
+                continue;
+            }
+        };
+
+        let timeout = Duration::from_secs(u64::from(proxy_config.timeout));
+        let result = time::timeout(timeout, fetch_collection(&client, &collection_config.url))
+            .await
+            .unwrap_or_else(|_| Err("timeout".to_owned()))
+            .and_then(|collection| {
+                routes_from_collection(&collection, collection_config, &proxy_config.route_groups)
+            });
+        match result {
+            Ok(routes) => state.collection_routes.set(routes),
+            Err(error) => error!(
+                "cannot sync routes with addon collection '{}': {}",
+                collection_config.url, error
+            ),
+        }
+    }
+}
+
+async fn fetch_collection(client: &CollectionClient, url: &Uri) -> Result<Value, String> {
+    let response = client
+        .get(url.clone())
+        .await
+        .map_err(|error| error.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("collection responded with {}", response.status()));
+    }
+    let body = body::to_bytes(response.into_body())
+        .await
+        .map_err(|error| error.to_string())?;
+    serde_json::from_slice(&body).map_err(|error| error.to_string())
+}
+
+// ------ routes_from_collection ------
+
+/// Create a route for each addon in the collection.
+///
+/// Addons with invalid transport URLs are skipped.
+/// Routes are sorted by `from` in the descending order and deduplicated.
+///
+/// # Errors
+///
+/// Returns error when the collection isn't a JSON array or when the route group is invalid.
+fn routes_from_collection(
+    collection: &Value,
+    collection_config: &AddonCollectionConfig,
+    route_groups: &BTreeMap<String, RouteGroup>,
+) -> Result<Vec<ProxyRoute>, String> {
+    let addons = collection
+        .as_array()
+        .ok_or_else(|| "the collection isn't a JSON array".to_owned())?;
+    let mut routes = Vec::with_capacity(addons.len());
+    for addon in addons {
+        let transport_url = match addon {
+            Value::String(transport_url) => Some(transport_url.as_str()),
+            Value::Object(addon) => addon.get("transportUrl").and_then(Value::as_str),
+            _ => None,
+        };
+        let route = match transport_url.and_then(|url| route_for_addon(url, collection_config)) {
+            Some(route) => route,
+            None => {
+                warn!("skipping invalid addon in collection: {}", addon);
+                continue;
+            }
+        };
+        routes.push(route);
+    }
+    for route in &mut routes {
+        route.inherit_groups(route_groups)?;
+    }
+    routes.sort_by(|route_a, route_b| route_b.from.cmp(&route_a.from));
+    routes.dedup_by(|route_a, route_b| route_a.from == route_b.from);
+    Ok(routes)
+}
+
+/// https://example.com/lite/manifest.json -> `<from>/example.com/lite` => `https://example.com/lite/`
+fn route_for_addon(
+    transport_url: &str,
+    collection_config: &AddonCollectionConfig,
+) -> Option<ProxyRoute> {
+    let transport_url = transport_url.parse::<Uri>().ok()?;
+    let scheme = transport_url.scheme_str()?;
+    if scheme != "http" && scheme != "https" {
+        return None;
+    }
+    let authority = transport_url.authority()?;
+    let path = transport_url.path();
+    if !path.ends_with(MANIFEST_PATH) {
+        return None;
+    }
+    let addon_path = &path[..path.len() - MANIFEST_PATH.len()];
+    Some(ProxyRoute {
+        from: format!(
+            "{}/{}{}",
+            collection_config.from.trim_end_matches('/'),
+            authority,
+            addon_path
+        ),
+        to: format!("{}://{}{}/", scheme, authority, addon_path)
+            .parse()
+            .ok()?,
+        validate: None,
+        negotiate_manifest: None,
+        cache_partition: None,
+        body_replacements: BTreeMap::new(),
+        json_filter: None,
+        verbose: None,
+        allowed_countries: None,
+        blocked_countries: None,
+        to_by_region: BTreeMap::new(),
+        head_revalidation: None,
+        cache_private: None,
+        group: collection_config.group.clone(),
+        signing_profile: None,
+        fix_content_type: None,
+        transcode_to_utf8: None,
+        latency_slo: None,
+        answer_preflight: None,
+        strategy: None,
+        cors: None,
+    })
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collection_routes() {
+        let collection_config = toml::from_str::<AddonCollectionConfig>(
+            r#"
+            url = "https://example.com/addons.json"
+            from = "127.0.0.1:5000/collection/"
+            group = "catalogs"
+            "#,
+        )
+        .unwrap();
+        let mut route_groups = BTreeMap::new();
+        route_groups.insert(
+            "catalogs".to_owned(),
+            toml::from_str::<RouteGroup>("head_revalidation = true").unwrap(),
+        );
+        let collection = serde_json::json!([
+            "https://addon.com/manifest.json",
+            { "transportUrl": "https://addon.com/lite/manifest.json", "flags": {} },
+            "http://localhost:7000/manifest.json",
+            "ftp://addon.com/manifest.json",
+            "https://addon.com/catalog.json",
+            42
+        ]);
+
+        let routes =
+            routes_from_collection(&collection, &collection_config, &route_groups).unwrap();
+        let routes = routes
+            .iter()
+            .map(|route| {
+                (
+                    route.from.as_str(),
+                    route.to.to_string(),
+                    route.head_revalidation,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            routes,
+            vec![
+                (
+                    "127.0.0.1:5000/collection/localhost:7000",
+                    "http://localhost:7000/".to_owned(),
+                    Some(true)
+                ),
+                (
+                    "127.0.0.1:5000/collection/addon.com/lite",
+                    "https://addon.com/lite/".to_owned(),
+                    Some(true)
+                ),
+                (
+                    "127.0.0.1:5000/collection/addon.com",
+                    "https://addon.com/".to_owned(),
+                    Some(true)
+                ),
+            ]
+        );
+
+        assert!(
+            routes_from_collection(&serde_json::json!({}), &collection_config, &route_groups)
+                .is_err()
+        );
+    }
+}
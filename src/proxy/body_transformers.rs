@@ -0,0 +1,563 @@
+use std::collections::BTreeMap;
+
+use encoding_rs::{Decoder, Encoding, UTF_8};
+use futures_util::stream::{self, StreamExt};
+use hyper::body::Bytes;
+use hyper::{header, Body, Response};
+use serde_json::Value;
+
+use crate::hyper_helpers::{prepend_chunks, StreamedBody};
+
+// ------ BodyTransformer ------
+
+/// Streaming processor of body chunks (e.g. URL rewriting or minification).
+///
+/// Chunks are transformed as they arrive from the origin, so transformers don't force
+/// buffering of the whole body. Transformers that need the whole body (e.g. JSON processing)
+/// buffer chunks themselves and return the result from `finish`.
+///
+/// See `transform_body`.
+pub trait BodyTransformer: Send {
+    /// Transform the next chunk.
+    ///
+    /// The output may be empty (e.g. when the transformer waits for more data).
+    fn transform(&mut self, chunk: Bytes) -> Bytes;
+
+    /// The body has ended - return the remaining output.
+    fn finish(&mut self) -> Bytes;
+}
+
+/// Pass body chunks through the transformers in order.
+///
+/// The body is returned untouched when there are no transformers.
+pub fn transform_body(body: Body, transformers: Vec<Box<dyn BodyTransformer>>) -> Body {
+    if transformers.is_empty() {
+        return body;
+    }
+    let chunks = stream::unfold(Some((body, transformers)), |state| async move {
+        let (mut body, mut transformers) = state?;
+        loop {
+            let chunk = match body.next().await {
+                Some(Ok(chunk)) => transform_chunk(&mut transformers, chunk),
+                Some(Err(error)) => return Some((Err(error), None)),
+                None => return Some((Ok(finish(&mut transformers)), None)),
+            };
+            // Don't send empty chunks while transformers are buffering.
+            if !chunk.is_empty() {
+                return Some((Ok(chunk), Some((body, transformers))));
+            }
+        }
+    });
+    Body::wrap_stream(chunks)
+}
+
+/// Transform the response body and remove headers invalidated by the transformation.
+///
+/// Encoded (e.g. gzipped) bodies aren't transformed.
+pub fn transform_response(
+    response: Response<Body>,
+    transformers: Vec<Box<dyn BodyTransformer>>,
+) -> Response<Body> {
+    if transformers.is_empty() || is_encoded(&response) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.extensions.insert(StreamedBody);
+    Response::from_parts(parts, transform_body(body, transformers))
+}
+
+/// The body is encoded (e.g. gzipped), so it can't be transformed.
+fn is_encoded(response: &Response<Body>) -> bool {
+    matches!(
+        response.headers().get(header::CONTENT_ENCODING),
+        Some(encoding) if encoding != "identity"
+    )
+}
+
+fn transform_chunk(transformers: &mut [Box<dyn BodyTransformer>], chunk: Bytes) -> Bytes {
+    transformers
+        .iter_mut()
+        .fold(chunk, |chunk, transformer| transformer.transform(chunk))
+}
+
+/// The output of each transformer's `finish` is passed to the next transformers.
+fn finish(transformers: &mut [Box<dyn BodyTransformer>]) -> Bytes {
+    transformers
+        .iter_mut()
+        .fold(Bytes::new(), |rest, transformer| {
+            let mut output = transformer.transform(rest).to_vec();
+            output.extend_from_slice(&transformer.finish());
+            Bytes::from(output)
+        })
+}
+
+// ------ fix_json_content_type ------
+
+/// Content types sent by some origins with JSON bodies (see `ProxyRoute::fix_content_type`).
+const WRONG_JSON_CONTENT_TYPES: &[&str] = &["text/html", "text/plain"];
+
+/// Set `Content-Type: application/json` when the content type is wrong or missing,
+/// but the body starts with `{` or `[`.
+///
+/// Only the beginning of the body is read. Encoded (e.g. gzipped) bodies aren't sniffed.
+///
+/// Returns the response and the original content type (empty when missing) if it has been corrected.
+pub async fn fix_json_content_type(
+    response: Response<Body>,
+) -> Result<(Response<Body>, Option<String>), hyper::Error> {
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|content_type| String::from_utf8_lossy(content_type.as_bytes()).into_owned());
+    let is_wrong = match &content_type {
+        Some(content_type) => {
+            let mime = content_type.split(';').next().unwrap_or_default().trim();
+            WRONG_JSON_CONTENT_TYPES
+                .iter()
+                .any(|wrong| mime.eq_ignore_ascii_case(wrong))
+        }
+        None => true,
+    };
+    if !is_wrong || is_encoded(&response) {
+        return Ok((response, None));
+    }
+
+    let (mut parts, mut body) = response.into_parts();
+    let mut chunks = Vec::new();
+    let mut looks_like_json = false;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        let first_byte = chunk
+            .iter()
+            .find(|byte| !byte.is_ascii_whitespace())
+            .copied();
+        chunks.push(chunk);
+        if let Some(first_byte) = first_byte {
+            looks_like_json = first_byte == b'{' || first_byte == b'[';
+            break;
+        }
+    }
+    let body = prepend_chunks(chunks, body);
+    if !looks_like_json {
+        return Ok((Response::from_parts(parts, body), None));
+    }
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json; charset=utf-8"),
+    );
+    Ok((
+        Response::from_parts(parts, body),
+        Some(content_type.unwrap_or_default()),
+    ))
+}
+
+// ------ transcode_to_utf8 ------
+
+/// Transcode the body from the charset declared in `Content-Type` to UTF-8
+/// and update `Content-Type` (see `ProxyRoute::transcode_to_utf8`).
+///
+/// Responses with UTF-8, unknown or missing charsets and encoded (e.g. gzipped) bodies are returned untouched.
+pub fn transcode_to_utf8(response: Response<Body>) -> Response<Body> {
+    if is_encoded(&response) {
+        return response;
+    }
+    let content_type = match response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+    {
+        Some(content_type) => content_type,
+        None => return response,
+    };
+    let encoding = content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|parameter| {
+            let mut name_and_value = parameter.splitn(2, '=');
+            match (name_and_value.next(), name_and_value.next()) {
+                (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("charset") => {
+                    Encoding::for_label(value.trim().trim_matches('"').as_bytes())
+                }
+                _ => None,
+            }
+        })
+        .next();
+    let encoding = match encoding {
+        Some(encoding) if encoding != UTF_8 => encoding,
+        _ => return response,
+    };
+    let content_type = content_type
+        .split(';')
+        .map(str::trim)
+        .filter(|part| !part.to_ascii_lowercase().starts_with("charset="))
+        .chain(std::iter::once("charset=utf-8"))
+        .collect::<Vec<_>>()
+        .join("; ");
+    let content_type = match header::HeaderValue::from_str(&content_type) {
+        Ok(content_type) => content_type,
+        Err(_) => return response,
+    };
+
+    let transcode: Box<dyn BodyTransformer> = Box::new(Transcode::new(encoding));
+    let mut response = transform_response(response, vec![transcode]);
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, content_type);
+    response
+}
+
+/// Decode chunks in the given charset to UTF-8, even if characters are split between chunks.
+///
+/// See `transcode_to_utf8`.
+pub struct Transcode {
+    decoder: Decoder,
+}
+
+impl Transcode {
+    pub fn new(encoding: &'static Encoding) -> Self {
+        Self {
+            decoder: encoding.new_decoder(),
+        }
+    }
+
+    fn decode(&mut self, input: &[u8], last: bool) -> Bytes {
+        let capacity = self
+            .decoder
+            .max_utf8_buffer_length(input.len())
+            .unwrap_or_else(|| input.len() * 3);
+        let mut output = String::with_capacity(capacity);
+        // The whole input fits into `output`. Invalid sequences are replaced with U+FFFD.
+        let _ = self.decoder.decode_to_string(input, &mut output, last);
+        Bytes::from(output)
+    }
+}
+
+impl BodyTransformer for Transcode {
+    fn transform(&mut self, chunk: Bytes) -> Bytes {
+        self.decode(&chunk, false)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        self.decode(&[], true)
+    }
+}
+
+// ------ Replace ------
+
+/// Replace all occurrences of a byte sequence, even if they are split between chunks.
+///
+/// See `ProxyRoute::body_replacements`.
+pub struct Replace {
+    pattern: Vec<u8>,
+    replacement: Vec<u8>,
+    // The end of the previous chunk that may be the beginning of a pattern.
+    pending: Vec<u8>,
+}
+
+impl Replace {
+    /// _Note:_ The transformer with an empty `pattern` doesn't change the body.
+    pub fn new(pattern: impl Into<Vec<u8>>, replacement: impl Into<Vec<u8>>) -> Self {
+        Self {
+            pattern: pattern.into(),
+            replacement: replacement.into(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl BodyTransformer for Replace {
+    fn transform(&mut self, chunk: Bytes) -> Bytes {
+        if self.pattern.is_empty() {
+            return chunk;
+        }
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(&chunk);
+
+        let mut output = Vec::with_capacity(input.len());
+        let mut position = 0;
+        while let Some(index) = input[position..]
+            .windows(self.pattern.len())
+            .position(|window| window == self.pattern.as_slice())
+        {
+            output.extend_from_slice(&input[position..position + index]);
+            output.extend_from_slice(&self.replacement);
+            position += index + self.pattern.len();
+        }
+        // The rest doesn't contain the whole pattern, but its end may contain its beginning.
+        let pending_len = (self.pattern.len() - 1).min(input.len() - position);
+        let pending_start = input.len() - pending_len;
+        output.extend_from_slice(&input[position..pending_start]);
+        self.pending = input[pending_start..].to_vec();
+        Bytes::from(output)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        Bytes::from(std::mem::take(&mut self.pending))
+    }
+}
+
+// ------ JsonFilter ------
+
+/// Keep only the included fields in the JSON body.
+///
+/// The body is buffered until its end. Invalid JSON bodies aren't changed.
+///
+/// See `ProxyRoute::json_filter`.
+pub struct JsonFilter {
+    fields: FieldTree,
+    body: Vec<u8>,
+}
+
+/// Kept fields and their kept subfields - the whole value is kept when there are no subfields.
+#[derive(Default)]
+struct FieldTree(BTreeMap<String, FieldTree>);
+
+impl JsonFilter {
+    /// See `JsonFilterConfig::include` for the path format.
+    pub fn new(include: &[String]) -> Self {
+        let mut fields = FieldTree::default();
+        for path in include {
+            path.split('.')
+                .map(|field| field.trim_end_matches("[]"))
+                .fold(&mut fields, |tree, field| {
+                    tree.0.entry(field.to_owned()).or_default()
+                });
+        }
+        Self {
+            fields,
+            body: Vec::new(),
+        }
+    }
+}
+
+impl FieldTree {
+    /// Fields of objects in arrays are filtered like fields of objects.
+    fn filter(&self, value: &mut Value) {
+        match value {
+            Value::Object(object) => {
+                *object = std::mem::take(object)
+                    .into_iter()
+                    .filter_map(|(field, mut value)| {
+                        let subfields = self.0.get(&field)?;
+                        if !subfields.0.is_empty() {
+                            subfields.filter(&mut value);
+                        }
+                        Some((field, value))
+                    })
+                    .collect();
+            }
+            Value::Array(values) => {
+                for value in values {
+                    self.filter(value);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl BodyTransformer for JsonFilter {
+    fn transform(&mut self, chunk: Bytes) -> Bytes {
+        self.body.extend_from_slice(&chunk);
+        Bytes::new()
+    }
+
+    fn finish(&mut self) -> Bytes {
+        let body = std::mem::take(&mut self.body);
+        let mut value = match serde_json::from_slice::<Value>(&body) {
+            Ok(value) => value,
+            Err(_) => return Bytes::from(body),
+        };
+        self.fields.filter(&mut value);
+        serde_json::to_vec(&value).map_or_else(|_| Bytes::from(body), Bytes::from)
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyper_helpers::body_to_bytes;
+
+    fn chunked_body(chunks: &[&'static str]) -> Body {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Ok::<_, hyper::Error>(Bytes::from_static(chunk.as_bytes())))
+            .collect::<Vec<_>>();
+        Body::wrap_stream(stream::iter(chunks))
+    }
+
+    #[test]
+    fn replace_across_chunks() {
+        let mut replace = Replace::new("http://origin", "https://proxy");
+        let mut output = Vec::new();
+        for chunk in &["{\"logo\":\"http://or", "igin/logo.png\",\"http://o", "\"}"] {
+            output.extend_from_slice(&replace.transform(Bytes::from_static(chunk.as_bytes())));
+        }
+        output.extend_from_slice(&replace.finish());
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"logo\":\"https://proxy/logo.png\",\"http://o\"}"
+        );
+    }
+
+    #[test]
+    fn json_filter_catalog() {
+        let include = ["metas[].id", "metas[].name", "metas[].links"]
+            .iter()
+            .map(|path| (*path).to_owned())
+            .collect::<Vec<_>>();
+        let mut json_filter = JsonFilter::new(&include);
+        json_filter.transform(Bytes::from_static(
+            br#"{"metas":[{"id":"tt1","name":"A","description":"Long","#,
+        ));
+        json_filter.transform(Bytes::from_static(
+            br#""links":[{"name":"x"}]},{"id":"tt2","cast":[]}],"cacheMaxAge":60}"#,
+        ));
+        assert_eq!(
+            json_filter.finish(),
+            r#"{"metas":[{"id":"tt1","links":[{"name":"x"}],"name":"A"},{"id":"tt2"}]}"#
+        );
+
+        let mut json_filter = JsonFilter::new(&include);
+        json_filter.transform(Bytes::from_static(b"not JSON"));
+        assert_eq!(json_filter.finish(), "not JSON");
+    }
+
+    #[tokio::test]
+    async fn transform_body_chain() {
+        let transformers: Vec<Box<dyn BodyTransformer>> = vec![
+            Box::new(Replace::new("a", "bb")),
+            Box::new(Replace::new("bbb", "c")),
+        ];
+
+        let body = transform_body(chunked_body(&["xa", "bx", "b"]), transformers);
+        assert_eq!(body_to_bytes(body).await.unwrap(), "xcxb");
+    }
+
+    #[tokio::test]
+    async fn transform_response_headers() {
+        let response = Response::builder()
+            .header(header::CONTENT_LENGTH, 3)
+            .body(Body::from("abc"))
+            .unwrap();
+        let response = transform_response(response, vec![Box::new(Replace::new("b", "bb"))]);
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+        assert_eq!(body_to_bytes(response.into_body()).await.unwrap(), "abbc");
+
+        let response = Response::builder()
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(Body::from("abc"))
+            .unwrap();
+        let response = transform_response(response, vec![Box::new(Replace::new("b", "bb"))]);
+        assert_eq!(body_to_bytes(response.into_body()).await.unwrap(), "abc");
+    }
+
+    #[tokio::test]
+    async fn fix_json_content_type_sniffing() {
+        let response = |content_type: Option<&str>, chunks: &[&'static str]| {
+            let mut response = Response::new(chunked_body(chunks));
+            if let Some(content_type) = content_type {
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            }
+            response
+        };
+
+        let (fixed, original) = fix_json_content_type(response(
+            Some("text/html; charset=utf-8"),
+            &[" \n", "{\"a\":", "1}"],
+        ))
+        .await
+        .unwrap();
+        assert_eq!(original.as_deref(), Some("text/html; charset=utf-8"));
+        assert_eq!(
+            fixed.headers()[header::CONTENT_TYPE],
+            "application/json; charset=utf-8"
+        );
+        // The sniffed chunks are still in the body.
+        assert_eq!(
+            body_to_bytes(fixed.into_body()).await.unwrap(),
+            " \n{\"a\":1}"
+        );
+
+        let (fixed, original) = fix_json_content_type(response(None, &["[]"]))
+            .await
+            .unwrap();
+        assert_eq!(original.as_deref(), Some(""));
+        assert_eq!(
+            fixed.headers()[header::CONTENT_TYPE],
+            "application/json; charset=utf-8"
+        );
+
+        // HTML error pages and correct content types are kept.
+        let (kept, original) = fix_json_content_type(response(Some("text/html"), &["<html>"]))
+            .await
+            .unwrap();
+        assert_eq!(original, None);
+        assert_eq!(kept.headers()[header::CONTENT_TYPE], "text/html");
+        assert_eq!(body_to_bytes(kept.into_body()).await.unwrap(), "<html>");
+
+        let (kept, original) = fix_json_content_type(response(Some("image/png"), &["{"]))
+            .await
+            .unwrap();
+        assert_eq!(original, None);
+        assert_eq!(kept.headers()[header::CONTENT_TYPE], "image/png");
+    }
+
+    #[tokio::test]
+    async fn transcode_windows_1250() {
+        // "Žluťoučký kůň" in windows-1250, split inside the text.
+        let mut response = Response::new(Body::wrap_stream(stream::iter(vec![
+            Ok::<_, hyper::Error>(Bytes::from_static(b"{\"title\":\"\x8Elu\x9Dou\xE8k")),
+            Ok(Bytes::from_static(b"\xFD k\xF9\xF2\"}")),
+        ])));
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/json; Charset=\"windows-1250\""
+                .parse()
+                .unwrap(),
+        );
+        headers.insert(header::CONTENT_LENGTH, "30".parse().unwrap());
+
+        let response = transcode_to_utf8(response);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/json; charset=utf-8"
+        );
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "{\"title\":\"Žluťoučký kůň\"}");
+    }
+
+    #[tokio::test]
+    async fn transcode_keeps_utf8_and_encoded_bodies() {
+        let response = |content_type: &str, content_encoding: Option<&str>| {
+            let mut response = Response::new(chunked_body(&["\u{17d}"]));
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            if let Some(content_encoding) = content_encoding {
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_ENCODING, content_encoding.parse().unwrap());
+            }
+            response
+        };
+
+        for (content_type, content_encoding) in &[
+            ("text/plain; charset=UTF-8", None),
+            ("text/plain", None),
+            ("text/plain; charset=unknown", None),
+            ("text/plain; charset=latin1", Some("gzip")),
+        ] {
+            let kept = transcode_to_utf8(response(content_type, *content_encoding));
+            assert_eq!(kept.headers()[header::CONTENT_TYPE], *content_type);
+            assert_eq!(body_to_bytes(kept.into_body()).await.unwrap(), "\u{17d}");
+        }
+    }
+}
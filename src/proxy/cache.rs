@@ -0,0 +1,1511 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::Mutex;
+
+use hyper::body::Bytes;
+
+use http::header::{HeaderName, HeaderValue};
+use http::uri::PathAndQuery;
+use http::{HeaderMap, Method, StatusCode, Uri};
+
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::helpers::{boot_id, monotonic_timestamp, now_timestamp};
+use crate::proxy::{CacheKeyConfig, Db};
+
+const META_TREE: &str = "meta";
+const FORMAT_VERSION_KEY: &str = "cache_format_version";
+/// Names of `VaryHeaders` by `CacheKey::to_db_key`.
+const VARY_TREE: &str = "vary";
+/// Keys of cached responses by `CachedRequest::uri` (see `index_uri`).
+const URI_INDEX_TREE: &str = "uri_index";
+/// Cache hits by keys of cached responses (see `CacheHits`).
+const HITS_TREE: &str = "hits";
+/// Keys of cached responses by `CacheMetadata::tags` (see `index_tags`).
+const TAG_INDEX_TREE: &str = "tag_index";
+/// Separates the tag from the key in `TAG_INDEX_TREE` (it's never a part of UTF-8 strings).
+const TAG_SEPARATOR: u8 = 0xFF;
+
+/// Bump it whenever `CacheKey` or `CacheValue` fields or the auxiliary trees change -
+/// old cached responses are removed on the proxy start.
+const FORMAT_VERSION: u32 = 10;
+
+/// Remove cached responses stored in an incompatible format (see `FORMAT_VERSION`).
+///
+/// # Errors
+///
+/// Returns error when DB reading or writing fails.
+pub fn ensure_format(db: &Db) -> Result<(), String> {
+    let meta = db.open_tree(META_TREE).map_err(|err| err.to_string())?;
+    let stored_version = meta
+        .get(FORMAT_VERSION_KEY)
+        .map_err(|err| err.to_string())?;
+    if matches!(stored_version, Some(version) if version.as_ref() == FORMAT_VERSION.to_be_bytes()) {
+        return Ok(());
+    }
+    clear(db)?;
+    meta.insert(FORMAT_VERSION_KEY, &FORMAT_VERSION.to_be_bytes())
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+/// Remove all cached responses.
+///
+/// # Errors
+///
+/// Returns error when DB writing fails.
+pub fn clear(db: &Db) -> Result<(), String> {
+    db.clear().map_err(|err| err.to_string())?;
+    for tree in &[VARY_TREE, URI_INDEX_TREE, HITS_TREE, TAG_INDEX_TREE] {
+        db.open_tree(tree)
+            .and_then(|tree| tree.clear())
+            .map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+// ------ URI index ------
+
+/// Store the key of the cached response under its `CachedRequest::uri`,
+/// so responses can be removed by the URI prefix (see `purge`).
+///
+/// # Errors
+///
+/// Returns error when DB writing fails.
+pub fn index_uri(db: &Db, uri: &str, db_key: [u8; 8]) -> Result<(), String> {
+    db.open_tree(URI_INDEX_TREE)
+        .and_then(|index| index.insert(uri_index_key(uri, &db_key), &[]))
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+/// Remove cached responses whose `CachedRequest::uri` starts with `prefix`.
+///
+/// The prefix is a URI (e.g. `example.com/catalog`, the scheme is ignored)
+/// or a path (e.g. `/catalog/movie`) matched against URIs of all origins.
+/// Returns the number of removed responses.
+///
+/// # Errors
+///
+/// Returns error when DB reading or writing fails.
+pub fn purge(db: &Db, prefix: &str) -> Result<usize, String> {
+    let index = db
+        .open_tree(URI_INDEX_TREE)
+        .map_err(|err| err.to_string())?;
+    let prefix = without_scheme(prefix);
+    // Paths are searched in all index entries.
+    let (scanned_prefix, path_prefix) = if prefix.starts_with('/') {
+        ("", Some(prefix.as_bytes()))
+    } else {
+        (prefix, None)
+    };
+
+    let mut index_keys = Vec::new();
+    for entry in index.scan_prefix(scanned_prefix) {
+        let (index_key, _) = entry.map_err(|err| err.to_string())?;
+        let uri = match index_key.len().checked_sub(8) {
+            Some(uri_length) => &index_key[..uri_length],
+            None => continue,
+        };
+        if let Some(path_prefix) = path_prefix {
+            let path = uri
+                .iter()
+                .position(|byte| *byte == b'/')
+                .map(|path_start| &uri[path_start..])
+                .unwrap_or_default();
+            if !path.starts_with(path_prefix) {
+                continue;
+            }
+        }
+        index_keys.push(index_key);
+    }
+
+    let mut purged = 0;
+    for index_key in index_keys {
+        if remove_entry(db, &index_key[index_key.len() - 8..])? {
+            purged += 1;
+        }
+        // The entry is left behind when the response has been already removed or it's corrupted.
+        index.remove(&index_key).map_err(|err| err.to_string())?;
+    }
+    Ok(purged)
+}
+
+/// Remove the cached response unless it has been changed since it was read
+/// (e.g. the response has been cached again). Its index entries and hits are removed too.
+///
+/// Returns `false` when the value has been changed.
+///
+/// # Errors
+///
+/// Returns error when DB writing fails.
+pub fn remove_unchanged(
+    db: &Db,
+    db_key: &[u8],
+    value: &[u8],
+    cached_response: Option<&CacheValueForDeserialization>,
+) -> Result<bool, String> {
+    let removed = db
+        .compare_and_swap(db_key, Some(value), None as Option<&[u8]>)
+        .map_err(|err| err.to_string())?
+        .is_ok();
+    if !removed {
+        return Ok(false);
+    }
+    if let Some(cached_response) = cached_response {
+        db.open_tree(URI_INDEX_TREE)
+            .and_then(|index| index.remove(uri_index_key(&cached_response.request.uri, db_key)))
+            .map_err(|err| err.to_string())?;
+        let tag_index = db
+            .open_tree(TAG_INDEX_TREE)
+            .map_err(|err| err.to_string())?;
+        for tag in &cached_response.metadata.tags {
+            tag_index
+                .remove(tag_index_key(tag, db_key))
+                .map_err(|err| err.to_string())?;
+        }
+    }
+    db.open_tree(HITS_TREE)
+        .and_then(|hits| hits.remove(db_key))
+        .map_err(|err| err.to_string())?;
+    Ok(true)
+}
+
+/// Remove the cached response with its index entries, hits and `VaryHeaders` names
+/// stored under the key (e.g. `cache rm`).
+///
+/// Returns `false` when there is no cached response with the key.
+///
+/// # Errors
+///
+/// Returns error when DB reading or writing fails.
+pub fn remove(db: &Db, db_key: [u8; 8]) -> Result<bool, String> {
+    db.open_tree(VARY_TREE)
+        .and_then(|vary| vary.remove(db_key))
+        .map_err(|err| err.to_string())?;
+    remove_entry(db, &db_key)
+}
+
+/// Remove the cached response with its index entries and hits (see `remove_unchanged`).
+///
+/// Returns `false` when there is no cached response with the key.
+fn remove_entry(db: &Db, db_key: &[u8]) -> Result<bool, String> {
+    loop {
+        let value = match db.get(db_key).map_err(|err| err.to_string())? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        let cached_response = bincode::deserialize::<CacheValueForDeserialization>(&value).ok();
+        // The response may have been cached again in the meantime.
+        if remove_unchanged(db, db_key, &value, cached_response.as_ref())? {
+            return Ok(true);
+        }
+    }
+}
+
+/// Keys of cached responses with the given `CachedRequest::uri` (the scheme is ignored).
+///
+/// There are multiple keys for different methods, bodies, users or `VaryHeaders`.
+///
+/// # Errors
+///
+/// Returns error when DB reading fails.
+pub fn keys_of_uri(db: &Db, uri: &str) -> Result<Vec<[u8; 8]>, String> {
+    let index = db
+        .open_tree(URI_INDEX_TREE)
+        .map_err(|err| err.to_string())?;
+    let uri = without_scheme(uri);
+    let mut keys = Vec::new();
+    for entry in index.scan_prefix(uri) {
+        let (index_key, _) = entry.map_err(|err| err.to_string())?;
+        // Longer URIs with the same prefix are skipped.
+        if index_key.len() != uri.len() + 8 {
+            continue;
+        }
+        let mut db_key = [0; 8];
+        db_key.copy_from_slice(&index_key[uri.len()..]);
+        keys.push(db_key);
+    }
+    Ok(keys)
+}
+
+// ------ Tags ------
+
+/// Store the key of the cached response under its tags, so responses can be removed
+/// by the tag (see `purge_tag`).
+///
+/// # Errors
+///
+/// Returns error when DB writing fails.
+pub fn index_tags(db: &Db, tags: &[String], db_key: [u8; 8]) -> Result<(), String> {
+    if tags.is_empty() {
+        return Ok(());
+    }
+    let tag_index = db
+        .open_tree(TAG_INDEX_TREE)
+        .map_err(|err| err.to_string())?;
+    for tag in tags {
+        tag_index
+            .insert(tag_index_key(tag, &db_key), &[])
+            .map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+/// Remove cached responses with the tag (see `ProxyConfig::cache_tags`).
+///
+/// `*` in the tag matches any characters (e.g. `catalog*` matches `catalog` and `catalog:movie`).
+/// Returns the number of removed responses.
+///
+/// # Errors
+///
+/// Returns error when DB reading or writing fails.
+pub fn purge_tag(db: &Db, tag: &str) -> Result<usize, String> {
+    let tag_index = db
+        .open_tree(TAG_INDEX_TREE)
+        .map_err(|err| err.to_string())?;
+    let entries = if tag.contains('*') {
+        // Only the part before the first wildcard can be searched.
+        let scanned_prefix = tag.split('*').next().unwrap_or_default();
+        tag_index.scan_prefix(scanned_prefix)
+    } else {
+        let mut prefix = tag.as_bytes().to_vec();
+        prefix.push(TAG_SEPARATOR);
+        tag_index.scan_prefix(prefix)
+    };
+
+    let mut index_keys = Vec::new();
+    for entry in entries {
+        let (index_key, _) = entry.map_err(|err| err.to_string())?;
+        let indexed_tag = match index_key.len().checked_sub(9) {
+            Some(tag_length) if index_key[tag_length] == TAG_SEPARATOR => {
+                String::from_utf8_lossy(&index_key[..tag_length])
+            }
+            _ => continue,
+        };
+        if matches_wildcard(tag, &indexed_tag) {
+            index_keys.push(index_key);
+        }
+    }
+
+    let mut purged = 0;
+    for index_key in index_keys {
+        // The response is removed with its other tags and its URI index entry.
+        if remove_entry(db, &index_key[index_key.len() - 8..])? {
+            purged += 1;
+        }
+        // The entry is left behind when the response has been already removed or it's corrupted.
+        tag_index
+            .remove(&index_key)
+            .map_err(|err| err.to_string())?;
+    }
+    Ok(purged)
+}
+
+/// `catalog` followed by `TAG_SEPARATOR` and `db_key`.
+fn tag_index_key(tag: &str, db_key: &[u8]) -> Vec<u8> {
+    let mut index_key = tag.as_bytes().to_vec();
+    index_key.push(TAG_SEPARATOR);
+    index_key.extend_from_slice(db_key);
+    index_key
+}
+
+/// `*` in the `pattern` matches any characters.
+fn matches_wildcard(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    if !text.starts_with(first) {
+        return false;
+    }
+    let mut rest = &text[first.len()..];
+    let parts = parts.collect::<Vec<_>>();
+    let (last, middle) = match parts.split_last() {
+        Some(last_and_middle) => last_and_middle,
+        // There isn't any wildcard.
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(position) => rest = &rest[position + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+// ------ Hits ------
+
+/// Counts responses served from the cache.
+///
+/// Hits are counted per key since the key has been cached for the first time
+/// or since the cache has been cleared.
+///
+/// Counting doesn't write to the DB - the counts are kept in memory
+/// until they are saved by `flush` (see `cache_sweeper::sweep_cache` and `Proxy::start`).
+#[derive(Default)]
+pub struct CacheHits {
+    pending: Mutex<HashMap<[u8; 8], u64>>,
+}
+
+impl CacheHits {
+    /// Count the response served from the cache.
+    pub fn record(&self, db_key: [u8; 8]) {
+        let mut pending = self.pending.lock().expect("lock cache hits");
+        let count = pending.entry(db_key).or_default();
+        *count = count.saturating_add(1);
+    }
+
+    /// Hits not saved by `flush` yet.
+    pub fn pending(&self, db_key: [u8; 8]) -> u64 {
+        let pending = self.pending.lock().expect("lock cache hits");
+        pending.get(&db_key).copied().unwrap_or_default()
+    }
+
+    /// Add the counted hits to the DB.
+    ///
+    /// Hits of responses that have been removed from the cache in the meantime are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns error when DB reading or writing fails.
+    pub fn flush(&self, db: &Db) -> Result<(), String> {
+        let pending = mem::take(&mut *self.pending.lock().expect("lock cache hits"));
+        let hits = db.open_tree(HITS_TREE).map_err(|err| err.to_string())?;
+        for (db_key, new_hits) in pending {
+            if !db.contains_key(db_key).map_err(|err| err.to_string())? {
+                continue;
+            }
+            hits.update_and_fetch(db_key, |count| {
+                let count = count.map_or(0, decode_hits);
+                Some(count.saturating_add(new_hits).to_be_bytes().to_vec())
+            })
+            .map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Hits saved by `CacheHits::flush`.
+///
+/// # Errors
+///
+/// Returns error when DB reading fails.
+pub fn hits(db: &Db, db_key: [u8; 8]) -> Result<u64, String> {
+    db.open_tree(HITS_TREE)
+        .and_then(|hits| hits.get(db_key))
+        .map(|count| count.map_or(0, |count| decode_hits(&count)))
+        .map_err(|err| err.to_string())
+}
+
+fn decode_hits(count: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    if count.len() == bytes.len() {
+        bytes.copy_from_slice(count);
+    }
+    u64::from_be_bytes(bytes)
+}
+
+/// `example.com/catalog/movie/top.json` followed by `db_key`
+/// for `http://example.com/catalog/movie/top.json`.
+fn uri_index_key(uri: &str, db_key: &[u8]) -> Vec<u8> {
+    let mut index_key = without_scheme(uri).as_bytes().to_vec();
+    index_key.extend_from_slice(db_key);
+    index_key
+}
+
+fn without_scheme(uri: &str) -> &str {
+    match uri.find("://") {
+        Some(index) => &uri[index + 3..],
+        None => uri,
+    }
+}
+
+// ------ CacheKey ------
+
+#[derive(Hash)]
+/// Key for Sled DB.
+pub struct CacheKey<'a> {
+    pub method: &'a Method,
+    pub uri: &'a Uri,
+    pub body: &'a Bytes,
+    /// See `ProxyRoute::cache_partition`.
+    pub user: Option<&'a str>,
+}
+
+impl<'a> CacheKey<'a> {
+    /// Convert to Sled DB compatible keys.
+    ///
+    /// _Notes:_
+    ///   - Sled DB supports only `AsRef<u8>` as the keys and values.
+    ///   - Big-endian is recommended by Sled DB docs.
+    pub fn to_db_key(&self) -> [u8; 8] {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish().to_be_bytes()
+    }
+
+    /// The request stored with the cached response.
+    pub fn to_cached_request(&self) -> CachedRequest {
+        CachedRequest {
+            method: self.method.to_string(),
+            uri: self.uri.to_string(),
+            body: self.body.to_vec(),
+            partitioned: self.user.is_some(),
+            vary: VaryHeaders::default(),
+        }
+    }
+}
+
+/// `CachedRequest::uri` of responses cached for requests to the origin URI
+/// (see `keys_of_uri`).
+pub fn cached_uri(upstream_uri: &Uri, config: Option<&CacheKeyConfig>) -> String {
+    match config {
+        Some(config) => normalize_uri(upstream_uri, config).to_string(),
+        None => upstream_uri.to_string(),
+    }
+}
+
+/// Remove ignored query parameters and the trailing slash from the URI according to `config`
+/// (see `ProxyConfig::cache_key`).
+pub fn normalize_uri(uri: &Uri, config: &CacheKeyConfig) -> Uri {
+    let mut path = uri.path();
+    if config.normalize_trailing_slash && path.len() > 1 {
+        path = path.trim_end_matches('/');
+        if path.is_empty() {
+            path = "/";
+        }
+    }
+    let query = uri.query().map(|query| {
+        query
+            .split('&')
+            .filter(|pair| {
+                let name = pair.split('=').next().unwrap_or_default();
+                !config
+                    .ignored_query_params
+                    .iter()
+                    .any(|ignored| ignored == name)
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    });
+    let path_and_query = match query {
+        Some(query) if !query.is_empty() => format!("{}?{}", path, query),
+        _ => path.to_owned(),
+    };
+    if uri.path_and_query().map(PathAndQuery::as_str) == Some(path_and_query.as_str()) {
+        return uri.clone();
+    }
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = match path_and_query.parse::<PathAndQuery>() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(_) => return uri.clone(),
+    };
+    Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+}
+
+// ------ VaryHeaders ------
+
+/// Request headers listed in the response header `Vary` with their values.
+///
+/// Responses varying by request headers (e.g. `Vary: Accept-Encoding`) are cached
+/// per combination of the header values. The names are stored by `CacheKey::to_db_key`
+/// (see `vary_names`), so the proxy knows which request headers to read before the lookup.
+#[derive(Debug, Default, Clone, PartialEq, Hash, Deserialize, Serialize)]
+pub struct VaryHeaders(Vec<(String, Option<ByteBuf>)>);
+
+impl VaryHeaders {
+    /// Read values of the headers `names` from the request headers.
+    ///
+    /// Multiple values of the same header are joined by `, `.
+    pub fn new(names: &[String], request_headers: &HeaderMap) -> Self {
+        let headers = names
+            .iter()
+            .map(|name| {
+                let mut values = request_headers.get_all(name.as_str()).iter();
+                let value = values.next().map(|first_value| {
+                    let mut value = first_value.as_bytes().to_vec();
+                    for next_value in values {
+                        value.extend_from_slice(b", ");
+                        value.extend_from_slice(next_value.as_bytes());
+                    }
+                    ByteBuf::from(value)
+                });
+                (name.clone(), value)
+            })
+            .collect();
+        Self(headers)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.0.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// The key of the response variant.
+    ///
+    /// It's `db_key` of the request when the response doesn't vary.
+    pub fn to_db_key(&self, db_key: [u8; 8]) -> [u8; 8] {
+        if self.is_empty() {
+            return db_key;
+        }
+        let mut hasher = DefaultHasher::new();
+        db_key.hash(&mut hasher);
+        self.hash(&mut hasher);
+        hasher.finish().to_be_bytes()
+    }
+
+    /// The headers to send with the request to get the same response variant.
+    pub fn to_header_map(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.0 {
+            let header = match (HeaderName::from_bytes(name.as_bytes()), value) {
+                (Ok(name), Some(value)) => {
+                    HeaderValue::from_bytes(value).map(|value| (name, value))
+                }
+                _ => continue,
+            };
+            if let Ok((name, value)) = header {
+                headers.insert(name, value);
+            }
+        }
+        headers
+    }
+}
+
+/// Lowercased, sorted and deduplicated header names from the response header `Vary`.
+///
+/// Returns `None` for `Vary: *` - such responses can't be cached.
+pub fn vary_header_names(response_headers: &HeaderMap) -> Option<Vec<String>> {
+    let mut names = Vec::new();
+    for value in response_headers.get_all(http::header::VARY) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            // Invalid values can't be matched against request headers.
+            Err(_) => return None,
+        };
+        for name in value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            if name == "*" {
+                return None;
+            }
+            names.push(name.to_ascii_lowercase());
+        }
+    }
+    names.sort();
+    names.dedup();
+    Some(names)
+}
+
+/// Names of `VaryHeaders` of the response cached for the request with the given `db_key`.
+///
+/// # Errors
+///
+/// Returns error when DB reading fails or the stored names are corrupted.
+pub fn vary_names(db: &Db, db_key: [u8; 8]) -> Result<Vec<String>, String> {
+    let vary = db.open_tree(VARY_TREE).map_err(|err| err.to_string())?;
+    match vary.get(db_key).map_err(|err| err.to_string())? {
+        Some(names) => bincode::deserialize(&names).map_err(|err| err.to_string()),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Store names returned by `vary_names` (empty `names` are removed).
+///
+/// # Errors
+///
+/// Returns error when DB writing fails.
+pub fn set_vary_names(db: &Db, db_key: [u8; 8], names: &[String]) -> Result<(), String> {
+    let vary = db.open_tree(VARY_TREE).map_err(|err| err.to_string())?;
+    if names.is_empty() {
+        return vary
+            .remove(db_key)
+            .map(|_| ())
+            .map_err(|err| err.to_string());
+    }
+    let names = bincode::serialize(names).map_err(|err| err.to_string())?;
+    // Names are usually the same for all responses of the request, don't write them again.
+    if vary.get(db_key).map_err(|err| err.to_string())?.as_deref() == Some(names.as_slice()) {
+        return Ok(());
+    }
+    vary.insert(db_key, names)
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+// ------ CachedRequest ------
+
+/// The request of the cached response.
+///
+/// It allows to inspect the cache and to re-key cached responses (see `migrate`).
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CachedRequest {
+    pub method: String,
+    /// The URI sent to the origin.
+    pub uri: String,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+    /// The response is cached per user (see `ProxyRoute::cache_partition`).
+    /// The user isn't stored, so the response cannot be re-keyed.
+    pub partitioned: bool,
+    /// The response is cached per values of these request headers.
+    pub vary: VaryHeaders,
+}
+
+// ------ CacheValue ------
+
+/// What the cached value represents.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CacheEntryKind {
+    /// The origin response.
+    Response,
+    /// The origin failure (see `ProxyConfig::negative_cache_validity`).
+    /// Only its status is stored.
+    Negative,
+}
+
+/// Why the value has been written to the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheReason {
+    /// The response hasn't been cached or it has been stale.
+    Request,
+    /// An admin requested a fresh response (`X-Proxy-Refresh`).
+    Refresh,
+    /// The stale response has been refreshed in the background.
+    Revalidation,
+    /// The origin confirmed that the stale response hasn't changed (see `ProxyRoute::head_revalidation`).
+    HeadRevalidation,
+    /// See `ProxyConfig::prefetch`.
+    Prefetch,
+    /// The response has been re-keyed for another origin (see `migrate`).
+    Migration,
+}
+
+/// Debugging information about the cached value (see `ProxyConfig::cache_entry_url_path`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheMetadata {
+    /// The URI requested by the client (e.g. `example.com/catalog/movie/top.json`).
+    pub original_uri: String,
+    /// `ProxyRoute::from` of the matched route.
+    pub route: Option<String>,
+    pub reason: CacheReason,
+    /// See `ProxyConfig::cache_tags`.
+    pub tags: Vec<String>,
+}
+
+/// Metadata of values written without the client request (e.g. idempotency journal entries).
+static UNKNOWN_METADATA: CacheMetadata = CacheMetadata {
+    original_uri: String::new(),
+    route: None,
+    reason: CacheReason::Request,
+    tags: Vec::new(),
+};
+
+/// Value for Sled DB.
+#[derive(Deserialize)]
+pub struct CacheValueForDeserialization {
+    #[serde(with = "http_serde::status_code")]
+    pub status: StatusCode,
+    #[serde(deserialize_with = "deserialize_header_map")]
+    pub headers: HeaderMap,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+    pub timestamp: i64,
+    // See `helpers::monotonic_timestamp`.
+    pub monotonic_timestamp: i64,
+    pub boot_id: u64,
+    // Cached response is valid for `validity` seconds.
+    pub validity: u32,
+    pub request: CachedRequest,
+    pub kind: CacheEntryKind,
+    // See `content_hash`.
+    pub content_hash: u64,
+    pub metadata: CacheMetadata,
+}
+
+impl CacheValueForDeserialization {
+    /// Borrow the value to serialize it again.
+    pub fn for_serialization(&self) -> CacheValueForSerialization<'_> {
+        CacheValueForSerialization {
+            status: self.status,
+            headers: &self.headers,
+            body: &self.body,
+            timestamp: self.timestamp,
+            monotonic_timestamp: self.monotonic_timestamp,
+            boot_id: self.boot_id,
+            validity: self.validity,
+            request: &self.request,
+            kind: self.kind,
+            content_hash: content_hash(self.status, &self.headers, &self.body, self.validity),
+            metadata: &self.metadata,
+        }
+    }
+
+    /// The value represents the origin failure (see `CacheEntryKind::Negative`).
+    pub fn is_negative(&self) -> bool {
+        self.kind == CacheEntryKind::Negative
+    }
+
+    /// Is the cached response still valid?
+    pub fn is_fresh(&self) -> bool {
+        self.age() <= i64::from(self.validity)
+    }
+
+    /// Seconds until the cached response becomes stale (`0` when it's already stale).
+    pub fn remaining_validity(&self) -> i64 {
+        i64::from(self.validity).saturating_sub(self.age()).max(0)
+    }
+
+    /// The cached response age in seconds.
+    ///
+    /// The monotonic age is used when the response has been cached by the current process,
+    /// so system clock changes (e.g. NTP or VM clock steps) don't affect it.
+    /// The wall-clock age is used only for responses cached before a restart.
+    /// The now getter (see `helpers::set_now_getter`) drives both clocks.
+    ///
+    /// The age of responses cached "in the future" (e.g. the clock has been set backwards
+    /// between restarts) is unknown and they are treated as too old.
+    pub fn age(&self) -> i64 {
+        self.age_at(now_timestamp(), monotonic_timestamp(), boot_id())
+    }
+
+    fn age_at(&self, now: i64, monotonic_now: i64, current_boot_id: u64) -> i64 {
+        // Saturate - timestamps in corrupted values can be arbitrary.
+        if self.boot_id == current_boot_id {
+            // The monotonic clock can go backwards only through the now getter.
+            return monotonic_now
+                .saturating_sub(self.monotonic_timestamp)
+                .max(0);
+        }
+        let wall_clock_age = now.saturating_sub(self.timestamp);
+        if wall_clock_age < 0 {
+            return i64::MAX;
+        }
+        wall_clock_age
+    }
+}
+
+/// The same format as `http_serde::header_map` in binary formats.
+///
+/// `http_serde::header_map` preallocates the map according to the stored length
+/// and `HeaderMap::with_capacity` panics on too big lengths in corrupted values.
+fn deserialize_header_map<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HeaderMap, D::Error> {
+    struct HeaderMapVisitor;
+
+    impl<'de> Visitor<'de> for HeaderMapVisitor {
+        type Value = HeaderMap;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("header names with lists of values")
+        }
+
+        fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<HeaderMap, M::Error> {
+            let mut headers = HeaderMap::new();
+            while let Some((name, values)) = access.next_entry::<String, Vec<ByteBuf>>()? {
+                let name = HeaderName::from_bytes(name.as_bytes()).map_err(de::Error::custom)?;
+                for value in values {
+                    let value = HeaderValue::from_bytes(&value).map_err(de::Error::custom)?;
+                    headers.append(&name, value);
+                }
+            }
+            Ok(headers)
+        }
+    }
+
+    deserializer.deserialize_map(HeaderMapVisitor)
+}
+
+/// Value for Sled DB.
+#[derive(Serialize)]
+pub struct CacheValueForSerialization<'a> {
+    #[serde(with = "http_serde::status_code")]
+    pub status: StatusCode,
+    #[serde(with = "http_serde::header_map")]
+    pub headers: &'a HeaderMap,
+    #[serde(with = "serde_bytes")]
+    pub body: &'a [u8],
+    pub timestamp: i64,
+    // See `helpers::monotonic_timestamp`.
+    pub monotonic_timestamp: i64,
+    pub boot_id: u64,
+    // Cached response is valid for `validity` seconds.
+    pub validity: u32,
+    pub request: &'a CachedRequest,
+    pub kind: CacheEntryKind,
+    // See `content_hash`.
+    pub content_hash: u64,
+    pub metadata: &'a CacheMetadata,
+}
+
+impl<'a> CacheValueForSerialization<'a> {
+    /// Create a value with the current timestamps.
+    pub fn new(
+        request: &'a CachedRequest,
+        status: StatusCode,
+        headers: &'a HeaderMap,
+        body: &'a [u8],
+        validity: u32,
+    ) -> Self {
+        Self {
+            status,
+            headers,
+            body,
+            timestamp: now_timestamp(),
+            monotonic_timestamp: monotonic_timestamp(),
+            boot_id: boot_id(),
+            validity,
+            request,
+            kind: CacheEntryKind::Response,
+            content_hash: content_hash(status, headers, body, validity),
+            metadata: &UNKNOWN_METADATA,
+        }
+    }
+
+    /// Store the metadata with the value.
+    pub fn with_metadata(self, metadata: &'a CacheMetadata) -> Self {
+        Self { metadata, ..self }
+    }
+
+    /// Mark the value as the origin failure (see `CacheEntryKind::Negative`).
+    pub fn negative(self) -> Self {
+        Self {
+            kind: CacheEntryKind::Negative,
+            ..self
+        }
+    }
+}
+
+/// Hash of the cached response without timestamps, so identical responses
+/// don't have to be written again (see `is_recently_stored`).
+fn content_hash(status: StatusCode, headers: &HeaderMap, body: &[u8], validity: u32) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    status.hash(&mut hasher);
+    for (name, value) in headers {
+        name.hash(&mut hasher);
+        value.hash(&mut hasher);
+    }
+    body.hash(&mut hasher);
+    validity.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Is the same response already stored under `db_key` and cached at most `window` seconds ago?
+///
+/// The stored response keeps its timestamps when it isn't written again,
+/// so the window should be shorter than cache validities
+/// (see `ProxyConfig::cache_write_coalescing_window`).
+///
+/// # Errors
+///
+/// Returns error when DB reading fails.
+pub fn is_recently_stored(
+    db: &Db,
+    db_key: [u8; 8],
+    value: &CacheValueForSerialization,
+    window: u32,
+) -> Result<bool, String> {
+    let stored = match db.get(db_key).map_err(|err| err.to_string())? {
+        Some(stored) => stored,
+        None => return Ok(false),
+    };
+    // Corrupted or old values are overwritten.
+    let stored = match bincode::deserialize::<CacheValueForDeserialization>(&stored) {
+        Ok(stored) => stored,
+        Err(_) => return Ok(false),
+    };
+    Ok(stored.kind == value.kind
+        && stored.content_hash == value.content_hash
+        && stored.request == *value.request
+        && stored.is_fresh()
+        && stored.age() <= i64::from(window))
+}
+
+// ------ migrate ------
+
+/// The result of `migrate`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Migration {
+    pub migrated: usize,
+    /// Responses cached per user or already cached for the new origin.
+    pub skipped: usize,
+}
+
+/// Re-key cached responses of requests sent to `old_origin` as if they were sent to `new_origin`,
+/// so the cache stays warm after the `to` of a route has been changed.
+///
+/// URIs are rewritten the same way as in `handle_routes`
+/// (e.g. `http://old.com/manifest.json` -> `http://new.com/addon/manifest.json`
+/// for origins `http://old.com` and `http://new.com/addon/`).
+///
+/// # Errors
+///
+/// Returns error when DB reading or writing fails.
+pub fn migrate(db: &Db, old_origin: &Uri, new_origin: &Uri) -> Result<Migration, String> {
+    let old_prefix = old_origin.to_string();
+    let request_of = |value: &[u8]| {
+        bincode::deserialize::<CacheValueForDeserialization>(value)
+            .ok()
+            // Origin failures aren't migrated.
+            .filter(|value| !value.is_negative() && value.request.uri.starts_with(&old_prefix))
+    };
+
+    // Keys are collected first - migrated responses may match `old_prefix` again
+    // (e.g. `http://example.com/` -> `http://example.com/v2/`).
+    let mut keys = Vec::new();
+    for entry in db.iter() {
+        let (key, value) = entry.map_err(|err| err.to_string())?;
+        if request_of(&value).is_some() {
+            keys.push(key);
+        }
+    }
+
+    let mut migration = Migration::default();
+    for key in keys {
+        let mut value = match db.get(&key).map_err(|err| err.to_string())? {
+            Some(value) => match request_of(&value) {
+                Some(value) => value,
+                None => continue,
+            },
+            None => continue,
+        };
+        if value.request.partitioned {
+            migration.skipped += 1;
+            continue;
+        }
+        let uri = format!("{}{}", new_origin, &value.request.uri[old_prefix.len()..])
+            .parse::<Uri>()
+            .map_err(|err| err.to_string())?;
+        let method =
+            Method::from_bytes(value.request.method.as_bytes()).map_err(|err| err.to_string())?;
+        let body = Bytes::from(value.request.body.clone());
+        let new_request_key = CacheKey {
+            method: &method,
+            uri: &uri,
+            body: &body,
+            user: None,
+        }
+        .to_db_key();
+        let new_key = value.request.vary.to_db_key(new_request_key);
+        // The response from the new origin is more relevant.
+        if db.contains_key(new_key).map_err(|err| err.to_string())? {
+            migration.skipped += 1;
+            continue;
+        }
+        if !value.request.vary.is_empty() {
+            set_vary_names(db, new_request_key, &value.request.vary.names())?;
+        }
+        let old_index_key = uri_index_key(&value.request.uri, &key);
+        value.request.uri = uri.to_string();
+        value.metadata.reason = CacheReason::Migration;
+        let serialized =
+            bincode::serialize(&value.for_serialization()).map_err(|err| err.to_string())?;
+        db.insert(new_key, serialized)
+            .map_err(|err| err.to_string())?;
+        index_uri(db, &value.request.uri, new_key)?;
+        index_tags(db, &value.metadata.tags, new_key)?;
+        db.remove(&key).map_err(|err| err.to_string())?;
+        db.open_tree(URI_INDEX_TREE)
+            .and_then(|index| index.remove(old_index_key))
+            .map_err(|err| err.to_string())?;
+        migration.migrated += 1;
+    }
+    Ok(migration)
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::with_now_getter;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    fn cached_value(
+        timestamp: i64,
+        monotonic_timestamp: i64,
+        boot_id: u64,
+    ) -> CacheValueForDeserialization {
+        CacheValueForDeserialization {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+            timestamp,
+            monotonic_timestamp,
+            boot_id,
+            validity: 600,
+            request: CachedRequest::default(),
+            kind: CacheEntryKind::Response,
+            content_hash: 0,
+            metadata: UNKNOWN_METADATA.clone(),
+        }
+    }
+
+    #[test]
+    fn age_clock_set_backwards() {
+        let value = cached_value(1_000_000, 100, 1);
+        // 20 minutes have passed, but the clock has been set 1 hour backwards.
+        assert_eq!(value.age_at(1_000_000 + 1200 - 3600, 1300, 1), 1200);
+    }
+
+    #[test]
+    fn age_clock_set_forwards() {
+        let value = cached_value(1_000_000, 100, 1);
+        // 1 minute has passed, but the clock has been set 1 hour forwards.
+        assert_eq!(value.age_at(1_000_000 + 60 + 3600, 160, 1), 60);
+    }
+
+    #[test]
+    fn fresh_after_clock_jumps_forwards() {
+        let headers = HeaderMap::new();
+        let request = CachedRequest::default();
+        let serialized = bincode::serialize(&CacheValueForSerialization::new(
+            &request,
+            StatusCode::OK,
+            &headers,
+            &[],
+            600,
+        ))
+        .unwrap();
+        let mut value = bincode::deserialize::<CacheValueForDeserialization>(&serialized).unwrap();
+        // The system clock has jumped 1 hour forwards since the response was cached.
+        value.timestamp -= 3600;
+        assert!(value.is_fresh());
+        assert!(value.remaining_validity() > 500);
+    }
+
+    #[test]
+    fn age_another_boot() {
+        let value = cached_value(1_000_000, 100_000, 1);
+        assert_eq!(value.age_at(1_000_060, 5, 2), 60);
+        // Cached "in the future".
+        assert_eq!(value.age_at(999_000, 5, 2), i64::MAX);
+    }
+
+    #[test]
+    fn age_extreme_timestamps() {
+        assert_eq!(
+            cached_value(i64::MIN, 0, 1).age_at(1_000_000, 5, 2),
+            i64::MAX
+        );
+        assert_eq!(
+            cached_value(0, i64::MIN, 1).age_at(1_000_000, 5, 1),
+            i64::MAX
+        );
+        assert_eq!(cached_value(i64::MAX, 0, 1).age_at(-1, 5, 2), i64::MAX);
+    }
+
+    #[tokio::test]
+    async fn fresh_after_clock_jumps() {
+        let headers = HeaderMap::new();
+        let request = CachedRequest::default();
+        let serialized = with_now_getter(Arc::new(|| 1_000_000), async {
+            bincode::serialize(&CacheValueForSerialization::new(
+                &request,
+                StatusCode::OK,
+                &headers,
+                &[],
+                600,
+            ))
+            .unwrap()
+        })
+        .await;
+        let value = bincode::deserialize::<CacheValueForDeserialization>(&serialized).unwrap();
+
+        let backwards = with_now_getter(Arc::new(|| 1_000_000 - 3600), async { value.is_fresh() });
+        assert!(backwards.await);
+
+        let forwards = with_now_getter(Arc::new(|| 1_000_000 + 601), async { value.is_fresh() });
+        assert!(!forwards.await);
+    }
+
+    #[test]
+    fn headers_roundtrip() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.append("set-cookie", "a=1".parse().unwrap());
+        headers.append("set-cookie", "b=2".parse().unwrap());
+        let request = CachedRequest::default();
+        let serialized = bincode::serialize(&CacheValueForSerialization::new(
+            &request,
+            StatusCode::OK,
+            &headers,
+            b"body",
+            600,
+        ))
+        .unwrap();
+
+        let value = bincode::deserialize::<CacheValueForDeserialization>(&serialized).unwrap();
+        assert_eq!(value.headers, headers);
+        assert_eq!(value.body, b"body");
+    }
+
+    #[test]
+    fn corrupted_header_count() {
+        // Status 200 and then `u64::MAX` headers.
+        let mut corrupted = 200_u16.to_le_bytes().to_vec();
+        corrupted.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(bincode::deserialize::<CacheValueForDeserialization>(&corrupted).is_err());
+    }
+
+    // ------ CacheKey properties ------
+
+    fn db_key(uri: &Uri, body: &Bytes, user: Option<&str>) -> [u8; 8] {
+        CacheKey {
+            method: &Method::GET,
+            uri,
+            body,
+            user,
+        }
+        .to_db_key()
+    }
+
+    proptest! {
+        #[test]
+        fn db_key_stable(
+            path in "(/[a-zA-Z0-9._~%=&-]{0,10}){1,4}",
+            body in proptest::collection::vec(any::<u8>(), 0..32),
+            user in proptest::option::of("[a-z0-9]{1,8}"),
+        ) {
+            let uri = format!("http://example.com{}", path).parse::<Uri>();
+            prop_assume!(uri.is_ok());
+            let uri = uri.unwrap();
+            // The key of the same request has to be the same after a restart (re-parsed URI, new body).
+            let reparsed_uri = uri.to_string().parse::<Uri>().unwrap();
+            let body = Bytes::from(body);
+            let copied_body = Bytes::copy_from_slice(&body);
+
+            prop_assert_eq!(
+                db_key(&uri, &body, user.as_deref()),
+                db_key(&reparsed_uri, &copied_body, user.as_deref())
+            );
+        }
+
+        #[test]
+        fn db_key_distinguishes_requests(
+            paths in proptest::collection::hash_set("/[a-z0-9]{1,12}", 1..50),
+            user in "[a-z0-9]{1,8}",
+        ) {
+            let body = Bytes::new();
+            let mut keys = HashSet::new();
+            for path in &paths {
+                let uri = format!("http://example.com{}", path).parse::<Uri>().unwrap();
+                // Another user's response must never be served from the partitioned cache.
+                prop_assert_ne!(db_key(&uri, &body, None), db_key(&uri, &body, Some(&user)));
+                keys.insert(db_key(&uri, &body, None));
+            }
+            prop_assert_eq!(keys.len(), paths.len());
+        }
+    }
+
+    #[test]
+    fn normalize_cache_uri() {
+        let config = CacheKeyConfig {
+            ignored_query_params: vec!["utm_source".to_owned(), "utm_medium".to_owned()],
+            ignored_headers: Vec::new(),
+            normalize_trailing_slash: true,
+            include_body: true,
+        };
+        let normalize = |uri: &str| normalize_uri(&uri.parse().unwrap(), &config).to_string();
+
+        assert_eq!(
+            normalize("http://a.com/catalog/?utm_source=x&skip=1&utm_medium"),
+            "http://a.com/catalog?skip=1"
+        );
+        assert_eq!(
+            normalize("http://a.com/catalog//?utm_source=x"),
+            "http://a.com/catalog"
+        );
+        assert_eq!(normalize("http://a.com/"), "http://a.com/");
+        assert_eq!(normalize("http://a.com/?a=1&b=2"), "http://a.com/?a=1&b=2");
+    }
+
+    #[test]
+    fn vary_headers() {
+        let mut response_headers = HeaderMap::new();
+        response_headers.append("vary", "Accept-Language, accept-encoding".parse().unwrap());
+        response_headers.append("vary", "Accept-Language".parse().unwrap());
+        let names = vary_header_names(&response_headers).unwrap();
+        assert_eq!(names, vec!["accept-encoding", "accept-language"]);
+
+        response_headers.append("vary", "*".parse().unwrap());
+        assert_eq!(vary_header_names(&response_headers), None);
+        assert_eq!(vary_header_names(&HeaderMap::new()), Some(Vec::new()));
+
+        let request_headers = |language: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("accept-language", language.parse().unwrap());
+            headers
+        };
+        let en = VaryHeaders::new(&names, &request_headers("en"));
+        let cs = VaryHeaders::new(&names, &request_headers("cs"));
+        assert_ne!(en.to_db_key([1; 8]), cs.to_db_key([1; 8]));
+        assert_ne!(en.to_db_key([1; 8]), en.to_db_key([2; 8]));
+        assert_eq!(VaryHeaders::default().to_db_key([1; 8]), [1; 8]);
+        // Missing headers aren't sent again.
+        assert_eq!(en.to_header_map(), request_headers("en"));
+    }
+
+    #[test]
+    fn migrate_to_new_origin() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let headers = HeaderMap::new();
+        let insert = |uri: &str, user: Option<&str>| {
+            let uri = uri.parse::<Uri>().unwrap();
+            let key = CacheKey {
+                method: &Method::GET,
+                uri: &uri,
+                body: &Bytes::new(),
+                user,
+            };
+            let request = key.to_cached_request();
+            let value =
+                CacheValueForSerialization::new(&request, StatusCode::OK, &headers, b"cached", 600);
+            db.insert(key.to_db_key(), bincode::serialize(&value).unwrap())
+                .unwrap();
+            key.to_db_key()
+        };
+        let old_key = insert("http://old.com/catalog/movie/top.json", None);
+        insert("http://old.com/catalog/movie/user.json", Some("user"));
+        let other_key = insert("http://other.com/catalog/movie/top.json", None);
+
+        let migration = migrate(
+            &db,
+            &"http://old.com".parse().unwrap(),
+            &"http://new.com/addon/".parse().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            migration,
+            Migration {
+                migrated: 1,
+                skipped: 1
+            }
+        );
+        assert!(!db.contains_key(old_key).unwrap());
+        assert!(db.contains_key(other_key).unwrap());
+
+        let new_uri = "http://new.com/addon/catalog/movie/top.json"
+            .parse::<Uri>()
+            .unwrap();
+        let new_key = CacheKey {
+            method: &Method::GET,
+            uri: &new_uri,
+            body: &Bytes::new(),
+            user: None,
+        }
+        .to_db_key();
+        let value = db.get(new_key).unwrap().unwrap();
+        let value = bincode::deserialize::<CacheValueForDeserialization>(&value).unwrap();
+        assert_eq!(value.body, b"cached");
+        assert_eq!(value.request.uri, new_uri.to_string());
+        assert_eq!(value.metadata.reason, CacheReason::Migration);
+    }
+
+    /// Cache an empty response with the URI and tags and index it.
+    fn insert_indexed(db: &Db, uri: &str, tags: &[&str], db_key: [u8; 8]) {
+        let request = CachedRequest {
+            uri: uri.to_owned(),
+            ..CachedRequest::default()
+        };
+        let metadata = CacheMetadata {
+            tags: tags.iter().map(|tag| (*tag).to_owned()).collect(),
+            ..UNKNOWN_METADATA.clone()
+        };
+        let headers = HeaderMap::new();
+        let value = CacheValueForSerialization::new(&request, StatusCode::OK, &headers, b"", 600)
+            .with_metadata(&metadata);
+        db.insert(db_key, bincode::serialize(&value).unwrap())
+            .unwrap();
+        index_uri(db, uri, db_key).unwrap();
+        index_tags(db, &metadata.tags, db_key).unwrap();
+    }
+
+    #[test]
+    fn purge_by_prefix() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let insert = |uri: &str, key: [u8; 8]| {
+            let tags: &[&str] = if uri.contains("/catalog/") {
+                &["catalog"]
+            } else {
+                &["meta"]
+            };
+            insert_indexed(&db, uri, tags, key);
+        };
+        insert("http://a.com/catalog/movie/top.json", [1; 8]);
+        insert("https://a.com/catalog/series/top.json", [2; 8]);
+        insert("http://a.com/meta/movie/tt1.json", [3; 8]);
+        insert("http://b.com/catalog/movie/top.json", [4; 8]);
+        // Removed by other means (e.g. `cache rm`).
+        index_uri(&db, "http://a.com/catalog/movie/old.json", [5; 8]).unwrap();
+
+        assert_eq!(purge(&db, "/catalog/movie").unwrap(), 2);
+        assert!(!db.contains_key([1; 8]).unwrap());
+        assert!(!db.contains_key([4; 8]).unwrap());
+
+        assert_eq!(purge(&db, "http://a.com/catalog").unwrap(), 1);
+        assert!(!db.contains_key([2; 8]).unwrap());
+        assert!(db.contains_key([3; 8]).unwrap());
+
+        assert_eq!(purge(&db, "a.com/catalog").unwrap(), 0);
+        assert_eq!(db.open_tree(URI_INDEX_TREE).unwrap().len(), 1);
+        // Tags of purged responses are removed too.
+        assert_eq!(db.open_tree(TAG_INDEX_TREE).unwrap().len(), 1);
+        assert_eq!(purge_tag(&db, "catalog").unwrap(), 0);
+    }
+
+    #[test]
+    fn purge_by_tag() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let insert = |tags: &[&str], key: [u8; 8]| {
+            let uri = format!("http://a.com/{}.json", key[0]);
+            insert_indexed(&db, &uri, tags, key);
+        };
+        insert(&["catalog", "a.com:catalog"], [1; 8]);
+        insert(&["catalog", "b.com:catalog"], [2; 8]);
+        insert(&["catalogs"], [3; 8]);
+        insert(&["meta", "a.com:meta"], [4; 8]);
+
+        assert_eq!(purge_tag(&db, "catalog").unwrap(), 2);
+        assert!(db.contains_key([3; 8]).unwrap());
+        // Other tags of purged responses are removed too.
+        assert_eq!(db.open_tree(TAG_INDEX_TREE).unwrap().len(), 3);
+        assert_eq!(purge_tag(&db, "a.com:catalog").unwrap(), 0);
+
+        assert_eq!(purge_tag(&db, "a.com:*").unwrap(), 1);
+        assert!(!db.contains_key([4; 8]).unwrap());
+        assert_eq!(purge_tag(&db, "*s").unwrap(), 1);
+        assert!(db.is_empty());
+        assert!(db.open_tree(TAG_INDEX_TREE).unwrap().is_empty());
+        assert!(db.open_tree(URI_INDEX_TREE).unwrap().is_empty());
+        assert_eq!(purge(&db, "a.com").unwrap(), 0);
+    }
+
+    #[test]
+    fn remove_with_indexes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let request = CachedRequest {
+            uri: "http://a.com/catalog/movie/top.json".to_owned(),
+            ..CachedRequest::default()
+        };
+        let metadata = CacheMetadata {
+            tags: vec!["catalog".to_owned()],
+            ..UNKNOWN_METADATA.clone()
+        };
+        let headers = HeaderMap::new();
+        let value = CacheValueForSerialization::new(&request, StatusCode::OK, &headers, b"", 600)
+            .with_metadata(&metadata);
+        db.insert([1; 8], bincode::serialize(&value).unwrap())
+            .unwrap();
+        index_uri(&db, &request.uri, [1; 8]).unwrap();
+        index_tags(&db, &metadata.tags, [1; 8]).unwrap();
+        let cache_hits = CacheHits::default();
+        cache_hits.record([1; 8]);
+        cache_hits.flush(&db).unwrap();
+        set_vary_names(&db, [1; 8], &["accept-language".to_owned()]).unwrap();
+
+        assert!(remove(&db, [1; 8]).unwrap());
+        assert!(db.is_empty());
+        for tree in &[VARY_TREE, URI_INDEX_TREE, HITS_TREE, TAG_INDEX_TREE] {
+            assert!(db.open_tree(tree).unwrap().is_empty());
+        }
+        assert!(!remove(&db, [1; 8]).unwrap());
+    }
+
+    #[test]
+    fn wildcard() {
+        assert!(matches_wildcard("catalog", "catalog"));
+        assert!(!matches_wildcard("catalog", "catalogs"));
+        assert!(matches_wildcard("catalog*", "catalogs"));
+        assert!(matches_wildcard("*:catalog", "a.com:catalog"));
+        assert!(matches_wildcard("a*:*g", "a.com:catalog"));
+        assert!(!matches_wildcard("a*:*g", "a.com:meta"));
+        assert!(!matches_wildcard("a*a", "a"));
+        assert!(matches_wildcard("*", ""));
+    }
+
+    #[test]
+    fn keys_and_hits() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        index_uri(&db, "http://a.com/catalog/movie/top.json", [1; 8]).unwrap();
+        index_uri(&db, "https://a.com/catalog/movie/top.json", [2; 8]).unwrap();
+        index_uri(&db, "http://a.com/catalog/movie/top.json?skip=100", [3; 8]).unwrap();
+
+        assert_eq!(
+            keys_of_uri(&db, "a.com/catalog/movie/top.json").unwrap(),
+            vec![[1; 8], [2; 8]]
+        );
+        assert!(keys_of_uri(&db, "http://a.com/catalog").unwrap().is_empty());
+
+        db.insert([1; 8], "cached").unwrap();
+        let cache_hits = CacheHits::default();
+        cache_hits.record([1; 8]);
+        cache_hits.record([1; 8]);
+        // Hits of uncached responses aren't saved.
+        cache_hits.record([2; 8]);
+        // Counted hits are kept in memory.
+        assert_eq!(hits(&db, [1; 8]).unwrap(), 0);
+        assert_eq!(cache_hits.pending([1; 8]), 2);
+
+        cache_hits.flush(&db).unwrap();
+        assert_eq!(hits(&db, [1; 8]).unwrap(), 2);
+        assert_eq!(hits(&db, [2; 8]).unwrap(), 0);
+        assert_eq!(cache_hits.pending([1; 8]), 0);
+
+        purge(&db, "/catalog").unwrap();
+        assert_eq!(hits(&db, [1; 8]).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn recently_stored() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let headers = HeaderMap::new();
+        let request = CachedRequest::default();
+        let value = |body: &'static [u8]| {
+            CacheValueForSerialization::new(&request, StatusCode::OK, &headers, body, 600)
+        };
+        with_now_getter(Arc::new(|| 1_000_000), async {
+            db.insert([1; 8], bincode::serialize(&value(b"body")).unwrap())
+                .unwrap();
+        })
+        .await;
+
+        let stored = with_now_getter(Arc::new(|| 1_000_030), async {
+            (
+                is_recently_stored(&db, [1; 8], &value(b"body"), 60).unwrap(),
+                is_recently_stored(&db, [1; 8], &value(b"body"), 10).unwrap(),
+                is_recently_stored(&db, [1; 8], &value(b"other body"), 60).unwrap(),
+                is_recently_stored(&db, [1; 8], &value(b"body").negative(), 60).unwrap(),
+                is_recently_stored(&db, [2; 8], &value(b"body"), 60).unwrap(),
+            )
+        });
+        assert_eq!(stored.await, (true, false, false, false, false));
+    }
+
+    #[test]
+    fn ensure_format_clears_old_cache() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert("old_response", "old").unwrap();
+
+        ensure_format(&db).unwrap();
+        assert!(db.is_empty());
+
+        db.insert("new_response", "new").unwrap();
+        ensure_format(&db).unwrap();
+        assert_eq!(db.len(), 1);
+    }
+}
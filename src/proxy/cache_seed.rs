@@ -0,0 +1,154 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response, Uri};
+use tokio::fs;
+use tokio::sync::{watch, Notify};
+use tracing::{error, info, warn};
+
+use super::{ProxyConfig, ProxyState};
+use crate::hyper_helpers::body_to_bytes;
+
+// ------ CacheSeed ------
+
+/// Warming of the cache with URLs from `ProxyConfig::cache_seed_file`.
+#[derive(Default)]
+pub struct CacheSeed {
+    /// `true` until the scheduled seeding is finished.
+    seeding: AtomicBool,
+    scheduled: Notify,
+}
+
+impl CacheSeed {
+    /// Seed the cache in the background (on the server start and after the cache is cleared).
+    ///
+    /// The proxy isn't ready until the seeding is finished.
+    pub fn schedule(&self) {
+        self.seeding.store(true, Ordering::SeqCst);
+        self.scheduled.notify();
+    }
+
+    /// See `schedule`.
+    pub fn is_seeding(&self) -> bool {
+        self.seeding.load(Ordering::SeqCst)
+    }
+
+    fn finish(&self) {
+        self.seeding.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Send requests with URLs from the seed file through `handle_request` every time
+/// the seeding is scheduled (see `CacheSeed::schedule`).
+///
+/// It's stopped when the config sender is dropped.
+pub async fn seed_cache<F>(
+    mut config_receiver: watch::Receiver<Arc<ProxyConfig>>,
+    state: Arc<ProxyState>,
+    handle_request: impl Fn(Request<Body>) -> F,
+) where
+    F: Future<Output = Result<Response<Body>, hyper::Error>>,
+{
+    loop {
+        tokio::select! {
+            new_proxy_config = config_receiver.recv() => {
+                match new_proxy_config {
+                    Some(_) => continue,
+                    None => return,
+                }
+            }
+            _ = state.cache_seed.scheduled.notified() => ()
+        }
+        let proxy_config = config_receiver.borrow().clone();
+        let seed_file = match &proxy_config.cache_seed_file {
+            Some(seed_file) if proxy_config.cache_enabled => seed_file,
+            _ => {
+                state.cache_seed.finish();
+                continue;
+            }
+        };
+        let uris = match fs::read_to_string(seed_file).await {
+            Ok(content) => parse_seed_file(&content),
+            Err(error) => {
+                error!(
+                    "cannot read cache seed file '{}': {}",
+                    seed_file.display(),
+                    error
+                );
+                state.cache_seed.finish();
+                continue;
+            }
+        };
+
+        info!(urls = uris.len(), "seeding cache");
+        let mut failed = 0;
+        for uri in uris {
+            let mut req = Request::new(Body::empty());
+            *req.uri_mut() = uri.clone();
+            // The response is cached when its body has been read.
+            let result = match handle_request(req).await {
+                Ok(response) if response.status().is_success() => {
+                    body_to_bytes(response.into_body()).await.map(|_| ())
+                }
+                Ok(response) => {
+                    warn!(%uri, status = %response.status(), "cannot seed cache");
+                    failed += 1;
+                    continue;
+                }
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                warn!(%uri, %error, "cannot seed cache");
+                failed += 1;
+            }
+        }
+        info!(failed, "cache seeded");
+        state.cache_seed.finish();
+    }
+}
+
+/// Absolute URLs - one per line. Empty lines and lines starting with `#` are ignored.
+fn parse_seed_file(content: &str) -> Vec<Uri> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match line.parse::<Uri>() {
+            Ok(uri) if uri.host().is_some() => Some(uri),
+            _ => {
+                warn!("invalid URL '{}' in cache seed file", line);
+                None
+            }
+        })
+        .collect()
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seed_file() {
+        let content = "
+            # Popular catalogs
+            http://example.com/catalog/movie/top.json
+
+            /relative/path.json
+            https://example.com:8443/manifest.json
+        ";
+        let uris = parse_seed_file(content)
+            .iter()
+            .map(Uri::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            uris,
+            [
+                "http://example.com/catalog/movie/top.json",
+                "https://example.com:8443/manifest.json"
+            ]
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+use sled::IVec;
+use tokio::sync::watch;
+use tokio::{task, time};
+use tracing::{error, info};
+
+use super::cache::{self, CacheValueForDeserialization};
+use super::{Db, ProxyConfig, ProxyState};
+
+/// Cached responses checked between yields to other tasks.
+const SWEEP_BATCH_SIZE: usize = 100;
+/// How often cache hits are saved when `ProxyConfig::cache_sweep_interval` is missing.
+const HITS_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+// ------ sweep_cache ------
+
+/// Remove expired cached responses every `ProxyConfig::cache_sweep_interval` seconds
+/// until the config sender is dropped.
+///
+/// Cache hits counted in `ProxyState::cache_hits` are saved before each sweep
+/// (or every `HITS_FLUSH_INTERVAL` when sweeping is disabled).
+pub async fn sweep_cache(
+    mut config_receiver: watch::Receiver<Arc<ProxyConfig>>,
+    db: Db,
+    state: Arc<ProxyState>,
+) {
+    let mut proxy_config: Option<Arc<ProxyConfig>> = None;
+
+    loop {
+        let sweep_interval = proxy_config
+            .as_ref()
+            .and_then(|proxy_config| proxy_config.cache_sweep_interval)
+            .map(|interval| Duration::from_secs(u64::from(interval.max(1))));
+        let sweep_time = time::delay_for(sweep_interval.unwrap_or(HITS_FLUSH_INTERVAL));
+        tokio::select! {
+            new_proxy_config = config_receiver.recv() => {
+                match new_proxy_config {
+                    Some(new_proxy_config) => proxy_config = Some(new_proxy_config),
+                    None => return,
+                }
+            }
+            _ = sweep_time => ()
+        }
+        if let Err(error) = state.cache_hits.flush(&db) {
+            error!("cannot save cache hits: {}", error);
+        }
+        let proxy_config = match &proxy_config {
+            Some(proxy_config) if proxy_config.cache_sweep_interval.is_some() => proxy_config,
+            _ => continue,
+        };
+        match sweep(&db, proxy_config).await {
+            Ok(0) => (),
+            Ok(removed) => info!(removed, "expired cached responses removed"),
+            Err(error) => error!("cannot remove expired cached responses: {}", error),
+        }
+    }
+}
+
+/// Remove expired and corrupted cached responses.
+///
+/// The DB is scanned in batches, so other tasks aren't blocked by big caches.
+///
+/// Returns the number of removed responses.
+async fn sweep(db: &Db, proxy_config: &ProxyConfig) -> Result<usize, String> {
+    let mut removed = 0;
+    let mut last_key: Option<IVec> = None;
+    loop {
+        let entries = match &last_key {
+            Some(last_key) => {
+                db.range::<&[u8], _>((Bound::Excluded(last_key.as_ref()), Bound::Unbounded))
+            }
+            None => db.iter(),
+        };
+        let batch = entries
+            .take(SWEEP_BATCH_SIZE)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.to_string())?;
+        last_key = match batch.last() {
+            Some((key, _)) => Some(key.clone()),
+            None => return Ok(removed),
+        };
+        for (key, value) in batch {
+            let cached_response = match bincode::deserialize::<CacheValueForDeserialization>(&value)
+            {
+                Ok(cached_response) if is_expired(&cached_response, proxy_config) => {
+                    Some(cached_response)
+                }
+                Ok(_) => continue,
+                // Corrupted responses can't be served.
+                Err(_) => None,
+            };
+            if cache::remove_unchanged(db, &key, &value, cached_response.as_ref())? {
+                removed += 1;
+            }
+        }
+        // Let other tasks run between batches.
+        let () = task::yield_now().await;
+    }
+}
+
+/// The cached response can't be served anymore - neither as a fresh one nor as a stale one
+/// (see `ProxyConfig::stale_while_revalidate` and `ProxyConfig::cache_stale_threshold_on_fail`).
+fn is_expired(cached_response: &CacheValueForDeserialization, proxy_config: &ProxyConfig) -> bool {
+    let validity = i64::from(cached_response.validity);
+    // Cached origin failures are served only when they are fresh.
+    let max_age = if cached_response.is_negative() {
+        validity
+    } else {
+        let stale_threshold = proxy_config
+            .cache_stale_threshold_on_fail
+            .max(proxy_config.stale_while_revalidate.unwrap_or_default());
+        validity.saturating_add(i64::from(stale_threshold))
+    };
+    cached_response.age() > max_age
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::with_now_getter;
+    use crate::proxy::cache::{CacheValueForSerialization, CachedRequest};
+    use http::{HeaderMap, StatusCode};
+
+    #[tokio::test]
+    async fn sweep_expired() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut proxy_config = toml::from_str::<ProxyConfig>(
+            r#"
+            reload_config_url_path = "/reload-proxy-config"
+            clear_cache_url_path = "/clear-cache"
+            status_url_path = "/status"
+            db_directory = "proxy_db"
+            ip = "0.0.0.0"
+            default_port = 5000
+            cache_enabled = true
+            default_cache_validity = 600
+            cache_stale_threshold_on_fail = 3600
+            timeout = 20
+            verbose = false
+            routes = []
+            "#,
+        )
+        .unwrap();
+        proxy_config.stale_while_revalidate = Some(60);
+        let headers = HeaderMap::new();
+        let now = 1_000_000;
+        with_now_getter(Arc::new(move || now), async {
+            for (key, age, negative) in &[
+                (1_u64, 600 + 3600 + 1, false),
+                (2, 600 + 3600, false),
+                (3, 601, true),
+                (4, 600, true),
+            ] {
+                let request = CachedRequest {
+                    uri: format!("http://example.com/{}.json", key),
+                    ..CachedRequest::default()
+                };
+                let timestamp = now - age;
+                let value = with_now_getter(Arc::new(move || timestamp), async {
+                    let value = CacheValueForSerialization::new(
+                        &request,
+                        StatusCode::OK,
+                        &headers,
+                        &[],
+                        600,
+                    );
+                    let value = if *negative { value.negative() } else { value };
+                    bincode::serialize(&value).unwrap()
+                })
+                .await;
+                db.insert(key.to_be_bytes(), value).unwrap();
+                cache::index_uri(&db, &request.uri, key.to_be_bytes()).unwrap();
+            }
+            db.insert(5_u64.to_be_bytes(), "corrupted").unwrap();
+            // More than one batch.
+            for key in 10..(10 + SWEEP_BATCH_SIZE as u64) {
+                let request = CachedRequest::default();
+                let value =
+                    CacheValueForSerialization::new(&request, StatusCode::OK, &headers, &[], 600);
+                db.insert(key.to_be_bytes(), bincode::serialize(&value).unwrap())
+                    .unwrap();
+            }
+
+            assert_eq!(sweep(&db, &proxy_config).await.unwrap(), 3);
+        })
+        .await;
+
+        assert!(!db.contains_key(1_u64.to_be_bytes()).unwrap());
+        assert!(db.contains_key(2_u64.to_be_bytes()).unwrap());
+        assert!(!db.contains_key(3_u64.to_be_bytes()).unwrap());
+        assert!(db.contains_key(4_u64.to_be_bytes()).unwrap());
+        assert!(!db.contains_key(5_u64.to_be_bytes()).unwrap());
+        assert_eq!(db.len(), 2 + SWEEP_BATCH_SIZE);
+        assert!(cache::keys_of_uri(&db, "http://example.com/1.json")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            cache::keys_of_uri(&db, "http://example.com/2.json").unwrap(),
+            vec![2_u64.to_be_bytes()]
+        );
+    }
+
+    #[test]
+    fn keep_changed_response() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert([1; 8], "new").unwrap();
+        assert!(!cache::remove_unchanged(&db, &[1; 8], b"old", None).unwrap());
+        assert!(db.contains_key([1; 8]).unwrap());
+    }
+}
@@ -0,0 +1,400 @@
+use std::error::Error;
+use std::io::{self, Write};
+use std::mem;
+
+use flate2::write::{GzDecoder, GzEncoder};
+use flate2::Compression;
+use futures_util::stream::{self, StreamExt};
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Method, Response, StatusCode};
+
+use super::CompressionConfig;
+
+// Brotli settings for on-the-fly compression (the max quality is too slow).
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_SIZE: u32 = 22;
+
+// ------ Coding ------
+
+/// Content codings supported by the proxy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Coding {
+    Gzip,
+    Brotli,
+}
+
+impl Coding {
+    /// The name used in headers `Accept-Encoding` and `Content-Encoding`.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+        }
+    }
+}
+
+/// The quality value of the coding in the header `Accept-Encoding` (0 for unaccepted codings).
+fn quality(accept_encoding: &str, coding: Coding) -> f32 {
+    let mut wildcard_quality = None;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .filter_map(|param| {
+                let mut key_and_value = param.trim().splitn(2, '=');
+                match (key_and_value.next(), key_and_value.next()) {
+                    (Some("q"), Some(value)) => value.trim().parse().ok(),
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(coding.name()) {
+            return quality;
+        }
+        if name == "*" {
+            wildcard_quality = Some(quality);
+        }
+    }
+    wildcard_quality.unwrap_or(0.0)
+}
+
+/// The accepted coding with the highest quality value, Brotli wins ties.
+pub fn preferred_coding(accept_encoding: &str) -> Option<Coding> {
+    let brotli = quality(accept_encoding, Coding::Brotli);
+    let gzip = quality(accept_encoding, Coding::Gzip);
+    if brotli > 0.0 && brotli >= gzip {
+        Some(Coding::Brotli)
+    } else if gzip > 0.0 {
+        Some(Coding::Gzip)
+    } else {
+        None
+    }
+}
+
+// ------ compress_response ------
+
+/// Compress the response by the coding preferred by the client
+/// or decompress the gzipped response when the client doesn't accept gzip
+/// (e.g. a response cached with `CompressionConfig::store_compressed`).
+///
+/// Bodies are (de)compressed while they're streamed.
+pub fn compress_response(
+    mut response: Response<Body>,
+    accept_encoding: Option<&str>,
+    method: &Method,
+    compression_config: &CompressionConfig,
+) -> Response<Body> {
+    if method == Method::HEAD || !has_body(response.status()) {
+        return response;
+    }
+    let accept_encoding = accept_encoding.unwrap_or_default();
+    let (encoder, coding) = match response.headers().get(header::CONTENT_ENCODING) {
+        Some(content_encoding) => {
+            if !content_encoding.as_bytes().eq_ignore_ascii_case(b"gzip")
+                || quality(accept_encoding, Coding::Gzip) > 0.0
+            {
+                return response;
+            }
+            (Encoder::Gunzip(GzDecoder::new(Vec::new())), None)
+        }
+        None => {
+            if !is_compressible(response.headers(), compression_config) {
+                return response;
+            }
+            match preferred_coding(accept_encoding) {
+                Some(coding) => (Encoder::new(coding), Some(coding)),
+                None => return response,
+            }
+        }
+    };
+
+    let headers = response.headers_mut();
+    headers.remove(header::CONTENT_LENGTH);
+    match coding {
+        Some(coding) => {
+            headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(coding.name()),
+            );
+        }
+        None => {
+            headers.remove(header::CONTENT_ENCODING);
+        }
+    }
+    mark_as_encoded(headers);
+    response.map(|body| encode_body(body, encoder))
+}
+
+/// Gzip the cached body (see `CompressionConfig::store_compressed`).
+///
+/// Returns `None` when the response shouldn't be compressed.
+pub fn compress_cached_body(
+    headers: &mut HeaderMap,
+    body: &[u8],
+    compression_config: &CompressionConfig,
+) -> Option<Vec<u8>> {
+    if headers.contains_key(header::CONTENT_ENCODING)
+        || body.len() < compression_config.min_size
+        || !is_compressible(headers, compression_config)
+    {
+        return None;
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed_body = encoder
+        .write_all(body)
+        .and_then(|()| encoder.finish())
+        .ok()?;
+
+    headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    if headers.contains_key(header::CONTENT_LENGTH) {
+        headers.insert(header::CONTENT_LENGTH, compressed_body.len().into());
+    }
+    mark_as_encoded(headers);
+    Some(compressed_body)
+}
+
+/// Responses with these statuses don't have a body or it can't be encoded.
+fn has_body(status: StatusCode) -> bool {
+    !matches!(
+        status,
+        StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED | StatusCode::PARTIAL_CONTENT
+    ) && !status.is_informational()
+}
+
+/// The response has an allowed content type, it's big enough and it can be transformed.
+fn is_compressible(headers: &HeaderMap, compression_config: &CompressionConfig) -> bool {
+    let no_transform = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.to_ascii_lowercase().contains("no-transform"));
+    if no_transform {
+        return false;
+    }
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+    // Streamed responses without `Content-Length` are compressed.
+    if matches!(content_length, Some(content_length) if content_length < compression_config.min_size)
+    {
+        return false;
+    }
+    let media_type = match headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(content_type) => content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase(),
+        None => return false,
+    };
+    compression_config
+        .content_types
+        .iter()
+        .map(|allowed| allowed.to_ascii_lowercase())
+        .any(|allowed| {
+            // E.g. "text/" allows all text types.
+            if allowed.ends_with('/') {
+                media_type.starts_with(&allowed)
+            } else {
+                media_type == allowed
+            }
+        })
+}
+
+/// The encoded response is a different representation - its `ETag` has to be weak
+/// and caches have to store it per `Accept-Encoding`.
+fn mark_as_encoded(headers: &mut HeaderMap) {
+    let strong_etag = headers
+        .get(header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"))
+        .and_then(|etag| HeaderValue::from_str(&format!("W/{}", etag)).ok());
+    if let Some(weak_etag) = strong_etag {
+        headers.insert(header::ETAG, weak_etag);
+    }
+    let varies_by_accept_encoding = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !varies_by_accept_encoding {
+        headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+}
+
+// ------ Encoder ------
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gunzip(GzDecoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(coding: Coding) -> Self {
+        match coding {
+            Coding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            Coding::Brotli => Self::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW_SIZE,
+            ))),
+        }
+    }
+
+    /// Encode the chunk and return the output available so far.
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let output = match self {
+            Self::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+            Self::Brotli(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+            Self::Gunzip(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+        };
+        Ok(mem::take(output))
+    }
+
+    /// Return the rest of the output.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Brotli(encoder) => Ok(encoder.into_inner()),
+            Self::Gunzip(decoder) => decoder.finish(),
+        }
+    }
+}
+
+fn encode_body(body: Body, encoder: Encoder) -> Body {
+    let chunks = stream::unfold(Some((body, encoder)), |state| async move {
+        let (mut body, mut encoder) = state?;
+        loop {
+            let output = match body.next().await {
+                Some(Ok(chunk)) => encoder.write(&chunk),
+                Some(Err(error)) => {
+                    return Some((Err(Box::new(error) as Box<dyn Error + Send + Sync>), None))
+                }
+                None => {
+                    let output = encoder
+                        .finish()
+                        .map(Bytes::from)
+                        .map_err(|error| Box::new(error) as Box<dyn Error + Send + Sync>);
+                    return Some((output, None));
+                }
+            };
+            match output {
+                // The encoder buffers the input.
+                Ok(output) if output.is_empty() => continue,
+                Ok(output) => return Some((Ok(Bytes::from(output)), Some((body, encoder)))),
+                Err(error) => return Some((Err(Box::new(error) as _), None)),
+            }
+        }
+    });
+    Body::wrap_stream(chunks)
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyper_helpers::body_to_bytes;
+    use std::io::Read;
+
+    fn json_response(body: &'static str) -> Response<Body> {
+        Response::builder()
+            .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+            .header(header::CONTENT_LENGTH, body.len())
+            .header(header::ETAG, "\"abc\"")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn compression_config() -> CompressionConfig {
+        toml::from_str("min_size = 10").unwrap()
+    }
+
+    #[test]
+    fn accept_encoding() {
+        assert_eq!(preferred_coding("gzip, deflate, br"), Some(Coding::Brotli));
+        assert_eq!(preferred_coding("gzip, br;q=0.5"), Some(Coding::Gzip));
+        assert_eq!(preferred_coding("GZIP"), Some(Coding::Gzip));
+        assert_eq!(preferred_coding("*;q=0.1"), Some(Coding::Brotli));
+        assert_eq!(preferred_coding("identity, br;q=0"), None);
+        assert_eq!(preferred_coding(""), None);
+    }
+
+    #[tokio::test]
+    async fn gzip_json_response() {
+        let body = r#"{"metas":[{"id":"tt1"},{"id":"tt2"}]}"#;
+        let response = compress_response(
+            json_response(body),
+            Some("gzip"),
+            &Method::GET,
+            &compression_config(),
+        );
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+        assert_eq!(response.headers()[header::ETAG], "W/\"abc\"");
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+
+        let compressed_body = body_to_bytes(response.into_body()).await.unwrap();
+        let mut decompressed_body = String::new();
+        flate2::read::GzDecoder::new(compressed_body.as_ref())
+            .read_to_string(&mut decompressed_body)
+            .unwrap();
+        assert_eq!(decompressed_body, body);
+    }
+
+    #[tokio::test]
+    async fn skip_small_and_other_responses() {
+        let config = compression_config();
+        let small = compress_response(json_response("{}"), Some("br"), &Method::GET, &config);
+        assert!(small.headers().get(header::CONTENT_ENCODING).is_none());
+
+        let mut image = json_response("not really an image");
+        image
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        let image = compress_response(image, Some("br"), &Method::GET, &config);
+        assert!(image.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[tokio::test]
+    async fn decompress_cached_body_for_clients_without_gzip() {
+        let body = r#"{"streams":[{"url":"http://example.com/video.mp4"}]}"#;
+        let config = compression_config();
+        let mut headers = json_response(body).headers().clone();
+        let compressed_body = compress_cached_body(&mut headers, body.as_bytes(), &config).unwrap();
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            headers[header::CONTENT_LENGTH],
+            compressed_body.len().to_string()
+        );
+
+        let mut cached_response = Response::new(Body::from(compressed_body));
+        *cached_response.headers_mut() = headers;
+        let response = compress_response(cached_response, None, &Method::GET, &config);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        let response_body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(response_body, body);
+    }
+}
@@ -1,14 +1,46 @@
-use http::Uri;
-use serde_derive::Deserialize;
+use http::header::{HeaderName, HeaderValue};
+use http::{StatusCode, Uri};
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::ser::SerializeMap;
+use serde::{Deserialize as _, Deserializer, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::hash::{Hash, Hasher};
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 use tokio::fs;
+use tracing::warn;
+
+use crate::helpers::now_timestamp;
+use crate::proxy::{RouteIndex, ADMIN_TOKEN_HEADER};
+
+/// Replaces secrets (e.g. API keys in headers) in the serialized config.
+const REDACTED: &str = "<redacted>";
+
+/// Connection-specific headers - they are never cached (see `ProxyConfig::cached_headers`).
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
 
 // ------ ProxyConfig ------
 
 /// Proxy configuration loaded from the TOML file.
+///
+/// _Note:_ Secrets are redacted in the serialized config.
 #[allow(clippy::module_name_repetitions)]
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
 pub struct ProxyConfig {
     /// Send a request with this url path to schedule reload of this configuration.
     ///
@@ -19,6 +51,7 @@ pub struct ProxyConfig {
     /// ```toml
     /// reload_config_url_path = "/reload-proxy-config"
     /// ```
+    #[serde(serialize_with = "serialize_redacted")]
     pub reload_config_url_path: String,
 
     /// Send a request with this url path to clear cache.
@@ -30,6 +63,7 @@ pub struct ProxyConfig {
     /// ```toml
     /// clear_cache_url_path = "/clear-cache"
     /// ```
+    #[serde(serialize_with = "serialize_redacted")]
     pub clear_cache_url_path: String,
 
     /// Send a request with this url path to check proxy status.
@@ -43,6 +77,150 @@ pub struct ProxyConfig {
     /// ```
     pub status_url_path: String,
 
+    /// Open this url path in a browser to see the admin dashboard.
+    ///
+    /// It shows the detailed status and allows to reload the config and clear the cache.
+    /// The page asks for `admin_token` and it doesn't contain the secret paths of the endpoints.
+    /// It's disabled when the field is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// admin_ui_url_path = "/admin/ui"
+    /// ```
+    pub admin_ui_url_path: Option<String>,
+
+    /// Send a request with this url path and `admin_token` to get the active configuration as JSON.
+    ///
+    /// Secrets, including paths of destructive admin endpoints, are redacted.
+    /// It's disabled when the field is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// config_dump_url_path = "/admin/config"
+    /// ```
+    pub config_dump_url_path: Option<String>,
+
+    /// Send a request with this url path and `admin_token` to get the list of cached responses
+    /// as JSON.
+    ///
+    /// Up to 1000 responses are listed by default, set the query parameter `limit` to change it
+    /// (e.g. `/admin/cache?limit=50`). It's disabled when the field is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// cache_inspect_url_path = "/admin/cache"
+    /// ```
+    pub cache_inspect_url_path: Option<String>,
+
+    /// Send a request with this url path, `admin_token` and the query parameter `uri` to get
+    /// JSON with metadata of responses cached for the proxied URL (e.g. the matched route, why
+    /// and when it has been cached and the number of cache hits).
+    ///
+    /// Everything after `uri=` is the URL
+    /// (e.g. `/cache-entry?uri=https://example.com/catalog/movie/top.json?skip=100`).
+    /// It responds with `404` and the route the URL is matched by when nothing is cached.
+    /// `DELETE` requests remove the reported responses (e.g. `addon_proxy cache rm <url>`
+    /// while the proxy is running). It's disabled when the field is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// cache_entry_url_path = "/cache-entry"
+    /// ```
+    pub cache_entry_url_path: Option<String>,
+
+    /// Send a request with this url path and the query parameter `prefix` to remove
+    /// only the cached responses whose origin URI starts with the prefix.
+    ///
+    /// The prefix is a URI without the scheme (e.g. `/purge-cache?prefix=example.com/catalog`)
+    /// or a path matched against URIs of all origins (e.g. `/purge-cache?prefix=/catalog/movie`).
+    /// It's disabled when the field is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// purge_cache_url_path = "/purge-cache"
+    /// ```
+    #[serde(default, serialize_with = "serialize_redacted_option")]
+    pub purge_cache_url_path: Option<String>,
+
+    /// Send a `POST` request with this url path and the JSON body `{"tag": "catalog"}`
+    /// to remove the cached responses with the tag (see `cache_tags`).
+    ///
+    /// `*` in the tag matches any characters (e.g. `{"tag": "catalog:*"}`).
+    /// It's disabled when the field is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// purge_tag_url_path = "/cache/purge-tag"
+    /// ```
+    #[serde(default, serialize_with = "serialize_redacted_option")]
+    pub purge_tag_url_path: Option<String>,
+
+    /// Send a request with this url path to get the health of origins as JSON
+    /// (see `health_checks`). It's disabled when the field is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// upstream_health_url_path = "/health/upstreams"
+    /// ```
+    pub upstream_health_url_path: Option<String>,
+
+    /// Requests with this token in the header `X-Proxy-Admin-Token` may override the cache behavior
+    /// by the headers `X-Proxy-Refresh: true` (ignore the cached response and overwrite it)
+    /// and `X-Proxy-Only-Cache: true` (never contact the origin).
+    ///
+    /// Responses to such requests contain the debug header `X-Proxy-Config-Epoch`
+    /// (see `ConfigVersion::epoch`).
+    ///
+    /// The override headers are ignored when the field is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// admin_token = "change-me"
+    /// ```
+    #[serde(default, serialize_with = "serialize_redacted_option")]
+    pub admin_token: Option<String>,
+
+    /// Requests with this query parameter (e.g. `?__nocache=1`) ignore the cached response
+    /// and overwrite it like with `X-Proxy-Refresh` - it allows to check freshness from a browser.
+    ///
+    /// The parameter is removed before routing, so it never reaches the origin.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [cache_bypass]
+    /// query_param = "__nocache"
+    /// require_admin_token = true  # `X-Proxy-Admin-Token: <admin_token>` is required
+    /// ```
+    pub cache_bypass: Option<CacheBypassConfig>,
+
+    /// Sign responses served by the proxy with HMAC-SHA256, so downstream services (e.g. CDNs)
+    /// can verify that the content comes from the proxy.
+    ///
+    /// The header `X-Proxy-Signature: t=<timestamp>,sha256=<signature>` is added to responses,
+    /// where the signature is computed from `<timestamp>.<body>`.
+    ///
+    /// _Note:_ Signed responses aren't streamed - the whole body is buffered. Streamed responses
+    /// (bigger than `max_cacheable_body_size` or changed by `body_replacements` and other
+    /// streaming body transformers without caching) aren't signed.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [response_signing]
+    /// key = "change-me"
+    /// header = "x-proxy-signature"
+    /// ```
+    pub response_signing: Option<ResponseSigningConfig>,
+
     /// The directory where the cached responses and other proxy data should be saved.
     ///
     /// _Note:_ The directory will be created if does not exists.
@@ -54,6 +232,19 @@ pub struct ProxyConfig {
     /// ```
     pub db_directory: PathBuf,
 
+    /// The file with URLs fetched through the proxy on the start and after the cache is cleared
+    /// (see `clear_cache_url_path`) to warm the cache - one absolute URL per line,
+    /// lines starting with `#` are ignored.
+    ///
+    /// The status endpoint responds with `503` until all URLs are fetched.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// cache_seed_file = "seed_urls.txt"
+    /// ```
+    pub cache_seed_file: Option<PathBuf>,
+
     /// Proxy server will be listening on this IP (v4 or v6).
     ///
     /// # Example (TOML)
@@ -73,6 +264,27 @@ pub struct ProxyConfig {
     /// ```
     pub default_port: u16,
 
+    /// Proxies in front of the proxy (e.g. load balancers) allowed to send the client IP
+    /// in `X-Forwarded-For` - IP addresses or CIDR ranges.
+    ///
+    /// The client IP (used by `geoip` and `fair_queue`) is the address of the connection peer.
+    /// When the peer is trusted, `X-Forwarded-For` is read from the right
+    /// and the first address not belonging to a trusted proxy is the client IP,
+    /// so clients can't fake their IP by sending the header themselves.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// trusted_proxies = ["10.0.0.0/8", "127.0.0.1"]
+    /// ```
+    #[serde(
+        default,
+        deserialize_with = "deserialize_from_str_vec",
+        serialize_with = "serialize_display_vec"
+    )]
+    #[schemars(with = "Vec<String>")]
+    pub trusted_proxies: Vec<TrustedProxy>,
+
     /// Allow to cache responses and load the cached ones.
     ///
     /// # Example (TOML)
@@ -105,6 +317,20 @@ pub struct ProxyConfig {
     /// ```
     pub cache_stale_threshold_on_fail: u32,
 
+    /// Cache origin failures for this number of seconds, so repeated requests for a dead addon
+    /// don't hammer the origin (it's disabled when the field is missing).
+    ///
+    /// Failures are cached only when there isn't a usable cached response.
+    /// Cached failures are origin responses with status `404` or `502`,
+    /// response timeouts and failed requests (e.g. the origin is unreachable).
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// negative_cache_validity = 30
+    /// ```
+    pub negative_cache_validity: Option<u32>,
+
     /// How many seconds to wait for the response from origins.
     ///
     /// # Example (TOML)
@@ -114,6 +340,123 @@ pub struct ProxyConfig {
     /// ```
     pub timeout: u32,
 
+    /// The total number of seconds for the origin response incl. its body.
+    ///
+    /// Unlike `timeout`, which limits each read, it stops also origins sending the body too slowly.
+    /// The cached response (see `cache_stale_threshold_on_fail`) or `504` is returned
+    /// when it expires before the response is sent to the client
+    /// (streamed bodies are cut off). There is no limit by default.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// response_timeout = 30
+    /// ```
+    pub response_timeout: Option<u32>,
+
+    /// Abort origin responses whose bodies are transferred too slowly
+    /// (e.g. origins stalling mid-body, which `timeout` doesn't catch in all cases).
+    ///
+    /// The average rate since the response headers is checked after `grace_period`.
+    /// Slow responses are handled like `response_timeout`. It's disabled when the section is missing.
+    /// See `MinTransferRateConfig`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [min_transfer_rate]
+    /// bytes_per_second = 1024
+    /// grace_period = 5
+    /// ```
+    pub min_transfer_rate: Option<MinTransferRateConfig>,
+
+    /// Connect to the next address of the origin host when the connection fails
+    /// (e.g. addons hosted behind round-robin DNS with a dead server).
+    ///
+    /// Failed addresses are tried last for `failure_memory` seconds.
+    /// It's disabled when the section is missing. Changes are applied after the proxy restart.
+    /// See `DnsFailoverConfig`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [dns_failover]
+    /// connect_timeout = 3
+    /// failure_memory = 60
+    /// ```
+    pub dns_failover: Option<DnsFailoverConfig>,
+
+    /// Race connections to IPv6 and IPv4 addresses of origins (RFC 8305 "Happy Eyeballs"),
+    /// so hosts with broken AAAA records don't wait for the connect timeout.
+    ///
+    /// The next address is tried after `connection_attempt_delay` milliseconds
+    /// while the previous attempts keep going. It's disabled when the section is missing.
+    /// Changes are applied after the proxy restart. See `HappyEyeballsConfig`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [happy_eyeballs]
+    /// connection_attempt_delay = 250
+    /// ```
+    pub happy_eyeballs: Option<HappyEyeballsConfig>,
+
+    /// Compress responses with gzip or Brotli for clients that send `Accept-Encoding`.
+    ///
+    /// Only responses with allowed content types and at least `min_size` bytes are compressed.
+    /// Cached responses may be stored gzipped to save disk space and CPU - they're
+    /// decompressed for clients that don't accept gzip. It's disabled when the section is missing.
+    /// See `CompressionConfig`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [compression]
+    /// min_size = 1024
+    /// content_types = ["application/json"]
+    /// store_compressed = true
+    /// ```
+    pub compression: Option<CompressionConfig>,
+
+    /// Measure wall times of request pipeline stages for a sample of requests.
+    ///
+    /// Stages: `request_body`, `validation`, `admin_endpoints`, `routing`, `middlewares`,
+    /// `cache_lookup`, `upstream`, `caching` (reading and storing the origin response)
+    /// and `response` (compression and signing).
+    /// Aggregated times are included in the detailed status (see `status_url_path`).
+    /// It's disabled when the section is missing. See `ProfilingConfig`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [profiling]
+    /// sample_every = 100
+    /// ```
+    pub profiling: Option<ProfilingConfig>,
+
+    /// How many seconds to wait for in-flight requests on the server stop
+    /// (incl. SIGTERM and SIGINT - see `Proxy::set_handle_signals`). Default is `10`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// shutdown_grace_period = 10
+    /// ```
+    #[serde(default = "ProxyConfig::default_shutdown_grace_period")]
+    pub shutdown_grace_period: u32,
+
+    /// How many seconds to keep accepting new connections on the server stop after the status
+    /// endpoint starts to report draining - it gives load balancers time to notice it.
+    /// The listener is closed and `shutdown_grace_period` starts after the delay. Default is `0`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// shutdown_drain_delay = 5
+    /// ```
+    #[serde(default)]
+    pub shutdown_drain_delay: u32,
+
     /// Routes for the proxy router.
     ///
     /// # Example (TOML)
@@ -130,8 +473,73 @@ pub struct ProxyConfig {
     /// ```
     pub routes: Vec<ProxyRoute>,
 
-    /// If `true`, proxy will call some `println!`s with info about
-    /// incoming requests, responses, etc.
+    /// Settings shared by routes that reference the group by `group`.
+    ///
+    /// Groups may reference their parent groups. Route settings override group settings
+    /// and child group settings override parent group settings.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [route_groups.catalogs]
+    /// head_revalidation = true
+    /// body_replacements = { "http://internal" = "https://public" }
+    ///
+    /// [route_groups.eu_catalogs]
+    /// group = "catalogs"
+    /// allowed_countries = ["DE", "FR"]
+    ///
+    /// [[routes]]
+    /// from = "catalog-a.com"
+    /// to = "http://localhost:8080"
+    /// group = "eu_catalogs"
+    /// ```
+    #[serde(default)]
+    pub route_groups: BTreeMap<String, RouteGroup>,
+
+    /// Shared secrets for signing requests to private origins
+    /// that are referenced by `ProxyRoute::signing_profile`.
+    ///
+    /// Requests get the header with the current date and the header with HMAC-SHA256
+    /// of `<METHOD>\n<path and query>\n<date>` (hex-encoded).
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [signing_profiles.private_backend]
+    /// key = "change-me"
+    /// signature_header = "x-signature"
+    /// date_header = "x-signature-date"
+    ///
+    /// [[routes]]
+    /// from = "private.com"
+    /// to = "http://localhost:8080"
+    /// signing_profile = "private_backend"
+    /// ```
+    #[serde(default)]
+    pub signing_profiles: BTreeMap<String, SigningProfile>,
+
+    /// Generate routes for all addons in a Stremio addon collection and keep them in sync.
+    ///
+    /// The collection is a JSON array of transport URLs or of objects with `transportUrl`
+    /// (e.g. an exported Stremio addon collection). It's fetched on each config (re)load
+    /// and then every `poll_interval` seconds (default is 3600).
+    ///
+    /// The addon `https://example.com/lite/manifest.json` is available
+    /// on `<from>/example.com/lite/manifest.json`. Routes from `routes` are matched first.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [addon_collection]
+    /// url = "https://example.com/my-addons.json"
+    /// from = "127.0.0.1:5000/collection"
+    /// poll_interval = 600
+    /// group = "catalogs"  # see `route_groups`
+    /// ```
+    pub addon_collection: Option<AddonCollectionConfig>,
+
+    /// If `true`, proxy will log (on the `info` level) incoming requests, responses, etc.
     ///
     /// It's useful for debugging but it causes a big performance penalty.   
     ///
@@ -140,43 +548,2514 @@ pub struct ProxyConfig {
     /// ```toml
     /// verbose = false
     /// ```
+    ///
+    /// See also `ProxyRoute::verbose`.
     pub verbose: bool,
-}
 
-impl ProxyConfig {
-    /// Read configuration from the TOML file and try to parse it into `ProxyConfig`.
+    /// The most verbose level of logged events (default is `info`).
     ///
-    /// # Errors
+    /// _Note:_ Logging is set up on the proxy start - config reloads don't change it.
     ///
-    /// Returns `String` error when reading the file fails or when TOML parsing fails.
-    pub async fn load(path: impl AsRef<Path> + Send) -> Result<Self, String> {
-        let config = fs::read_to_string(path)
-            .await
-            .map_err(|err| err.to_string())?;
-        toml::from_str(&config).map_err(|err| err.to_string())
-    }
-}
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// log_level = "warn"
+    /// ```
+    #[serde(default = "ProxyConfig::default_log_level")]
+    pub log_level: LogLevel,
 
-// ------ ProxyRoute ------
+    /// Human-readable lines (`text`, default) or one JSON object per event (`json`).
+    ///
+    /// Events of each request are logged in the span `request` with fields
+    /// `method`, `uri`, `route` and `cache_hit`.
+    /// Like `log_level`, it's applied on the proxy start.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// log_format = "json"
+    /// ```
+    #[serde(default = "ProxyConfig::default_log_format")]
+    pub log_format: LogFormat,
 
-/// Route for the proxy router.
-///
-/// # Example (TOML)
-///
-/// ```toml
-/// [[routes]]
-/// from = "sub.domain.com"
-/// to = "http://localhost:8080"
-///
-/// [[routes]]
-/// from = "dont-validate.com"
-/// to = "http://localhost:8080"
-/// validate = false
-/// ```
-#[derive(Debug, Deserialize, Clone)]
-pub struct ProxyRoute {
-    pub from: String,
-    #[serde(with = "http_serde::uri")]
-    pub to: Uri,
-    pub validate: Option<bool>,
+    /// Values of these headers (case-insensitive) are replaced with `<redacted>`
+    /// in verbose logs and in the inspection output (e.g. `cache get`).
+    ///
+    /// Default is `["authorization", "cookie", "set-cookie"]`.
+    /// `X-Proxy-Admin-Token` is always redacted.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// redact_headers = ["authorization", "cookie", "x-api-key"]
+    /// ```
+    #[serde(default = "ProxyConfig::default_redact_headers")]
+    pub redact_headers: Vec<String>,
+
+    /// Limit concurrent requests to each origin and share them fairly among clients,
+    /// so one aggressive client cannot starve the others.
+    ///
+    /// It's disabled when the section is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [fair_queue]
+    /// max_concurrent_requests = 64
+    /// client_key_header = "x-api-key"
+    /// queue_timeout_ms = 2000
+    /// max_queued_requests = 1000
+    ///
+    /// [fair_queue.weights]
+    /// "premium-api-key" = 4
+    /// ```
+    pub fair_queue: Option<FairQueueConfig>,
+
+    /// Rhai script that can rewrite requests and responses (requires the feature `scripting`).
+    ///
+    /// The script is compiled when the config is (re)loaded.
+    /// See `ScriptConfig` for the supported script functions.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [script]
+    /// path = "proxy_script.rhai"
+    /// ```
+    pub script: Option<ScriptConfig>,
+
+    /// Tag requests with the client's country and ASN from MaxMind databases
+    /// (requires the feature `geoip`).
+    ///
+    /// The client IP is the address of the connection or the address reported
+    /// by `trusted_proxies`, so clients can't get around geo restrictions by faking
+    /// `X-Forwarded-For`.
+    /// Tags are forwarded to origins in the headers `X-GeoIP-Country` and `X-GeoIP-ASN`,
+    /// they are used by `ProxyRoute::allowed_countries` / `blocked_countries`
+    /// and requests are counted per country in metrics.
+    ///
+    /// Databases are opened when the config is (re)loaded.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [geoip]
+    /// country_database = "GeoLite2-Country.mmdb"
+    /// asn_database = "GeoLite2-ASN.mmdb"
+    /// ```
+    pub geoip: Option<GeoIpConfig>,
+
+    /// Declarative rules evaluated in order for each request before routing.
+    ///
+    /// See `ProxyRule` for all conditions and actions.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [[rules]]
+    /// path = "^/admin"
+    /// deny = 403
+    ///
+    /// [[rules]]
+    /// path = "^/v1/(.*)"
+    /// rewrite_path = "/$1"
+    /// skip_cache = true
+    /// ```
+    #[serde(default)]
+    pub rules: Vec<ProxyRule>,
+
+    /// Prefetch data the clients will probably request soon into the cache.
+    ///
+    /// It's disabled when the section is missing or when the cache is disabled.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [prefetch]
+    /// catalog_pages = 2
+    /// ```
+    pub prefetch: Option<PrefetchConfig>,
+
+    /// Shorten cache validities by up to this percentage (default is `0`),
+    /// so responses cached at the same time with the same `max-age` don't expire together.
+    ///
+    /// The jitter is derived from the cache key - it's stable for the given request.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// cache_ttl_jitter = 10
+    /// ```
+    pub cache_ttl_jitter: Option<u8>,
+
+    /// Don't write a response to the cache again when the identical response
+    /// (compared by a content hash) has been cached at most this many seconds ago.
+    ///
+    /// It saves DB writes when the same response is fetched repeatedly (e.g. hot manifests).
+    /// The stored response keeps its timestamp, so its validity isn't extended by skipped writes -
+    /// keep the window shorter than cache validities. Responses are always written when it's missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// cache_write_coalescing_window = 30
+    /// ```
+    pub cache_write_coalescing_window: Option<u32>,
+
+    /// Remove expired cached responses every this number of seconds
+    /// (it's disabled when the field is missing).
+    ///
+    /// Responses are removed when they are older than their validity plus
+    /// `cache_stale_threshold_on_fail` (or `stale_while_revalidate` if it's longer),
+    /// so they couldn't be served even as stale ones. Cached origin failures are removed
+    /// when they expire. Corrupted responses are removed too.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// cache_sweep_interval = 3600
+    /// ```
+    pub cache_sweep_interval: Option<u32>,
+
+    /// Tags assigned to cached responses, so they can be removed together
+    /// (see `purge_tag_url_path`).
+    ///
+    /// Placeholders `{route}` (the route `from`), `{group}` (the route `group`) and `{resource}`
+    /// (the Stremio resource, e.g. `catalog`) are replaced by values of the request.
+    /// Tags with a placeholder without a value (e.g. `{resource}` for `/manifest.json`) are skipped.
+    /// No tags are assigned when the field is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// cache_tags = ["{resource}", "{route}", "{route}:{resource}"]
+    /// ```
+    pub cache_tags: Option<Vec<String>>,
+
+    /// Make semantically identical requests share cached responses.
+    ///
+    /// The cache key is derived from the method, URI and body of the request sent to the origin
+    /// and from request headers listed in the response `Vary` by default. See `CacheKeyConfig`.
+    ///
+    /// _Note:_ Responses cached with another key strategy aren't found after a change.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [cache_key]
+    /// ignored_query_params = ["utm_source", "utm_medium"]
+    /// ignored_headers = ["user-agent"]
+    /// normalize_trailing_slash = true
+    /// include_body = false
+    /// ```
+    pub cache_key: Option<CacheKeyConfig>,
+
+    /// Response headers (case-insensitive) stored with cached responses.
+    ///
+    /// All headers except `Set-Cookie` are stored by default, so cached responses
+    /// don't replay per-user cookies to other clients. Hop-by-hop headers (e.g. `Connection`)
+    /// and headers bigger than `max_cached_header_size` are never stored.
+    ///
+    /// _Note:_ Keep `last-modified` and `content-length` for `ProxyRoute::head_revalidation`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// cached_headers = ["cache-control", "content-type", "content-length", "last-modified"]
+    /// ```
+    pub cached_headers: Option<Vec<String>>,
+
+    /// Response headers with bigger values (in bytes) aren't stored with cached responses
+    /// (default is `4096`).
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// max_cached_header_size = 1024
+    /// ```
+    #[serde(default = "ProxyConfig::default_max_cached_header_size")]
+    pub max_cached_header_size: usize,
+
+    /// Responses with bigger bodies (in bytes) aren't cached (no limit by default).
+    ///
+    /// Such responses are streamed to clients without buffering the whole body in memory.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// max_cacheable_body_size = 5_000_000
+    /// ```
+    pub max_cacheable_body_size: Option<usize>,
+
+    /// Limits of origin response headers (e.g. huge `Set-Cookie` or thousands of `Via` entries).
+    ///
+    /// Headers over the limits are dropped or the whole response is rejected
+    /// like an invalid one, see `ResponseHeaderLimitsConfig`. There are no limits by default.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [response_header_limits]
+    /// max_count = 100
+    /// max_total_size = 32_768
+    /// policy = "truncate"
+    /// ```
+    pub response_header_limits: Option<ResponseHeaderLimitsConfig>,
+
+    /// Log requests of a route like with `ProxyRoute::verbose` for a while
+    /// when its origin fails too often, so transient issues are logged
+    /// without enabling `verbose` globally.
+    ///
+    /// Origin failures (request errors, invalid responses and exceeded deadlines)
+    /// are counted per route in a rolling window. See `ErrorBudgetConfig`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [error_budget]
+    /// max_failures = 10
+    /// window = 60
+    /// escalation_duration = 300
+    /// webhook_url = "https://example.com/hooks/addon-proxy"
+    /// ```
+    pub error_budget: Option<ErrorBudgetConfig>,
+
+    /// Evaluation of `ProxyRoute::latency_slo`.
+    ///
+    /// Latencies of requests sent to origins (incl. the time in the fair queue, without cache hits)
+    /// are evaluated on every metrics push (see `metrics`) - the gauge `latency_slo_violation`
+    /// is 1 for routes whose p99 latency in the window exceeds the objective.
+    /// The default window is 300 seconds when the section is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [latency_slo_alerts]
+    /// window = 300
+    /// webhook_url = "https://example.com/hooks/addon-proxy"
+    /// ```
+    pub latency_slo_alerts: Option<LatencySloAlertsConfig>,
+
+    /// Replay responses to duplicated POST requests with an idempotency key
+    /// instead of sending them to the origin again (e.g. during client retry storms).
+    ///
+    /// Successful responses are stored in a journal in the database for `ttl` seconds,
+    /// independently of the cache. Only exact duplicates (the same key, URI, body
+    /// and `Authorization`) are answered from the journal, with the header `Idempotent-Replayed: true`.
+    /// It's disabled when the section is missing. See `IdempotencyConfig`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [idempotency]
+    /// header = "idempotency-key"
+    /// ttl = 60
+    /// max_body_size = 1_048_576
+    /// ```
+    pub idempotency: Option<IdempotencyConfig>,
+
+    /// Probe origins of routes (`ProxyRoute::to` and `ProxyRoute::to_by_region`) periodically
+    /// in the background.
+    ///
+    /// Requests for unhealthy origins aren't sent - the cached response
+    /// (see `cache_stale_threshold_on_fail`) or `502` is returned instead,
+    /// and unhealthy replicas are skipped by `ProxyRoute::strategy`.
+    /// It's disabled when the section is missing. See `HealthChecksConfig`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [health_checks]
+    /// path = "/manifest.json"
+    /// interval = 10
+    /// timeout = 5
+    /// unhealthy_threshold = 2
+    /// ```
+    pub health_checks: Option<HealthChecksConfig>,
+
+    /// Refresh cached responses in the background shortly before they expire,
+    /// so clients of popular addons aren't waiting for origins.
+    ///
+    /// Refreshes are queued and sent one by one.
+    /// It's disabled when the section is missing or when the cache is disabled.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [revalidation]
+    /// remaining_validity = 10
+    /// queue_size = 100
+    /// interval_ms = 200
+    /// ```
+    pub revalidation: Option<RevalidationConfig>,
+
+    /// Expired cached responses are served for up to `stale_while_revalidate` seconds
+    /// after their expiration while they are refreshed in the background.
+    ///
+    /// Clients of popular addons don't wait for origins when their responses have just expired.
+    /// Only GET requests without `ProxyRoute::cache_partition` are refreshed,
+    /// other requests don't get stale responses. Refreshes are queued
+    /// according to `revalidation` when the section is present.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// stale_while_revalidate = 60
+    /// ```
+    pub stale_while_revalidate: Option<u32>,
+
+    /// Default cache validities for Stremio resources (instead of `default_cache_validity`).
+    ///
+    /// `Cache-Control: max-age` of responses still has a higher priority.
+    /// See `ResourceTtlsConfig` for the built-in defaults.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [resource_ttls]
+    /// stream = 300
+    /// meta = 86_400
+    /// subtitles = 0  # Don't cache subtitles.
+    /// ```
+    pub resource_ttls: Option<ResourceTtlsConfig>,
+
+    /// Push proxy counters periodically to a StatsD or OpenTelemetry (OTLP/HTTP) collector.
+    ///
+    /// It's disabled when the section is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [metrics]
+    /// push_protocol = "statsd"
+    /// push_endpoint = "127.0.0.1:8125"
+    /// push_interval = 10
+    /// ```
+    pub metrics: Option<MetricsConfig>,
+
+    /// Respect the time budget sent by clients in a request header.
+    ///
+    /// The remaining budget is used as the timeout for the origin request and it's propagated
+    /// to the origin in the same header. The proxy responds with `504` when the budget runs out.
+    /// It's disabled when the section is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [request_deadline]
+    /// header = "x-request-deadline"
+    /// overhead_ms = 10
+    /// ```
+    pub request_deadline: Option<RequestDeadlineConfig>,
+
+    /// Synthetic endpoints served by the proxy without any origin
+    /// (e.g. stub manifests, `robots.txt` or maintenance notices).
+    ///
+    /// See `VirtualEndpoint` for the supported template variables.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [[virtual_endpoints]]
+    /// path = "/robots.txt"
+    /// headers = { "content-type" = "text/plain" }
+    /// body = "User-agent: *\nDisallow: /"
+    /// ```
+    #[serde(default)]
+    pub virtual_endpoints: Vec<VirtualEndpoint>,
+
+    /// Respond to `/robots.txt` (disallow all) and `/favicon.ico` (`204 No Content`)
+    /// without any origin, so crawlers and browsers don't produce pointless origin requests
+    /// (default is `true`).
+    ///
+    /// Define `virtual_endpoints` with the same paths to customize the responses.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// builtin_endpoints = false
+    /// ```
+    pub builtin_endpoints: Option<bool>,
+
+    /// Answer `OPTIONS` requests of all routes in the proxy instead of forwarding them
+    /// to origins (see `ProxyRoute::answer_preflight` for single routes).
+    ///
+    /// CORS preflights are answered with the configured `Access-Control-Allow-*` headers,
+    /// other `OPTIONS` requests with the header `Allow`. See `PreflightConfig`.
+    ///
+    /// It can't be combined with `cors` (incl. `ProxyRoute::cors`), which answers CORS
+    /// preflights with its own settings, or with `ProxyRoute::answer_preflight`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [preflight]
+    /// allowed_methods = ["GET", "HEAD", "OPTIONS"]
+    /// allowed_headers = ["content-type", "authorization"]
+    /// max_age = 3600
+    /// ```
+    pub preflight: Option<PreflightConfig>,
+
+    /// Inject `Access-Control-Allow-Origin` and related headers into proxied and cached
+    /// responses of all routes and answer CORS preflight requests in the proxy.
+    ///
+    /// Routes may have their own settings (see `ProxyRoute::cors`). See `CorsConfig`.
+    /// It replaces `preflight` and `ProxyRoute::answer_preflight` - configs combining them
+    /// are rejected. It's disabled when the section is missing.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [cors]
+    /// allowed_origins = ["*"]
+    /// allowed_methods = ["GET", "HEAD", "OPTIONS"]
+    /// max_age = 86400
+    /// ```
+    pub cors: Option<CorsConfig>,
+
+    /// Run as a pure caching proxy for embedders - the pipeline contains only routing
+    /// and caching (plus explicitly configured features like `rules` or `virtual_endpoints`).
+    ///
+    /// The landing page, the 404 page, admin endpoints (status, config reload, cache clearing, etc.),
+    /// `builtin_endpoints` and Stremio-specific request validation are disabled
+    /// unless they are enabled again in the section. See `LibraryModeConfig`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [library_mode]
+    /// route_miss_status = 502
+    /// admin_endpoints = true
+    /// ```
+    pub library_mode: Option<LibraryModeConfig>,
+
+    /// Set by `ProxyConfig::load`.
+    #[serde(skip)]
+    pub version: ConfigVersion,
+
+    /// `routes` indexed by `ProxyConfig::load` or on the first `find_route` call.
+    ///
+    /// _Note:_ Don't modify `routes` after indexing, clone the config instead.
+    #[serde(skip)]
+    pub route_index: RouteIndex,
+}
+
+impl ProxyConfig {
+    /// Read configuration from the TOML file and try to parse it into `ProxyConfig`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `String` error when reading the file fails or when TOML parsing fails.
+    pub async fn load(path: impl AsRef<Path> + Send) -> Result<Self, String> {
+        let config = fs::read_to_string(path)
+            .await
+            .map_err(|err| err.to_string())?;
+        let mut hasher = DefaultHasher::new();
+        config.hash(&mut hasher);
+        let version = ConfigVersion {
+            hash: format!("{:016x}", hasher.finish()),
+            loaded_at: now_timestamp(),
+            // Set by the running proxy.
+            epoch: 0,
+        };
+        let mut config: Self = toml::from_str(&config).map_err(|err| err.to_string())?;
+        config.version = version;
+        config.apply_route_groups()?;
+        config.route_index.get_or_build(&config.routes);
+        if let Some(group) = config
+            .addon_collection
+            .as_ref()
+            .and_then(|addon_collection| addon_collection.group.as_ref())
+        {
+            if !config.route_groups.contains_key(group) {
+                return Err(format!(
+                    "addon collection references unknown route group '{}'",
+                    group
+                ));
+            }
+        }
+        if let Some(script) = config.script.as_mut() {
+            script.compile()?;
+        }
+        match config.geoip.as_mut() {
+            Some(geoip) => geoip.open()?,
+            None => {
+                if let Some(route) = config.routes.iter().find(|route| route.uses_geoip()) {
+                    return Err(format!(
+                        "route '{}' depends on the client location, but the section 'geoip' is missing",
+                        route.from
+                    ));
+                }
+            }
+        }
+        for rule in &config.rules {
+            rule.check()?;
+        }
+        for json_filter in config
+            .routes
+            .iter()
+            .filter_map(|route| route.json_filter.as_ref())
+        {
+            json_filter.check()?;
+        }
+        for virtual_endpoint in &config.virtual_endpoints {
+            virtual_endpoint.check()?;
+        }
+        if let Some(request_deadline) = &config.request_deadline {
+            HeaderName::from_bytes(request_deadline.header.as_bytes()).map_err(|_| {
+                format!(
+                    "invalid request deadline header '{}'",
+                    request_deadline.header
+                )
+            })?;
+        }
+        for (name, signing_profile) in &config.signing_profiles {
+            for header in &[
+                &signing_profile.signature_header,
+                &signing_profile.date_header,
+            ] {
+                HeaderName::from_bytes(header.as_bytes()).map_err(|_| {
+                    format!("invalid header '{}' in signing profile '{}'", header, name)
+                })?;
+            }
+        }
+        let referenced_signing_profiles = config
+            .routes
+            .iter()
+            .filter_map(|route| route.signing_profile.as_ref())
+            .chain(
+                config
+                    .route_groups
+                    .values()
+                    .filter_map(|group| group.signing_profile.as_ref()),
+            );
+        for signing_profile in referenced_signing_profiles {
+            if !config.signing_profiles.contains_key(signing_profile) {
+                return Err(format!("unknown signing profile '{}'", signing_profile));
+            }
+        }
+        for warning in config.shadowed_routes() {
+            warn!("{}", warning);
+        }
+        if let Some(response_signing) = &config.response_signing {
+            HeaderName::from_bytes(response_signing.header.as_bytes()).map_err(|_| {
+                format!(
+                    "invalid response signature header '{}'",
+                    response_signing.header
+                )
+            })?;
+        }
+        Ok(config)
+    }
+
+    /// Copy settings from `route_groups` to routes.
+    ///
+    /// # Errors
+    ///
+    /// Returns error when a group doesn't exist or when groups reference each other in a cycle.
+    pub(crate) fn apply_route_groups(&mut self) -> Result<(), String> {
+        for route in &mut self.routes {
+            route.inherit_groups(&self.route_groups)?;
+        }
+        Ok(())
+    }
+
+    /// The route with the longest `from` that is a prefix of concatenated `parts`
+    /// (e.g. the host, path and query, so they don't have to be joined into a new string).
+    ///
+    /// The first route wins when more routes have the same `from`.
+    pub fn find_route(&self, parts: &[&str]) -> Option<&Arc<ProxyRoute>> {
+        self.route_index.get_or_build(&self.routes).find(parts)
+    }
+
+    /// Routes that are never matched because a previous route has the same `from`.
+    fn shadowed_routes(&self) -> Vec<String> {
+        self.routes
+            .iter()
+            .enumerate()
+            .filter(|(index, route)| {
+                self.routes[..*index]
+                    .iter()
+                    .any(|previous| previous.from == route.from)
+            })
+            .map(|(index, route)| {
+                format!(
+                    "route #{} '{}' is unreachable because a previous route has the same 'from'",
+                    index + 1,
+                    route.from
+                )
+            })
+            .collect()
+    }
+
+    /// The port from the environment variable `PORT` or `default_port`.
+    pub fn port(&self) -> u16 {
+        env::var("PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(self.default_port)
+    }
+
+    /// Find mistakes that don't prevent the config from loading
+    /// (e.g. a route that is never matched because a previous route has the same `from`).
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = self.shadowed_routes();
+
+        for route in &self.routes {
+            for target in route.to.iter() {
+                if target.host().is_none() {
+                    problems.push(format!(
+                        "route '{}' has no host in 'to' ('{}')",
+                        route.from, target
+                    ));
+                }
+            }
+            for (region, target) in &route.to_by_region {
+                if target.host().is_none() {
+                    problems.push(format!(
+                        "route '{}' has no host in the target for region '{}' ('{}')",
+                        route.from, region, target
+                    ));
+                }
+            }
+        }
+
+        let url_paths = [
+            ("reload_config_url_path", Some(&self.reload_config_url_path)),
+            ("clear_cache_url_path", Some(&self.clear_cache_url_path)),
+            ("status_url_path", Some(&self.status_url_path)),
+            ("admin_ui_url_path", self.admin_ui_url_path.as_ref()),
+            ("config_dump_url_path", self.config_dump_url_path.as_ref()),
+            (
+                "cache_inspect_url_path",
+                self.cache_inspect_url_path.as_ref(),
+            ),
+            ("cache_entry_url_path", self.cache_entry_url_path.as_ref()),
+            ("purge_cache_url_path", self.purge_cache_url_path.as_ref()),
+            ("purge_tag_url_path", self.purge_tag_url_path.as_ref()),
+            (
+                "upstream_health_url_path",
+                self.upstream_health_url_path.as_ref(),
+            ),
+        ];
+        for (index, (name, url_path)) in url_paths.iter().enumerate() {
+            let url_path = match url_path {
+                Some(url_path) => url_path,
+                None => continue,
+            };
+            for (other_name, other_url_path) in &url_paths[..index] {
+                if Some(url_path) == other_url_path.as_ref() {
+                    problems.push(format!(
+                        "'{}' and '{}' have the same value '{}'",
+                        other_name, name, url_path
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Check the loaded config before it replaces the running one (see `Proxy::start`).
+    ///
+    /// # Errors
+    ///
+    /// Returns all `problems` and unusable settings (e.g. origins with unsupported schemes,
+    /// zero timeouts or more than one of `cors`, `preflight` and `ProxyRoute::answer_preflight`
+    /// answering preflights of the same route).
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = self.problems();
+
+        for route in &self.routes {
+            for target in route.to.iter().chain(route.to_by_region.values()) {
+                if !matches!(target.scheme_str(), Some("http") | Some("https")) {
+                    errors.push(format!(
+                        "route '{}' has an unsupported scheme in '{}' (expected http or https)",
+                        route.from, target
+                    ));
+                }
+            }
+            // Only one setting answers preflights of the route.
+            if route.answer_preflight == Some(true) {
+                let other_setting = if route.cors.is_some() || self.cors.is_some() {
+                    Some("cors")
+                } else if self.preflight.is_some() {
+                    Some("preflight")
+                } else {
+                    None
+                };
+                if let Some(other_setting) = other_setting {
+                    errors.push(format!(
+                        "route '{}' has 'answer_preflight' together with '{}' - remove one of them",
+                        route.from, other_setting
+                    ));
+                }
+            }
+        }
+        if self.preflight.is_some()
+            && (self.cors.is_some() || self.routes.iter().any(|route| route.cors.is_some()))
+        {
+            errors.push(
+                "'preflight' can't be combined with 'cors' - set 'allowed_methods', \
+                 'allowed_headers' and 'max_age' in 'cors' instead"
+                    .to_owned(),
+            );
+        }
+
+        let timeouts = [
+            ("timeout", Some(self.timeout)),
+            ("response_timeout", self.response_timeout),
+            (
+                "health_checks.timeout",
+                self.health_checks.as_ref().map(|checks| checks.timeout),
+            ),
+            (
+                "dns_failover.connect_timeout",
+                self.dns_failover
+                    .as_ref()
+                    .map(|dns_failover| dns_failover.connect_timeout),
+            ),
+        ];
+        for (name, timeout) in &timeouts {
+            if *timeout == Some(0) {
+                errors.push(format!("'{}' has to be greater than 0", name));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// How many seconds is a cached response of the given resource (e.g. "stream") valid,
+    /// if its validity isn't explicitly defined by its response headers.
+    ///
+    /// Returns `None` when responses of the resource shouldn't be cached at all.
+    pub fn default_cache_validity_for(&self, resource: Option<&str>) -> Option<u32> {
+        let resource_ttl = match (&self.resource_ttls, resource) {
+            (Some(ttls), Some("stream")) => Some(ttls.stream),
+            (Some(ttls), Some("meta")) => Some(ttls.meta),
+            (Some(ttls), Some("catalog")) => ttls.catalog,
+            (Some(ttls), Some("subtitles")) => ttls.subtitles,
+            _ => None,
+        };
+        match resource_ttl {
+            Some(0) => None,
+            Some(ttl) => Some(ttl),
+            None => Some(self.default_cache_validity),
+        }
+    }
+
+    /// The header value to show in logs and in the inspection output (see `redact_headers`).
+    ///
+    /// The admin token header is always redacted.
+    pub fn loggable_header_value(&self, name: &str, value: &[u8]) -> String {
+        if name.eq_ignore_ascii_case(ADMIN_TOKEN_HEADER)
+            || self
+                .redact_headers
+                .iter()
+                .any(|redacted| redacted.eq_ignore_ascii_case(name))
+        {
+            return REDACTED.to_owned();
+        }
+        String::from_utf8_lossy(value).into_owned()
+    }
+
+    fn default_redact_headers() -> Vec<String> {
+        vec![
+            "authorization".to_owned(),
+            "cookie".to_owned(),
+            "set-cookie".to_owned(),
+        ]
+    }
+
+    /// Should the response header be stored with the cached response? (see `cached_headers`)
+    pub fn is_cached_header(&self, name: &str, value: &[u8]) -> bool {
+        if value.len() > self.max_cached_header_size
+            || HOP_BY_HOP_HEADERS
+                .iter()
+                .any(|hop_by_hop| hop_by_hop.eq_ignore_ascii_case(name))
+        {
+            return false;
+        }
+        match &self.cached_headers {
+            Some(cached_headers) => cached_headers
+                .iter()
+                .any(|cached| cached.eq_ignore_ascii_case(name)),
+            None => !name.eq_ignore_ascii_case("set-cookie"),
+        }
+    }
+
+    const fn default_log_level() -> LogLevel {
+        LogLevel::Info
+    }
+
+    const fn default_log_format() -> LogFormat {
+        LogFormat::Text
+    }
+
+    const fn default_shutdown_grace_period() -> u32 {
+        10
+    }
+
+    fn default_max_cached_header_size() -> usize {
+        4096
+    }
+
+    /// Responses of the given resource with a bigger body shouldn't be cached.
+    pub fn max_cached_body_size_for(&self, resource: Option<&str>) -> Option<usize> {
+        let resource_max_size = match (&self.resource_ttls, resource) {
+            (Some(ttls), Some("subtitles")) => Some(ttls.max_subtitles_size),
+            _ => None,
+        };
+        match (self.max_cacheable_body_size, resource_max_size) {
+            (Some(max_size), Some(resource_max_size)) => Some(max_size.min(resource_max_size)),
+            (max_size, resource_max_size) => max_size.or(resource_max_size),
+        }
+    }
+
+    /// Is the IP one of `trusted_proxies`?
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|trusted_proxy| trusted_proxy.contains(ip))
+    }
+
+    /// Should the landing page and the 404 page be served? See `library_mode`.
+    pub fn builtin_pages_enabled(&self) -> bool {
+        self.library_mode
+            .as_ref()
+            .map(|library_mode| library_mode.builtin_pages)
+            .unwrap_or(true)
+    }
+
+    /// Should status, config reload, cache and other admin endpoints be handled?
+    /// See `library_mode`.
+    pub fn admin_endpoints_enabled(&self) -> bool {
+        self.library_mode
+            .as_ref()
+            .map(|library_mode| library_mode.admin_endpoints)
+            .unwrap_or(true)
+    }
+
+    /// Should requests be validated as Stremio addon requests (see `ProxyRoute::validate`
+    /// and `ProxyRoute::negotiate_manifest`)? See `library_mode`.
+    pub fn stremio_validation_enabled(&self) -> bool {
+        self.library_mode
+            .as_ref()
+            .map(|library_mode| library_mode.stremio_validation)
+            .unwrap_or(true)
+    }
+
+    /// See `builtin_endpoints` and `library_mode`.
+    pub fn builtin_endpoints_enabled(&self) -> bool {
+        self.builtin_endpoints
+            .unwrap_or_else(|| self.library_mode.is_none())
+    }
+}
+
+// ------ TrustedProxy ------
+
+/// IP address or CIDR range (e.g. `10.0.0.0/8`) - see the field `trusted_proxies` in `ProxyConfig`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrustedProxy {
+    network: IpAddr,
+    prefix_length: u8,
+}
+
+impl TrustedProxy {
+    /// Does the range contain the IP?
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_length))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_length))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut network_and_prefix = value.trim().splitn(2, '/');
+        let network = network_and_prefix
+            .next()
+            .unwrap_or_default()
+            .parse::<IpAddr>()
+            .map_err(|err| format!("invalid trusted proxy '{}': {}", value, err))?;
+        let max_prefix_length = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match network_and_prefix.next() {
+            Some(prefix_length) => match prefix_length.parse::<u8>() {
+                Ok(prefix_length) if prefix_length <= max_prefix_length => prefix_length,
+                _ => {
+                    return Err(format!(
+                        "invalid prefix length in trusted proxy '{}'",
+                        value
+                    ))
+                }
+            },
+            None => max_prefix_length,
+        };
+        Ok(Self {
+            network,
+            prefix_length,
+        })
+    }
+}
+
+impl std::fmt::Display for TrustedProxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_length)
+    }
+}
+
+// ------ LogLevel ------
+
+/// See the field `log_level` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+// ------ LogFormat ------
+
+/// See the field `log_format` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+// ------ ConfigVersion ------
+
+/// Identifies the loaded config file.
+///
+/// See the field `version` in `ProxyConfig`.
+#[derive(Debug, Default, Serialize, Clone)]
+pub struct ConfigVersion {
+    /// The hash of the TOML file content.
+    pub hash: String,
+    /// Unix timestamp of the (re)load.
+    pub loaded_at: i64,
+    /// The number of the config (re)loaded by the running proxy (the first config has `1`).
+    ///
+    /// It's `0` for configs loaded outside of the proxy (e.g. by CLI commands).
+    ///
+    /// A request is handled by one config, so all its decisions are tagged by the same epoch.
+    pub epoch: u64,
+}
+
+// ------ ProxyRoute ------
+
+/// Route for the proxy router.
+///
+/// # Example (TOML)
+///
+/// ```toml
+/// [[routes]]
+/// from = "sub.domain.com"
+/// to = "http://localhost:8080"
+///
+/// [[routes]]
+/// from = "dont-validate.com"
+/// to = "http://localhost:8080"
+/// validate = false
+///
+/// [[routes]]
+/// from = "negotiated.com"
+/// to = "http://localhost:8080"
+/// negotiate_manifest = true
+///
+/// [[routes]]
+/// from = "authenticated.com"
+/// to = "http://localhost:8080"
+/// cache_partition = { header = "authorization" }
+///
+/// [[routes]]
+/// from = "rewritten.com"
+/// to = "http://localhost:8080"
+/// body_replacements = { "http://localhost:8080" = "https://rewritten.com" }
+///
+/// [[routes]]
+/// from = "filtered.com"
+/// to = "http://localhost:8080"
+/// json_filter = { include = ["metas[].id", "metas[].type", "metas[].name", "metas[].poster"] }
+///
+/// [[routes]]
+/// from = "verbose.com"
+/// to = "http://localhost:8080"
+/// verbose = true
+///
+/// [[routes]]
+/// from = "licensed.com"
+/// to = "http://localhost:8080"
+/// allowed_countries = ["CZ", "SK"]
+///
+/// [[routes]]
+/// from = "regional.com"
+/// to = "http://localhost:8080"
+/// to_by_region = { EU = "http://eu.example.com", US = "http://us.example.com" }
+///
+/// [[routes]]
+/// from = "big-catalogs.com"
+/// to = "http://localhost:8080"
+/// head_revalidation = true
+///
+/// [[routes]]
+/// from = "html-json.com"
+/// to = "http://localhost:8080"
+/// fix_content_type = true
+///
+/// [[routes]]
+/// from = "legacy-charset.com"
+/// to = "http://localhost:8080"
+/// transcode_to_utf8 = true
+///
+/// [[routes]]
+/// from = "slow.com"
+/// to = "http://localhost:8080"
+/// latency_slo = { p99_ms = 300 }
+///
+/// [[routes]]
+/// from = "replicated.com"
+/// to = ["http://10.0.0.1:8080", "http://10.0.0.2:8080"]
+/// strategy = "least_connections"
+/// ```
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct ProxyRoute {
+    pub from: String,
+    /// The origin or a list of origin replicas - see `strategy`.
+    #[schemars(with = "UpstreamsSchema")]
+    pub to: Upstreams,
+    /// How requests sent to origins are distributed when `to` is a list
+    /// (default is `round_robin`).
+    ///
+    /// Responses are cached under the first origin, so replicas share cached responses.
+    /// Background requests (e.g. revalidations) are sent to the first origin.
+    pub strategy: Option<LoadBalancingStrategy>,
+    pub validate: Option<bool>,
+    /// Fetch the origin manifest (again every hour), reject requests for resources the addon
+    /// doesn't advertise and validate other requests according to the manifest in addition
+    /// to the static whitelist (default is `false`).
+    pub negotiate_manifest: Option<bool>,
+    /// Cache responses per user for addons whose responses depend on a user token.
+    ///
+    /// Requests without the user identifier share one partition.
+    pub cache_partition: Option<CachePartitionConfig>,
+    /// Replace texts in response bodies (e.g. origin URLs in manifests) - in the order of the keys.
+    ///
+    /// Bodies are rewritten while they are streamed, encoded (e.g. gzipped) bodies are skipped.
+    #[serde(default)]
+    pub body_replacements: BTreeMap<String, String>,
+    /// Keep only the listed fields in catalog responses to shrink cached responses.
+    pub json_filter: Option<JsonFilterConfig>,
+    /// Log requests and responses of this route as if `ProxyConfig::verbose` was `true`
+    /// (default is `false`).
+    pub verbose: Option<bool>,
+    /// Respond with `403 Forbidden` to clients from other countries
+    /// or from unknown locations (ISO 3166-1 alpha-2 codes, requires `ProxyConfig::geoip`).
+    pub allowed_countries: Option<Vec<String>>,
+    /// Respond with `403 Forbidden` to clients from these countries (requires `ProxyConfig::geoip`).
+    pub blocked_countries: Option<Vec<String>>,
+    /// Region-specific origins used instead of `to` (requires `ProxyConfig::geoip`).
+    ///
+    /// Keys are country codes (e.g. "US") or continent codes (e.g. "EU" - Europe),
+    /// the client's country is matched first. `to` is used for other and unknown locations.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_uri_map",
+        serialize_with = "serialize_display_map"
+    )]
+    #[schemars(with = "BTreeMap<String, String>")]
+    pub to_by_region: BTreeMap<String, Uri>,
+    /// Before downloading an expired cached response again, send a HEAD request and keep using
+    /// the cached response if its `Last-Modified` and `Content-Length` haven't changed
+    /// (default is `false`).
+    ///
+    /// It saves bandwidth for big responses that rarely change (e.g. catalogs).
+    /// Only validators stored with the cached response are compared - e.g. `Content-Length`
+    /// isn't stored when the route changes bodies (see `body_replacements` and `json_filter`).
+    /// Responses without any validator are always downloaded again.
+    pub head_revalidation: Option<bool>,
+    /// Cache responses with `Set-Cookie` and responses of requests with `Authorization`
+    /// or `Cookie` headers (default is `false`).
+    ///
+    /// They are personal by default - they aren't loaded from nor stored to the shared cache,
+    /// unless the request is identified by `cache_partition`.
+    pub cache_private: Option<bool>,
+    /// Use settings of the group from `ProxyConfig::route_groups` unless they are set here.
+    pub group: Option<String>,
+    /// Sign requests to the origin - see `ProxyConfig::signing_profiles`.
+    pub signing_profile: Option<String>,
+    /// Set `Content-Type: application/json` for `.json` paths when the origin responds
+    /// with `text/html`, `text/plain` or without any content type, but the body looks like JSON
+    /// (default is `false`).
+    ///
+    /// Corrections are logged. Encoded (e.g. gzipped) bodies aren't corrected.
+    pub fix_content_type: Option<bool>,
+    /// Transcode response bodies with other charsets declared in `Content-Type`
+    /// (e.g. `windows-1250`) to UTF-8, because Stremio clients expect UTF-8 (default is `false`).
+    ///
+    /// Bodies are transcoded while they are streamed, encoded (e.g. gzipped) bodies are skipped.
+    pub transcode_to_utf8: Option<bool>,
+    /// The latency objective of requests sent to the origin - see `ProxyConfig::latency_slo_alerts`.
+    pub latency_slo: Option<LatencySlo>,
+    /// Answer CORS preflight requests in the proxy instead of forwarding them
+    /// to the origin that doesn't handle them (default is `false`).
+    ///
+    /// Stremio addons are available from all origins, so the requested method and headers
+    /// are allowed for `*` and browsers may cache the answer for a day.
+    /// Forwarded preflights are cached per `Origin` and requested method and headers,
+    /// their validity is `Access-Control-Max-Age` when the origin sends it.
+    ///
+    /// It can't be combined with `cors` or `ProxyConfig::cors` and it's redundant
+    /// with `ProxyConfig::preflight` - such configs are rejected.
+    pub answer_preflight: Option<bool>,
+    /// CORS settings of the route - they replace `ProxyConfig::cors`.
+    ///
+    /// # Example (TOML)
+    ///
+    /// ```toml
+    /// [[routes]]
+    /// from = "example.com"
+    /// to = "http://localhost:8080"
+    /// cors = { allowed_origins = ["https://web.stremio.com"], max_age = 600 }
+    /// ```
+    pub cors: Option<CorsConfig>,
+}
+
+impl ProxyRoute {
+    /// Fill settings missing in the route from its group and the group's ancestors.
+    ///
+    /// # Errors
+    ///
+    /// Returns error when a group doesn't exist or when groups reference each other in a cycle.
+    pub(crate) fn inherit_groups(
+        &mut self,
+        route_groups: &BTreeMap<String, RouteGroup>,
+    ) -> Result<(), String> {
+        let mut visited_groups = Vec::new();
+        let mut group_name = self.group.clone();
+        while let Some(name) = group_name {
+            if visited_groups.contains(&name) {
+                return Err(format!("route group '{}' is part of a cycle", name));
+            }
+            let group = route_groups.get(&name).ok_or_else(|| {
+                format!(
+                    "route '{}' references unknown route group '{}'",
+                    self.from, name
+                )
+            })?;
+            self.inherit(group);
+            group_name = group.group.clone();
+            visited_groups.push(name);
+        }
+        Ok(())
+    }
+
+    /// Fill settings missing in the route from the group.
+    fn inherit(&mut self, group: &RouteGroup) {
+        fn fill<T: Clone>(value: &mut Option<T>, default: &Option<T>) {
+            if value.is_none() {
+                *value = default.clone();
+            }
+        }
+        fill(&mut self.validate, &group.validate);
+        fill(&mut self.negotiate_manifest, &group.negotiate_manifest);
+        fill(&mut self.cache_partition, &group.cache_partition);
+        fill(&mut self.json_filter, &group.json_filter);
+        fill(&mut self.verbose, &group.verbose);
+        fill(&mut self.allowed_countries, &group.allowed_countries);
+        fill(&mut self.blocked_countries, &group.blocked_countries);
+        fill(&mut self.head_revalidation, &group.head_revalidation);
+        fill(&mut self.cache_private, &group.cache_private);
+        fill(&mut self.signing_profile, &group.signing_profile);
+        fill(&mut self.fix_content_type, &group.fix_content_type);
+        fill(&mut self.transcode_to_utf8, &group.transcode_to_utf8);
+        fill(&mut self.latency_slo, &group.latency_slo);
+        fill(&mut self.answer_preflight, &group.answer_preflight);
+        fill(&mut self.strategy, &group.strategy);
+        fill(&mut self.cors, &group.cors);
+        for (pattern, replacement) in &group.body_replacements {
+            self.body_replacements
+                .entry(pattern.clone())
+                .or_insert_with(|| replacement.clone());
+        }
+    }
+
+    /// The origin for the client from the given country and continent (see `to_by_region`).
+    pub fn target(&self, country: Option<&str>, continent: Option<&str>) -> &Uri {
+        let region_target = |region: Option<&str>| {
+            let region = region?;
+            self.to_by_region
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(region))
+                .map(|(_, target)| target)
+        };
+        region_target(country)
+            .or_else(|| region_target(continent))
+            .unwrap_or_else(|| self.to.primary())
+    }
+
+    /// The route needs the client location - see `ProxyConfig::geoip`.
+    fn uses_geoip(&self) -> bool {
+        self.allowed_countries.is_some()
+            || self.blocked_countries.is_some()
+            || !self.to_by_region.is_empty()
+    }
+
+    /// The client from the given country (`None` when unknown) may use the route.
+    pub fn allows_country(&self, country: Option<&str>) -> bool {
+        let is_listed = |countries: &Vec<String>| match country {
+            Some(country) => countries
+                .iter()
+                .any(|listed| listed.eq_ignore_ascii_case(country)),
+            None => false,
+        };
+        if matches!(&self.blocked_countries, Some(blocked) if is_listed(blocked)) {
+            return false;
+        }
+        match &self.allowed_countries {
+            Some(allowed) => is_listed(allowed),
+            None => true,
+        }
+    }
+}
+
+// ------ Upstreams ------
+
+/// Origins of a route - see the field `to` in `ProxyRoute`.
+///
+/// It's one URI or a non-empty list of URIs in the config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upstreams(Vec<Uri>);
+
+impl Upstreams {
+    /// The first origin - responses are cached under it.
+    pub fn primary(&self) -> &Uri {
+        &self.0[0]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Uri> {
+        self.0.iter()
+    }
+
+    /// `true` when requests have to be distributed among replicas.
+    pub fn is_replicated(&self) -> bool {
+        self.0.len() > 1
+    }
+}
+
+impl FromStr for Upstreams {
+    type Err = http::uri::InvalidUri;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        Ok(Self(vec![uri.parse()?]))
+    }
+}
+
+impl std::fmt::Display for Upstreams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, uri) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", uri)?;
+        }
+        Ok(())
+    }
+}
+
+/// The config representation of `Upstreams`.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged, rename = "Upstreams")]
+enum UpstreamsSchema {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl<'de> serde::Deserialize<'de> for Upstreams {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let uris = match UpstreamsSchema::deserialize(deserializer)? {
+            UpstreamsSchema::One(uri) => vec![uri],
+            UpstreamsSchema::Many(uris) => uris,
+        };
+        if uris.is_empty() {
+            return Err(serde::de::Error::custom("the list of origins is empty"));
+        }
+        uris.iter()
+            .map(|uri| uri.parse().map_err(serde::de::Error::custom))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl serde::Serialize for Upstreams {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if self.is_replicated() {
+            serializer.collect_seq(self.0.iter().map(Uri::to_string))
+        } else {
+            serializer.collect_str(self.primary())
+        }
+    }
+}
+
+// ------ LoadBalancingStrategy ------
+
+/// See the field `strategy` in `ProxyRoute`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancingStrategy {
+    /// Origins take turns.
+    RoundRobin,
+    /// A random origin for each request.
+    Random,
+    /// The origin with the fewest requests in progress (the first one on ties).
+    LeastConnections,
+}
+
+// ------ RouteGroup ------
+
+/// Route settings shared by routes - see the field `route_groups` in `ProxyConfig`.
+///
+/// The fields have the same meaning as in `ProxyRoute`.
+#[derive(Debug, Default, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct RouteGroup {
+    /// The parent group.
+    pub group: Option<String>,
+    pub validate: Option<bool>,
+    pub negotiate_manifest: Option<bool>,
+    pub cache_partition: Option<CachePartitionConfig>,
+    #[serde(default)]
+    pub body_replacements: BTreeMap<String, String>,
+    pub json_filter: Option<JsonFilterConfig>,
+    pub verbose: Option<bool>,
+    pub allowed_countries: Option<Vec<String>>,
+    pub blocked_countries: Option<Vec<String>>,
+    pub head_revalidation: Option<bool>,
+    pub cache_private: Option<bool>,
+    pub signing_profile: Option<String>,
+    pub fix_content_type: Option<bool>,
+    pub transcode_to_utf8: Option<bool>,
+    pub latency_slo: Option<LatencySlo>,
+    pub answer_preflight: Option<bool>,
+    pub strategy: Option<LoadBalancingStrategy>,
+    pub cors: Option<CorsConfig>,
+}
+
+// ------ VirtualEndpoint ------
+
+/// Endpoint served by the proxy without any origin.
+///
+/// It's matched by the request path (any method).
+/// The body is a template with variables in double braces:
+/// - `{{path}}` - the request path.
+/// - `{{query.<name>}}` - the query parameter (percent-encoded, empty when missing).
+///
+/// # Example (TOML)
+///
+/// ```toml
+/// [[virtual_endpoints]]
+/// path = "/stub/manifest.json"
+/// status = 200
+/// headers = { "content-type" = "application/json" }
+/// body = '{ "id": "stub", "version": "1.0.0", "name": "Stub", "resources": [], "types": [] }'
+///
+/// [[virtual_endpoints]]
+/// path = "/echo"
+/// body = "Hello {{query.name}}!"
+/// ```
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct VirtualEndpoint {
+    /// The request path (e.g. `/robots.txt`).
+    pub path: String,
+    /// The response status code (default is `200`).
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_status_code",
+        serialize_with = "serialize_optional_status_code"
+    )]
+    #[schemars(with = "Option<u16>")]
+    pub status: Option<StatusCode>,
+    /// The response headers.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// The response body template.
+    #[serde(default)]
+    pub body: String,
+}
+
+impl VirtualEndpoint {
+    fn check(&self) -> Result<(), String> {
+        for (name, value) in &self.headers {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                format!(
+                    "invalid header name '{}' of virtual endpoint '{}'",
+                    name, self.path
+                )
+            })?;
+            HeaderValue::from_str(value).map_err(|_| {
+                format!(
+                    "invalid value of header '{}' of virtual endpoint '{}'",
+                    name, self.path
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// ------ JsonFilterConfig ------
+
+/// Fields kept in JSON responses.
+///
+/// See the field `json_filter` in `ProxyRoute`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct JsonFilterConfig {
+    /// Field paths separated by dots, `[]` marks arrays (e.g. `metas[].name`).
+    ///
+    /// The whole value is kept when the path ends with an object or an array (e.g. `metas[].links`).
+    pub include: Vec<String>,
+}
+
+impl JsonFilterConfig {
+    fn check(&self) -> Result<(), String> {
+        for path in &self.include {
+            if path
+                .split('.')
+                .any(|field| field.trim_end_matches("[]").is_empty())
+            {
+                return Err(format!("invalid JSON filter path '{}'", path));
+            }
+        }
+        Ok(())
+    }
+}
+
+// ------ LatencySlo ------
+
+/// See the field `latency_slo` in `ProxyRoute`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq)]
+pub struct LatencySlo {
+    /// 99 % of requests sent to the origin during `LatencySloAlertsConfig::window`
+    /// should be handled in `p99_ms` milliseconds.
+    pub p99_ms: u32,
+}
+
+// ------ CachePartitionConfig ------
+
+/// Request parts that identify the user.
+///
+/// The header has a higher priority than the query parameter.
+///
+/// See the field `cache_partition` in `ProxyRoute`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct CachePartitionConfig {
+    pub header: Option<String>,
+    pub query_param: Option<String>,
+}
+
+// ------ ProxyRule ------
+
+/// Declarative rule for requests.
+///
+/// The rule is applied when all its conditions match (a rule without conditions matches every request).
+/// Actions are applied in this order: `deny`, `set_headers`, `rewrite_path`, `route_to`, `skip_cache`.
+///
+/// # Example (TOML)
+///
+/// ```toml
+/// [[rules]]
+/// path = "^/catalog/(.*)"
+/// method = "GET"
+/// headers = { "x-api-key" = "beta-tester" }
+/// set_headers = { "x-beta" = "1" }
+/// rewrite_path = "/beta/catalog/$1"
+/// route_to = "http://localhost:8081"
+/// validate = false
+/// skip_cache = true
+/// ```
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct ProxyRule {
+    /// Condition - regex matched against the request path.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_from_str",
+        serialize_with = "serialize_optional_display"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub path: Option<Regex>,
+    /// Condition - the request method (case-insensitive).
+    pub method: Option<String>,
+    /// Condition - the request headers have to contain these values.
+    #[serde(default, serialize_with = "serialize_redacted_values")]
+    pub headers: HashMap<String, String>,
+
+    /// Action - respond with the given status code.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_status_code",
+        serialize_with = "serialize_optional_status_code"
+    )]
+    #[schemars(with = "Option<u16>")]
+    pub deny: Option<StatusCode>,
+    /// Action - set request headers.
+    #[serde(default, serialize_with = "serialize_redacted_values")]
+    pub set_headers: HashMap<String, String>,
+    /// Action - replace the request path.
+    /// It may contain capture groups from `path` (e.g. `$1`).
+    pub rewrite_path: Option<String>,
+    /// Action - send the request to this origin instead of the one defined by `routes`.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_from_str",
+        serialize_with = "serialize_optional_display"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub route_to: Option<Uri>,
+    /// Validate the request routed by `route_to` (default is `true`). See `ProxyRoute`.
+    pub validate: Option<bool>,
+    /// Action - don't load the response from the cache and don't cache it.
+    #[serde(default)]
+    pub skip_cache: bool,
+}
+
+impl ProxyRule {
+    /// Check values that are used only during the rule evaluation.
+    fn check(&self) -> Result<(), String> {
+        if let Some(method) = &self.method {
+            http::Method::from_bytes(method.as_bytes())
+                .map_err(|_| format!("invalid rule method '{}'", method))?;
+        }
+        for (name, value) in self.headers.iter().chain(&self.set_headers) {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid rule header name '{}'", name))?;
+            HeaderValue::from_str(value)
+                .map_err(|_| format!("invalid value of rule header '{}'", name))?;
+        }
+        Ok(())
+    }
+}
+
+fn deserialize_optional_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn deserialize_uri_map<'de, D>(deserializer: D) -> Result<BTreeMap<String, Uri>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    BTreeMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, uri)| Ok((key, uri.parse().map_err(serde::de::Error::custom)?)))
+        .collect()
+}
+
+fn deserialize_optional_status_code<'de, D>(deserializer: D) -> Result<Option<StatusCode>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<u16>::deserialize(deserializer)?
+        .map(|code| StatusCode::from_u16(code).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn serialize_optional_display<S, T>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: std::fmt::Display,
+{
+    match value {
+        Some(value) => serializer.collect_str(value),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_from_str_vec<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|value| value.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn serialize_display_vec<S, T>(values: &[T], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: std::fmt::Display,
+{
+    serializer.collect_seq(values.iter().map(ToString::to_string))
+}
+
+fn serialize_display_map<S, T>(map: &BTreeMap<String, T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: std::fmt::Display,
+{
+    serializer.collect_map(map.iter().map(|(key, value)| (key, value.to_string())))
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn serialize_optional_status_code<S>(
+    status: &Option<StatusCode>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match status {
+        Some(status) => serializer.serialize_u16(status.as_u16()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Header values may contain API keys or tokens.
+fn serialize_redacted_values<S>(
+    map: &HashMap<String, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let names = map.keys().collect::<BTreeSet<_>>();
+    let mut map_serializer = serializer.serialize_map(Some(names.len()))?;
+    for name in names {
+        map_serializer.serialize_entry(name, REDACTED)?;
+    }
+    map_serializer.end()
+}
+
+/// The admin token and paths of destructive admin endpoints are secrets.
+fn serialize_redacted_option<S>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match value {
+        Some(_) => serializer.serialize_str(REDACTED),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_redacted<S>(_: &str, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(REDACTED)
+}
+
+// ------ PrefetchConfig ------
+
+/// Settings for the cache prefetching.
+///
+/// See the field `prefetch` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct PrefetchConfig {
+    /// How many next catalog pages (`skip=`) are prefetched when a catalog page is served.
+    pub catalog_pages: u32,
+}
+
+// ------ CacheBypassConfig ------
+
+/// See the field `cache_bypass` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct CacheBypassConfig {
+    /// Default is `__nocache`.
+    #[serde(default = "CacheBypassConfig::default_query_param")]
+    pub query_param: String,
+    /// Requests have to contain `ProxyConfig::admin_token` in the header `X-Proxy-Admin-Token`
+    /// (default is `false`). The token isn't accepted in the query parameter - URLs are logged.
+    #[serde(default)]
+    pub require_admin_token: bool,
+}
+
+impl CacheBypassConfig {
+    fn default_query_param() -> String {
+        "__nocache".to_owned()
+    }
+}
+
+// ------ ResponseSigningConfig ------
+
+/// See the field `response_signing` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct ResponseSigningConfig {
+    /// The secret shared with downstream services.
+    #[serde(serialize_with = "serialize_redacted")]
+    pub key: String,
+    /// Default is `x-proxy-signature`.
+    #[serde(default = "ResponseSigningConfig::default_header")]
+    pub header: String,
+}
+
+impl ResponseSigningConfig {
+    fn default_header() -> String {
+        "x-proxy-signature".to_owned()
+    }
+}
+
+// ------ SigningProfile ------
+
+/// See the field `signing_profiles` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct SigningProfile {
+    /// The secret shared with the origin.
+    #[serde(serialize_with = "serialize_redacted")]
+    pub key: String,
+    /// Default is `x-signature`.
+    #[serde(default = "SigningProfile::default_signature_header")]
+    pub signature_header: String,
+    /// Default is `x-signature-date`.
+    #[serde(default = "SigningProfile::default_date_header")]
+    pub date_header: String,
+}
+
+impl SigningProfile {
+    fn default_signature_header() -> String {
+        "x-signature".to_owned()
+    }
+
+    fn default_date_header() -> String {
+        "x-signature-date".to_owned()
+    }
+}
+
+// ------ RevalidationConfig ------
+
+/// Settings for background refreshes of cached responses.
+///
+/// Only GET requests without `ProxyRoute::cache_partition` are refreshed.
+///
+/// See the field `revalidation` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct RevalidationConfig {
+    /// Cached responses served in the last `remaining_validity` percents of their validity
+    /// are refreshed (default is 10).
+    #[serde(default = "RevalidationConfig::default_remaining_validity")]
+    pub remaining_validity: u8,
+    /// Refreshes over the limit are dropped (default is 100).
+    #[serde(default = "RevalidationConfig::default_queue_size")]
+    pub queue_size: usize,
+    /// The pause before each refresh (default is 200 ms).
+    #[serde(default = "RevalidationConfig::default_interval_ms")]
+    pub interval_ms: u64,
+}
+
+impl RevalidationConfig {
+    const fn default_remaining_validity() -> u8 {
+        10
+    }
+
+    const fn default_queue_size() -> usize {
+        100
+    }
+
+    const fn default_interval_ms() -> u64 {
+        200
+    }
+}
+
+// ------ ResourceTtlsConfig ------
+
+/// Default cache validities (in seconds) for Stremio resources.
+///
+/// Streams change often (e.g. expiring links), while metadata are almost static.
+/// `0` means that responses of the resource aren't cached at all.
+///
+/// See the field `resource_ttls` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct ResourceTtlsConfig {
+    /// Default is 5 minutes.
+    #[serde(default = "ResourceTtlsConfig::default_stream")]
+    pub stream: u32,
+    /// Default is 1 day.
+    #[serde(default = "ResourceTtlsConfig::default_meta")]
+    pub meta: u32,
+    /// Default is `ProxyConfig::default_cache_validity`.
+    pub catalog: Option<u32>,
+    /// Default is `ProxyConfig::default_cache_validity`.
+    pub subtitles: Option<u32>,
+    /// Bigger subtitles responses aren't cached (default is 1 MiB).
+    #[serde(default = "ResourceTtlsConfig::default_max_subtitles_size")]
+    pub max_subtitles_size: usize,
+}
+
+impl ResourceTtlsConfig {
+    const fn default_stream() -> u32 {
+        5 * 60
+    }
+
+    const fn default_meta() -> u32 {
+        24 * 60 * 60
+    }
+
+    const fn default_max_subtitles_size() -> usize {
+        1024 * 1024
+    }
+}
+
+// ------ MetricsConfig ------
+
+/// Settings for pushing metrics.
+///
+/// See the field `metrics` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct MetricsConfig {
+    pub push_protocol: MetricsPushProtocol,
+    /// `host:port` for StatsD (UDP) or the full URL for OTLP
+    /// (e.g. `http://localhost:4318/v1/metrics`).
+    pub push_endpoint: String,
+    /// Seconds between pushes (default is 10).
+    #[serde(default = "MetricsConfig::default_push_interval")]
+    pub push_interval: u32,
+    /// Metric names are `<prefix>.<counter>` (default is `addon_proxy`).
+    #[serde(default = "MetricsConfig::default_prefix")]
+    pub prefix: String,
+}
+
+impl MetricsConfig {
+    const fn default_push_interval() -> u32 {
+        10
+    }
+
+    fn default_prefix() -> String {
+        "addon_proxy".to_owned()
+    }
+}
+
+/// See `MetricsConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsPushProtocol {
+    /// Counters are sent as deltas (`|c`), `in_flight` and `queued_requests` as gauges (`|g`).
+    Statsd,
+    /// JSON-encoded OTLP over HTTP - cumulative sums since the proxy start
+    /// and `in_flight` and `queued_requests` gauges.
+    Otlp,
+}
+
+// ------ ResponseHeaderLimitsConfig ------
+
+/// See the field `response_header_limits` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct ResponseHeaderLimitsConfig {
+    /// The maximum number of headers (repeated headers are counted separately).
+    pub max_count: Option<usize>,
+    /// The maximum sum of header name and value lengths in bytes.
+    pub max_total_size: Option<usize>,
+    /// What to do with responses over the limits (default is `truncate`).
+    #[serde(default = "ResponseHeaderLimitsConfig::default_policy")]
+    pub policy: HeaderLimitPolicy,
+}
+
+impl ResponseHeaderLimitsConfig {
+    const fn default_policy() -> HeaderLimitPolicy {
+        HeaderLimitPolicy::Truncate
+    }
+}
+
+/// See `ResponseHeaderLimitsConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderLimitPolicy {
+    /// Drop headers over the limits and log a warning.
+    ///
+    /// `Content-Type` and framing headers (`Content-Length`, etc.) are always kept.
+    Truncate,
+    /// Handle the response like an invalid one - the cached response or `502` is returned.
+    Reject,
+}
+
+// ------ ErrorBudgetConfig ------
+
+/// See the field `error_budget` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct ErrorBudgetConfig {
+    /// The route is escalated when its origin fails more times during `window`.
+    pub max_failures: u32,
+    /// The rolling window in seconds (default is 60).
+    #[serde(default = "ErrorBudgetConfig::default_window")]
+    pub window: u32,
+    /// How long the route is logged in detail in seconds (default is 300).
+    #[serde(default = "ErrorBudgetConfig::default_escalation_duration")]
+    pub escalation_duration: u32,
+    /// The escalation event is POSTed as JSON to this URL:
+    /// `{ "event": "error_budget_exceeded", "route": "<from>", "max_failures": 10, "window": 60, "verbose_until": <unix timestamp> }`.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_from_str",
+        serialize_with = "serialize_optional_display"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub webhook_url: Option<Uri>,
+}
+
+impl ErrorBudgetConfig {
+    const fn default_window() -> u32 {
+        60
+    }
+
+    const fn default_escalation_duration() -> u32 {
+        300
+    }
+}
+
+// ------ IdempotencyConfig ------
+
+/// See the field `idempotency` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct IdempotencyConfig {
+    /// The request header with the idempotency key (default is `idempotency-key`).
+    #[serde(default = "IdempotencyConfig::default_header")]
+    pub header: String,
+    /// How long responses are replayed in seconds (default is 60).
+    #[serde(default = "IdempotencyConfig::default_ttl")]
+    pub ttl: u32,
+    /// Bigger responses aren't journaled (default is 1 MiB).
+    #[serde(default = "IdempotencyConfig::default_max_body_size")]
+    pub max_body_size: usize,
+}
+
+impl IdempotencyConfig {
+    fn default_header() -> String {
+        "idempotency-key".to_owned()
+    }
+
+    const fn default_ttl() -> u32 {
+        60
+    }
+
+    const fn default_max_body_size() -> usize {
+        1_048_576
+    }
+}
+
+// ------ MinTransferRateConfig ------
+
+/// See the field `min_transfer_rate` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct MinTransferRateConfig {
+    /// The minimum average rate of origin response bodies.
+    pub bytes_per_second: u64,
+    /// Seconds after the response headers when the rate isn't checked yet (default is 5).
+    #[serde(default = "MinTransferRateConfig::default_grace_period")]
+    pub grace_period: u32,
+}
+
+impl MinTransferRateConfig {
+    const fn default_grace_period() -> u32 {
+        5
+    }
+}
+
+// ------ DnsFailoverConfig ------
+
+/// See the field `dns_failover` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct DnsFailoverConfig {
+    /// Seconds to wait for the connection to one address (default is 3).
+    #[serde(default = "DnsFailoverConfig::default_connect_timeout")]
+    pub connect_timeout: u32,
+    /// Seconds to remember the failed address (default is 60).
+    #[serde(default = "DnsFailoverConfig::default_failure_memory")]
+    pub failure_memory: u32,
+}
+
+impl DnsFailoverConfig {
+    const fn default_connect_timeout() -> u32 {
+        3
+    }
+
+    const fn default_failure_memory() -> u32 {
+        60
+    }
+}
+
+// ------ HappyEyeballsConfig ------
+
+/// See the field `happy_eyeballs` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct HappyEyeballsConfig {
+    /// Milliseconds before the next address is tried (default is 250).
+    #[serde(default = "HappyEyeballsConfig::default_connection_attempt_delay")]
+    pub connection_attempt_delay: u32,
+}
+
+impl HappyEyeballsConfig {
+    const fn default_connection_attempt_delay() -> u32 {
+        250
+    }
+}
+
+// ------ CompressionConfig ------
+
+/// See the field `compression` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct CompressionConfig {
+    /// Smaller responses aren't compressed (in bytes, default is 1024).
+    /// Responses without `Content-Length` are always compressed.
+    #[serde(default = "CompressionConfig::default_min_size")]
+    pub min_size: usize,
+    /// Compressed media types (default is `["application/json"]`).
+    /// Types ending with `/` allow all subtypes (e.g. `text/`).
+    #[serde(default = "CompressionConfig::default_content_types")]
+    pub content_types: Vec<String>,
+    /// Store cached responses gzipped (default is false).
+    ///
+    /// _Note:_ Clear the cache when you remove the section `compression`,
+    /// otherwise gzipped responses are served also to clients that don't accept gzip.
+    #[serde(default)]
+    pub store_compressed: bool,
+}
+
+impl CompressionConfig {
+    const fn default_min_size() -> usize {
+        1024
+    }
+
+    fn default_content_types() -> Vec<String> {
+        vec!["application/json".to_owned()]
+    }
+}
+
+// ------ PreflightConfig ------
+
+/// See the field `preflight` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct PreflightConfig {
+    /// Methods allowed for CORS requests (the requested method is allowed when it's empty).
+    /// They are listed also in `Allow` of other `OPTIONS` responses (`GET, HEAD, OPTIONS` by default).
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed for CORS requests (the requested headers are allowed when it's empty).
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    /// How long browsers may cache the answer in seconds (default is one day).
+    #[serde(default = "PreflightConfig::default_max_age")]
+    pub max_age: u32,
+}
+
+impl PreflightConfig {
+    const fn default_max_age() -> u32 {
+        86_400
+    }
+}
+
+/// Used by routes with `ProxyRoute::answer_preflight` when `ProxyConfig::preflight` is missing.
+impl Default for PreflightConfig {
+    fn default() -> Self {
+        Self {
+            allowed_methods: Vec::new(),
+            allowed_headers: Vec::new(),
+            max_age: Self::default_max_age(),
+        }
+    }
+}
+
+// ------ CorsConfig ------
+
+/// See the field `cors` in `ProxyConfig` and `ProxyRoute`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct CorsConfig {
+    /// Origins (e.g. `https://web.stremio.com`) allowed to read responses
+    /// (default is `["*"]` - all origins).
+    #[serde(default = "CorsConfig::default_allowed_origins")]
+    pub allowed_origins: Vec<String>,
+    /// Methods allowed by preflight answers (the requested method is allowed when it's empty).
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed by preflight answers
+    /// (the requested headers are allowed when it's empty).
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    /// How long browsers may cache preflight answers in seconds (default is one day).
+    #[serde(default = "CorsConfig::default_max_age")]
+    pub max_age: u32,
+}
+
+impl CorsConfig {
+    fn default_allowed_origins() -> Vec<String> {
+        vec!["*".to_owned()]
+    }
+
+    const fn default_max_age() -> u32 {
+        86_400
+    }
+
+    /// Allow all origins to read responses.
+    pub fn allows_all_origins(&self) -> bool {
+        self.allowed_origins.iter().any(|origin| origin == "*")
+    }
+
+    /// Is the origin (the value of the request header `Origin`) allowed?
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allows_all_origins()
+            || self
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+}
+
+// ------ CacheKeyConfig ------
+
+/// See the field `cache_key` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct CacheKeyConfig {
+    /// Query parameters (case-sensitive) removed from the URI in the key
+    /// (e.g. tracking parameters).
+    #[serde(default)]
+    pub ignored_query_params: Vec<String>,
+    /// Request headers (case-insensitive) ignored even when the response `Vary` lists them.
+    #[serde(default)]
+    pub ignored_headers: Vec<String>,
+    /// Paths with and without a trailing slash share cached responses (default is false).
+    #[serde(default)]
+    pub normalize_trailing_slash: bool,
+    /// The request body is a part of the key (default is true).
+    #[serde(default = "CacheKeyConfig::default_include_body")]
+    pub include_body: bool,
+}
+
+impl CacheKeyConfig {
+    const fn default_include_body() -> bool {
+        true
+    }
+
+    /// Is the request header ignored in the key?
+    pub fn is_ignored_header(&self, name: &str) -> bool {
+        self.ignored_headers
+            .iter()
+            .any(|ignored| ignored.eq_ignore_ascii_case(name))
+    }
+}
+
+// ------ LibraryModeConfig ------
+
+/// See the field `library_mode` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct LibraryModeConfig {
+    /// The status of empty responses to requests that don't match any route (default is `404`).
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_status_code",
+        serialize_with = "serialize_optional_status_code"
+    )]
+    #[schemars(with = "Option<u16>")]
+    pub route_miss_status: Option<StatusCode>,
+    /// Serve the landing page and the 404 page to requests that don't match any route
+    /// (default is false).
+    #[serde(default)]
+    pub builtin_pages: bool,
+    /// Handle status, config reload, cache clearing and other admin endpoints (default is false).
+    #[serde(default)]
+    pub admin_endpoints: bool,
+    /// Validate requests according to `ProxyRoute::validate`
+    /// and `ProxyRoute::negotiate_manifest` (default is false).
+    #[serde(default)]
+    pub stremio_validation: bool,
+}
+
+impl LibraryModeConfig {
+    /// See `route_miss_status`.
+    pub fn route_miss_status(&self) -> StatusCode {
+        self.route_miss_status.unwrap_or(StatusCode::NOT_FOUND)
+    }
+}
+
+// ------ ProfilingConfig ------
+
+/// See the field `profiling` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct ProfilingConfig {
+    /// Profile every n-th request (default is 100, `1` profiles all requests).
+    #[serde(default = "ProfilingConfig::default_sample_every")]
+    pub sample_every: u32,
+}
+
+impl ProfilingConfig {
+    const fn default_sample_every() -> u32 {
+        100
+    }
+}
+
+// ------ HealthChecksConfig ------
+
+/// See the field `health_checks` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct HealthChecksConfig {
+    /// The probed path relative to the origin (default is `/manifest.json`).
+    ///
+    /// The origin is healthy when it responds with a `2xx` status code.
+    #[serde(default = "HealthChecksConfig::default_path")]
+    pub path: String,
+    /// Seconds between probes (default is 10).
+    #[serde(default = "HealthChecksConfig::default_interval")]
+    pub interval: u32,
+    /// The probe timeout in seconds (default is 5).
+    #[serde(default = "HealthChecksConfig::default_timeout")]
+    pub timeout: u32,
+    /// The origin is unhealthy after this number of failed probes in a row (default is 2).
+    /// One successful probe makes it healthy again.
+    #[serde(default = "HealthChecksConfig::default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+impl HealthChecksConfig {
+    fn default_path() -> String {
+        "/manifest.json".to_owned()
+    }
+
+    const fn default_interval() -> u32 {
+        10
+    }
+
+    const fn default_timeout() -> u32 {
+        5
+    }
+
+    const fn default_unhealthy_threshold() -> u32 {
+        2
+    }
+}
+
+// ------ LatencySloAlertsConfig ------
+
+/// See the field `latency_slo_alerts` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct LatencySloAlertsConfig {
+    /// The sliding window in seconds (default is 300).
+    #[serde(default = "LatencySloAlertsConfig::default_window")]
+    pub window: u32,
+    /// New violations are POSTed as JSON to this URL:
+    /// `{ "event": "latency_slo_violated", "route": "<from>", "p99_ms": 512, "slo_p99_ms": 300, "window": 300 }`.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_from_str",
+        serialize_with = "serialize_optional_display"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub webhook_url: Option<Uri>,
+}
+
+impl LatencySloAlertsConfig {
+    pub const fn default_window() -> u32 {
+        300
+    }
+}
+
+// ------ AddonCollectionConfig ------
+
+/// See the field `addon_collection` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct AddonCollectionConfig {
+    /// The collection URL.
+    #[serde(with = "http_serde::uri")]
+    #[schemars(with = "String")]
+    pub url: Uri,
+    /// The prefix of `from` of generated routes (e.g. `127.0.0.1:5000/collection`).
+    pub from: String,
+    /// Seconds between collection fetches (default is 3600).
+    #[serde(default = "AddonCollectionConfig::default_poll_interval")]
+    pub poll_interval: u32,
+    /// The route group of generated routes (see `ProxyConfig::route_groups`).
+    pub group: Option<String>,
+}
+
+impl AddonCollectionConfig {
+    const fn default_poll_interval() -> u32 {
+        3600
+    }
+}
+
+// ------ RequestDeadlineConfig ------
+
+/// Settings for client deadlines.
+///
+/// See the field `request_deadline` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct RequestDeadlineConfig {
+    /// The request header with the client's remaining time budget in milliseconds
+    /// (default is `x-request-deadline`).
+    #[serde(default = "RequestDeadlineConfig::default_header")]
+    pub header: String,
+    /// Milliseconds reserved for the response processing in the proxy (default is 10).
+    #[serde(default = "RequestDeadlineConfig::default_overhead_ms")]
+    pub overhead_ms: u32,
+}
+
+impl RequestDeadlineConfig {
+    fn default_header() -> String {
+        "x-request-deadline".to_owned()
+    }
+
+    const fn default_overhead_ms() -> u32 {
+        10
+    }
+}
+
+// ------ FairQueueConfig ------
+
+/// Settings for the fair-queuing of requests to origins.
+///
+/// See the field `fair_queue` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct FairQueueConfig {
+    /// The maximum number of requests sent to one origin (the matched route target) at the same time.
+    ///
+    /// Each origin has its own queue, so a saturated origin doesn't delay requests to the others.
+    pub max_concurrent_requests: usize,
+    /// The request header with a client identifier (e.g. an API key).
+    ///
+    /// Only keys listed in `weights` are accepted. Clients are identified by their IP
+    /// (the address of the connection or the address reported by `ProxyConfig::trusted_proxies`)
+    /// when the header is not set, it's missing in the request or its value is an unknown key.
+    pub client_key_header: Option<String>,
+    /// How long a request may wait for a free slot in milliseconds (unlimited by default).
+    ///
+    /// The cached response (see `cache_stale_threshold_on_fail`) or `503` is returned
+    /// when the time is up.
+    pub queue_timeout_ms: Option<u32>,
+    /// The maximum number of requests waiting for one origin (unlimited by default).
+    ///
+    /// Requests over the limit are handled like the ones waiting too long.
+    pub max_queued_requests: Option<usize>,
+    /// How many requests of the client are served in a row during one round (default is 1).
+    ///
+    /// _Note:_ Client keys are redacted in the serialized config.
+    #[serde(default, serialize_with = "serialize_redacted_keys")]
+    pub weights: HashMap<String, u32>,
+}
+
+/// Client keys may be API keys.
+fn serialize_redacted_keys<S>(map: &HashMap<String, u32>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let sorted = map.iter().collect::<BTreeMap<_, _>>();
+    let mut map_serializer = serializer.serialize_map(Some(sorted.len()))?;
+    for (index, weight) in sorted.values().enumerate() {
+        map_serializer.serialize_entry(&format!("{} #{}", REDACTED, index + 1), weight)?;
+    }
+    map_serializer.end()
+}
+
+// ------ GeoIpConfig ------
+
+/// MaxMind databases (e.g. free GeoLite2) for the client location lookup.
+///
+/// See the field `geoip` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct GeoIpConfig {
+    /// Path to the Country (or City) database.
+    pub country_database: PathBuf,
+    /// Path to the ASN database (ASN isn't looked up when it's missing).
+    pub asn_database: Option<PathBuf>,
+    #[cfg(feature = "geoip")]
+    #[serde(skip)]
+    pub(crate) databases: Option<std::sync::Arc<crate::proxy::geoip::Databases>>,
+}
+
+impl GeoIpConfig {
+    /// Open the databases.
+    #[cfg(feature = "geoip")]
+    fn open(&mut self) -> Result<(), String> {
+        let databases = crate::proxy::geoip::Databases::open(
+            &self.country_database,
+            self.asn_database.as_deref(),
+        )?;
+        self.databases = Some(std::sync::Arc::new(databases));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    #[allow(clippy::unused_self)]
+    fn open(&mut self) -> Result<(), String> {
+        Err("the proxy has been compiled without the feature `geoip`".to_owned())
+    }
+}
+
+// ------ ScriptConfig ------
+
+/// User script for request and response rewriting.
+///
+/// The script may define these functions (both are optional):
+///
+/// ```rhai
+/// // `request` is a map `#{ method, uri, headers }`, where `uri` is the routed (origin) URI.
+/// // Return the modified `request` or a response `#{ status, body, headers }`.
+/// fn on_request(request) {
+///     if request.headers["x-api-key"] == () {
+///         return #{ status: 401, body: "Missing API key." };
+///     }
+///     request.headers["x-proxy"] = "addon_proxy";
+///     request
+/// }
+///
+/// // `response` is a map `#{ status, headers }` of the origin response.
+/// // Return the modified `response`.
+/// fn on_response(response) {
+///     response.headers["access-control-allow-origin"] = "*";
+///     response
+/// }
+/// ```
+///
+/// See the field `script` in `ProxyConfig`.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone)]
+pub struct ScriptConfig {
+    /// Path to the Rhai script file.
+    pub path: PathBuf,
+    #[cfg(feature = "scripting")]
+    #[serde(skip)]
+    pub(crate) ast: Option<std::sync::Arc<rhai::AST>>,
+}
+
+impl ScriptConfig {
+    /// Compile the script.
+    #[cfg(feature = "scripting")]
+    fn compile(&mut self) -> Result<(), String> {
+        let ast = crate::proxy::scripting::compile(&self.path)?;
+        self.ast = Some(std::sync::Arc::new(ast));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    #[allow(clippy::unused_self)]
+    fn compile(&mut self) -> Result<(), String> {
+        Err("the proxy has been compiled without the feature `scripting`".to_owned())
+    }
 }
@@ -1,4 +1,14 @@
+use std::future::Future;
+use std::time::Duration;
+
+use futures_util::future;
 use tokio::sync::oneshot;
+use tracing::error;
+
+use super::StatsSnapshot;
+
+// ------ ProxyController ------
+
 /// `ProxyController` is passed to the callback registered by `Proxy::set_on_server_start`.
 #[allow(clippy::module_name_repetitions)]
 pub struct ProxyController {
@@ -14,3 +24,72 @@ impl ProxyController {
         self.shutdown_sender.send(()).expect("send shutdown signal");
     }
 }
+
+// ------ ShutdownSummary ------
+
+/// `ShutdownSummary` is passed to the callback registered by `Proxy::set_on_server_stop`.
+#[derive(Debug, Clone)]
+pub struct ShutdownSummary {
+    /// Why the server has been stopped.
+    pub reason: ShutdownReason,
+    /// How long the server has been running.
+    pub uptime: Duration,
+    /// The number of requests that were still being processed when the server stopped.
+    pub pending_requests: u64,
+    /// Counters at the moment of the server stop (they have been already persisted).
+    pub stats: StatsSnapshot,
+}
+
+// ------ ShutdownReason ------
+
+/// See `ShutdownSummary`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShutdownReason {
+    /// `ProxyController::stop` has been called (or a shutdown signal has been received)
+    /// and all requests have been finished.
+    Requested,
+    /// `ProxyController::stop` has been called (or a shutdown signal has been received),
+    /// but some requests haven't been finished during the grace period.
+    GracePeriodElapsed,
+    /// The server failed.
+    ServerError(String),
+}
+
+// ------ shutdown_signal ------
+
+/// Resolves to the name of the received SIGTERM or SIGINT (see `Proxy::set_handle_signals`).
+///
+/// Signal handlers are registered immediately, so signals received before
+/// the returned future is polled aren't lost.
+#[cfg(unix)]
+pub(crate) fn shutdown_signal() -> impl Future<Output = &'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let signals = signal(SignalKind::terminate())
+        .and_then(|terminate| Ok((terminate, signal(SignalKind::interrupt())?)));
+    async move {
+        let (mut terminate, mut interrupt) = match signals {
+            Ok(signals) => signals,
+            Err(e) => {
+                error!("cannot listen for shutdown signals: {}", e);
+                return future::pending().await;
+            }
+        };
+        tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+        }
+    }
+}
+
+/// Resolves when Ctrl+C is pressed (see `Proxy::set_handle_signals`).
+#[cfg(not(unix))]
+pub(crate) fn shutdown_signal() -> impl Future<Output = &'static str> {
+    async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("cannot listen for shutdown signals: {}", e);
+            future::pending::<()>().await;
+        }
+        "Ctrl+C"
+    }
+}
@@ -1,4 +1,4 @@
-use super::ProxyConfig;
+use super::{FailoverConnector, ProxyConfig};
 
 use std::time::Duration;
 
@@ -9,12 +9,20 @@ use hyper_tls::HttpsConnector;
 
 /// Creates a default client for `Proxy`.
 ///
-/// It handles also HTTPS connnections and its timeout value is loaded from `proxy_config`.
+/// It handles also HTTPS connnections and its timeout value is loaded from `proxy_config`
+/// (as well as `ProxyConfig::dns_failover` and `ProxyConfig::happy_eyeballs`).
 #[allow(clippy::must_use_candidate)]
 pub fn default_client(
     proxy_config: &ProxyConfig,
-) -> Client<TimeoutConnector<HttpsConnector<HttpConnector>>> {
-    let https = HttpsConnector::new();
+) -> Client<TimeoutConnector<HttpsConnector<FailoverConnector>>> {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    let failover = FailoverConnector::new(
+        http,
+        proxy_config.dns_failover.clone(),
+        proxy_config.happy_eyeballs.clone(),
+    );
+    let https = HttpsConnector::new_with_connector(failover);
     let mut connector = TimeoutConnector::new(https);
     connector.set_read_timeout(Some(Duration::from_secs(u64::from(proxy_config.timeout))));
     Client::builder().build(connector)
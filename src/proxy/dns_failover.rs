@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures_util::future;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use http::uri::Scheme;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time;
+use tracing::warn;
+
+use super::{DnsFailoverConfig, HappyEyeballsConfig};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+// ------ AddressFailures ------
+
+/// Recent connection failures of resolved origin addresses.
+#[derive(Default)]
+struct AddressFailures {
+    failed_at: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl AddressFailures {
+    fn record_failure(&self, address: SocketAddr) {
+        let mut failed_at = self.failed_at.lock().expect("lock address failures");
+        failed_at.insert(address, Instant::now());
+    }
+
+    fn record_success(&self, address: SocketAddr) {
+        let mut failed_at = self.failed_at.lock().expect("lock address failures");
+        failed_at.remove(&address);
+    }
+
+    /// Addresses without failures in the last `failure_memory` keep the resolved order
+    /// and they're followed by the other ones - the longest failed first.
+    fn order(&self, addresses: Vec<SocketAddr>, failure_memory: Duration) -> Vec<SocketAddr> {
+        let mut failed_at = self.failed_at.lock().expect("lock address failures");
+        failed_at.retain(|_, failed_at| failed_at.elapsed() < failure_memory);
+
+        let (mut failed, mut ordered): (Vec<_>, Vec<_>) = addresses
+            .into_iter()
+            .partition(|address| failed_at.contains_key(address));
+        failed.sort_by_key(|address| failed_at[address]);
+        ordered.append(&mut failed);
+        ordered
+    }
+}
+
+// ------ FailoverConnector ------
+
+/// Connects to the next resolved address of the origin when the connection fails
+/// (see `ProxyConfig::dns_failover`) and races IPv6 and IPv4 addresses
+/// (see `ProxyConfig::happy_eyeballs`).
+///
+/// It passes requests to the wrapped `HttpConnector` when both are disabled.
+#[derive(Clone)]
+pub struct FailoverConnector {
+    http: HttpConnector,
+    config: Option<DnsFailoverConfig>,
+    happy_eyeballs: Option<HappyEyeballsConfig>,
+    failures: Arc<AddressFailures>,
+}
+
+impl FailoverConnector {
+    pub fn new(
+        http: HttpConnector,
+        config: Option<DnsFailoverConfig>,
+        happy_eyeballs: Option<HappyEyeballsConfig>,
+    ) -> Self {
+        Self {
+            http,
+            config,
+            happy_eyeballs,
+            failures: Arc::default(),
+        }
+    }
+}
+
+impl Service<Uri> for FailoverConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let mut http = self.http.clone();
+        if self.config.is_none() && self.happy_eyeballs.is_none() {
+            return Box::pin(async move { http.call(uri).await.map_err(Into::into) });
+        }
+        let config = self.config.clone();
+        let attempt_delay = self.happy_eyeballs.as_ref().map(|happy_eyeballs| {
+            Duration::from_millis(u64::from(happy_eyeballs.connection_attempt_delay))
+        });
+        let failures = Arc::clone(&self.failures);
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .ok_or("missing host")?
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            let port = match uri.port_u16() {
+                Some(port) => port,
+                None if uri.scheme() == Some(&Scheme::HTTPS) => 443,
+                None => 80,
+            };
+            let mut addresses = lookup_host((host, port)).await?.collect::<Vec<_>>();
+            if attempt_delay.is_some() {
+                addresses = interleave_families(addresses);
+            }
+            let connect_timeout = match &config {
+                Some(config) => {
+                    let failure_memory = Duration::from_secs(u64::from(config.failure_memory));
+                    addresses = failures.order(addresses, failure_memory);
+                    Some(Duration::from_secs(u64::from(config.connect_timeout)))
+                }
+                None => None,
+            };
+
+            // Attempts are started one by one - the next one when any attempt fails
+            // or after `attempt_delay` (the previous ones keep going). The first connection wins.
+            let mut addresses = addresses.into_iter().peekable();
+            let start = |address| connect(http.clone(), &uri, address, connect_timeout);
+            let mut attempts = addresses
+                .next()
+                .map(start)
+                .into_iter()
+                .collect::<FuturesUnordered<_>>();
+            let mut last_error: Option<BoxError> = None;
+            while !attempts.is_empty() {
+                let delay = match attempt_delay {
+                    Some(attempt_delay) if addresses.peek().is_some() => Some(attempt_delay),
+                    _ => None,
+                };
+                let next_attempt = async {
+                    match delay {
+                        Some(delay) => time::delay_for(delay).await,
+                        None => future::pending().await,
+                    }
+                };
+                let finished = tokio::select! {
+                    finished = attempts.next() => finished,
+                    _ = next_attempt => None,
+                };
+                match finished {
+                    Some((address, Ok(stream))) => {
+                        if config.is_some() {
+                            failures.record_success(address);
+                        }
+                        return Ok(stream);
+                    }
+                    Some((address, Err(error))) => {
+                        warn!(host, %address, %error, "cannot connect to origin address");
+                        if config.is_some() {
+                            failures.record_failure(address);
+                        }
+                        last_error = Some(error);
+                    }
+                    // The delay has elapsed.
+                    None => (),
+                }
+                attempts.extend(addresses.next().map(start));
+            }
+            Err(last_error.unwrap_or_else(|| format!("host '{}' not resolved", host).into()))
+        })
+    }
+}
+
+/// Connect to the `address` resolved from the host of `origin`.
+async fn connect(
+    mut http: HttpConnector,
+    origin: &Uri,
+    address: SocketAddr,
+    timeout: Option<Duration>,
+) -> (SocketAddr, Result<TcpStream, BoxError>) {
+    let uri = match address_uri(origin, address) {
+        Ok(uri) => uri,
+        Err(error) => return (address, Err(error)),
+    };
+    let result = match timeout {
+        Some(timeout) => match time::timeout(timeout, http.call(uri)).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err("connect timeout".into()),
+        },
+        None => http.call(uri).await.map_err(Into::into),
+    };
+    (address, result)
+}
+
+/// Alternate IPv6 and IPv4 addresses (RFC 8305), starting with the family
+/// of the first resolved address.
+fn interleave_families(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = matches!(addresses.first(), Some(address) if address.is_ipv6());
+    let (ipv6, ipv4): (Vec<_>, Vec<_>) = addresses.into_iter().partition(SocketAddr::is_ipv6);
+    let (mut preferred, mut fallback) = if first_is_ipv6 {
+        (ipv6.into_iter(), ipv4.into_iter())
+    } else {
+        (ipv4.into_iter(), ipv6.into_iter())
+    };
+    let mut interleaved = Vec::new();
+    loop {
+        match (preferred.next(), fallback.next()) {
+            (None, None) => return interleaved,
+            (preferred, fallback) => interleaved.extend(preferred.into_iter().chain(fallback)),
+        }
+    }
+}
+
+/// `uri` with the host replaced by the resolved `address`.
+fn address_uri(uri: &Uri, address: SocketAddr) -> Result<Uri, BoxError> {
+    let scheme = uri.scheme().cloned().unwrap_or(Scheme::HTTP);
+    Ok(Uri::builder()
+        .scheme(scheme)
+        .authority(address.to_string().as_str())
+        .path_and_query("/")
+        .build()?)
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn failed_addresses_last() {
+        let failures = AddressFailures::default();
+        let [a, b, c]: [SocketAddr; 3] = [
+            "10.0.0.1:80".parse().unwrap(),
+            "10.0.0.2:80".parse().unwrap(),
+            "[::1]:80".parse().unwrap(),
+        ];
+        let memory = Duration::from_secs(60);
+
+        failures.record_failure(b);
+        failures.record_failure(a);
+        assert_eq!(failures.order(vec![a, b, c], memory), [c, b, a]);
+
+        failures.record_success(b);
+        assert_eq!(failures.order(vec![a, b, c], memory), [b, c, a]);
+        // Failures are forgotten after `failure_memory`.
+        assert_eq!(
+            failures.order(vec![a, b, c], Duration::from_secs(0)),
+            [a, b, c]
+        );
+    }
+
+    #[test]
+    fn interleaved_families() {
+        let addresses = [
+            "[::1]:80",
+            "[::2]:80",
+            "[::3]:80",
+            "10.0.0.1:80",
+            "10.0.0.2:80",
+        ]
+        .iter()
+        .map(|address| address.parse().unwrap())
+        .collect::<Vec<SocketAddr>>();
+        let interleaved = interleave_families(addresses)
+            .iter()
+            .map(SocketAddr::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            interleaved,
+            [
+                "[::1]:80",
+                "10.0.0.1:80",
+                "[::2]:80",
+                "10.0.0.2:80",
+                "[::3]:80"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_connect() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { listener.accept().await });
+
+        let happy_eyeballs = toml::from_str::<HappyEyeballsConfig>("").unwrap();
+        let mut connector =
+            FailoverConnector::new(HttpConnector::new(), None, Some(happy_eyeballs));
+        let uri = format!("http://localhost:{}", port).parse().unwrap();
+        let stream = connector.call(uri).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn connect_to_resolved_address() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { listener.accept().await });
+
+        let config = toml::from_str::<DnsFailoverConfig>("").unwrap();
+        let mut connector = FailoverConnector::new(HttpConnector::new(), Some(config), None);
+        // `localhost` may resolve also to `::1` where nobody listens.
+        let uri = format!("http://localhost:{}/manifest.json", port)
+            .parse()
+            .unwrap();
+        let stream = connector.call(uri).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+    }
+}
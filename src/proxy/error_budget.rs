@@ -0,0 +1,116 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::ErrorBudgetConfig;
+
+// ------ ErrorBudgets ------
+
+/// Origin failures per route in a rolling window.
+///
+/// Routes that exceed their budget are escalated - they're logged
+/// like routes with `ProxyRoute::verbose` for a while.
+///
+/// See `ProxyConfig::error_budget`.
+#[derive(Default)]
+pub struct ErrorBudgets {
+    // Keys are `ProxyRoute::from`.
+    routes: Mutex<HashMap<String, RouteBudget>>,
+}
+
+#[derive(Default)]
+struct RouteBudget {
+    // Times of the latest failures (at most `max_failures + 1`) inside the window.
+    failures: VecDeque<Instant>,
+    escalated_until: Option<Instant>,
+}
+
+impl ErrorBudgets {
+    /// Count the origin failure of the route.
+    ///
+    /// Returns the end of the escalation when the route has just exceeded its budget.
+    pub fn record_failure(
+        &self,
+        route: &str,
+        config: &ErrorBudgetConfig,
+        now: Instant,
+    ) -> Option<Instant> {
+        let window = Duration::from_secs(u64::from(config.window));
+        let mut routes = self.routes.lock().expect("lock error budgets");
+        let budget = routes.entry(route.to_owned()).or_default();
+
+        budget.failures.push_back(now);
+        while matches!(budget.failures.front(), Some(failure) if now.duration_since(*failure) >= window)
+            || budget.failures.len() > config.max_failures as usize + 1
+        {
+            budget.failures.pop_front();
+        }
+
+        let escalated = matches!(budget.escalated_until, Some(until) if until > now);
+        if escalated || budget.failures.len() <= config.max_failures as usize {
+            return None;
+        }
+        let until = now + Duration::from_secs(u64::from(config.escalation_duration));
+        budget.escalated_until = Some(until);
+        Some(until)
+    }
+
+    /// The route has exceeded its budget recently.
+    pub fn is_escalated(&self, route: &str, now: Instant) -> bool {
+        let routes = self.routes.lock().expect("lock error budgets");
+        matches!(
+            routes.get(route),
+            Some(RouteBudget { escalated_until: Some(until), .. }) if *until > now
+        )
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ErrorBudgetConfig {
+        ErrorBudgetConfig {
+            max_failures: 2,
+            window: 60,
+            escalation_duration: 300,
+            webhook_url: None,
+        }
+    }
+
+    #[test]
+    fn escalate_over_budget() {
+        let budgets = ErrorBudgets::default();
+        let config = config();
+        let now = Instant::now();
+
+        assert!(budgets.record_failure("a", &config, now).is_none());
+        assert!(budgets.record_failure("a", &config, now).is_none());
+        assert!(budgets.record_failure("b", &config, now).is_none());
+        assert!(!budgets.is_escalated("a", now));
+
+        let until = budgets.record_failure("a", &config, now);
+        assert_eq!(until, Some(now + Duration::from_secs(300)));
+        assert!(budgets.is_escalated("a", now));
+        assert!(!budgets.is_escalated("b", now));
+        // The escalation isn't restarted by further failures.
+        assert!(budgets.record_failure("a", &config, now).is_none());
+
+        assert!(!budgets.is_escalated("a", now + Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn old_failures_leave_window() {
+        let budgets = ErrorBudgets::default();
+        let config = config();
+        let now = Instant::now();
+
+        budgets.record_failure("a", &config, now);
+        budgets.record_failure("a", &config, now + Duration::from_secs(30));
+        let later = now + Duration::from_secs(61);
+        assert!(budgets.record_failure("a", &config, later).is_none());
+        assert!(!budgets.is_escalated("a", later));
+    }
+}
@@ -0,0 +1,88 @@
+use hyper::{Body, Response, StatusCode};
+
+// ------ ProxyErrorKind ------
+
+/// Why the proxy responds with an error instead of the origin response.
+///
+/// The kind is stored in the response extensions by `error_response`.
+/// `on_request` counts it (see `Stats::error_responses`), logs it
+/// and adds it to the debug header `x-proxy-error-kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProxyErrorKind {
+    /// No route matches the request.
+    RouteMiss,
+    /// The request has been rejected by hardening checks or validations.
+    ValidationFailed,
+    /// The request has been denied by a proxy rule or geo restrictions.
+    Denied,
+    /// The addon doesn't support the requested resource.
+    UnsupportedResource,
+    /// The routed request URI is invalid.
+    RoutingFailed,
+    /// The origin is unhealthy or its capacity is exceeded.
+    UpstreamUnavailable,
+    /// The request to the origin failed.
+    UpstreamFailed,
+    /// The origin response hasn't been received or transferred in time.
+    UpstreamTimeout,
+    /// The origin response is invalid.
+    UpstreamInvalid,
+    /// The client's time budget has run out (see `ProxyConfig::request_deadline`).
+    DeadlineExceeded,
+    /// The response isn't cached and the admin requested only cached responses.
+    NotCached,
+    /// The database couldn't be read.
+    CacheReadError,
+    /// The database couldn't be written.
+    CacheWriteError,
+    /// The cached response is corrupted or it has an old format.
+    CacheDeserializeError,
+    /// The origin has failed recently (see `ProxyConfig::negative_cache_validity`).
+    CachedFailure,
+    /// The script function `on_request` failed (see `ProxyConfig::script`).
+    ScriptError,
+    /// Other proxy errors (e.g. JSON serialization failed).
+    Internal,
+}
+
+impl ProxyErrorKind {
+    /// The name used in metrics, logs and debug headers.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::RouteMiss => "route_miss",
+            Self::ValidationFailed => "validation_failed",
+            Self::Denied => "denied",
+            Self::UnsupportedResource => "unsupported_resource",
+            Self::RoutingFailed => "routing_failed",
+            Self::UpstreamUnavailable => "upstream_unavailable",
+            Self::UpstreamFailed => "upstream_failed",
+            Self::UpstreamTimeout => "upstream_timeout",
+            Self::UpstreamInvalid => "upstream_invalid",
+            Self::DeadlineExceeded => "deadline_exceeded",
+            Self::NotCached => "not_cached",
+            Self::CacheReadError => "cache_read_error",
+            Self::CacheWriteError => "cache_write_error",
+            Self::CacheDeserializeError => "cache_deserialize_error",
+            Self::CachedFailure => "cached_failure",
+            Self::ScriptError => "script_error",
+            Self::Internal => "internal",
+        }
+    }
+
+    /// The kind of the error response created by `error_response`.
+    pub fn of<T>(response: &Response<T>) -> Option<Self> {
+        response.extensions().get::<Self>().copied()
+    }
+}
+
+/// Create the error response with the given status and text body.
+pub fn error_response(
+    kind: ProxyErrorKind,
+    status: StatusCode,
+    message: &'static str,
+) -> Response<Body> {
+    let mut response = Response::new(Body::from(message));
+    *response.status_mut() = status;
+    response.extensions_mut().insert(kind);
+    response
+}
@@ -0,0 +1,342 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use tokio::sync::oneshot;
+
+// ------ FairQueue ------
+
+/// Limits the number of concurrent requests to each origin
+/// and distributes free slots among clients in a weighted round-robin order.
+///
+/// Requests from one client are served in FIFO order,
+/// but a client with many waiting requests cannot starve other clients.
+/// Origins have independent queues, so a saturated origin doesn't block the others.
+#[derive(Default)]
+pub struct FairQueue {
+    // Keys are origins.
+    origins: Mutex<HashMap<String, Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // The number of granted permits.
+    active: usize,
+    // The latest `capacity` passed to `acquire`.
+    capacity: usize,
+    // Waiting requests per client key.
+    queues: HashMap<String, ClientQueue>,
+    // Client keys with at least one waiting request in the round-robin order.
+    ready: VecDeque<String>,
+}
+
+impl Inner {
+    fn queued(&self) -> usize {
+        self.queues.values().map(|queue| queue.waiters.len()).sum()
+    }
+}
+
+struct ClientQueue {
+    waiters: VecDeque<oneshot::Sender<()>>,
+    // How many requests are served in a row during one round.
+    weight: u32,
+    // How many requests have been served in the current round.
+    served: u32,
+}
+
+impl FairQueue {
+    /// Wait for a free slot.
+    ///
+    /// The slot is released when the returned `FairQueuePermit` is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// - `origin` - Identifies the origin (e.g. the matched route target).
+    ///
+    /// - `client_key` - Identifies the client (e.g. IP address or API key).
+    ///
+    /// - `weight` - How many requests of the client are served in a row during one round.
+    ///
+    /// - `capacity` - The maximum number of concurrent requests to the origin.
+    ///
+    /// - `max_queued` - The maximum number of requests waiting for the origin (unlimited when `None`).
+    ///
+    /// # Errors
+    ///
+    /// Returns `QueueFull` when there isn't a free slot and `max_queued` requests are waiting.
+    pub async fn acquire(
+        &self,
+        origin: &str,
+        client_key: String,
+        weight: u32,
+        capacity: usize,
+        max_queued: Option<usize>,
+    ) -> Result<FairQueuePermit<'_>, QueueFull> {
+        let receiver = {
+            let mut origins = self.origins.lock().expect("lock fair queue");
+            let inner = origins.entry(origin.to_owned()).or_default();
+            inner.capacity = capacity;
+            if inner.active < capacity && inner.ready.is_empty() {
+                inner.active += 1;
+                return Ok(FairQueuePermit::new(self, origin));
+            }
+            // Checked under the same lock as enqueueing, so concurrent requests can't exceed the limit.
+            if matches!(max_queued, Some(max_queued) if inner.queued() >= max_queued) {
+                return Err(QueueFull);
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            let queue = inner
+                .queues
+                .entry(client_key.clone())
+                .or_insert_with(|| ClientQueue {
+                    waiters: VecDeque::new(),
+                    weight: weight.max(1),
+                    served: 0,
+                });
+            let was_empty = queue.waiters.is_empty();
+            queue.waiters.push_back(sender);
+            if was_empty {
+                inner.ready.push_back(client_key);
+            }
+            receiver
+        };
+
+        let mut waiting = Waiting {
+            queue: self,
+            origin,
+            receiver: Some(receiver),
+        };
+        // The slot is handed over by a dropped `FairQueuePermit`.
+        if let Some(receiver) = waiting.receiver.as_mut() {
+            receiver.await.ok();
+        }
+        waiting.receiver = None;
+        Ok(FairQueuePermit::new(self, origin))
+    }
+
+    /// The number of requests waiting for a free slot.
+    pub fn queued(&self) -> usize {
+        let origins = self.origins.lock().expect("lock fair queue");
+        origins.values().map(Inner::queued).sum()
+    }
+
+    /// Hand over the released slot to the next waiting request or free it.
+    fn release(&self, origin: &str) {
+        let mut origins = self.origins.lock().expect("lock fair queue");
+        let inner = match origins.get_mut(origin) {
+            Some(inner) => inner,
+            None => return,
+        };
+        if inner.active > inner.capacity {
+            // Capacity has been decreased by a config reload.
+            inner.active -= 1;
+            return;
+        }
+        while let Some(client_key) = inner.ready.pop_front() {
+            let queue = match inner.queues.get_mut(&client_key) {
+                Some(queue) => queue,
+                None => continue,
+            };
+            let sender = queue.waiters.pop_front();
+            queue.served += 1;
+            let keep_turn = queue.served < queue.weight;
+            if queue.waiters.is_empty() {
+                inner.queues.remove(&client_key);
+            } else if keep_turn {
+                inner.ready.push_front(client_key);
+            } else {
+                queue.served = 0;
+                inner.ready.push_back(client_key);
+            }
+            // The send fails when the waiting request has been cancelled - try the next one.
+            if let Some(sender) = sender {
+                if sender.send(()).is_ok() {
+                    return;
+                }
+            }
+        }
+        inner.active -= 1;
+        if inner.active == 0 {
+            // Don't keep idle origins (e.g. removed routes).
+            origins.remove(origin);
+        }
+    }
+}
+
+// ------ QueueFull ------
+
+/// Too many requests are waiting for the origin. See `FairQueue::acquire`.
+#[derive(Debug)]
+pub struct QueueFull;
+
+// ------ FairQueuePermit ------
+
+/// A granted slot. See `FairQueue::acquire`.
+#[allow(clippy::module_name_repetitions)]
+pub struct FairQueuePermit<'a> {
+    queue: &'a FairQueue,
+    origin: String,
+}
+
+impl<'a> FairQueuePermit<'a> {
+    fn new(queue: &'a FairQueue, origin: &str) -> Self {
+        Self {
+            queue,
+            origin: origin.to_owned(),
+        }
+    }
+}
+
+impl Drop for FairQueuePermit<'_> {
+    fn drop(&mut self) {
+        self.queue.release(&self.origin);
+    }
+}
+
+// ------ Waiting ------
+
+/// Releases the slot when the request is cancelled after the slot has been handed over,
+/// but before it has been picked by `FairQueue::acquire`.
+struct Waiting<'a, 'b> {
+    queue: &'a FairQueue,
+    origin: &'b str,
+    receiver: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Waiting<'_, '_> {
+    fn drop(&mut self) {
+        if let Some(mut receiver) = self.receiver.take() {
+            receiver.close();
+            if receiver.try_recv().is_ok() {
+                self.queue.release(self.origin);
+            }
+        }
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::FutureExt;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn acquire_under_capacity() {
+        let queue = FairQueue::default();
+        let _first = queue
+            .acquire("o", "a".to_owned(), 1, 2, None)
+            .await
+            .unwrap();
+        let _second = queue
+            .acquire("o", "a".to_owned(), 1, 2, None)
+            .await
+            .unwrap();
+        assert_eq!(queue.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn full_queue() {
+        let queue = FairQueue::default();
+        let _permit = queue
+            .acquire("o", "a".to_owned(), 1, 1, Some(1))
+            .await
+            .unwrap();
+
+        let waiting = queue.acquire("o", "b".to_owned(), 1, 1, Some(1));
+        futures_util::pin_mut!(waiting);
+        assert!((&mut waiting).now_or_never().is_none());
+        assert_eq!(queue.queued(), 1);
+
+        let overflow = queue
+            .acquire("o", "c".to_owned(), 1, 1, Some(1))
+            .now_or_never();
+        assert!(matches!(overflow, Some(Err(QueueFull))));
+        assert_eq!(queue.queued(), 1);
+    }
+
+    #[tokio::test]
+    async fn independent_origins() {
+        let queue = FairQueue::default();
+        let _permit = queue
+            .acquire("saturated", "a".to_owned(), 1, 1, Some(0))
+            .await
+            .unwrap();
+        assert!(matches!(
+            queue
+                .acquire("saturated", "b".to_owned(), 1, 1, Some(0))
+                .now_or_never(),
+            Some(Err(QueueFull))
+        ));
+        assert!(matches!(
+            queue
+                .acquire("healthy", "b".to_owned(), 1, 1, Some(0))
+                .now_or_never(),
+            Some(Ok(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn round_robin_between_clients() {
+        let queue = Arc::new(FairQueue::default());
+        let permit = queue
+            .acquire("o", "bulk".to_owned(), 1, 1, None)
+            .await
+            .unwrap();
+
+        let (order_sender, mut order_receiver) = mpsc::unbounded_channel();
+        let mut tasks = Vec::new();
+        for client_key in &["bulk", "bulk", "bulk", "interactive"] {
+            let queue = Arc::clone(&queue);
+            let order_sender = order_sender.clone();
+            tasks.push(tokio::spawn(async move {
+                let _permit = queue
+                    .acquire("o", (*client_key).to_owned(), 1, 1, None)
+                    .await
+                    .unwrap();
+                order_sender.send(*client_key).unwrap();
+            }));
+            // Let the task enqueue its request.
+            time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(queue.queued(), 4);
+
+        drop(permit);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        drop(order_sender);
+
+        let mut order = Vec::new();
+        while let Some(client_key) = order_receiver.recv().await {
+            order.push(client_key);
+        }
+        assert_eq!(order, vec!["bulk", "interactive", "bulk", "bulk"]);
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiter_is_skipped() {
+        let queue = FairQueue::default();
+        let permit = queue
+            .acquire("o", "a".to_owned(), 1, 1, None)
+            .await
+            .unwrap();
+
+        // Enqueue and cancel the request.
+        assert!(queue
+            .acquire("o", "b".to_owned(), 1, 1, None)
+            .now_or_never()
+            .is_none());
+
+        drop(permit);
+        assert_eq!(queue.queued(), 0);
+        assert!(queue
+            .acquire("o", "c".to_owned(), 1, 1, None)
+            .now_or_never()
+            .is_some());
+    }
+}
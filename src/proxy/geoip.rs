@@ -0,0 +1,70 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+
+use maxminddb::{geoip2, Reader};
+
+/// Opened MaxMind databases - see `GeoIpConfig`.
+pub struct Databases {
+    country: Reader<Vec<u8>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+// `Debug` output of readers contains whole databases.
+impl fmt::Debug for Databases {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Databases")
+            .field("country", &self.country.metadata.database_type)
+            .field(
+                "asn",
+                &self.asn.as_ref().map(|asn| &asn.metadata.database_type),
+            )
+            .finish()
+    }
+}
+
+impl Databases {
+    /// Load the database files into memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns error when a file cannot be read or when it isn't a MaxMind database.
+    pub fn open(country_database: &Path, asn_database: Option<&Path>) -> Result<Self, String> {
+        let open = |path: &Path| {
+            Reader::open_readfile(path).map_err(|error| {
+                format!("cannot open GeoIP database '{}': {}", path.display(), error)
+            })
+        };
+        Ok(Self {
+            country: open(country_database)?,
+            asn: asn_database.map(open).transpose()?,
+        })
+    }
+
+    /// ISO 3166-1 alpha-2 country code and continent code (e.g. "EU")
+    /// or `None`s when the IP isn't in the database.
+    pub fn country_and_continent(&self, ip: IpAddr) -> (Option<String>, Option<String>) {
+        match self.country.lookup::<geoip2::Country>(ip) {
+            Ok(record) => (
+                record
+                    .country
+                    .and_then(|country| country.iso_code)
+                    .map(str::to_owned),
+                record
+                    .continent
+                    .and_then(|continent| continent.code)
+                    .map(str::to_owned),
+            ),
+            Err(_) => (None, None),
+        }
+    }
+
+    /// Autonomous system number or `None` when the IP or the ASN database is unknown.
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        self.asn
+            .as_ref()?
+            .lookup::<geoip2::Asn>(ip)
+            .ok()?
+            .autonomous_system_number
+    }
+}
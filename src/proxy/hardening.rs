@@ -0,0 +1,345 @@
+use hyper::body::Bytes;
+use hyper::{header, HeaderMap, Request};
+
+/// Response headers kept by `limit_response_headers` even over the limits -
+/// the body can't be read correctly without them.
+const ESSENTIAL_RESPONSE_HEADERS: &[header::HeaderName] = &[
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_ENCODING,
+    header::TRANSFER_ENCODING,
+];
+
+/// Protocol-level checks that protect the origin against request smuggling.
+///
+/// The proxy reconstructs upstream URIs by string concatenation and forwards
+/// the original headers mostly untouched, so ambiguous requests have to be rejected
+/// before they are routed.
+///
+/// # Errors
+///
+/// Returns a short description of the first detected problem.
+pub fn check_request(req: &Request<Bytes>) -> Result<(), &'static str> {
+    check_framing_headers(req)?;
+    check_host(req)?;
+    check_path(req.uri().path())?;
+    Ok(())
+}
+
+/// Remove framing headers so the client computes them again from the buffered body.
+///
+/// _Note:_ The request body has been already read into `Bytes`,
+/// so the original framing headers are meaningless for the origin.
+pub fn normalize_framing_headers(req: &mut Request<Bytes>) {
+    let headers = req.headers_mut();
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.remove(header::CONTENT_LENGTH);
+}
+
+/// Drop origin response headers over `max_count` and `max_total_size`
+/// (the sum of header name and value lengths in bytes).
+///
+/// Headers are kept in their original order until a limit is reached,
+/// `ESSENTIAL_RESPONSE_HEADERS` are always kept (but counted).
+/// Returns the number of dropped headers.
+pub fn limit_response_headers(
+    headers: &mut HeaderMap,
+    max_count: Option<usize>,
+    max_total_size: Option<usize>,
+) -> usize {
+    let max_count = max_count.unwrap_or(usize::MAX);
+    let max_total_size = max_total_size.unwrap_or(usize::MAX);
+    let header_size =
+        |name: &header::HeaderName, value: &header::HeaderValue| name.as_str().len() + value.len();
+
+    let total_size = headers
+        .iter()
+        .map(|(name, value)| header_size(name, value))
+        .sum::<usize>();
+    if headers.len() <= max_count && total_size <= max_total_size {
+        return 0;
+    }
+
+    let mut limited_headers = HeaderMap::new();
+    let mut count = 0;
+    let mut total_size = 0;
+    for name in ESSENTIAL_RESPONSE_HEADERS {
+        for value in headers.get_all(name) {
+            limited_headers.append(name.clone(), value.clone());
+            count += 1;
+            total_size += header_size(name, value);
+        }
+    }
+    let mut dropped = 0;
+    for (name, value) in headers.iter() {
+        if ESSENTIAL_RESPONSE_HEADERS.contains(name) {
+            continue;
+        }
+        let size = header_size(name, value);
+        if count < max_count && total_size + size <= max_total_size {
+            limited_headers.append(name.clone(), value.clone());
+            count += 1;
+            total_size += size;
+        } else {
+            dropped += 1;
+        }
+    }
+    *headers = limited_headers;
+    dropped
+}
+
+/// Reject conflicting `Content-Length` / `Transfer-Encoding` headers.
+fn check_framing_headers(req: &Request<Bytes>) -> Result<(), &'static str> {
+    let headers = req.headers();
+
+    let has_transfer_encoding = headers.contains_key(header::TRANSFER_ENCODING);
+    if has_transfer_encoding && headers.contains_key(header::CONTENT_LENGTH) {
+        return Err("Both Content-Length and Transfer-Encoding are set.");
+    }
+
+    let mut content_length = None;
+    for value in headers.get_all(header::CONTENT_LENGTH) {
+        let value = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .ok_or("Invalid Content-Length.")?;
+        match content_length {
+            Some(previous) if previous != value => {
+                return Err("Conflicting Content-Length values.")
+            }
+            _ => content_length = Some(value),
+        }
+    }
+
+    for value in headers.get_all(header::TRANSFER_ENCODING) {
+        // Only `chunked` is understood by the proxy - other codings (or their combinations)
+        // may be interpreted differently by the origin.
+        let is_chunked = value
+            .to_str()
+            .map(|value| value.trim().eq_ignore_ascii_case("chunked"))
+            .unwrap_or_default();
+        if !is_chunked {
+            return Err("Unsupported Transfer-Encoding.");
+        }
+    }
+    Ok(())
+}
+
+/// Reject multiple `Host` headers and absolute-form URIs that disagree with `Host`.
+fn check_host(req: &Request<Bytes>) -> Result<(), &'static str> {
+    let mut host_headers = req.headers().get_all(header::HOST).iter();
+    let host_header = host_headers.next();
+    if host_headers.next().is_some() {
+        return Err("Multiple Host headers.");
+    }
+
+    let (uri_authority, host_header) = match (req.uri().authority(), host_header) {
+        (Some(uri_authority), Some(host_header)) => (uri_authority, host_header),
+        _ => return Ok(()),
+    };
+    let host_header = host_header.to_str().map_err(|_| "Invalid Host header.")?;
+
+    // example.com:8080 -> (example.com, Some(8080))
+    let (header_host, header_port) = match host_header.rfind(':') {
+        Some(index) if !host_header.ends_with(']') => (
+            &host_header[..index],
+            Some(
+                host_header[index + 1..]
+                    .parse::<u16>()
+                    .map_err(|_| "Invalid Host header.")?,
+            ),
+        ),
+        _ => (host_header, None),
+    };
+
+    if !uri_authority.host().eq_ignore_ascii_case(header_host) {
+        return Err("Request URI host disagrees with Host header.");
+    }
+    if let (Some(uri_port), Some(header_port)) = (uri_authority.port_u16(), header_port) {
+        if uri_port != header_port {
+            return Err("Request URI port disagrees with Host header.");
+        }
+    }
+    Ok(())
+}
+
+/// Reject NUL and other control characters in the path - both raw and percent-encoded.
+fn check_path(path: &str) -> Result<(), &'static str> {
+    let is_control = |byte: u8| byte < 0x20 || byte == 0x7f;
+
+    let bytes = path.as_bytes();
+    for (index, byte) in bytes.iter().enumerate() {
+        if is_control(*byte) {
+            return Err("Control character in the path.");
+        }
+        if *byte == b'%' {
+            let decoded = bytes
+                .get(index + 1..index + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if matches!(decoded, Some(byte) if is_control(byte)) {
+                return Err("Encoded control character in the path.");
+            }
+        }
+    }
+    Ok(())
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str) -> http::request::Builder {
+        Request::builder().uri(uri)
+    }
+
+    // ------ check_framing_headers ------
+
+    #[test]
+    fn framing_content_length_and_transfer_encoding() {
+        let req = request("/")
+            .header("content-length", "5")
+            .header("transfer-encoding", "chunked")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(check_request(&req).is_err());
+    }
+
+    #[test]
+    fn framing_conflicting_content_lengths() {
+        let req = request("/")
+            .header("content-length", "5")
+            .header("content-length", "6")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(check_request(&req).is_err());
+    }
+
+    #[test]
+    fn framing_duplicated_content_length() {
+        let req = request("/")
+            .header("content-length", "5")
+            .header("content-length", "5")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(check_request(&req).is_ok());
+    }
+
+    #[test]
+    fn framing_obfuscated_transfer_encoding() {
+        let req = request("/")
+            .header("transfer-encoding", "chunked, identity")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(check_request(&req).is_err());
+    }
+
+    // ------ check_host ------
+
+    #[test]
+    fn host_matches_absolute_uri() {
+        let req = request("http://example.com/manifest.json")
+            .header("host", "Example.com")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(check_request(&req).is_ok());
+    }
+
+    #[test]
+    fn host_disagrees_with_absolute_uri() {
+        let req = request("http://example.com/manifest.json")
+            .header("host", "internal.example.com")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(check_request(&req).is_err());
+    }
+
+    #[test]
+    fn host_port_disagrees_with_absolute_uri() {
+        let req = request("http://example.com:5000/manifest.json")
+            .header("host", "example.com:5005")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(check_request(&req).is_err());
+    }
+
+    #[test]
+    fn multiple_hosts() {
+        let req = request("/manifest.json")
+            .header("host", "example.com")
+            .header("host", "example.com")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(check_request(&req).is_err());
+    }
+
+    // ------ limit_response_headers ------
+
+    fn response_headers(count: usize) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        for index in 0..count {
+            headers.append(header::VIA, format!("1.1 proxy-{}", index).parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn response_headers_within_limits() {
+        let mut headers = response_headers(3);
+        assert_eq!(limit_response_headers(&mut headers, Some(4), Some(1000)), 0);
+        assert_eq!(headers.len(), 4);
+    }
+
+    #[test]
+    fn response_headers_over_max_count() {
+        let mut headers = response_headers(1000);
+        assert_eq!(limit_response_headers(&mut headers, Some(10), None), 991);
+        assert_eq!(headers.len(), 10);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        assert_eq!(
+            headers.get_all(header::VIA).iter().next().unwrap(),
+            "1.1 proxy-0"
+        );
+    }
+
+    #[test]
+    fn response_headers_over_max_total_size() {
+        let mut headers = response_headers(0);
+        headers.insert(header::SET_COOKIE, "a".repeat(10_000).parse().unwrap());
+        headers.insert(header::CACHE_CONTROL, "max-age=60".parse().unwrap());
+        assert_eq!(limit_response_headers(&mut headers, None, Some(1000)), 1);
+        assert!(headers.get(header::SET_COOKIE).is_none());
+        assert_eq!(headers[header::CACHE_CONTROL], "max-age=60");
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+    }
+
+    // ------ check_path ------
+
+    #[test]
+    fn path_encoded_nul() {
+        let req = request("/catalog/movie/top%00.json")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(check_request(&req).is_err());
+    }
+
+    #[test]
+    fn path_encoded_newline() {
+        let req = request("/catalog/movie/top%0d%0a.json")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(check_request(&req).is_err());
+    }
+
+    #[test]
+    fn path_encoded_space() {
+        let req = request("/catalog/movie/top%20rated.json")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(check_request(&req).is_ok());
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::future;
+use hyper::client::HttpConnector;
+use hyper::{Client, Uri};
+use hyper_tls::HttpsConnector;
+use serde::Serialize;
+use tokio::sync::watch;
+use tokio::time;
+use tracing::{info, warn};
+
+use super::{HealthChecksConfig, ProxyConfig, ProxyState};
+use crate::helpers::now_timestamp;
+
+type ProbeClient = Client<HttpsConnector<HttpConnector>>;
+
+// ------ UpstreamHealth ------
+
+/// Health of origins probed by `check_upstreams` (see `ProxyConfig::health_checks`).
+#[derive(Default)]
+pub struct UpstreamHealth {
+    upstreams: Mutex<HashMap<Uri, UpstreamStatus>>,
+}
+
+/// The result of the latest probes of the origin.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct UpstreamStatus {
+    pub healthy: bool,
+    /// Failed probes in a row.
+    pub consecutive_failures: u32,
+    /// Unix timestamp of the latest probe.
+    pub last_check: i64,
+    pub last_error: Option<String>,
+}
+
+impl UpstreamHealth {
+    /// Origins that haven't been probed yet are healthy.
+    pub fn is_healthy(&self, origin: &Uri) -> bool {
+        let upstreams = self.upstreams.lock().expect("lock upstream health");
+        !matches!(upstreams.get(origin), Some(status) if !status.healthy)
+    }
+
+    /// Store the probe result.
+    ///
+    /// Returns the new status when the origin has just become healthy or unhealthy.
+    pub fn record(
+        &self,
+        origin: &Uri,
+        result: Result<(), String>,
+        unhealthy_threshold: u32,
+    ) -> Option<UpstreamStatus> {
+        let mut upstreams = self.upstreams.lock().expect("lock upstream health");
+        let status = upstreams
+            .entry(origin.clone())
+            .or_insert_with(|| UpstreamStatus {
+                healthy: true,
+                consecutive_failures: 0,
+                last_check: 0,
+                last_error: None,
+            });
+        let was_healthy = status.healthy;
+        status.last_check = now_timestamp();
+        match result {
+            Ok(()) => {
+                status.healthy = true;
+                status.consecutive_failures = 0;
+                status.last_error = None;
+            }
+            Err(error) => {
+                status.consecutive_failures = status.consecutive_failures.saturating_add(1);
+                status.healthy = status.consecutive_failures < unhealthy_threshold.max(1);
+                status.last_error = Some(error);
+            }
+        }
+        if status.healthy == was_healthy {
+            return None;
+        }
+        Some(status.clone())
+    }
+
+    /// Forget origins that aren't probed anymore - they're healthy again.
+    fn retain(&self, origins: &HashSet<Uri>) {
+        let mut upstreams = self.upstreams.lock().expect("lock upstream health");
+        upstreams.retain(|origin, _| origins.contains(origin));
+    }
+
+    /// Statuses of probed origins.
+    pub fn snapshot(&self) -> BTreeMap<String, UpstreamStatus> {
+        let upstreams = self.upstreams.lock().expect("lock upstream health");
+        upstreams
+            .iter()
+            .map(|(origin, status)| (origin.to_string(), status.clone()))
+            .collect()
+    }
+}
+
+// ------ check_upstreams ------
+
+/// Probe origins of routes according to the current `ProxyConfig::health_checks`
+/// until the config sender is dropped.
+pub async fn check_upstreams(
+    mut config_receiver: watch::Receiver<Arc<ProxyConfig>>,
+    state: Arc<ProxyState>,
+) {
+    let client = Client::builder().build(HttpsConnector::new());
+    let mut proxy_config: Option<Arc<ProxyConfig>> = None;
+
+    loop {
+        let check_interval = proxy_config
+            .as_ref()
+            .and_then(|proxy_config| proxy_config.health_checks.as_ref())
+            .map(|health_checks| Duration::from_secs(u64::from(health_checks.interval.max(1))));
+        let check_time = async {
+            match check_interval {
+                Some(check_interval) => time::delay_for(check_interval).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            new_proxy_config = config_receiver.recv() => {
+                match new_proxy_config {
+                    Some(new_proxy_config) => proxy_config = Some(new_proxy_config),
+                    None => return,
+                }
+            }
+            _ = check_time => ()
+        }
+        let proxy_config = match &proxy_config {
+            Some(proxy_config) => proxy_config,
+            None => continue,
+        };
+        let origins = match &proxy_config.health_checks {
+            Some(_) => probed_origins(proxy_config),
+            None => HashSet::new(),
+        };
+        state.upstream_health.retain(&origins);
+        let health_checks = match &proxy_config.health_checks {
+            Some(health_checks) => health_checks,
+            None => continue,
+        };
+
+        let client = &client;
+        let probes = origins
+            .iter()
+            .map(|origin| async move { (origin, probe(&client, origin, health_checks).await) });
+        for (origin, result) in future::join_all(probes).await {
+            let status =
+                state
+                    .upstream_health
+                    .record(origin, result, health_checks.unhealthy_threshold);
+            match status {
+                Some(status) if status.healthy => info!(%origin, "origin is healthy again"),
+                Some(status) => warn!(
+                    %origin,
+                    error = status.last_error.as_deref().unwrap_or_default(),
+                    "origin is unhealthy"
+                ),
+                None => (),
+            }
+        }
+    }
+}
+
+/// All origins of routes in the config.
+fn probed_origins(proxy_config: &ProxyConfig) -> HashSet<Uri> {
+    proxy_config
+        .routes
+        .iter()
+        .flat_map(|route| route.to.iter().chain(route.to_by_region.values()))
+        .cloned()
+        .collect()
+}
+
+async fn probe(
+    client: &ProbeClient,
+    origin: &Uri,
+    health_checks: &HealthChecksConfig,
+) -> Result<(), String> {
+    // The path is relative to the origin like paths of routed requests.
+    let uri = format!("{}{}", origin, health_checks.path.trim_start_matches('/'))
+        .parse::<Uri>()
+        .map_err(|error| error.to_string())?;
+    let timeout = Duration::from_secs(u64::from(health_checks.timeout));
+    let response = time::timeout(timeout, client.get(uri))
+        .await
+        .map_err(|_| "timeout".to_owned())?
+        .map_err(|error| error.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("origin responded with {}", response.status()));
+    }
+    Ok(())
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unhealthy_after_threshold() {
+        let health = UpstreamHealth::default();
+        let origin = "http://localhost:8080".parse::<Uri>().unwrap();
+        let fail = || Err("timeout".to_owned());
+
+        assert!(health.is_healthy(&origin));
+        assert!(health.record(&origin, fail(), 2).is_none());
+        assert!(health.is_healthy(&origin));
+
+        let status = health.record(&origin, fail(), 2).unwrap();
+        assert!(!status.healthy);
+        assert_eq!(status.last_error.as_deref(), Some("timeout"));
+        assert!(!health.is_healthy(&origin));
+        assert!(health.record(&origin, fail(), 2).is_none());
+
+        assert!(health.record(&origin, Ok(()), 2).unwrap().healthy);
+        assert!(health.is_healthy(&origin));
+        assert_eq!(
+            health.snapshot()["http://localhost:8080/"].consecutive_failures,
+            0
+        );
+
+        health.retain(&HashSet::new());
+        assert!(health.snapshot().is_empty());
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt;
+use std::sync::Arc;
+
+use hyper::{Method, Uri};
+
+pub type OnUpstreamConnect = Arc<dyn Fn(&UpstreamRequest) + Send + Sync>;
+pub type OnUpstreamError = Arc<dyn Fn(&UpstreamRequest, &UpstreamError) + Send + Sync>;
+pub type OnCacheEvent = Arc<dyn Fn(&CacheEvent) + Send + Sync>;
+
+// ------ ProxyHooks ------
+
+/// Callbacks invoked synchronously while requests are handled by `on_request`.
+///
+/// Embedders may use them for custom accounting or adaptive behavior.
+/// They should return quickly - the request waits for them.
+///
+/// See `Proxy::set_on_upstream_connect`, `Proxy::set_on_upstream_error`
+/// and `Proxy::set_on_cache_event`.
+#[derive(Default, Clone)]
+pub struct ProxyHooks {
+    /// Invoked just before the request is sent to the origin.
+    pub on_upstream_connect: Option<OnUpstreamConnect>,
+    /// Invoked when the origin fails (see `UpstreamError`).
+    pub on_upstream_error: Option<OnUpstreamError>,
+    /// Invoked when the cache is looked up.
+    pub on_cache_event: Option<OnCacheEvent>,
+}
+
+impl ProxyHooks {
+    pub fn upstream_connect(&self, request: &UpstreamRequest) {
+        if let Some(on_upstream_connect) = &self.on_upstream_connect {
+            on_upstream_connect(request);
+        }
+    }
+
+    pub fn upstream_error(&self, request: &UpstreamRequest, error: &UpstreamError) {
+        if let Some(on_upstream_error) = &self.on_upstream_error {
+            on_upstream_error(request, error);
+        }
+    }
+
+    pub fn cache_event(&self, event: &CacheEvent) {
+        if let Some(on_cache_event) = &self.on_cache_event {
+            on_cache_event(event);
+        }
+    }
+}
+
+impl fmt::Debug for ProxyHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyHooks")
+            .field("on_upstream_connect", &self.on_upstream_connect.is_some())
+            .field("on_upstream_error", &self.on_upstream_error.is_some())
+            .field("on_cache_event", &self.on_cache_event.is_some())
+            .finish()
+    }
+}
+
+// ------ Events ------
+
+/// The request sent to the origin.
+#[derive(Debug)]
+pub struct UpstreamRequest<'a> {
+    /// `ProxyRoute::from` of the matched route.
+    pub route: Option<&'a str>,
+    pub method: &'a Method,
+    pub uri: &'a Uri,
+}
+
+/// Why the origin failed.
+#[derive(Debug)]
+pub enum UpstreamError<'a> {
+    /// The request couldn't be sent or the response couldn't be received.
+    Request(&'a hyper::Error),
+    /// The response is invalid (see `ProxyRoute::validate`) or its headers are over
+    /// `ProxyConfig::response_header_limits`.
+    InvalidResponse,
+    /// The client's time budget has run out (see `ProxyConfig::request_deadline`).
+    DeadlineExceeded,
+    /// The response hasn't been received in time (see `ProxyConfig::response_timeout`).
+    ResponseTimeout,
+    /// The body has been transferred too slowly (see `ProxyConfig::min_transfer_rate`).
+    TransferTooSlow,
+}
+
+/// The result of the cache lookup for the request.
+#[derive(Debug)]
+pub enum CacheEvent<'a> {
+    /// The cached response is served (`fresh` is `false` for stale responses
+    /// served while they're refreshed).
+    Hit { uri: &'a Uri, fresh: bool },
+    /// The response isn't cached or it's stale.
+    Miss { uri: &'a Uri },
+}
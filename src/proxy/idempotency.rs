@@ -0,0 +1,178 @@
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryInto;
+use std::hash::{Hash, Hasher};
+
+use http::{header, Request};
+use hyper::body::Bytes;
+
+use crate::helpers::now_timestamp;
+use crate::proxy::cache::{CacheValueForDeserialization, CacheValueForSerialization};
+use crate::proxy::Db;
+
+/// Journaled responses by `journal_key`.
+const JOURNAL_TREE: &str = "idempotency_journal";
+/// Journal keys prefixed by the expiration timestamp, so expired responses can be found quickly.
+const EXPIRATION_TREE: &str = "idempotency_expiration";
+
+// ------ Idempotency journal ------
+
+/// The key of the request with the given idempotency key (see `ProxyConfig::idempotency`).
+///
+/// Only exact duplicates have the same key - the method, URI, body
+/// and the header `Authorization` have to match, too.
+pub fn journal_key(idempotency_key: &[u8], req: &Request<Bytes>) -> [u8; 8] {
+    let mut hasher = DefaultHasher::new();
+    idempotency_key.hash(&mut hasher);
+    req.method().hash(&mut hasher);
+    req.uri().hash(&mut hasher);
+    req.body().hash(&mut hasher);
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .map(header::HeaderValue::as_bytes)
+        .hash(&mut hasher);
+    hasher.finish().to_be_bytes()
+}
+
+/// Get the journaled response of the previous request with the same `journal_key`.
+///
+/// Expired responses and responses stored in an incompatible format are ignored.
+///
+/// # Errors
+///
+/// Returns error when DB reading fails.
+pub fn replay(
+    db: &Db,
+    journal_key: [u8; 8],
+) -> Result<Option<CacheValueForDeserialization>, String> {
+    let journal = db.open_tree(JOURNAL_TREE).map_err(|err| err.to_string())?;
+    let value = match journal.get(journal_key).map_err(|err| err.to_string())? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    Ok(bincode::deserialize::<CacheValueForDeserialization>(&value)
+        .ok()
+        .filter(CacheValueForDeserialization::is_fresh))
+}
+
+/// Store the response to the journal for `CacheValue::validity` seconds
+/// and remove expired responses.
+///
+/// # Errors
+///
+/// Returns error when serialization or DB writing fails.
+pub fn record(
+    db: &Db,
+    journal_key: [u8; 8],
+    value: &CacheValueForSerialization,
+) -> Result<(), String> {
+    let journal = db.open_tree(JOURNAL_TREE).map_err(|err| err.to_string())?;
+    let expiration = db
+        .open_tree(EXPIRATION_TREE)
+        .map_err(|err| err.to_string())?;
+    let serialized_value = bincode::serialize(value).map_err(|err| err.to_string())?;
+
+    journal
+        .insert(journal_key, serialized_value)
+        .map_err(|err| err.to_string())?;
+    let expires = value.timestamp.saturating_add(i64::from(value.validity));
+    expiration
+        .insert(expiration_key(expires, journal_key), &[])
+        .map_err(|err| err.to_string())?;
+    remove_expired(db, now_timestamp())
+}
+
+/// Remove responses that expired before `now`.
+///
+/// # Errors
+///
+/// Returns error when DB reading or writing fails.
+fn remove_expired(db: &Db, now: i64) -> Result<(), String> {
+    let journal = db.open_tree(JOURNAL_TREE).map_err(|err| err.to_string())?;
+    let expiration = db
+        .open_tree(EXPIRATION_TREE)
+        .map_err(|err| err.to_string())?;
+
+    for item in expiration.range(..expiration_key(now, [0; 8])) {
+        let (key, _) = item.map_err(|err| err.to_string())?;
+        let journal_key: [u8; 8] = key[8..].try_into().map_err(|_| "invalid expiration key")?;
+        let expires = i64::from_be_bytes(key[..8].try_into().expect("8 bytes"));
+        // The response may have been journaled again with a later expiration.
+        let journaled_expires = journal
+            .get(journal_key)
+            .map_err(|err| err.to_string())?
+            .and_then(|value| bincode::deserialize::<CacheValueForDeserialization>(&value).ok())
+            .map(|value| value.timestamp.saturating_add(i64::from(value.validity)));
+        if matches!(journaled_expires, Some(journaled_expires) if journaled_expires > expires) {
+            expiration.remove(key).map_err(|err| err.to_string())?;
+            continue;
+        }
+        journal.remove(journal_key).map_err(|err| err.to_string())?;
+        expiration.remove(key).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+fn expiration_key(expires: i64, journal_key: [u8; 8]) -> Vec<u8> {
+    // Big-endian timestamps are sorted chronologically (they're positive).
+    let mut key = expires.max(0).to_be_bytes().to_vec();
+    key.extend_from_slice(&journal_key);
+    key
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::cache::CacheKey;
+    use http::{HeaderMap, Method, StatusCode};
+
+    fn request(body: &'static str, authorization: &str) -> Request<Bytes> {
+        Request::post("http://example.com/api")
+            .header(header::AUTHORIZATION, authorization)
+            .body(Bytes::from(body))
+            .unwrap()
+    }
+
+    #[test]
+    fn exact_duplicates_only() {
+        let key = journal_key(b"abc", &request("{}", "user_a"));
+        assert_eq!(key, journal_key(b"abc", &request("{}", "user_a")));
+        assert_ne!(key, journal_key(b"abd", &request("{}", "user_a")));
+        assert_ne!(key, journal_key(b"abc", &request("{ }", "user_a")));
+        assert_ne!(key, journal_key(b"abc", &request("{}", "user_b")));
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let uri = "http://example.com/api".parse().unwrap();
+        let cached_request = CacheKey {
+            method: &Method::POST,
+            uri: &uri,
+            body: &Bytes::new(),
+            user: None,
+        }
+        .to_cached_request();
+        let headers = HeaderMap::new();
+
+        let value =
+            CacheValueForSerialization::new(&cached_request, StatusCode::OK, &headers, b"ok", 60);
+        record(&db, [1; 8], &value).unwrap();
+        assert_eq!(replay(&db, [1; 8]).unwrap().unwrap().body, b"ok");
+        assert!(replay(&db, [2; 8]).unwrap().is_none());
+
+        let mut expired =
+            CacheValueForSerialization::new(&cached_request, StatusCode::OK, &headers, b"old", 60);
+        expired.timestamp -= 120;
+        record(&db, [2; 8], &expired).unwrap();
+        assert!(replay(&db, [2; 8]).unwrap().is_none());
+        // Removed by `remove_expired`.
+        assert!(!db
+            .open_tree(JOURNAL_TREE)
+            .unwrap()
+            .contains_key([2; 8])
+            .unwrap());
+        assert_eq!(db.open_tree(EXPIRATION_TREE).unwrap().len(), 1);
+    }
+}
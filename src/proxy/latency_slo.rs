@@ -0,0 +1,131 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::LatencySlo;
+
+/// Older latencies are dropped when a route gets more requests during the window.
+const MAX_SAMPLES_PER_ROUTE: usize = 10_000;
+
+// ------ LatencySlos ------
+
+/// Latencies of requests sent to origins per route with `ProxyRoute::latency_slo`.
+///
+/// See `ProxyConfig::latency_slo_alerts`.
+#[derive(Default)]
+pub struct LatencySlos {
+    // Keys are `ProxyRoute::from`.
+    routes: Mutex<HashMap<String, RouteLatencies>>,
+}
+
+struct RouteLatencies {
+    slo: LatencySlo,
+    // Request end times and latencies in milliseconds.
+    samples: VecDeque<(Instant, u64)>,
+    violated: bool,
+}
+
+/// The result of `LatencySlos::evaluate` for one route.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SloEvaluation {
+    /// The p99 latency in the window in milliseconds.
+    pub p99_ms: u64,
+    pub slo: LatencySlo,
+    pub violated: bool,
+    /// The objective wasn't violated during the previous evaluation.
+    pub newly_violated: bool,
+}
+
+impl LatencySlos {
+    /// Store the latency of the request handled at `now`.
+    pub fn record(&self, route: &str, slo: LatencySlo, latency: Duration, now: Instant) {
+        let mut routes = self.routes.lock().expect("lock latency SLOs");
+        let latencies = routes
+            .entry(route.to_owned())
+            .or_insert_with(|| RouteLatencies {
+                slo,
+                samples: VecDeque::new(),
+                violated: false,
+            });
+        // The objective may have been changed by a config reload.
+        latencies.slo = slo;
+        if latencies.samples.len() == MAX_SAMPLES_PER_ROUTE {
+            latencies.samples.pop_front();
+        }
+        let latency = u64::try_from(latency.as_millis()).unwrap_or(u64::MAX);
+        latencies.samples.push_back((now, latency));
+    }
+
+    /// Compare p99 latencies in the sliding `window` with the objectives.
+    ///
+    /// Routes without requests in the window are forgotten.
+    pub fn evaluate(&self, window: Duration, now: Instant) -> BTreeMap<String, SloEvaluation> {
+        let mut routes = self.routes.lock().expect("lock latency SLOs");
+        routes.retain(|_, latencies| {
+            while matches!(latencies.samples.front(), Some((time, _)) if now.duration_since(*time) >= window)
+            {
+                latencies.samples.pop_front();
+            }
+            !latencies.samples.is_empty()
+        });
+
+        routes
+            .iter_mut()
+            .map(|(route, latencies)| {
+                let mut sorted_latencies = latencies
+                    .samples
+                    .iter()
+                    .map(|(_, latency)| *latency)
+                    .collect::<Vec<_>>();
+                sorted_latencies.sort_unstable();
+                let p99_ms = sorted_latencies[(sorted_latencies.len() - 1) * 99 / 100];
+                let violated = p99_ms > u64::from(latencies.slo.p99_ms);
+                let evaluation = SloEvaluation {
+                    p99_ms,
+                    slo: latencies.slo,
+                    violated,
+                    newly_violated: violated && !latencies.violated,
+                };
+                latencies.violated = violated;
+                (route.clone(), evaluation)
+            })
+            .collect()
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLO: LatencySlo = LatencySlo { p99_ms: 300 };
+
+    #[test]
+    fn evaluate_p99_in_window() {
+        let slos = LatencySlos::default();
+        let now = Instant::now();
+        for _ in 0..99 {
+            slos.record("a", SLO, Duration::from_millis(100), now);
+        }
+        slos.record("a", SLO, Duration::from_millis(1000), now);
+        slos.record("b", SLO, Duration::from_millis(500), now);
+
+        let evaluations = slos.evaluate(Duration::from_secs(60), now);
+        assert_eq!(evaluations["a"].p99_ms, 100);
+        assert!(!evaluations["a"].violated);
+        assert!(evaluations["b"].violated && evaluations["b"].newly_violated);
+
+        let evaluations = slos.evaluate(Duration::from_secs(60), now);
+        assert!(evaluations["b"].violated && !evaluations["b"].newly_violated);
+
+        let later = now + Duration::from_secs(60);
+        slos.record("a", SLO, Duration::from_millis(400), later);
+        let evaluations = slos.evaluate(Duration::from_secs(60), later);
+        assert_eq!(evaluations["a"].p99_ms, 400);
+        assert!(evaluations["a"].newly_violated);
+        // Forgotten - no requests in the window.
+        assert!(!evaluations.contains_key("b"));
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use http::Uri;
+
+use super::{LoadBalancingStrategy, Upstreams};
+
+// ------ LoadBalancer ------
+
+/// Distributes requests among origin replicas of routes (see `ProxyRoute::strategy`).
+#[derive(Default)]
+pub struct LoadBalancer {
+    // Keys are `ProxyRoute::from`.
+    routes: Mutex<HashMap<String, RouteUpstreams>>,
+}
+
+#[derive(Default)]
+struct RouteUpstreams {
+    // The index of the next origin for `LoadBalancingStrategy::RoundRobin`.
+    next: usize,
+    // Requests in progress per origin.
+    connections: HashMap<Uri, Arc<AtomicUsize>>,
+}
+
+/// A request in progress. The origin's counter is decremented when it's dropped.
+pub struct UpstreamConnection {
+    counter: Arc<AtomicUsize>,
+}
+
+impl Drop for UpstreamConnection {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl LoadBalancer {
+    /// Choose the origin for a request of the route and count the request
+    /// until the returned connection is dropped.
+    ///
+    /// Unavailable origins (e.g. unhealthy ones) are skipped unless all origins are unavailable.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn pick(
+        &self,
+        route: &str,
+        upstreams: &Upstreams,
+        strategy: LoadBalancingStrategy,
+        is_available: impl Fn(&Uri) -> bool,
+    ) -> (Uri, UpstreamConnection) {
+        let mut candidates = upstreams
+            .iter()
+            .filter(|upstream| is_available(upstream))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            candidates = upstreams.iter().collect();
+        }
+
+        let mut routes = self.routes.lock().expect("lock load balancer");
+        let route_upstreams = routes.entry(route.to_owned()).or_default();
+        // Origins may have been changed by a config reload.
+        // (Connections in progress keep their counters.)
+        route_upstreams
+            .connections
+            .retain(|uri, _| upstreams.iter().any(|upstream| upstream == uri));
+
+        let upstream = match strategy {
+            LoadBalancingStrategy::RoundRobin => {
+                let upstream = candidates[route_upstreams.next % candidates.len()];
+                route_upstreams.next = route_upstreams.next.wrapping_add(1);
+                upstream
+            }
+            LoadBalancingStrategy::Random => {
+                let random = RandomState::new().build_hasher().finish() as usize;
+                candidates[random % candidates.len()]
+            }
+            LoadBalancingStrategy::LeastConnections => {
+                let connections = &route_upstreams.connections;
+                candidates
+                    .iter()
+                    .copied()
+                    .min_by_key(|upstream| match connections.get(upstream) {
+                        Some(counter) => counter.load(Ordering::SeqCst),
+                        None => 0,
+                    })
+                    .unwrap_or_else(|| upstreams.primary())
+            }
+        };
+        let counter = Arc::clone(
+            route_upstreams
+                .connections
+                .entry(upstream.clone())
+                .or_default(),
+        );
+        counter.fetch_add(1, Ordering::SeqCst);
+        (upstream.clone(), UpstreamConnection { counter })
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstreams() -> Upstreams {
+        toml::from_str::<toml::Value>(r#"to = ["http://a.com", "http://b.com", "http://c.com"]"#)
+            .unwrap()["to"]
+            .clone()
+            .try_into()
+            .unwrap()
+    }
+
+    fn hosts(picks: &[(Uri, UpstreamConnection)]) -> Vec<&str> {
+        picks
+            .iter()
+            .map(|(uri, _)| uri.host().unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn round_robin() {
+        let balancer = LoadBalancer::default();
+        let upstreams = upstreams();
+        let picks = (0..4)
+            .map(|_| balancer.pick("r", &upstreams, LoadBalancingStrategy::RoundRobin, |_| true))
+            .collect::<Vec<_>>();
+        assert_eq!(hosts(&picks), ["a.com", "b.com", "c.com", "a.com"]);
+
+        // `b.com` is unhealthy.
+        let is_available = |upstream: &Uri| upstream.host() != Some("b.com");
+        let picks = (0..2)
+            .map(|_| {
+                balancer.pick(
+                    "r",
+                    &upstreams,
+                    LoadBalancingStrategy::RoundRobin,
+                    is_available,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(hosts(&picks), ["a.com", "c.com"]);
+    }
+
+    #[test]
+    fn least_connections() {
+        let balancer = LoadBalancer::default();
+        let upstreams = upstreams();
+        let pick = || {
+            balancer.pick(
+                "r",
+                &upstreams,
+                LoadBalancingStrategy::LeastConnections,
+                |_| true,
+            )
+        };
+
+        let mut picks = vec![pick(), pick()];
+        assert_eq!(hosts(&picks), ["a.com", "b.com"]);
+        // The request sent to `a.com` has been finished.
+        picks.remove(0);
+        picks.push(pick());
+        picks.push(pick());
+        assert_eq!(hosts(&picks), ["b.com", "a.com", "c.com"]);
+    }
+}
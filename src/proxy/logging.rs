@@ -0,0 +1,23 @@
+use tracing::Level;
+
+use super::{LogFormat, LogLevel, ProxyConfig};
+
+/// Set the global `tracing` subscriber according to `ProxyConfig::log_level`
+/// and `ProxyConfig::log_format`.
+///
+/// Only the first call sets the subscriber - e.g. when more proxies run in one process (tests).
+pub fn init(proxy_config: &ProxyConfig) {
+    let level = match proxy_config.log_level {
+        LogLevel::Error => Level::ERROR,
+        LogLevel::Warn => Level::WARN,
+        LogLevel::Info => Level::INFO,
+        LogLevel::Debug => Level::DEBUG,
+        LogLevel::Trace => Level::TRACE,
+    };
+    let subscriber = tracing_subscriber::fmt().with_max_level(level);
+    // The subscriber has been already set.
+    let _ = match proxy_config.log_format {
+        LogFormat::Text => subscriber.try_init(),
+        LogFormat::Json => subscriber.json().try_init(),
+    };
+}
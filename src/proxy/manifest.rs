@@ -0,0 +1,337 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+/// How many seconds to wait before the next attempt to fetch an unavailable manifest.
+const RETRY_INTERVAL: i64 = 60;
+/// How many seconds learned capabilities are used before the manifest is fetched again
+/// (e.g. the addon has been updated or the route points to another origin).
+const CAPABILITIES_TTL: i64 = 3600;
+
+// ------ AddonProtocol ------
+
+/// Addon protocol version detected from the manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddonProtocol {
+    /// The current protocol with `resources` in the manifest.
+    V3,
+    /// The legacy protocol with `methods` in the manifest.
+    /// Its capabilities aren't checked.
+    Legacy,
+}
+
+// ------ AddonCapabilities ------
+
+/// Resources and types supported by the addon according to its manifest.
+#[derive(Debug, Clone, Serialize)]
+pub struct AddonCapabilities {
+    pub protocol: AddonProtocol,
+    pub types: Vec<String>,
+    pub id_prefixes: Option<Vec<String>>,
+    pub resources: Vec<ResourceCapability>,
+    pub catalogs: Vec<CatalogCapability>,
+    /// Catalogs of addons (manifest `addonCatalogs`) for the resource `addon_catalog`.
+    pub addon_catalogs: Vec<CatalogCapability>,
+    /// The addon serves the configuration page `/configure`.
+    pub configurable: bool,
+    /// URLs of the addon logo and background.
+    pub assets: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResourceCapability {
+    pub name: String,
+    /// Resource-specific types (manifest `types` are used when it's `None`).
+    pub types: Option<Vec<String>>,
+    /// Resource-specific id prefixes (manifest `idPrefixes` are used when it's `None`).
+    #[serde(rename = "idPrefixes")]
+    pub id_prefixes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogCapability {
+    #[serde(rename = "type")]
+    pub type_name: String,
+    pub id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawManifest {
+    resources: Option<Vec<RawResource>>,
+    #[serde(default)]
+    types: Vec<String>,
+    id_prefixes: Option<Vec<String>>,
+    #[serde(default)]
+    catalogs: Vec<CatalogCapability>,
+    #[serde(default)]
+    addon_catalogs: Vec<CatalogCapability>,
+    logo: Option<String>,
+    background: Option<String>,
+    #[serde(default)]
+    behavior_hints: RawBehaviorHints,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawBehaviorHints {
+    #[serde(default)]
+    configurable: bool,
+    #[serde(default)]
+    configuration_required: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawResource {
+    Short(String),
+    Full(ResourceCapability),
+}
+
+impl AddonCapabilities {
+    /// Parse the manifest JSON.
+    ///
+    /// # Errors
+    ///
+    /// Returns error when the manifest isn't a valid JSON object.
+    pub fn from_manifest(manifest: &[u8]) -> Result<Self, String> {
+        let manifest =
+            serde_json::from_slice::<RawManifest>(manifest).map_err(|err| err.to_string())?;
+        let (protocol, resources) = match manifest.resources {
+            Some(resources) => (AddonProtocol::V3, resources),
+            None => (AddonProtocol::Legacy, Vec::new()),
+        };
+        let resources = resources
+            .into_iter()
+            .map(|resource| match resource {
+                RawResource::Short(name) => ResourceCapability {
+                    name,
+                    types: None,
+                    id_prefixes: None,
+                },
+                RawResource::Full(resource) => resource,
+            })
+            .collect();
+        Ok(Self {
+            protocol,
+            types: manifest.types,
+            id_prefixes: manifest.id_prefixes,
+            resources,
+            catalogs: manifest.catalogs,
+            addon_catalogs: manifest.addon_catalogs,
+            configurable: manifest.behavior_hints.configurable
+                || manifest.behavior_hints.configuration_required,
+            assets: manifest
+                .logo
+                .into_iter()
+                .chain(manifest.background)
+                .collect(),
+        })
+    }
+
+    /// Does the addon advertise the resource?
+    ///
+    /// _Note:_ `id` is a catalog id for resources `catalog` and `addon_catalog`.
+    pub fn supports(&self, resource: &str, type_name: &str, id: &str) -> bool {
+        if self.protocol == AddonProtocol::Legacy {
+            return true;
+        }
+        let capability = match self.resources.iter().find(|cap| cap.name == resource) {
+            Some(capability) => capability,
+            None => return false,
+        };
+        let catalogs = match resource {
+            "catalog" => Some(&self.catalogs),
+            "addon_catalog" => Some(&self.addon_catalogs),
+            _ => None,
+        };
+        if let Some(catalogs) = catalogs {
+            return catalogs.is_empty()
+                || catalogs
+                    .iter()
+                    .any(|catalog| catalog.type_name == type_name && catalog.id == id);
+        }
+
+        let types = capability.types.as_ref().unwrap_or(&self.types);
+        if !types
+            .iter()
+            .any(|supported_type| supported_type == type_name)
+        {
+            return false;
+        }
+        match capability
+            .id_prefixes
+            .as_ref()
+            .or(self.id_prefixes.as_ref())
+        {
+            Some(id_prefixes) => id_prefixes
+                .iter()
+                .any(|prefix| id.starts_with(prefix.as_str())),
+            None => true,
+        }
+    }
+}
+
+// ------ ManifestRegistry ------
+
+/// Capabilities learned from origin manifests.
+#[derive(Default)]
+pub struct ManifestRegistry {
+    // Keys are origin URIs.
+    origins: RwLock<HashMap<String, ManifestEntry>>,
+}
+
+enum ManifestEntry {
+    // The capabilities are kept until the refetched manifest is learned.
+    Known {
+        capabilities: Arc<AddonCapabilities>,
+        fetched_at: i64,
+    },
+    // The manifest is being fetched or fetching has failed.
+    Unavailable {
+        since: i64,
+    },
+}
+
+impl ManifestRegistry {
+    /// Get the learned capabilities.
+    pub fn capabilities(&self, origin: &str) -> Option<Arc<AddonCapabilities>> {
+        match self.origins.read().expect("read manifests").get(origin) {
+            Some(ManifestEntry::Known { capabilities, .. }) => Some(Arc::clone(capabilities)),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` when the caller should fetch the manifest.
+    ///
+    /// The origin is marked as unavailable until `learn` is called
+    /// so the manifest is fetched only once.
+    /// Known capabilities are refetched after `CAPABILITIES_TTL`.
+    pub fn begin_fetch(&self, origin: &str, now: i64) -> bool {
+        let mut origins = self.origins.write().expect("write manifests");
+        match origins.get_mut(origin) {
+            Some(ManifestEntry::Known { fetched_at, .. }) => {
+                if now - *fetched_at < CAPABILITIES_TTL {
+                    return false;
+                }
+                // Other requests keep using the capabilities until the manifest is learned.
+                *fetched_at = now;
+                true
+            }
+            Some(ManifestEntry::Unavailable { since }) if now - *since < RETRY_INTERVAL => false,
+            _ => {
+                origins.insert(origin.to_owned(), ManifestEntry::Unavailable { since: now });
+                true
+            }
+        }
+    }
+
+    /// Save capabilities parsed from the manifest fetched at `now`.
+    pub fn learn(&self, origin: &str, capabilities: AddonCapabilities, now: i64) {
+        self.origins.write().expect("write manifests").insert(
+            origin.to_owned(),
+            ManifestEntry::Known {
+                capabilities: Arc::new(capabilities),
+                fetched_at: now,
+            },
+        );
+    }
+
+    /// All learned capabilities (`None` for unavailable manifests).
+    pub fn snapshot(&self) -> BTreeMap<String, Option<AddonCapabilities>> {
+        self.origins
+            .read()
+            .expect("read manifests")
+            .iter()
+            .map(|(origin, entry)| {
+                let capabilities = match entry {
+                    ManifestEntry::Known { capabilities, .. } => {
+                        Some(AddonCapabilities::clone(capabilities))
+                    }
+                    ManifestEntry::Unavailable { .. } => None,
+                };
+                (origin.clone(), capabilities)
+            })
+            .collect()
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"{
+        "id": "org.stremio.example",
+        "version": "1.0.0",
+        "name": "Example",
+        "types": ["movie", "series"],
+        "idPrefixes": ["tt"],
+        "resources": [
+            "catalog",
+            "addon_catalog",
+            "stream",
+            { "name": "meta", "types": ["movie"], "idPrefixes": ["ex"] }
+        ],
+        "catalogs": [{ "type": "movie", "id": "top" }],
+        "addonCatalogs": [{ "type": "all", "id": "community" }]
+    }"#;
+
+    #[test]
+    fn supports_resources() {
+        let capabilities = AddonCapabilities::from_manifest(MANIFEST.as_bytes()).unwrap();
+        assert_eq!(capabilities.protocol, AddonProtocol::V3);
+
+        assert!(capabilities.supports("stream", "series", "tt123"));
+        assert!(!capabilities.supports("stream", "channel", "tt123"));
+        assert!(!capabilities.supports("stream", "movie", "yt123"));
+        assert!(!capabilities.supports("subtitles", "movie", "tt123"));
+
+        assert!(capabilities.supports("meta", "movie", "ex1"));
+        assert!(!capabilities.supports("meta", "series", "ex1"));
+        assert!(!capabilities.supports("meta", "movie", "tt123"));
+
+        assert!(capabilities.supports("catalog", "movie", "top"));
+        assert!(!capabilities.supports("catalog", "movie", "popular"));
+        assert!(!capabilities.supports("catalog", "all", "community"));
+
+        assert!(capabilities.supports("addon_catalog", "all", "community"));
+        assert!(!capabilities.supports("addon_catalog", "movie", "top"));
+
+        assert!(!capabilities.configurable);
+        assert!(capabilities.assets.is_empty());
+    }
+
+    #[test]
+    fn legacy_protocol() {
+        let manifest = r#"{ "id": "org.legacy", "methods": ["stream.find"] }"#;
+        let capabilities = AddonCapabilities::from_manifest(manifest.as_bytes()).unwrap();
+        assert_eq!(capabilities.protocol, AddonProtocol::Legacy);
+        assert!(capabilities.supports("stream", "movie", "tt123"));
+    }
+
+    #[test]
+    fn registry_fetches_once() {
+        let registry = ManifestRegistry::default();
+        let origin = "http://localhost:8080/";
+
+        assert!(registry.begin_fetch(origin, 0));
+        assert!(!registry.begin_fetch(origin, 10));
+        // Retry an unavailable manifest.
+        assert!(registry.begin_fetch(origin, RETRY_INTERVAL + 1));
+
+        let capabilities = AddonCapabilities::from_manifest(MANIFEST.as_bytes()).unwrap();
+        registry.learn(origin, capabilities, 100);
+        assert!(!registry.begin_fetch(origin, 1000));
+        assert!(registry.capabilities(origin).is_some());
+        assert_eq!(registry.snapshot().len(), 1);
+
+        // Refetch expired capabilities, they are used until the new ones are learned.
+        assert!(registry.begin_fetch(origin, 100 + CAPABILITIES_TTL));
+        assert!(!registry.begin_fetch(origin, 100 + CAPABILITIES_TTL + 1));
+        assert!(registry.capabilities(origin).is_some());
+    }
+}
@@ -0,0 +1,601 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures_util::future;
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use serde_json::{json, Value};
+use tokio::net::{self, UdpSocket};
+use tokio::sync::watch;
+use tokio::time;
+use tracing::{error, warn};
+
+use crate::helpers;
+
+use super::{
+    LatencySloAlertsConfig, MetricsConfig, MetricsPushProtocol, ProxyConfig, ProxyState,
+    SloEvaluation, StatsSnapshot,
+};
+
+type PushClient = Client<HttpsConnector<HttpConnector>>;
+
+/// Counters at the given moment.
+struct Sample {
+    stats: StatsSnapshot,
+    /// See `FairQueue::queued`.
+    queued: usize,
+    /// See `Stats::country_requests`.
+    countries: BTreeMap<String, u64>,
+    /// See `Stats::error_responses`.
+    errors: BTreeMap<&'static str, u64>,
+    /// See `LatencySlos::evaluate` - it's empty in the start sample.
+    slo_evaluations: BTreeMap<String, SloEvaluation>,
+}
+
+impl Sample {
+    fn take(state: &ProxyState) -> Self {
+        Self {
+            stats: state.stats.snapshot(),
+            queued: state.fair_queue.queued(),
+            countries: state.stats.country_requests(),
+            errors: state.stats.error_responses(),
+            slo_evaluations: BTreeMap::new(),
+        }
+    }
+
+    /// Requests from the country in `self` minus the ones in `previous`.
+    fn country_delta(&self, country: &str, previous: &Self) -> u64 {
+        let previous = previous.countries.get(country).copied().unwrap_or_default();
+        self.countries[country].saturating_sub(previous)
+    }
+
+    /// Error responses of the kind in `self` minus the ones in `previous`.
+    fn error_delta(&self, kind: &str, previous: &Self) -> u64 {
+        let previous = previous.errors.get(kind).copied().unwrap_or_default();
+        self.errors[kind].saturating_sub(previous)
+    }
+}
+
+// ------ push_metrics ------
+
+/// Push counters according to the current `ProxyConfig::metrics` until the config sender is dropped.
+///
+/// Counters restored from the database aren't pushed -
+/// StatsD gets deltas and OTLP gets cumulative values since the proxy start.
+///
+/// Latency SLOs are evaluated before every push (see `ProxyConfig::latency_slo_alerts`).
+pub async fn push_metrics(
+    mut config_receiver: watch::Receiver<Arc<ProxyConfig>>,
+    state: Arc<ProxyState>,
+) {
+    let start = Sample::take(&state);
+    let start_time = unix_nanos();
+    let mut previous = Sample::take(&state);
+    let client = Client::builder().build(HttpsConnector::new());
+    let mut metrics_config: Option<MetricsConfig> = None;
+    let mut slo_alerts_config: Option<LatencySloAlertsConfig> = None;
+
+    loop {
+        let push_interval = metrics_config.as_ref().map(|metrics_config| {
+            Duration::from_secs(u64::from(metrics_config.push_interval.max(1)))
+        });
+        let push_time = async {
+            match push_interval {
+                Some(push_interval) => time::delay_for(push_interval).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            proxy_config = config_receiver.recv() => {
+                match proxy_config {
+                    Some(proxy_config) => {
+                        metrics_config = proxy_config.metrics.clone();
+                        slo_alerts_config = proxy_config.latency_slo_alerts.clone();
+                    }
+                    None => return,
+                }
+                continue;
+            }
+            _ = push_time => ()
+        }
+        let (metrics_config, push_interval) = match (&metrics_config, push_interval) {
+            (Some(metrics_config), Some(push_interval)) => (metrics_config, push_interval),
+            _ => continue,
+        };
+
+        let mut current = Sample::take(&state);
+        current.slo_evaluations =
+            evaluate_latency_slos(&state, slo_alerts_config.as_ref(), &client);
+        let push = async {
+            match metrics_config.push_protocol {
+                MetricsPushProtocol::Statsd => {
+                    let payload = statsd_payload(&metrics_config.prefix, &previous, &current);
+                    push_statsd(&metrics_config.push_endpoint, &payload).await
+                }
+                MetricsPushProtocol::Otlp => {
+                    let payload = otlp_payload(
+                        &metrics_config.prefix,
+                        (&start, start_time),
+                        (&current, unix_nanos()),
+                    );
+                    push_otlp(&client, &metrics_config.push_endpoint, &payload).await
+                }
+            }
+        };
+        let result = time::timeout(push_interval, push)
+            .await
+            .unwrap_or_else(|_| Err("timeout".to_owned()));
+        if let Err(error) = result {
+            error!(
+                "cannot push metrics to '{}': {}",
+                metrics_config.push_endpoint, error
+            );
+        }
+        previous = current;
+    }
+}
+
+/// Log new violations of `ProxyRoute::latency_slo` and send them to the webhook.
+fn evaluate_latency_slos(
+    state: &ProxyState,
+    slo_alerts_config: Option<&LatencySloAlertsConfig>,
+    client: &PushClient,
+) -> BTreeMap<String, SloEvaluation> {
+    let window = slo_alerts_config.map_or_else(LatencySloAlertsConfig::default_window, |config| {
+        config.window
+    });
+    let evaluations = state
+        .latency_slos
+        .evaluate(Duration::from_secs(u64::from(window)), Instant::now());
+
+    for (route, evaluation) in &evaluations {
+        if !evaluation.newly_violated {
+            continue;
+        }
+        warn!(
+            "Route '{}' violates its latency SLO (p99: {} ms, objective: {} ms)",
+            route, evaluation.p99_ms, evaluation.slo.p99_ms
+        );
+        let webhook_url = match slo_alerts_config.and_then(|config| config.webhook_url.as_ref()) {
+            Some(webhook_url) => webhook_url.clone(),
+            None => continue,
+        };
+        let alert = json!({
+            "event": "latency_slo_violated",
+            "route": route,
+            "p99_ms": evaluation.p99_ms,
+            "slo_p99_ms": evaluation.slo.p99_ms,
+            "window": window,
+        });
+        let client = client.clone();
+        helpers::spawn_with_now_getter(helpers::scoped_now_getter(), async move {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(webhook_url)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(alert.to_string()))
+                .expect("latency SLO alert request");
+            match client.request(request).await {
+                Ok(response) if response.status().is_success() => (),
+                Ok(response) => error!("Latency SLO webhook failed: {}", response.status()),
+                Err(error) => error!("Latency SLO webhook failed: {}", error),
+            }
+        });
+    }
+    evaluations
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+// ------ StatsD ------
+
+/// StatsD lines - counter deltas since `previous` and `in_flight` and `queued_requests` gauges.
+///
+/// Requests per country are sent as `<prefix>.requests_by_country.<country>`.
+/// Error responses are sent as `<prefix>.errors.<kind>` (see `ProxyErrorKind::as_str`).
+/// Latency SLO gauges are sent as `<prefix>.latency_p99_ms.<route>`
+/// and `<prefix>.latency_slo_violation.<route>` (`1` when violated),
+/// non-alphanumeric characters in route names are replaced with `_`.
+fn statsd_payload(prefix: &str, previous: &Sample, current: &Sample) -> String {
+    let mut lines = current
+        .stats
+        .counters()
+        .iter()
+        .zip(previous.stats.counters().iter())
+        .map(|((name, value), (_, previous_value))| {
+            format!(
+                "{}.{}:{}|c",
+                prefix,
+                name,
+                value.saturating_sub(*previous_value)
+            )
+        })
+        .collect::<Vec<_>>();
+    lines.push(format!(
+        "{}.in_flight:{}|g",
+        prefix, current.stats.in_flight
+    ));
+    lines.push(format!("{}.queued_requests:{}|g", prefix, current.queued));
+    for country in current.countries.keys() {
+        lines.push(format!(
+            "{}.requests_by_country.{}:{}|c",
+            prefix,
+            country,
+            current.country_delta(country, previous)
+        ));
+    }
+    for kind in current.errors.keys() {
+        lines.push(format!(
+            "{}.errors.{}:{}|c",
+            prefix,
+            kind,
+            current.error_delta(kind, previous)
+        ));
+    }
+    for (route, evaluation) in &current.slo_evaluations {
+        let route = route
+            .chars()
+            .map(|character| {
+                if character.is_ascii_alphanumeric() {
+                    character
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        lines.push(format!(
+            "{}.latency_p99_ms.{}:{}|g",
+            prefix, route, evaluation.p99_ms
+        ));
+        lines.push(format!(
+            "{}.latency_slo_violation.{}:{}|g",
+            prefix,
+            route,
+            u8::from(evaluation.violated)
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Send the payload in one UDP datagram.
+async fn push_statsd(endpoint: &str, payload: &str) -> Result<(), String> {
+    let address = net::lookup_host(endpoint)
+        .await
+        .map_err(|error| error.to_string())?
+        .next()
+        .ok_or_else(|| "no address".to_owned())?;
+    let local_address: SocketAddr = if address.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0; 16], 0).into()
+    };
+    let mut socket = UdpSocket::bind(local_address)
+        .await
+        .map_err(|error| error.to_string())?;
+    socket
+        .send_to(payload.as_bytes(), &address)
+        .await
+        .map(|_| ())
+        .map_err(|error| error.to_string())
+}
+
+// ------ OTLP ------
+
+/// JSON-encoded OTLP `ExportMetricsServiceRequest` - cumulative sums since `start`
+/// and `in_flight` and `queued_requests` gauges.
+///
+/// Requests per country are data points of the sum `<prefix>.requests_by_country`
+/// with the attribute `country`, error responses are data points of the sum `<prefix>.errors`
+/// with the attribute `kind`. Latency SLOs are data points of the gauges
+/// `<prefix>.latency_p99_ms` and `<prefix>.latency_slo_violation` with the attribute `route`.
+///
+/// _Note:_ 64-bit integers are encoded as strings according to the Protobuf JSON mapping.
+fn otlp_payload(
+    prefix: &str,
+    (start, start_time): (&Sample, u128),
+    (current, time): (&Sample, u128),
+) -> Value {
+    let mut metrics = current
+        .stats
+        .counters()
+        .iter()
+        .zip(start.stats.counters().iter())
+        .map(|((name, value), (_, start_value))| {
+            json!({
+                "name": format!("{}.{}", prefix, name),
+                "sum": {
+                    "dataPoints": [{
+                        "asInt": value.saturating_sub(*start_value).to_string(),
+                        "startTimeUnixNano": start_time.to_string(),
+                        "timeUnixNano": time.to_string(),
+                    }],
+                    // CUMULATIVE
+                    "aggregationTemporality": 2,
+                    "isMonotonic": true,
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    metrics.push(json!({
+        "name": format!("{}.in_flight", prefix),
+        "gauge": {
+            "dataPoints": [{
+                "asInt": current.stats.in_flight.to_string(),
+                "timeUnixNano": time.to_string(),
+            }]
+        }
+    }));
+    metrics.push(json!({
+        "name": format!("{}.queued_requests", prefix),
+        "gauge": {
+            "dataPoints": [{
+                "asInt": current.queued.to_string(),
+                "timeUnixNano": time.to_string(),
+            }]
+        }
+    }));
+    if !current.countries.is_empty() {
+        let data_points = current
+            .countries
+            .keys()
+            .map(|country| {
+                json!({
+                    "attributes": [{ "key": "country", "value": { "stringValue": country } }],
+                    "asInt": current.country_delta(country, start).to_string(),
+                    "startTimeUnixNano": start_time.to_string(),
+                    "timeUnixNano": time.to_string(),
+                })
+            })
+            .collect::<Vec<_>>();
+        metrics.push(json!({
+            "name": format!("{}.requests_by_country", prefix),
+            "sum": {
+                "dataPoints": data_points,
+                // CUMULATIVE
+                "aggregationTemporality": 2,
+                "isMonotonic": true,
+            }
+        }));
+    }
+    if !current.errors.is_empty() {
+        let data_points = current
+            .errors
+            .keys()
+            .map(|kind| {
+                json!({
+                    "attributes": [{ "key": "kind", "value": { "stringValue": kind } }],
+                    "asInt": current.error_delta(kind, start).to_string(),
+                    "startTimeUnixNano": start_time.to_string(),
+                    "timeUnixNano": time.to_string(),
+                })
+            })
+            .collect::<Vec<_>>();
+        metrics.push(json!({
+            "name": format!("{}.errors", prefix),
+            "sum": {
+                "dataPoints": data_points,
+                // CUMULATIVE
+                "aggregationTemporality": 2,
+                "isMonotonic": true,
+            }
+        }));
+    }
+    if !current.slo_evaluations.is_empty() {
+        let gauge = |name: &str, value: &dyn Fn(&SloEvaluation) -> u64| {
+            let data_points = current
+                .slo_evaluations
+                .iter()
+                .map(|(route, evaluation)| {
+                    json!({
+                        "attributes": [{ "key": "route", "value": { "stringValue": route } }],
+                        "asInt": value(evaluation).to_string(),
+                        "timeUnixNano": time.to_string(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({
+                "name": format!("{}.{}", prefix, name),
+                "gauge": { "dataPoints": data_points }
+            })
+        };
+        metrics.push(gauge("latency_p99_ms", &|evaluation| evaluation.p99_ms));
+        metrics.push(gauge("latency_slo_violation", &|evaluation| {
+            u64::from(evaluation.violated)
+        }));
+    }
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": prefix } }]
+            },
+            "scopeMetrics": [{
+                "scope": { "name": "addon_proxy" },
+                "metrics": metrics,
+            }]
+        }]
+    })
+}
+
+async fn push_otlp(client: &PushClient, endpoint: &str, payload: &Value) -> Result<(), String> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(endpoint)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(payload.to_string()))
+        .map_err(|error| error.to_string())?;
+    let response = client
+        .request(request)
+        .await
+        .map_err(|error| error.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("collector responded with {}", response.status()))
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::LatencySlo;
+    use crate::testing::MockOrigin;
+
+    fn snapshot(requests: u64, cache_hits: u64, in_flight: u64) -> Sample {
+        Sample {
+            stats: StatsSnapshot {
+                requests,
+                cache_hits,
+                in_flight,
+                ..StatsSnapshot::default()
+            },
+            queued: 0,
+            countries: BTreeMap::new(),
+            errors: BTreeMap::new(),
+            slo_evaluations: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn statsd_deltas() {
+        let payload = statsd_payload("proxy", &snapshot(10, 4, 0), &snapshot(15, 4, 2));
+        assert_eq!(
+            payload,
+            "proxy.requests:5|c\nproxy.cache_hits:0|c\nproxy.origin_requests:0|c\nproxy.origin_failures:0|c\nproxy.panics:0|c\nproxy.in_flight:2|g\nproxy.queued_requests:0|g"
+        );
+    }
+
+    #[test]
+    fn otlp_cumulative_since_start() {
+        let payload = otlp_payload("proxy", (&snapshot(10, 4, 0), 1), (&snapshot(15, 6, 2), 2));
+        let metrics = &payload["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["name"], "proxy.requests");
+        assert_eq!(metrics[0]["sum"]["dataPoints"][0]["asInt"], "5");
+        assert_eq!(metrics[1]["sum"]["dataPoints"][0]["asInt"], "2");
+        assert_eq!(metrics[5]["name"], "proxy.in_flight");
+        assert_eq!(metrics[5]["gauge"]["dataPoints"][0]["asInt"], "2");
+    }
+
+    #[test]
+    fn requests_by_country() {
+        let mut previous = snapshot(0, 0, 0);
+        previous.countries.insert("CZ".to_owned(), 3);
+        let mut current = snapshot(0, 0, 0);
+        current.countries.insert("CZ".to_owned(), 5);
+        current.countries.insert("US".to_owned(), 1);
+
+        let payload = statsd_payload("proxy", &previous, &current);
+        assert!(
+            payload.ends_with("proxy.requests_by_country.CZ:2|c\nproxy.requests_by_country.US:1|c")
+        );
+
+        let payload = otlp_payload("proxy", (&previous, 1), (&current, 2));
+        let metric = &payload["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][7];
+        assert_eq!(metric["name"], "proxy.requests_by_country");
+        let data_point = &metric["sum"]["dataPoints"][1];
+        assert_eq!(data_point["attributes"][0]["value"]["stringValue"], "US");
+        assert_eq!(data_point["asInt"], "1");
+    }
+
+    #[test]
+    fn errors_by_kind() {
+        let mut previous = snapshot(0, 0, 0);
+        previous.errors.insert("upstream_timeout", 2);
+        let mut current = snapshot(0, 0, 0);
+        current.errors.insert("upstream_timeout", 5);
+
+        let payload = statsd_payload("proxy", &previous, &current);
+        assert!(payload.ends_with("proxy.errors.upstream_timeout:3|c"));
+
+        let payload = otlp_payload("proxy", (&previous, 1), (&current, 2));
+        let metric = &payload["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][7];
+        assert_eq!(metric["name"], "proxy.errors");
+        let data_point = &metric["sum"]["dataPoints"][0];
+        assert_eq!(
+            data_point["attributes"][0]["value"]["stringValue"],
+            "upstream_timeout"
+        );
+        assert_eq!(data_point["asInt"], "3");
+    }
+
+    #[test]
+    fn latency_slo_gauges() {
+        let mut current = snapshot(0, 0, 0);
+        current.slo_evaluations.insert(
+            "127.0.0.1:5000/addon".to_owned(),
+            SloEvaluation {
+                p99_ms: 512,
+                slo: LatencySlo { p99_ms: 300 },
+                violated: true,
+                newly_violated: true,
+            },
+        );
+
+        let payload = statsd_payload("proxy", &snapshot(0, 0, 0), &current);
+        assert!(payload.ends_with(
+            "proxy.latency_p99_ms.127_0_0_1_5000_addon:512|g\nproxy.latency_slo_violation.127_0_0_1_5000_addon:1|g"
+        ));
+
+        let payload = otlp_payload("proxy", (&snapshot(0, 0, 0), 1), (&current, 2));
+        let metrics = &payload["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[7]["name"], "proxy.latency_p99_ms");
+        assert_eq!(metrics[8]["name"], "proxy.latency_slo_violation");
+        let data_point = &metrics[8]["gauge"]["dataPoints"][0];
+        assert_eq!(
+            data_point["attributes"][0]["value"]["stringValue"],
+            "127.0.0.1:5000/addon"
+        );
+        assert_eq!(data_point["asInt"], "1");
+    }
+
+    #[tokio::test]
+    async fn push_to_statsd() {
+        let collector = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let endpoint = collector.local_addr().unwrap().to_string();
+
+        push_statsd(&endpoint, "proxy.requests:1|c").await.unwrap();
+
+        let mut buffer = [0; 64];
+        let length = collector.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"proxy.requests:1|c");
+    }
+
+    #[tokio::test]
+    async fn push_to_otlp_collector() {
+        let collector = MockOrigin::start(0).unwrap();
+        let resource = collector.resource("/v1/metrics");
+        resource.expect("is JSON POST", |request| {
+            request.method == Method::POST
+                && request.headers[header::CONTENT_TYPE] == "application/json"
+        });
+        let client = Client::builder().build(HttpsConnector::new());
+        let payload = otlp_payload("proxy", (&snapshot(0, 0, 0), 1), (&snapshot(1, 0, 0), 2));
+
+        push_otlp(
+            &client,
+            &format!("{}/v1/metrics", collector.url()),
+            &payload,
+        )
+        .await
+        .unwrap();
+
+        let body = &resource.requests()[0].body;
+        assert_eq!(serde_json::from_slice::<Value>(body).unwrap(), payload);
+        resource.verify();
+
+        let error = push_otlp(&client, &format!("{}/unknown", collector.url()), &payload).await;
+        assert_eq!(
+            error,
+            Err("collector responded with 404 Not Found".to_owned())
+        );
+    }
+}
@@ -1,85 +1,380 @@
-use std::collections::hash_map::DefaultHasher;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
-use std::hash::{Hash, Hasher};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use hyper::body::Bytes;
-use hyper::client::HttpConnector;
+use hyper::body::{Bytes, HttpBody};
 use hyper::{header, Body, Client, Request, Response};
 use hyper_timeout::TimeoutConnector;
 use hyper_tls::HttpsConnector;
+use tokio::time;
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
 
-use http::{HeaderMap, Method, StatusCode, Uri};
+use http::{Method, StatusCode, Uri};
 
 use cache_control::CacheControl;
 use serde::{Deserialize, Serialize};
+use stremio_core::types::addons::ResourceRef;
 
-use crate::helpers::now_timestamp;
+use crate::helpers::{self, http_date, now_timestamp};
 use crate::hyper_helpers::{
-    body_to_bytes, bytes_to_body, clone_request, fork_response, map_request_body,
+    body_to_bytes, body_to_bytes_with_limit, bytes_to_body, clone_response, fork_response,
+    is_caused_by, map_request_body, map_response_body, with_body_deadline, with_min_transfer_rate,
+    BodyDeadlineExceeded, BodyTooSlow, StreamedBody,
+};
+use crate::proxy::body_transformers::{
+    fix_json_content_type, transcode_to_utf8, transform_response, BodyTransformer, JsonFilter,
+    Replace,
+};
+use crate::proxy::cache::{
+    self, CacheKey, CacheMetadata, CacheReason, CacheValueForDeserialization,
+    CacheValueForSerialization, CachedRequest, VaryHeaders,
+};
+use crate::proxy::error_kind::{error_response, ProxyErrorKind};
+use crate::proxy::rules::{self, RuleFlags};
+#[cfg(feature = "scripting")]
+use crate::proxy::scripting;
+use crate::proxy::{
+    compression, hardening, idempotency, prefetch, signing, validations, virtual_endpoints,
+};
+use crate::proxy::{
+    AddonCapabilities, CacheEvent, CachePartitionConfig, ConfigReload, ConfigVersion, CorsConfig,
+    Db, FailoverConnector, FairQueueConfig, FairQueuePermit, HeaderLimitPolicy, IdempotencyConfig,
+    IndexedRoutes, LoadBalancingStrategy, PreflightConfig, ProxyConfig, ProxyRoute, ProxyState,
+    QueueFull, RemoteAddr, RequestProfile, ScheduleConfigReload, SigningProfile, StageProfile,
+    Stats, StatsSnapshot, UpstreamConnection, UpstreamError, UpstreamRequest,
 };
-use crate::proxy::validations;
-use crate::proxy::{Db, ProxyConfig, ScheduleConfigReload};
 
-// ------ CacheKey ------
+// ------ RouteMatch ------
 
-#[derive(Hash)]
-/// Key for Sled DB.
-struct CacheKey<'a> {
-    method: &'a Method,
-    uri: &'a Uri,
-    body: &'a Bytes,
+/// The route matched by `handle_routes`. It's stored in the request extensions,
+/// so later middlewares don't have to match the route or parse the path again.
+#[derive(Clone)]
+struct RouteMatch {
+    // Shared with `ProxyConfig` or `CollectionRoutes`.
+    route: Arc<ProxyRoute>,
+    origin: Uri,
+    // The path and query relative to the origin (e.g. "/catalog/movie/top.json").
+    path_and_query: String,
+    // Stremio resource name (e.g. "stream") or `None` for non-resource paths.
+    resource: Option<String>,
+    negotiate_manifest: bool,
+    validate: bool,
+    verbose: bool,
+    head_revalidation: bool,
+    cache_private: bool,
+    // See `ProxyRoute::fix_content_type` - it's enabled and the path ends with `.json`.
+    fix_content_type: bool,
+    transcode_to_utf8: bool,
+    answer_preflight: bool,
+    signing: Option<SigningProfile>,
 }
 
-impl<'a> CacheKey<'a> {
-    /// Convert to Sled DB compatible keys.
-    ///
-    /// _Notes:_
-    ///   - Sled DB supports only `AsRef<u8>` as the keys and values.
-    ///   - Big-endian is recommended by Sled DB docs.
-    fn to_db_key(&self) -> [u8; 8] {
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        hasher.finish().to_be_bytes()
+impl RouteMatch {
+    fn new(
+        route: Arc<ProxyRoute>,
+        origin: Uri,
+        path_and_query: String,
+        proxy_config: &ProxyConfig,
+    ) -> Self {
+        let path = path_and_query.split('?').next().unwrap_or_default();
+        let resource = ResourceRef::from_str(path)
+            .ok()
+            .map(|resource| resource.resource);
+        let signing = route
+            .signing_profile
+            .as_ref()
+            .and_then(|signing_profile| proxy_config.signing_profiles.get(signing_profile))
+            .cloned();
+        Self {
+            origin,
+            resource,
+            // See `ProxyConfig::library_mode`.
+            negotiate_manifest: route.negotiate_manifest == Some(true)
+                && proxy_config.stremio_validation_enabled(),
+            validate: route.validate != Some(false) && proxy_config.stremio_validation_enabled(),
+            verbose: route.verbose == Some(true),
+            head_revalidation: route.head_revalidation == Some(true),
+            cache_private: route.cache_private == Some(true),
+            fix_content_type: route.fix_content_type == Some(true) && path.ends_with(".json"),
+            transcode_to_utf8: route.transcode_to_utf8 == Some(true),
+            answer_preflight: route.answer_preflight == Some(true),
+            signing,
+            path_and_query,
+            route,
+        }
+    }
+
+    /// See `ProxyRoute::json_filter` and `ProxyRoute::body_replacements`.
+    fn body_transformers(&self) -> Vec<Box<dyn BodyTransformer>> {
+        let mut transformers = Vec::<Box<dyn BodyTransformer>>::new();
+        if let Some(json_filter) = &self.route.json_filter {
+            if self.resource() == Some("catalog") {
+                transformers.push(Box::new(JsonFilter::new(&json_filter.include)));
+            }
+        }
+        for (pattern, replacement) in &self.route.body_replacements {
+            transformers.push(Box::new(Replace::new(
+                pattern.as_str(),
+                replacement.as_str(),
+            )));
+        }
+        transformers
+    }
+
+    /// Stremio resource name (e.g. "stream") or `None` for non-resource paths.
+    fn resource(&self) -> Option<&str> {
+        self.resource.as_deref()
+    }
+
+    /// Tags of the cached response - see `ProxyConfig::cache_tags`.
+    fn cache_tags(&self, proxy_config: &ProxyConfig) -> Vec<String> {
+        let templates = match &proxy_config.cache_tags {
+            Some(templates) => templates,
+            None => return Vec::new(),
+        };
+        let placeholders = [
+            ("{route}", Some(self.route.from.as_str())),
+            ("{group}", self.route.group.as_deref()),
+            ("{resource}", self.resource()),
+        ];
+        let mut tags = Vec::new();
+        'templates: for template in templates {
+            let mut tag = template.clone();
+            for (placeholder, value) in &placeholders {
+                if tag.contains(placeholder) {
+                    match value {
+                        Some(value) => tag = tag.replace(placeholder, value),
+                        None => continue 'templates,
+                    }
+                }
+            }
+            if !tag.is_empty() && !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        tags
     }
 }
 
-// ------ CacheValue ------
+// ------ Logging ------
 
-/// Value for Sled DB.
-#[derive(Deserialize)]
-struct CacheValueForDeserialization {
-    #[serde(with = "http_serde::status_code")]
-    status: StatusCode,
-    #[serde(with = "http_serde::header_map")]
-    headers: HeaderMap,
-    #[serde(with = "serde_bytes")]
-    body: Vec<u8>,
-    timestamp: i64,
-    // Cached response is valid for `validity` seconds.
-    validity: u32,
+/// Verbose logging is enabled globally or for the matched route (see `ProxyRoute::verbose`).
+fn is_verbose<B>(req: &Request<B>, proxy_config: &ProxyConfig) -> bool {
+    proxy_config.verbose
+        || matches!(req.extensions().get::<RouteMatch>(), Some(route_match) if route_match.verbose)
 }
 
-/// Value for Sled DB.
-#[derive(Serialize)]
-struct CacheValueForSerialization<'a> {
-    #[serde(with = "http_serde::status_code")]
-    status: StatusCode,
-    #[serde(with = "http_serde::header_map")]
-    headers: &'a HeaderMap,
-    #[serde(with = "serde_bytes")]
-    body: &'a [u8],
-    timestamp: i64,
-    // Cached response is valid for `validity` seconds.
-    validity: u32,
+/// Headers with values redacted according to `ProxyConfig::redact_headers`.
+struct LoggableHeaders<'a> {
+    headers: &'a header::HeaderMap,
+    proxy_config: &'a ProxyConfig,
+}
+
+impl fmt::Debug for LoggableHeaders<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(self.headers.iter().map(|(name, value)| {
+                let value = self
+                    .proxy_config
+                    .loggable_header_value(name.as_str(), value.as_bytes());
+                (name, value)
+            }))
+            .finish()
+    }
+}
+
+fn log_request<B: fmt::Debug>(label: &str, req: &Request<B>, proxy_config: &ProxyConfig) {
+    info!(
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        config_epoch = proxy_config.version.epoch,
+        headers = ?LoggableHeaders {
+            headers: req.headers(),
+            proxy_config
+        },
+        body = ?req.body(),
+        "{}",
+        label
+    );
+}
+
+fn log_response(label: &str, response: &Response<Body>, proxy_config: &ProxyConfig) {
+    info!(
+        status = %response.status(),
+        version = ?response.version(),
+        config_epoch = proxy_config.version.epoch,
+        headers = ?LoggableHeaders {
+            headers: response.headers(),
+            proxy_config
+        },
+        "{}",
+        label
+    );
+}
+
+// ------ CacheOverride ------
+
+/// The request header with `ProxyConfig::admin_token` required by admin endpoints.
+pub const ADMIN_TOKEN_HEADER: &str = "x-proxy-admin-token";
+const REFRESH_HEADER: &str = "x-proxy-refresh";
+const ONLY_CACHE_HEADER: &str = "x-proxy-only-cache";
+const CONFIG_EPOCH_HEADER: &str = "x-proxy-config-epoch";
+/// See `ProxyErrorKind`.
+const ERROR_KIND_HEADER: &str = "x-proxy-error-kind";
+
+/// The request contains the valid `ProxyConfig::admin_token` (compared in constant time).
+fn is_admin_request<B>(req: &Request<B>, proxy_config: &ProxyConfig) -> bool {
+    match (
+        &proxy_config.admin_token,
+        req.headers().get(ADMIN_TOKEN_HEADER),
+    ) {
+        (Some(expected_token), Some(admin_token)) => {
+            signing::secret_matches(expected_token.as_bytes(), admin_token.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// Response to requests for admin endpoints without the valid `ProxyConfig::admin_token`.
+fn unauthorized_response() -> Response<Body> {
+    error_response(
+        ProxyErrorKind::Denied,
+        StatusCode::UNAUTHORIZED,
+        "Missing or invalid admin token.",
+    )
+}
+
+/// Cache behavior requested by an admin. It's stored in the request extensions.
+///
+/// See `ProxyConfig::admin_token`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct CacheOverride {
+    /// Don't load the response from the cache, but cache the new one.
+    refresh: bool,
+    /// Respond with the cached response (even if it's stale) or with `GATEWAY_TIMEOUT`.
+    only_cache: bool,
+}
+
+impl CacheOverride {
+    fn of<T>(req: &Request<T>) -> Self {
+        req.extensions().get::<Self>().copied().unwrap_or_default()
+    }
+}
+
+// ------ GeoTag ------
+
+const GEOIP_COUNTRY_HEADER: &str = "x-geoip-country";
+const GEOIP_ASN_HEADER: &str = "x-geoip-asn";
+
+/// The client location. It's stored in the request extensions.
+///
+/// See `ProxyConfig::geoip`.
+#[cfg_attr(not(feature = "geoip"), allow(dead_code))]
+#[derive(Debug, Default, Clone)]
+struct GeoTag {
+    /// ISO 3166-1 alpha-2 country code.
+    country: Option<String>,
+    /// Continent code (e.g. "EU").
+    continent: Option<String>,
+    /// Autonomous system number.
+    asn: Option<u32>,
+}
+
+impl GeoTag {
+    fn of<T>(req: &Request<T>) -> Option<&Self> {
+        req.extensions().get::<Self>()
+    }
+}
+
+// ------ test_route ------
+
+/// The result of `test_route`.
+#[derive(Debug)]
+pub struct RouteTestReport {
+    /// `ProxyRoute::from` of the matched route.
+    pub route: Option<String>,
+    /// The request has been routed by `ProxyRule::route_to`.
+    pub routed_by_rule: bool,
+    /// The URI of the request sent to the origin.
+    pub upstream_uri: Option<Uri>,
+    /// `None` when the request has been validated later by the origin manifest
+    /// (see `ProxyRoute::negotiate_manifest`) or when the validation is disabled.
+    pub validation_passed: Option<bool>,
+    /// The response returned by the proxy instead of sending the request to the origin.
+    pub rejected_with: Option<StatusCode>,
+    /// Hex-encoded key of the cached response.
+    pub cache_key: Option<String>,
+}
+
+/// Pass a GET request with the given URL through rules and routes without sending it.
+///
+/// # Errors
+///
+/// Returns `String` error when the URL is invalid.
+pub fn test_route(url: &str, proxy_config: &ProxyConfig) -> Result<RouteTestReport, String> {
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|error| format!("invalid URL '{}': {}", url, error))?;
+    // Clients send only the path and query in the request line and the authority in `host`.
+    let mut req = Request::builder().uri(
+        uri.path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str()),
+    );
+    if let Some(authority) = uri.authority() {
+        req = req.header("host", authority.as_str());
+    }
+    let req = req.body(Bytes::new()).map_err(|error| error.to_string())?;
+
+    let mut report = RouteTestReport {
+        route: None,
+        routed_by_rule: false,
+        upstream_uri: None,
+        validation_passed: None,
+        rejected_with: None,
+        cache_key: None,
+    };
+    let result = handle_rules(req, proxy_config).and_then(|req| {
+        report.routed_by_rule = RuleFlags::of(&req).routed;
+        handle_routes(req, proxy_config, &IndexedRoutes::default())
+    });
+    match result {
+        Ok(req) => {
+            let req = handle_cache_key(req, proxy_config);
+            let route_match = req.extensions().get::<RouteMatch>();
+            report.route = route_match.map(|route_match| route_match.route.from.clone());
+            report.validation_passed = match route_match {
+                Some(route_match) if route_match.negotiate_manifest || !route_match.validate => {
+                    None
+                }
+                _ => Some(true),
+            };
+            report.cache_key = Some(hex(&cache_db_key(&req)));
+            report.upstream_uri = Some(req.uri().clone());
+        }
+        Err(response) => {
+            if response.status() == StatusCode::BAD_REQUEST {
+                report.validation_passed = Some(false);
+            }
+            report.rejected_with = Some(response.status());
+        }
+    }
+    Ok(report)
 }
 
 // ------ on_request ------
 
-type OnRequestClient = Arc<Client<TimeoutConnector<HttpsConnector<HttpConnector>>>>;
+type OnRequestClient = Arc<Client<TimeoutConnector<HttpsConnector<FailoverConnector>>>>;
 
 /// See documentation for struct `Proxy` fields.
 ///
+/// Events are logged in the span `request` (see `ProxyConfig::log_format`).
+///
 /// # Errors
 ///
 /// Returns error when HTTP stream handling fails.
@@ -89,424 +384,5546 @@ pub async fn on_request(
     proxy_config: Arc<ProxyConfig>,
     schedule_config_reload: ScheduleConfigReload,
     db: Db,
+    state: Arc<ProxyState>,
+) -> Result<Response<Body>, hyper::Error> {
+    let span = info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        client = RemoteAddr::of(&req).map(field::display),
+        // Recorded by `handle_routes`.
+        route = field::Empty,
+        // Recorded when the response is loaded from the cache or requested from the origin.
+        cache_hit = field::Empty,
+        // Recorded when the proxy responds with an error (see `ProxyErrorKind`).
+        error_kind = field::Empty,
+    );
+    handle_request(req, client, proxy_config, schedule_config_reload, db, state)
+        .instrument(span)
+        .await
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    client: OnRequestClient,
+    proxy_config: Arc<ProxyConfig>,
+    schedule_config_reload: ScheduleConfigReload,
+    db: Db,
+    state: Arc<ProxyState>,
 ) -> Result<Response<Body>, hyper::Error> {
     if proxy_config.verbose {
-        println!("original req: {:#?}", req);
+        log_request("original req", &req, &proxy_config);
     }
+    let deadline = request_deadline(&req, &proxy_config, Instant::now());
+    let debug_headers = is_admin_request(&req, &proxy_config);
+    // See `ProxyConfig::compression`.
+    let accept_encoding = req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let method = req.method().clone();
+    // See `ProxyConfig::profiling`.
+    let mut profile = state.profiler.start(proxy_config.profiling.as_ref());
 
     let req = map_request_body(req, body_to_bytes).await?;
+    profile.lap("request_body");
 
-    let req_or_response =
-        apply_request_middlewares(req, &proxy_config, &schedule_config_reload, &db);
+    // Set after routing (see `ProxyConfig::cors`).
+    let mut cors_headers = header::HeaderMap::new();
+    let req_or_response = apply_request_middlewares(
+        req,
+        &proxy_config,
+        &schedule_config_reload,
+        &db,
+        &state,
+        &mut profile,
+        &mut cors_headers,
+    );
+    profile.lap("middlewares");
 
-    if proxy_config.verbose {
-        println!("mapped req or response: {:#?}", req_or_response);
+    match &req_or_response {
+        Ok(req) if is_verbose(req, &proxy_config) => log_request("mapped req", req, &proxy_config),
+        Err(response) if proxy_config.verbose => {
+            log_response("middleware response", response, &proxy_config)
+        }
+        _ => (),
     }
 
-    match req_or_response {
+    let mut response = match req_or_response {
         // A middleware failed or it didn't want to send the given request -
         // just return prepared `Response`.
-        Err(response) => Ok(response),
+        Err(mut response) => {
+            schedule_revalidation(&mut response, &client, &proxy_config, &db, &state);
+            finish_config_reload(response).await
+        }
         // Send the modified request.
-        Ok(req) => send_request_and_handle_response(req, &client, &proxy_config, &db).await,
+        Ok(req) => match handle_capabilities(req, &client, &state).await {
+            Ok(req) => {
+                profile.lap("validation");
+                // See `ProxyRoute::latency_slo`.
+                let latency_slo = req
+                    .extensions()
+                    .get::<RouteMatch>()
+                    .and_then(|route_match| {
+                        let latency_slo = route_match.route.latency_slo?;
+                        Some((Arc::clone(&route_match.route), latency_slo))
+                    });
+                let start = Instant::now();
+                let response = send_request_and_handle_response(
+                    req,
+                    deadline,
+                    &client,
+                    &proxy_config,
+                    &db,
+                    &state,
+                    &mut profile,
+                )
+                .await?;
+                profile.lap("upstream");
+                if let Some((route, latency_slo)) = latency_slo {
+                    let now = Instant::now();
+                    state.latency_slos.record(
+                        &route.from,
+                        latency_slo,
+                        now.duration_since(start),
+                        now,
+                    );
+                }
+                response
+            }
+            Err(response) => response,
+        },
+    };
+    if let Some(kind) = ProxyErrorKind::of(&response) {
+        state.stats.increment_error(kind);
+        Span::current().record("error_kind", kind.as_str());
+        if response.status().is_server_error() {
+            warn!(status = response.status().as_u16(), "error response");
+        } else {
+            info!(status = response.status().as_u16(), "error response");
+        }
+        if debug_headers {
+            response.headers_mut().insert(
+                ERROR_KIND_HEADER,
+                header::HeaderValue::from_static(kind.as_str()),
+            );
+        }
+    }
+    if debug_headers {
+        response.headers_mut().insert(
+            CONFIG_EPOCH_HEADER,
+            header::HeaderValue::from(proxy_config.version.epoch),
+        );
+    }
+    apply_cors_headers(&mut response, &cors_headers);
+    if method == Method::HEAD {
+        response = without_body(response);
+    }
+    if let Some(compression_config) = &proxy_config.compression {
+        response = compression::compress_response(
+            response,
+            accept_encoding.as_deref(),
+            &method,
+            compression_config,
+        );
+    }
+    let response = match &proxy_config.response_signing {
+        Some(signing_config) => signing::sign_response(response, signing_config).await,
+        None => Ok(response),
+    };
+    profile.lap("response");
+    state.profiler.record(profile);
+    response
+}
+
+/// Remove the body of the response to a HEAD request (e.g. the body of the cached GET response).
+///
+/// `Content-Length` of the removed body is kept.
+fn without_body(response: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    if let Some(length) = HttpBody::size_hint(&body).exact() {
+        if length > 0 && !parts.headers.contains_key(header::CONTENT_LENGTH) {
+            parts
+                .headers
+                .insert(header::CONTENT_LENGTH, header::HeaderValue::from(length));
+        }
     }
+    Response::from_parts(parts, Body::empty())
 }
 
 /// Send the request to origin and handle request fails and origin response.
+///
+/// The request is sent only when there is time left before the `deadline`
+/// (see `ProxyConfig::request_deadline`).
 async fn send_request_and_handle_response(
-    req: Request<Bytes>,
+    mut req: Request<Bytes>,
+    deadline: Option<Instant>,
     client: &OnRequestClient,
-    proxy_config: &ProxyConfig,
+    proxy_config: &Arc<ProxyConfig>,
     db: &Db,
+    state: &Arc<ProxyState>,
+    profile: &mut RequestProfile,
 ) -> Result<Response<Body>, hyper::Error> {
-    let response_db_key = CacheKey {
-        method: req.method(),
-        uri: req.uri(),
-        body: req.body(),
+    // Wait for a free slot if the fair queue is enabled.
+    // The slot is released at the end of this function.
+    let _fair_queue_permit = match acquire_fair_queue_slot(&req, proxy_config, state).await {
+        Ok(permit) => permit,
+        Err(QueueOverflow) => {
+            return Ok(handle_queue_overflow(
+                cache_db_key(&req),
+                is_verbose(&req, proxy_config),
+                proxy_config,
+                db,
+            ))
+        }
+    };
+
+    // The time spent in the proxy (incl. the fair queue) is subtracted from the client's budget.
+    let remaining_time = match deadline {
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining_time) if remaining_time.as_millis() > 0 => {
+                propagate_deadline(&mut req, remaining_time, proxy_config);
+                Some(remaining_time)
+            }
+            _ => return Ok(deadline_exceeded_response()),
+        },
+        None => None,
+    };
+
+    // HEAD responses don't have bodies, so they aren't cached
+    // (cached GET responses are served to HEAD requests - see `request_cache_key`).
+    let skip_cache =
+        RuleFlags::of(&req).skip_cache || is_private_request(&req) || req.method() == Method::HEAD;
+    let verbose = is_verbose(&req, proxy_config);
+    let route_match = req.extensions().get::<RouteMatch>().cloned();
+    let is_get_request = req.method() == Method::GET;
+    // The key is used later to cache the response or to get at least the cached response
+    // when the request or response fails.
+    let response_db_key = cache_db_key(&req);
+    let request_db_key = request_db_key(&req);
+    let cached_request = request_cache_key(&req).to_cached_request();
+    let cache_metadata = CacheMetadata {
+        original_uri: OriginalUri::of(&req),
+        route: route_match
+            .as_ref()
+            .map(|route_match| route_match.route.from.clone()),
+        reason: if CacheOverride::of(&req).refresh {
+            CacheReason::Refresh
+        } else {
+            CacheReason::Request
+        },
+        tags: route_match
+            .as_ref()
+            .map(|route_match| route_match.cache_tags(proxy_config))
+            .unwrap_or_default(),
+    };
+    let journal_key = req.extensions().get::<IdempotencyJournalKey>().copied();
+
+    // The replica's request is counted until the response is received.
+    let upstream = balance_upstreams(&mut req, route_match.as_ref(), state);
+    let origin = match &upstream {
+        Some((upstream, _)) => Some(upstream),
+        None => route_match.as_ref().map(|route_match| &route_match.origin),
+    };
+    // Requests for unhealthy origins aren't sent (see `ProxyConfig::health_checks`).
+    if matches!(origin, Some(origin) if !state.upstream_health.is_healthy(origin)) {
+        if verbose {
+            info!("origin is unhealthy, the request hasn't been sent");
+        }
+        return Ok(handle_origin_fail(
+            response_db_key,
+            verbose,
+            proxy_config,
+            db,
+            ProxyErrorKind::UpstreamUnavailable,
+        ));
+    }
+
+    if let Some(response) = revalidate_with_head(
+        &req,
+        response_db_key,
+        remaining_time,
+        client,
+        proxy_config,
+        db,
+        state,
+    )
+    .await
+    {
+        return Ok(response);
     }
-    .to_db_key();
 
-    // We need to clone the request so we can use it later, when the request or response fails,
-    // so we can try to get at least cached response.
-    let req_clone = clone_request(&req);
+    // The response may vary by request headers (see `VaryHeaders`).
+    let request_headers = if proxy_config.cache_enabled && !skip_cache {
+        req.headers().clone()
+    } else {
+        header::HeaderMap::new()
+    };
 
+    let uri = req.uri().clone();
     // We need to convert `Request<Bytes>` to `Request<Body>` to send it.
-    let req = map_request_body(req, bytes_to_body).await?;
+    let mut req = map_request_body(req, bytes_to_body).await?;
+    if let Some(signing_profile) = route_match
+        .as_ref()
+        .and_then(|route_match| route_match.signing.as_ref())
+    {
+        signing::sign_request(&mut req, signing_profile);
+    }
+    let method = req.method().clone();
+    let upstream_request = UpstreamRequest {
+        route: route_match
+            .as_ref()
+            .map(|route_match| route_match.route.from.as_str()),
+        method: &method,
+        uri: &uri,
+    };
+
+    // The total time for the response incl. the body (see `ProxyConfig::response_timeout`).
+    let response_timeout = proxy_config
+        .response_timeout
+        .map(|response_timeout| Duration::from_secs(u64::from(response_timeout)));
+    let response_deadline =
+        response_timeout.map(|response_timeout| Instant::now() + response_timeout);
+    // The client's budget may run out sooner.
+    let (timeout, timeout_error) = match (remaining_time, response_timeout) {
+        (Some(remaining_time), Some(response_timeout)) if response_timeout < remaining_time => {
+            (Some(response_timeout), UpstreamError::ResponseTimeout)
+        }
+        (Some(remaining_time), _) => (Some(remaining_time), UpstreamError::DeadlineExceeded),
+        (None, response_timeout) => (response_timeout, UpstreamError::ResponseTimeout),
+    };
+    // Origin failures are cached when there isn't a usable cached response
+    // (see `ProxyConfig::negative_cache_validity`).
+    let cache_failure = |response: Response<Body>| {
+        let resource = route_match.as_ref().and_then(RouteMatch::resource);
+        if proxy_config.cache_enabled
+            && !skip_cache
+            && proxy_config.default_cache_validity_for(resource).is_some()
+        {
+            store_failure(
+                &response,
+                response_db_key,
+                &cached_request,
+                &cache_metadata,
+                verbose,
+                proxy_config,
+                db,
+            );
+        }
+        response
+    };
+    // Bodies read by the proxy (e.g. to cache them) may exceed `response_timeout`
+    // or be transferred too slowly (see `ProxyConfig::min_transfer_rate`).
+    let handle_body_timeout = |error: hyper::Error| {
+        let upstream_error = if is_caused_by::<BodyDeadlineExceeded>(&error) {
+            UpstreamError::ResponseTimeout
+        } else if is_caused_by::<BodyTooSlow>(&error) {
+            UpstreamError::TransferTooSlow
+        } else {
+            return Err(error);
+        };
+        record_origin_failure(
+            route_match.as_ref(),
+            &upstream_request,
+            &upstream_error,
+            client,
+            proxy_config,
+            state,
+        );
+        Ok(handle_origin_timeout(
+            response_db_key,
+            verbose,
+            proxy_config,
+            db,
+        ))
+    };
 
     // Send request.
-    match client.request(req).await {
-        Ok(response) => {
-            if !validations::validate_response(&response) {
-                return Ok(handle_origin_fail(&req_clone, proxy_config, db));
+    state.hooks.upstream_connect(&upstream_request);
+    Span::current().record("cache_hit", false);
+    Stats::increment(&state.stats.origin_requests);
+    let response = match timeout {
+        Some(timeout) => match time::timeout(timeout, client.request(req)).await {
+            Ok(response) => response,
+            Err(_) => {
+                record_origin_failure(
+                    route_match.as_ref(),
+                    &upstream_request,
+                    &timeout_error,
+                    client,
+                    proxy_config,
+                    state,
+                );
+                return Ok(match timeout_error {
+                    UpstreamError::ResponseTimeout => cache_failure(handle_origin_timeout(
+                        response_db_key,
+                        verbose,
+                        proxy_config,
+                        db,
+                    )),
+                    _ => deadline_exceeded_response(),
+                });
             }
-            if !proxy_config.cache_enabled {
-                if proxy_config.verbose {
-                    println!("original response: {:#?}", response);
+        },
+        None => client.request(req).await,
+    };
+    match response {
+        Ok(response) => {
+            let response = match response_deadline {
+                Some(response_deadline) => {
+                    response.map(|body| with_body_deadline(body, response_deadline))
+                }
+                None => response,
+            };
+            let response = match &proxy_config.min_transfer_rate {
+                Some(min_transfer_rate) => response.map(|body| {
+                    with_min_transfer_rate(
+                        body,
+                        min_transfer_rate.bytes_per_second,
+                        Duration::from_secs(u64::from(min_transfer_rate.grace_period)),
+                    )
+                }),
+                None => response,
+            };
+            let origin_status = response.status();
+            let response = if validations::validate_response(&response) {
+                limit_response_headers(response, &uri, proxy_config)
+            } else {
+                None
+            };
+            let response = match response {
+                Some(response) => response,
+                None => {
+                    record_origin_failure(
+                        route_match.as_ref(),
+                        &upstream_request,
+                        &UpstreamError::InvalidResponse,
+                        client,
+                        proxy_config,
+                        state,
+                    );
+                    let response = handle_origin_fail(
+                        response_db_key,
+                        verbose,
+                        proxy_config,
+                        db,
+                        ProxyErrorKind::UpstreamInvalid,
+                    );
+                    return Ok(match origin_status {
+                        StatusCode::NOT_FOUND | StatusCode::BAD_GATEWAY => cache_failure(response),
+                        _ => response,
+                    });
+                }
+            };
+            let response = match &route_match {
+                Some(route_match) => {
+                    let response = match handle_content_type(response, route_match).await {
+                        Ok(response) => response,
+                        Err(error) => return handle_body_timeout(error),
+                    };
+                    transform_response(response, route_match.body_transformers())
+                }
+                None => response,
+            };
+            let response = handle_response_script(response, proxy_config);
+            let response = match (journal_key, &proxy_config.idempotency) {
+                (Some(IdempotencyJournalKey(journal_key)), Some(idempotency_config)) => {
+                    let journal_result = journal_response(
+                        response,
+                        journal_key,
+                        &cached_request,
+                        idempotency_config,
+                        verbose,
+                        proxy_config,
+                        db,
+                    )
+                    .await;
+                    match journal_result {
+                        Ok(response) => response,
+                        Err(error) => return handle_body_timeout(error),
+                    }
+                }
+                _ => response,
+            };
+            if !proxy_config.cache_enabled
+                || skip_cache
+                || is_private_response(&response, route_match.as_ref())
+            {
+                if verbose {
+                    log_response("original response", &response, proxy_config);
                 }
                 return Ok(response);
             }
-            cache_response(response, response_db_key, proxy_config, db).await
+            let resource = route_match.as_ref().and_then(RouteMatch::resource);
+            profile.lap("upstream");
+            let cache_result = cache_response(
+                response,
+                request_db_key,
+                &cached_request,
+                &cache_metadata,
+                &request_headers,
+                resource,
+                verbose,
+                proxy_config,
+                db,
+            )
+            .await;
+            profile.lap("caching");
+            let (response, body) = match cache_result {
+                Ok(response_and_body) => response_and_body,
+                Err(error) => return handle_body_timeout(error),
+            };
+            if let Some(route_match) = route_match {
+                // Pages of user-specific catalogs can't be prefetched without user credentials.
+                if is_get_request && route_match.route.cache_partition.is_none() {
+                    schedule_catalog_prefetch(&route_match, &body, client, proxy_config, db, state);
+                }
+            }
+            Ok(response)
         }
         // Request failed - return the response without caching.
         Err(error) => {
-            eprintln!("Request error: {:#?}", error);
-            Ok(handle_origin_fail(&req_clone, proxy_config, db))
+            error!("Request error: {:#?}", error);
+            record_origin_failure(
+                route_match.as_ref(),
+                &upstream_request,
+                &UpstreamError::Request(&error),
+                client,
+                proxy_config,
+                state,
+            );
+            Ok(cache_failure(handle_origin_fail(
+                response_db_key,
+                verbose,
+                proxy_config,
+                db,
+                ProxyErrorKind::UpstreamFailed,
+            )))
         }
     }
 }
 
-/// Request to origin failed (e.g. timeout) or the response is invalid.
-fn handle_origin_fail(req: &Request<Bytes>, proxy_config: &ProxyConfig, db: &Db) -> Response<Body> {
-    let cache_key = CacheKey {
-        method: req.method(),
-        uri: req.uri(),
-        body: req.body(),
+// ------ Load balancing ------
+
+/// Send the request to one of the origin replicas of the route (see `ProxyRoute::strategy`).
+///
+/// The request has been routed to the first origin by `handle_routes`,
+/// so responses of all replicas are cached under the same key.
+///
+/// Returns the chosen origin and its connection.
+fn balance_upstreams(
+    req: &mut Request<Bytes>,
+    route_match: Option<&RouteMatch>,
+    state: &ProxyState,
+) -> Option<(Uri, UpstreamConnection)> {
+    let route_match = route_match?;
+    let route = &route_match.route;
+    let primary = route.to.primary();
+    // Region-specific origins (see `ProxyRoute::to_by_region`) aren't replicated.
+    if !route.to.is_replicated() || &route_match.origin != primary {
+        return None;
+    }
+    let primary = primary.to_string();
+    let uri = req.uri().to_string();
+    // The URI may have been changed by the request script.
+    if !uri.starts_with(&primary) {
+        return None;
+    }
+    let (upstream, connection) = state.load_balancer.pick(
+        &route.from,
+        &route.to,
+        route.strategy.unwrap_or(LoadBalancingStrategy::RoundRobin),
+        |upstream| state.upstream_health.is_healthy(upstream),
+    );
+    let upstream_uri = match format!("{}{}", upstream, &uri[primary.len()..]).parse::<Uri>() {
+        Ok(upstream_uri) => upstream_uri,
+        Err(error) => {
+            error!("Invalid URI of the origin replica: {}", error);
+            return None;
+        }
     };
+    if let Some(host) = upstream_uri.host().and_then(|host| host.parse().ok()) {
+        req.headers_mut().insert("host", host);
+    }
+    *req.uri_mut() = upstream_uri;
+    Some((upstream, connection))
+}
 
-    match db.get(cache_key.to_db_key()) {
-        // The cached response has been found.
-        Ok(Some(cached_response)) => {
-            match bincode::deserialize::<CacheValueForDeserialization>(cached_response.as_ref()) {
-                // Return the cached response.
-                Ok(cached_response) => {
-                    if now_timestamp() - cached_response.timestamp
-                        > i64::from(proxy_config.cache_stale_threshold_on_fail)
-                    {
-                        let mut response = Response::new(Body::from(
-                            "No valid response. Cached response too old.",
-                        ));
-                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                        return response;
-                    }
+// ------ Request deadline ------
 
-                    if proxy_config.verbose {
-                        println!("response has been successfully loaded from the cache");
-                    }
+/// The moment when the response should be sent according to the client's time budget.
+///
+/// See `ProxyConfig::request_deadline`.
+fn request_deadline<B>(
+    req: &Request<B>,
+    proxy_config: &ProxyConfig,
+    received_at: Instant,
+) -> Option<Instant> {
+    let deadline_config = proxy_config.request_deadline.as_ref()?;
+    let budget_ms = req
+        .headers()
+        .get(deadline_config.header.as_str())?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    // Reserve time for the response processing.
+    let budget_ms = budget_ms.saturating_sub(u64::from(deadline_config.overhead_ms));
+    received_at.checked_add(Duration::from_millis(budget_ms))
+}
 
-                    let mut response = Response::new(Body::from(cached_response.body));
-                    *response.status_mut() = cached_response.status;
-                    *response.headers_mut() = cached_response.headers;
-                    response
-                }
-                // Deserialization failed.
-                Err(error) => {
-                    eprintln!("cannot deserialize a response`: {}", error);
-                    let mut response =
-                        Response::new(Body::from("Cannot deserialize a cached response."));
-                    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                    response
-                }
-            }
-        }
+/// Send the remaining budget to the origin, so it can give up in time too.
+fn propagate_deadline(
+    req: &mut Request<Bytes>,
+    remaining_time: Duration,
+    proxy_config: &ProxyConfig,
+) {
+    let header_name = proxy_config
+        .request_deadline
+        .as_ref()
+        .and_then(|deadline_config| {
+            header::HeaderName::from_bytes(deadline_config.header.as_bytes()).ok()
+        });
+    if let Some(header_name) = header_name {
+        let remaining_ms = u64::try_from(remaining_time.as_millis()).unwrap_or(u64::MAX);
+        req.headers_mut()
+            .insert(header_name, header::HeaderValue::from(remaining_ms));
+    }
+}
 
-        // The cached response hasn't been found.
-        Ok(None) => {
-            // We weren't able to get a fresh response and there isn't a cached one.
-            let mut response = Response::new(Body::from("No valid response."));
-            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            response
+fn deadline_exceeded_response() -> Response<Body> {
+    error_response(
+        ProxyErrorKind::DeadlineExceeded,
+        StatusCode::GATEWAY_TIMEOUT,
+        "Request deadline exceeded.",
+    )
+}
+
+/// Prefetch next pages of the served catalog into the cache in the background.
+///
+/// See `ProxyConfig::prefetch`.
+fn schedule_catalog_prefetch(
+    route_match: &RouteMatch,
+    body: &[u8],
+    client: &OnRequestClient,
+    proxy_config: &Arc<ProxyConfig>,
+    db: &Db,
+    state: &Arc<ProxyState>,
+) {
+    let depth = match &proxy_config.prefetch {
+        Some(prefetch_config) if prefetch_config.catalog_pages > 0 => prefetch_config.catalog_pages,
+        _ => return,
+    };
+    let page_size = match prefetch::catalog_page_size(body) {
+        Some(page_size) => page_size,
+        None => return,
+    };
+    let path = route_match
+        .path_and_query
+        .split('?')
+        .next()
+        .unwrap_or_default();
+    // /catalog/movie/top/skip=100.json -> http://localhost:8000/catalog/movie/top/skip=100.json
+    let uris = prefetch::next_catalog_page_paths(path, page_size, depth)
+        .into_iter()
+        .filter_map(|path| {
+            format!("{}{}", route_match.origin, path.trim_start_matches('/'))
+                .parse::<Uri>()
+                .ok()
+        })
+        .collect::<Vec<_>>();
+    if uris.is_empty() {
+        return;
+    }
+
+    let client = Arc::clone(client);
+    let proxy_config = Arc::clone(proxy_config);
+    let db = db.clone();
+    let state = Arc::clone(state);
+    let route_match = route_match.clone();
+    helpers::spawn_with_now_getter(helpers::scoped_now_getter(), async move {
+        for uri in uris {
+            prefetch_catalog_page(uri, &route_match, &client, &proxy_config, &db, &state).await;
         }
+    });
+}
 
-        // DB reading failed.
-        Err(error) => {
-            eprintln!("cannot read from DB`: {}", error);
-            let mut response = Response::new(Body::from("Cannot read from the cache."));
-            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            response
+/// Fetch and cache the catalog page if it isn't already cached.
+async fn prefetch_catalog_page(
+    uri: Uri,
+    route_match: &RouteMatch,
+    client: &OnRequestClient,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+    state: &ProxyState,
+) {
+    let body = Bytes::new();
+    // See `ProxyConfig::cache_key`.
+    let key_uri = match &proxy_config.cache_key {
+        Some(cache_key_config) => cache::normalize_uri(&uri, cache_key_config),
+        None => uri.clone(),
+    };
+    let cache_key = CacheKey {
+        method: &Method::GET,
+        uri: &key_uri,
+        body: &body,
+        user: None,
+    };
+    let response_db_key = cache_key.to_db_key();
+    let cached_request = cache_key.to_cached_request();
+
+    if let Ok(Some(cached_response)) = db.get(response_db_key) {
+        let is_fresh =
+            bincode::deserialize::<CacheValueForDeserialization>(cached_response.as_ref())
+                .map(|cached_response| cached_response.is_fresh())
+                .unwrap_or_default();
+        if is_fresh {
+            return;
         }
     }
+
+    CacheRefresh {
+        cache_metadata: CacheMetadata {
+            original_uri: String::new(),
+            route: Some(route_match.route.from.clone()),
+            reason: CacheReason::Prefetch,
+            tags: route_match.cache_tags(proxy_config),
+        },
+        uri,
+        response_db_key,
+        request_db_key: response_db_key,
+        cached_request,
+        route_match: route_match.clone(),
+        fair_queue_client_key: "prefetch",
+    }
+    .run(client, proxy_config, db, state)
+    .await;
 }
 
-/// Cache response.
+// ------ CacheRefresh ------
+
+/// GET request whose response is cached without any client waiting for it.
 ///
-/// _Note:_: It only logs cache errors because it's not a reason to not deliver response to the user.
-async fn cache_response(
-    response: Response<Body>,
+/// See `ProxyConfig::prefetch` and `ProxyConfig::revalidation`.
+#[derive(Clone)]
+struct CacheRefresh {
+    uri: Uri,
     response_db_key: [u8; 8],
-    proxy_config: &ProxyConfig,
-    db: &Db,
-) -> Result<Response<Body>, hyper::Error> {
-    let (response, response_with_byte_body) = fork_response(response).await?;
-
-    let serialization_result = bincode::serialize(&CacheValueForSerialization {
-        status: response_with_byte_body.status(),
-        headers: response_with_byte_body.headers(),
-        body: response_with_byte_body.body(),
-        timestamp: now_timestamp(),
-        validity: validity_from_response(&response, proxy_config),
-    });
-    match serialization_result {
-        Err(error) => {
-            eprintln!("cannot serialize response: {}", error);
+    /// See `request_db_key`.
+    request_db_key: [u8; 8],
+    cached_request: CachedRequest,
+    cache_metadata: CacheMetadata,
+    route_match: RouteMatch,
+    // Refreshes share the fair queue with clients.
+    fair_queue_client_key: &'static str,
+}
+
+impl CacheRefresh {
+    /// Fetch and cache the response.
+    async fn run(
+        self,
+        client: &OnRequestClient,
+        proxy_config: &ProxyConfig,
+        db: &Db,
+        state: &ProxyState,
+    ) {
+        let origin = self.route_match.origin.to_string();
+        let _fair_queue_permit = match &proxy_config.fair_queue {
+            Some(fair_queue_config) => state
+                .fair_queue
+                .acquire(
+                    &origin,
+                    self.fair_queue_client_key.to_owned(),
+                    1,
+                    fair_queue_config.max_concurrent_requests,
+                    None,
+                )
+                .await
+                .ok(),
+            None => None,
+        };
+
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = self.uri.clone();
+        // Request the same response variant.
+        let request_headers = self.cached_request.vary.to_header_map();
+        *request.headers_mut() = request_headers.clone();
+        if let Some(signing_profile) = &self.route_match.signing {
+            signing::sign_request(&mut request, signing_profile);
         }
-        Ok(cache_value) => {
-            // Try to cache the response.
-            if let Err(error) = db.insert(response_db_key, cache_value) {
-                eprintln!("cannot cache response with the key: {}", error);
-            } else if proxy_config.verbose {
-                println!("response has been successfully cached");
+
+        Stats::increment(&state.stats.origin_requests);
+        let response = match client.request(request).await {
+            Ok(response) if validations::validate_response(&response) => {
+                limit_response_headers(response, &self.uri, proxy_config)
+            }
+            Ok(_) | Err(_) => None,
+        };
+        let response = match response {
+            Some(response) => response,
+            None => {
+                Stats::increment(&state.stats.origin_failures);
+                return;
             }
+        };
+        let response = match handle_content_type(response, &self.route_match).await {
+            Ok(response) => transform_response(response, self.route_match.body_transformers()),
+            Err(error) => {
+                error!(
+                    "cannot refresh cached response of '{}': {}",
+                    self.uri, error
+                );
+                return;
+            }
+        };
+        if is_private_response(&response, Some(&self.route_match)) {
+            return;
+        }
+        let resource = self.route_match.resource();
+        let cache_result = cache_response(
+            response,
+            self.request_db_key,
+            &self.cached_request,
+            &self.cache_metadata,
+            &request_headers,
+            resource,
+            proxy_config.verbose || self.route_match.verbose,
+            proxy_config,
+            db,
+        )
+        .await;
+        if let Err(error) = cache_result {
+            error!(
+                "cannot refresh cached response of '{}': {}",
+                self.uri, error
+            );
         }
     }
-    if proxy_config.verbose {
-        println!("original and just cached response: {:#?}", response);
+}
+
+/// Count the origin failure, notify `ProxyHooks::on_upstream_error` and escalate the route
+/// when it exceeds its error budget (see `ProxyConfig::error_budget`).
+fn record_origin_failure(
+    route_match: Option<&RouteMatch>,
+    upstream_request: &UpstreamRequest,
+    upstream_error: &UpstreamError,
+    client: &OnRequestClient,
+    proxy_config: &ProxyConfig,
+    state: &ProxyState,
+) {
+    Stats::increment(&state.stats.origin_failures);
+    state.hooks.upstream_error(upstream_request, upstream_error);
+    let (route_match, error_budget_config) = match (route_match, &proxy_config.error_budget) {
+        (Some(route_match), Some(error_budget_config)) => (route_match, error_budget_config),
+        _ => return,
+    };
+    let route = route_match.route.from.as_str();
+    if state
+        .error_budgets
+        .record_failure(route, error_budget_config, Instant::now())
+        .is_none()
+    {
+        return;
     }
-    Ok(response)
+    warn!(
+        "Route '{}' exceeded its error budget ({} origin failures in {} s) - verbose logging enabled for {} s",
+        route,
+        error_budget_config.max_failures,
+        error_budget_config.window,
+        error_budget_config.escalation_duration
+    );
+
+    let webhook_url = match &error_budget_config.webhook_url {
+        Some(webhook_url) => webhook_url.clone(),
+        None => return,
+    };
+    let event = ErrorBudgetEvent {
+        event: "error_budget_exceeded",
+        route,
+        max_failures: error_budget_config.max_failures,
+        window: error_budget_config.window,
+        verbose_until: now_timestamp() + i64::from(error_budget_config.escalation_duration),
+    };
+    let event = match serde_json::to_vec(&event) {
+        Ok(event) => event,
+        Err(error) => {
+            error!("cannot serialize error budget event: {}", error);
+            return;
+        }
+    };
+    let mut request = Request::new(Body::from(event));
+    *request.method_mut() = Method::POST;
+    *request.uri_mut() = webhook_url;
+    request.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    let client = Arc::clone(client);
+    helpers::spawn_with_now_getter(helpers::scoped_now_getter(), async move {
+        match client.request(request).await {
+            Ok(response) if response.status().is_success() => (),
+            Ok(response) => error!("Error budget webhook failed: {}", response.status()),
+            Err(error) => error!("Error budget webhook failed: {}", error),
+        }
+    });
 }
 
-/// Get `validity` from cache headers or use the default value from `ProxyConfig`.
-fn validity_from_response(response: &Response<Body>, proxy_config: &ProxyConfig) -> u32 {
-    // Try to get the value from `Cache-Control: max-age=<seconds>`,
-    // where `seconds` is `u32`.
-    response
-        .headers()
-        .get(header::CACHE_CONTROL)
-        .and_then(|header_value| header_value.to_str().ok())
-        .and_then(CacheControl::from_value)
-        .and_then(|cache_control| cache_control.max_age)
-        .and_then(|duration| u32::try_from(duration.num_seconds()).ok())
-        .unwrap_or(proxy_config.default_cache_validity)
+/// See `record_origin_failure`.
+#[derive(Serialize)]
+struct ErrorBudgetEvent<'a> {
+    event: &'static str,
+    route: &'a str,
+    max_failures: u32,
+    window: u32,
+    verbose_until: i64,
 }
 
-/// Aka "middleware pipeline".
-fn apply_request_middlewares(
-    mut req: Request<Bytes>,
+/// Drop origin response headers over `ProxyConfig::response_header_limits`.
+///
+/// Returns `None` when the response should be rejected (see `HeaderLimitPolicy::Reject`).
+fn limit_response_headers(
+    mut response: Response<Body>,
+    uri: &Uri,
     proxy_config: &ProxyConfig,
-    schedule_config_reload: &ScheduleConfigReload,
+) -> Option<Response<Body>> {
+    let limits = match &proxy_config.response_header_limits {
+        Some(limits) => limits,
+        None => return Some(response),
+    };
+    let dropped = hardening::limit_response_headers(
+        response.headers_mut(),
+        limits.max_count,
+        limits.max_total_size,
+    );
+    if dropped == 0 {
+        return Some(response);
+    }
+    match limits.policy {
+        HeaderLimitPolicy::Truncate => {
+            warn!(
+                "Dropped {} response headers over the limits (uri: '{}')",
+                dropped, uri
+            );
+            Some(response)
+        }
+        HeaderLimitPolicy::Reject => {
+            warn!(
+                "Rejected response with headers over the limits (uri: '{}')",
+                uri
+            );
+            None
+        }
+    }
+}
+
+/// Transcode bodies to UTF-8 (see `ProxyRoute::transcode_to_utf8`)
+/// and correct `Content-Type` of JSON responses (see `ProxyRoute::fix_content_type`).
+async fn handle_content_type(
+    mut response: Response<Body>,
+    route_match: &RouteMatch,
+) -> Result<Response<Body>, hyper::Error> {
+    // The corrected `Content-Type` declares UTF-8.
+    if route_match.transcode_to_utf8 {
+        response = transcode_to_utf8(response);
+    }
+    if route_match.fix_content_type {
+        let (fixed_response, original_content_type) = fix_json_content_type(response).await?;
+        if let Some(original_content_type) = original_content_type {
+            warn!(
+                "Corrected Content-Type '{}' to JSON (origin: '{}', path: '{}')",
+                original_content_type, route_match.origin, route_match.path_and_query
+            );
+        }
+        response = fixed_response;
+    }
+    Ok(response)
+}
+
+/// The cached response is about to expire (see `ProxyConfig::revalidation`)
+/// or it has expired recently (see `ProxyConfig::stale_while_revalidate`) - refresh it.
+///
+/// Only GET and HEAD requests without `ProxyRoute::cache_partition` are refreshed.
+fn revalidation_refresh(
+    req: &Request<Bytes>,
+    cached_response: &CacheValueForDeserialization,
+    proxy_config: &ProxyConfig,
+) -> Option<CacheRefresh> {
+    let route_match = req.extensions().get::<RouteMatch>()?;
+    let is_get_or_head = req.method() == Method::GET || req.method() == Method::HEAD;
+    if !is_get_or_head || route_match.route.cache_partition.is_some() {
+        return None;
+    }
+    if cached_response.is_fresh() {
+        let revalidation_config = proxy_config.revalidation.as_ref()?;
+        let threshold =
+            u64::from(cached_response.validity) * u64::from(revalidation_config.remaining_validity);
+        let remaining_validity = u64::try_from(cached_response.remaining_validity()).ok()?;
+        if remaining_validity * 100 > threshold {
+            return None;
+        }
+    } else {
+        let stale_while_revalidate = proxy_config.stale_while_revalidate?;
+        let max_age = i64::from(cached_response.validity) + i64::from(stale_while_revalidate);
+        if cached_response.age() > max_age {
+            return None;
+        }
+    }
+    Some(CacheRefresh {
+        uri: req.uri().clone(),
+        response_db_key: cache_db_key(req),
+        request_db_key: request_db_key(req),
+        cached_request: CachedRequest {
+            vary: cached_response.request.vary.clone(),
+            ..request_cache_key(req).to_cached_request()
+        },
+        cache_metadata: CacheMetadata {
+            original_uri: OriginalUri::of(req),
+            route: Some(route_match.route.from.clone()),
+            reason: CacheReason::Revalidation,
+            tags: route_match.cache_tags(proxy_config),
+        },
+        route_match: route_match.clone(),
+        fair_queue_client_key: "revalidation",
+    })
+}
+
+/// The queue size of refreshes of stale responses when `ProxyConfig::revalidation` is missing.
+const STALE_REFRESH_QUEUE_SIZE: usize = 100;
+
+/// Queue the refresh attached to the cached response by `handle_cache`.
+fn schedule_revalidation(
+    response: &mut Response<Body>,
+    client: &OnRequestClient,
+    proxy_config: &Arc<ProxyConfig>,
     db: &Db,
-) -> Result<Request<Bytes>, Response<Body>> {
-    req = handle_config_reload(req, proxy_config, schedule_config_reload)?;
-    req = handle_clear_cache(req, proxy_config, db)?;
-    req = handle_status(req, proxy_config)?;
-    req = handle_routes(req, proxy_config)?;
-    if proxy_config.cache_enabled {
-        req = handle_cache(req, db, proxy_config.verbose)?;
+    state: &Arc<ProxyState>,
+) {
+    let cache_refresh = match response.extensions_mut().remove::<CacheRefresh>() {
+        Some(cache_refresh) => cache_refresh,
+        None => return,
+    };
+    let (queue_size, interval) = match &proxy_config.revalidation {
+        Some(revalidation_config) => (
+            revalidation_config.queue_size,
+            Duration::from_millis(revalidation_config.interval_ms),
+        ),
+        // Only stale responses are refreshed (see `ProxyConfig::stale_while_revalidate`).
+        None => (STALE_REFRESH_QUEUE_SIZE, Duration::from_millis(0)),
+    };
+    let response_db_key = cache_refresh.response_db_key;
+    let client = Arc::clone(client);
+    let job_proxy_config = Arc::clone(proxy_config);
+    let db = db.clone();
+    let job_state = Arc::clone(state);
+    state.revalidation.push(
+        response_db_key,
+        async move {
+            cache_refresh
+                .run(&client, &job_proxy_config, &db, &job_state)
+                .await;
+        },
+        queue_size,
+        interval,
+    );
+}
+
+// ------ HEAD revalidation ------
+
+/// Validators compared by `revalidate_with_head`.
+const HEAD_VALIDATORS: &[header::HeaderName] = &[header::LAST_MODIFIED, header::CONTENT_LENGTH];
+
+/// Keep using the expired cached response when the origin's HEAD response says
+/// it hasn't changed.
+///
+/// Returns `None` when the full response has to be downloaded.
+///
+/// See `ProxyRoute::head_revalidation`.
+async fn revalidate_with_head(
+    req: &Request<Bytes>,
+    response_db_key: [u8; 8],
+    remaining_time: Option<Duration>,
+    client: &OnRequestClient,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+    state: &ProxyState,
+) -> Option<Response<Body>> {
+    let route_match = req.extensions().get::<RouteMatch>()?;
+    if !route_match.head_revalidation
+        || req.method() != Method::GET
+        || !proxy_config.cache_enabled
+        || RuleFlags::of(req).skip_cache
+        || CacheOverride::of(req).refresh
+        || is_private_request(req)
+    {
+        return None;
     }
-    Ok(req)
+    let mut cached_response =
+        db.get(response_db_key)
+            .ok()
+            .flatten()
+            .and_then(|cached_response| {
+                bincode::deserialize::<CacheValueForDeserialization>(cached_response.as_ref()).ok()
+            })?;
+    let default_validity = proxy_config.default_cache_validity_for(route_match.resource())?;
+
+    let mut head_request = Request::new(Body::empty());
+    *head_request.method_mut() = Method::HEAD;
+    *head_request.uri_mut() = req.uri().clone();
+    *head_request.headers_mut() = req.headers().clone();
+    if let Some(signing_profile) = &route_match.signing {
+        signing::sign_request(&mut head_request, signing_profile);
+    }
+
+    Stats::increment(&state.stats.origin_requests);
+    let head_response = match remaining_time {
+        Some(remaining_time) => time::timeout(remaining_time, client.request(head_request))
+            .await
+            .ok()?,
+        None => client.request(head_request).await,
+    };
+    let head_response = match head_response {
+        Ok(head_response) if head_response.status().is_success() => head_response,
+        Ok(_) => return None,
+        Err(error) => {
+            error!("HEAD request error: {:#?}", error);
+            Stats::increment(&state.stats.origin_failures);
+            return None;
+        }
+    };
+    if !is_unchanged(&cached_response.headers, head_response.headers()) {
+        return None;
+    }
+
+    // Cache the response again with a new validity.
+    let validity = with_ttl_jitter(
+        validity_from_response(&head_response, default_validity),
+        response_db_key,
+        proxy_config.cache_ttl_jitter,
+    );
+    cached_response.metadata.reason = CacheReason::HeadRevalidation;
+    let cache_value = CacheValueForSerialization::new(
+        &cached_response.request,
+        cached_response.status,
+        &cached_response.headers,
+        &cached_response.body,
+        validity,
+    )
+    .with_metadata(&cached_response.metadata);
+    let (timestamp, monotonic_timestamp, boot_id) = (
+        cache_value.timestamp,
+        cache_value.monotonic_timestamp,
+        cache_value.boot_id,
+    );
+    match bincode::serialize(&cache_value) {
+        Ok(cache_value) => {
+            if let Err(error) = db.insert(response_db_key, cache_value) {
+                error!("cannot cache response with the key: {}", error);
+            }
+        }
+        Err(error) => error!("cannot serialize response: {}", error),
+    }
+    cached_response.timestamp = timestamp;
+    cached_response.monotonic_timestamp = monotonic_timestamp;
+    cached_response.boot_id = boot_id;
+    cached_response.validity = validity;
+
+    if is_verbose(req, proxy_config) {
+        info!("cached response has been revalidated by a HEAD request");
+    }
+    Span::current().record("cache_hit", true);
+    Stats::increment(&state.stats.cache_hits);
+    state.cache_hits.record(response_db_key);
+    Some(response_from_cache(cached_response))
+}
+
+/// All validators stored with the cached response match the HEAD response.
+///
+/// Responses without validators are never unchanged.
+fn is_unchanged(cached_headers: &header::HeaderMap, head_headers: &header::HeaderMap) -> bool {
+    let mut compared = false;
+    for validator in HEAD_VALIDATORS {
+        if let Some(cached_value) = cached_headers.get(validator) {
+            if head_headers.get(validator) != Some(cached_value) {
+                return false;
+            }
+            compared = true;
+        }
+    }
+    compared
+}
+
+/// Get the cache key of the request.
+///
+/// Responses of routes with `ProxyRoute::cache_partition` are cached per user.
+fn request_cache_key(req: &Request<Bytes>) -> CacheKey<'_> {
+    let user = req
+        .extensions()
+        .get::<RouteMatch>()
+        .and_then(|route_match| route_match.route.cache_partition.as_ref())
+        .and_then(|cache_partition| cache_partition_user(req, cache_partition));
+    let (uri, body) = match req.extensions().get::<NormalizedCacheKey>() {
+        Some(normalized) => (&normalized.uri, &normalized.body),
+        None => (req.uri(), req.body()),
+    };
+    // HEAD requests share cached responses with GET requests.
+    let method = if req.method() == Method::HEAD {
+        &Method::GET
+    } else {
+        req.method()
+    };
+    CacheKey {
+        method,
+        uri,
+        body,
+        user,
+    }
+}
+
+/// The URI and body used in the cache key instead of the request ones.
+/// It's stored in the request extensions by `handle_cache_key`.
+struct NormalizedCacheKey {
+    uri: Uri,
+    body: Bytes,
+}
+
+/// Normalize the cache key of the request according to `ProxyConfig::cache_key`.
+///
+/// It has to be called after all middlewares that modify the request URI or body.
+fn handle_cache_key(mut req: Request<Bytes>, proxy_config: &ProxyConfig) -> Request<Bytes> {
+    if let Some(cache_key_config) = &proxy_config.cache_key {
+        let normalized = NormalizedCacheKey {
+            uri: cache::normalize_uri(req.uri(), cache_key_config),
+            body: if cache_key_config.include_body {
+                req.body().clone()
+            } else {
+                Bytes::new()
+            },
+        };
+        req.extensions_mut().insert(normalized);
+    }
+    req
+}
+
+/// The request carries credentials, so its response is personal (see `ProxyRoute::cache_private`).
+fn is_private_request(req: &Request<Bytes>) -> bool {
+    let allowed = match req.extensions().get::<RouteMatch>() {
+        Some(route_match) => route_match.cache_private || request_cache_key(req).user.is_some(),
+        None => false,
+    };
+    !allowed
+        && (req.headers().contains_key(header::AUTHORIZATION)
+            || req.headers().contains_key(header::COOKIE))
+}
+
+/// The response sets cookies, so it's personal (see `ProxyRoute::cache_private`).
+fn is_private_response(response: &Response<Body>, route_match: Option<&RouteMatch>) -> bool {
+    response.headers().contains_key(header::SET_COOKIE)
+        && !matches!(route_match, Some(route_match) if route_match.cache_private)
+}
+
+/// The URI requested by the client before routing (e.g. `example.com/catalog/movie/top.json`).
+/// It's stored in the request extensions by `apply_request_middlewares`.
+struct OriginalUri(String);
+
+impl OriginalUri {
+    fn new<B>(req: &Request<B>) -> Self {
+        let host = req
+            .uri()
+            .host()
+            .or_else(|| {
+                req.headers()
+                    .get("host")
+                    .and_then(|value| value.to_str().ok())
+            })
+            .unwrap_or_default();
+        let path_and_query = req
+            .uri()
+            .path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str());
+        Self(format!("{}{}", host, path_and_query))
+    }
+
+    /// The original URI or an empty string for requests created by the proxy.
+    fn of<B>(req: &Request<B>) -> String {
+        req.extensions()
+            .get::<Self>()
+            .map(|original_uri| original_uri.0.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Keys stored by `handle_cache`, so the request body isn't hashed again when the response is cached.
+#[derive(Clone, Copy)]
+struct CacheDbKey {
+    /// `CacheKey::to_db_key` of the request.
+    request: [u8; 8],
+    /// `VaryHeaders::to_db_key` of the cached response variant.
+    variant: [u8; 8],
+}
+
+/// The key of the cached response (see `request_cache_key` and `VaryHeaders`).
+fn cache_db_key(req: &Request<Bytes>) -> [u8; 8] {
+    match req.extensions().get::<CacheDbKey>() {
+        Some(db_key) => db_key.variant,
+        None => request_cache_key(req).to_db_key(),
+    }
+}
+
+/// `CacheKey::to_db_key` of the request (see `request_cache_key`).
+fn request_db_key(req: &Request<Bytes>) -> [u8; 8] {
+    match req.extensions().get::<CacheDbKey>() {
+        Some(db_key) => db_key.request,
+        None => request_cache_key(req).to_db_key(),
+    }
+}
+
+/// Get the user identifier from the configured header or query parameter.
+fn cache_partition_user<'a>(
+    req: &'a Request<Bytes>,
+    cache_partition: &CachePartitionConfig,
+) -> Option<&'a str> {
+    let from_header = cache_partition.header.as_ref().and_then(|header_name| {
+        req.headers()
+            .get(header_name.as_str())
+            .and_then(|value| value.to_str().ok())
+    });
+    let from_query = || {
+        let query_param = cache_partition.query_param.as_ref()?;
+        req.uri()
+            .query()?
+            .split('&')
+            .filter_map(|pair| {
+                let mut name_and_value = pair.splitn(2, '=');
+                match (name_and_value.next(), name_and_value.next()) {
+                    (Some(name), Some(value)) if name == query_param => Some(value),
+                    _ => None,
+                }
+            })
+            .next()
+    };
+    from_header.or_else(from_query)
+}
+
+/// The fair queue is full or the request has waited too long for a free slot.
+struct QueueOverflow;
+
+/// Wait for a free slot if the fair queue is enabled.
+///
+/// See `FairQueueConfig::queue_timeout_ms` and `FairQueueConfig::max_queued_requests`.
+async fn acquire_fair_queue_slot<'a>(
+    req: &Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    state: &'a ProxyState,
+) -> Result<Option<FairQueuePermit<'a>>, QueueOverflow> {
+    let fair_queue_config = match &proxy_config.fair_queue {
+        Some(fair_queue_config) => fair_queue_config,
+        None => return Ok(None),
+    };
+    // Origins are limited independently - see `FairQueueConfig::max_concurrent_requests`.
+    let origin = req
+        .extensions()
+        .get::<RouteMatch>()
+        .map(|route_match| route_match.origin.to_string())
+        .unwrap_or_default();
+    let client_key = fair_queue_client_key(req, fair_queue_config, proxy_config);
+    let weight = fair_queue_config
+        .weights
+        .get(&client_key)
+        .copied()
+        .unwrap_or(1);
+    let permit = state.fair_queue.acquire(
+        &origin,
+        client_key,
+        weight,
+        fair_queue_config.max_concurrent_requests,
+        fair_queue_config.max_queued_requests,
+    );
+    let permit = match fair_queue_config.queue_timeout_ms {
+        Some(queue_timeout_ms) => {
+            let queue_timeout = Duration::from_millis(u64::from(queue_timeout_ms));
+            time::timeout(queue_timeout, permit)
+                .await
+                .map_err(|_| QueueOverflow)?
+        }
+        None => permit.await,
+    };
+    permit.map(Some).map_err(|QueueFull| QueueOverflow)
+}
+
+/// Return the cached response like when the origin fails or `503` when it's not available.
+fn handle_queue_overflow(
+    response_db_key: [u8; 8],
+    verbose: bool,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+) -> Response<Body> {
+    let response = handle_origin_fail(
+        response_db_key,
+        verbose,
+        proxy_config,
+        db,
+        ProxyErrorKind::UpstreamUnavailable,
+    );
+    if response.status() != StatusCode::INTERNAL_SERVER_ERROR {
+        return response;
+    }
+    error_response(
+        ProxyErrorKind::UpstreamUnavailable,
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Origin capacity exceeded.",
+    )
+}
+
+/// Identify the client by the configured header or by its IP (see `client_ip`).
+///
+/// Only header values listed in `FairQueueConfig::weights` are accepted,
+/// so a client can't get a new fair share by rotating arbitrary keys.
+fn fair_queue_client_key(
+    req: &Request<Bytes>,
+    fair_queue_config: &FairQueueConfig,
+    proxy_config: &ProxyConfig,
+) -> String {
+    fair_queue_config
+        .client_key_header
+        .as_deref()
+        .and_then(|header_name| {
+            req.headers()
+                .get(header_name)
+                .and_then(|value| value.to_str().ok())
+        })
+        .filter(|client_key| fair_queue_config.weights.contains_key(*client_key))
+        .map(str::to_owned)
+        .or_else(|| client_ip(req, proxy_config).map(|ip| ip.to_string()))
+        .unwrap_or_else(|| "unknown".to_owned())
 }
 
-/// Schedule proxy config reload and return simple 200 response when the predefined URL path is matched.
-fn handle_config_reload(
-    req: Request<Bytes>,
-    proxy_config: &ProxyConfig,
-    schedule_config_reload: &ScheduleConfigReload,
-) -> Result<Request<Bytes>, Response<Body>> {
-    if req.uri().path() == proxy_config.reload_config_url_path {
-        schedule_config_reload();
-        return Err(Response::new(Body::from("Proxy config reload scheduled.")));
-    }
-    Ok(req)
-}
+/// The IP of the connection (see `RemoteAddr`) or the right-most IP in `X-Forwarded-For`
+/// not belonging to `ProxyConfig::trusted_proxies` when the connection comes from a trusted proxy.
+///
+/// Addresses in `X-Forwarded-For` left of the first untrusted one may be sent by the client
+/// itself, so they are ignored.
+fn client_ip<T>(req: &Request<T>, proxy_config: &ProxyConfig) -> Option<IpAddr> {
+    let mut client_ip = RemoteAddr::of(req)?.ip();
+    let hops = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .map(|value| value.to_str().unwrap_or_default())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    // Each trusted proxy has appended the address of its peer.
+    for hop in hops.iter().rev() {
+        if !proxy_config.is_trusted_proxy(client_ip) {
+            break;
+        }
+        client_ip = match hop.trim().parse() {
+            Ok(ip) => ip,
+            Err(_) => break,
+        };
+    }
+    Some(client_ip)
+}
+
+/// Request to origin failed (e.g. timeout) or the response is invalid.
+///
+/// `kind` describes the failure when there isn't a usable cached response.
+fn handle_origin_fail(
+    response_db_key: [u8; 8],
+    verbose: bool,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+    kind: ProxyErrorKind,
+) -> Response<Body> {
+    fallback_to_cache(
+        response_db_key,
+        verbose,
+        proxy_config,
+        db,
+        (kind, StatusCode::INTERNAL_SERVER_ERROR),
+    )
+}
+
+/// The origin response hasn't been received in time (see `ProxyConfig::response_timeout`).
+///
+/// Like `handle_origin_fail`, but `GATEWAY_TIMEOUT` is returned when there isn't a usable cached response.
+fn handle_origin_timeout(
+    response_db_key: [u8; 8],
+    verbose: bool,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+) -> Response<Body> {
+    fallback_to_cache(
+        response_db_key,
+        verbose,
+        proxy_config,
+        db,
+        (ProxyErrorKind::UpstreamTimeout, StatusCode::GATEWAY_TIMEOUT),
+    )
+}
+
+/// Cache the origin failure when the proxy responds with an error
+/// (i.e. there isn't a usable cached response - see `fallback_to_cache`).
+///
+/// _Note:_: It only logs DB errors like `cache_response`.
+fn store_failure(
+    response: &Response<Body>,
+    response_db_key: [u8; 8],
+    cached_request: &CachedRequest,
+    cache_metadata: &CacheMetadata,
+    verbose: bool,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+) {
+    let validity = match proxy_config.negative_cache_validity {
+        Some(validity) => validity,
+        None => return,
+    };
+    if !matches!(
+        ProxyErrorKind::of(response),
+        Some(ProxyErrorKind::UpstreamFailed)
+            | Some(ProxyErrorKind::UpstreamTimeout)
+            | Some(ProxyErrorKind::UpstreamInvalid)
+    ) {
+        return;
+    }
+    let headers = header::HeaderMap::new();
+    let cache_value =
+        CacheValueForSerialization::new(cached_request, response.status(), &headers, &[], validity)
+            .with_metadata(cache_metadata)
+            .negative();
+    let cache_result = bincode::serialize(&cache_value)
+        .map_err(|err| err.to_string())
+        .and_then(|cache_value| {
+            db.insert(response_db_key, cache_value)
+                .map_err(|err| err.to_string())
+        })
+        .and_then(|_| cache::index_uri(db, &cached_request.uri, response_db_key))
+        .and_then(|_| cache::index_tags(db, &cache_metadata.tags, response_db_key));
+    match cache_result {
+        Err(error) => error!("cannot cache origin failure: {}", error),
+        Ok(()) if verbose => info!("origin failure has been cached"),
+        Ok(()) => (),
+    }
+}
+
+/// The response for the cached origin failure (see `ProxyConfig::negative_cache_validity`).
+fn cached_failure_response(status: StatusCode) -> Response<Body> {
+    error_response(
+        ProxyErrorKind::CachedFailure,
+        status,
+        "No valid response. The origin has failed recently.",
+    )
+}
+
+/// Return the cached response if it isn't older than `ProxyConfig::cache_stale_threshold_on_fail`,
+/// or respond with `failure_status`.
+fn fallback_to_cache(
+    response_db_key: [u8; 8],
+    verbose: bool,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+    (failure_kind, failure_status): (ProxyErrorKind, StatusCode),
+) -> Response<Body> {
+    match db.get(response_db_key) {
+        // The cached response has been found.
+        Ok(Some(cached_response)) => {
+            match bincode::deserialize::<CacheValueForDeserialization>(cached_response.as_ref()) {
+                // Cached origin failures aren't usable.
+                Ok(cached_response) if cached_response.is_negative() => {
+                    error_response(failure_kind, failure_status, "No valid response.")
+                }
+                // Return the cached response.
+                Ok(cached_response) => {
+                    if cached_response.age() > i64::from(proxy_config.cache_stale_threshold_on_fail)
+                    {
+                        return error_response(
+                            failure_kind,
+                            failure_status,
+                            "No valid response. Cached response too old.",
+                        );
+                    }
+
+                    if verbose {
+                        info!("response has been successfully loaded from the cache");
+                    }
+
+                    response_from_cache(cached_response)
+                }
+                // Deserialization failed.
+                Err(error) => {
+                    error!("cannot deserialize a response`: {}", error);
+                    error_response(
+                        ProxyErrorKind::CacheDeserializeError,
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Cannot deserialize a cached response.",
+                    )
+                }
+            }
+        }
+
+        // The cached response hasn't been found.
+        Ok(None) => {
+            // We weren't able to get a fresh response and there isn't a cached one.
+            error_response(failure_kind, failure_status, "No valid response.")
+        }
+
+        // DB reading failed.
+        Err(error) => {
+            error!("cannot read from DB`: {}", error);
+            error_response(
+                ProxyErrorKind::CacheReadError,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Cannot read from the cache.",
+            )
+        }
+    }
+}
+
+/// Create the response from the cached one.
+///
+/// The origin's `Date` is replaced with the current one and `Expires` is set according to
+/// the remaining validity, so downstream caches don't see the response as fresher than it is.
+fn response_from_cache(cached_response: CacheValueForDeserialization) -> Response<Body> {
+    let now = now_timestamp();
+    let expires = now.saturating_add(cached_response.remaining_validity());
+
+    let mut response = Response::new(Body::from(cached_response.body));
+    *response.status_mut() = cached_response.status;
+    *response.headers_mut() = cached_response.headers;
+    for (name, timestamp) in &[(header::DATE, now), (header::EXPIRES, expires)] {
+        if let Ok(value) = header::HeaderValue::from_str(&http_date(*timestamp)) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// Cache response.
+///
+/// Responses of some resources (e.g. "subtitles") may not be cached - see `ProxyConfig::resource_ttls`.
+///
+/// Responses with the header `Vary` are cached per values of the listed `request_headers`
+/// (see `VaryHeaders`). `Vary: *` responses aren't cached.
+///
+/// Returns the response and its body (the body is empty when the response isn't cached).
+///
+/// _Note:_: It only logs cache errors because it's not a reason to not deliver response to the user.
+#[allow(clippy::too_many_arguments)]
+async fn cache_response(
+    response: Response<Body>,
+    request_db_key: [u8; 8],
+    cached_request: &CachedRequest,
+    cache_metadata: &CacheMetadata,
+    request_headers: &header::HeaderMap,
+    resource: Option<&str>,
+    verbose: bool,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+) -> Result<(Response<Body>, Bytes), hyper::Error> {
+    let default_validity = match proxy_config.default_cache_validity_for(resource) {
+        Some(default_validity) => default_validity,
+        None => return Ok((response, Bytes::new())),
+    };
+    let is_preflight = cached_request.method == Method::OPTIONS.as_str();
+    let mut vary_names = match cache::vary_header_names(response.headers()) {
+        Some(vary_names) => vary_names,
+        None => {
+            if verbose {
+                info!("response varies by all request headers, it can't be cached");
+            }
+            return Ok((response, Bytes::new()));
+        }
+    };
+    // See `CacheKeyConfig::ignored_headers`.
+    if let Some(cache_key_config) = &proxy_config.cache_key {
+        vary_names.retain(|name| !cache_key_config.is_ignored_header(name));
+    }
+    let (response, response_with_byte_body) = match proxy_config.max_cached_body_size_for(resource)
+    {
+        Some(max_body_size) => {
+            let content_length = response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+            if matches!(content_length, Some(content_length) if content_length > max_body_size) {
+                if verbose {
+                    info!("response is too big to be cached");
+                }
+                let mut response = response;
+                response.extensions_mut().insert(StreamedBody);
+                return Ok((response, Bytes::new()));
+            }
+            let (parts, body) = response.into_parts();
+            match body_to_bytes_with_limit(body, max_body_size).await? {
+                Ok(bytes) => {
+                    let response_with_byte_body = Response::from_parts(parts, bytes);
+                    let response =
+                        map_response_body(clone_response(&response_with_byte_body), bytes_to_body)
+                            .await?;
+                    (response, response_with_byte_body)
+                }
+                // Stream the rest of the body without caching.
+                Err(body) => {
+                    if verbose {
+                        info!("response is too big to be cached");
+                    }
+                    let mut response = Response::from_parts(parts, body);
+                    response.extensions_mut().insert(StreamedBody);
+                    return Ok((response, Bytes::new()));
+                }
+            }
+        }
+        None => fork_response(response).await?,
+    };
+
+    // Preflight responses allow only the given origin, method and headers.
+    if is_preflight {
+        vary_names.extend(
+            PREFLIGHT_REQUEST_HEADERS
+                .iter()
+                .map(|name| (*name).to_owned()),
+        );
+        vary_names.sort();
+        vary_names.dedup();
+    }
+    let vary = VaryHeaders::new(&vary_names, request_headers);
+    let response_db_key = vary.to_db_key(request_db_key);
+    let cached_request = if vary.is_empty() {
+        Cow::Borrowed(cached_request)
+    } else {
+        Cow::Owned(CachedRequest {
+            vary,
+            ..cached_request.clone()
+        })
+    };
+    if let Err(error) = cache::set_vary_names(db, request_db_key, &vary_names) {
+        error!("cannot store Vary header names: {}", error);
+    }
+
+    let mut cached_headers = response_with_byte_body
+        .headers()
+        .iter()
+        .filter(|(name, value)| proxy_config.is_cached_header(name.as_str(), value.as_bytes()))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<header::HeaderMap>();
+    // See `CompressionConfig::store_compressed`.
+    let compressed_body = match &proxy_config.compression {
+        Some(compression_config) if compression_config.store_compressed => {
+            compression::compress_cached_body(
+                &mut cached_headers,
+                response_with_byte_body.body(),
+                compression_config,
+            )
+        }
+        _ => None,
+    };
+    let cache_value = CacheValueForSerialization::new(
+        &cached_request,
+        response_with_byte_body.status(),
+        &cached_headers,
+        compressed_body
+            .as_deref()
+            .unwrap_or_else(|| response_with_byte_body.body()),
+        with_ttl_jitter(
+            if is_preflight {
+                preflight_validity(&response, default_validity)
+            } else {
+                validity_from_response(&response, default_validity)
+            },
+            response_db_key,
+            proxy_config.cache_ttl_jitter,
+        ),
+    )
+    .with_metadata(cache_metadata);
+    // See `ProxyConfig::cache_write_coalescing_window`.
+    if let Some(window) = proxy_config.cache_write_coalescing_window {
+        match cache::is_recently_stored(db, response_db_key, &cache_value, window) {
+            Ok(true) => {
+                if verbose {
+                    info!("identical response has been cached recently, it's not written again");
+                    log_response(
+                        "original and already cached response",
+                        &response,
+                        proxy_config,
+                    );
+                }
+                return Ok((response, response_with_byte_body.into_body()));
+            }
+            Ok(false) => (),
+            Err(error) => error!("cannot read cached response: {}", error),
+        }
+    }
+    match bincode::serialize(&cache_value) {
+        Err(error) => {
+            error!("cannot serialize response: {}", error);
+        }
+        Ok(cache_value) => {
+            // Try to cache the response.
+            let cache_result = db
+                .insert(response_db_key, cache_value)
+                .map_err(|err| err.to_string())
+                .and_then(|_| cache::index_uri(db, &cached_request.uri, response_db_key))
+                .and_then(|_| cache::index_tags(db, &cache_metadata.tags, response_db_key));
+            if let Err(error) = cache_result {
+                error!("cannot cache response with the key: {}", error);
+            } else if verbose {
+                info!("response has been successfully cached");
+            }
+        }
+    }
+    if verbose {
+        log_response("original and just cached response", &response, proxy_config);
+    }
+    Ok((response, response_with_byte_body.into_body()))
+}
+
+/// Store the successful response to the idempotency journal (see `ProxyConfig::idempotency`).
+///
+/// _Note:_: It only logs journal errors like `cache_response`.
+async fn journal_response(
+    response: Response<Body>,
+    journal_key: [u8; 8],
+    cached_request: &CachedRequest,
+    idempotency_config: &IdempotencyConfig,
+    verbose: bool,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+) -> Result<Response<Body>, hyper::Error> {
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+    let content_length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+    if matches!(content_length, Some(content_length) if content_length > idempotency_config.max_body_size)
+    {
+        if verbose {
+            info!("response is too big to be journaled");
+        }
+        return Ok(response);
+    }
+    let (parts, body) = response.into_parts();
+    let response_with_byte_body =
+        match body_to_bytes_with_limit(body, idempotency_config.max_body_size).await? {
+            Ok(bytes) => Response::from_parts(parts, bytes),
+            // Stream the rest of the body without journaling.
+            Err(body) => {
+                if verbose {
+                    info!("response is too big to be journaled");
+                }
+                return Ok(Response::from_parts(parts, body));
+            }
+        };
+
+    let journaled_headers = response_with_byte_body
+        .headers()
+        .iter()
+        .filter(|(name, value)| proxy_config.is_cached_header(name.as_str(), value.as_bytes()))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<header::HeaderMap>();
+    let value = CacheValueForSerialization::new(
+        cached_request,
+        response_with_byte_body.status(),
+        &journaled_headers,
+        response_with_byte_body.body(),
+        idempotency_config.ttl,
+    );
+    match idempotency::record(db, journal_key, &value) {
+        Err(error) => error!("cannot journal response: {}", error),
+        Ok(()) if verbose => info!("response has been journaled"),
+        Ok(()) => (),
+    }
+    map_response_body(response_with_byte_body, bytes_to_body).await
+}
+
+/// Shorten the validity by up to `jitter` percents (see `ProxyConfig::cache_ttl_jitter`).
+///
+/// The jitter is derived from the cache key, so it's the same for the given request.
+fn with_ttl_jitter(validity: u32, response_db_key: [u8; 8], jitter: Option<u8>) -> u32 {
+    let max_jitter = u64::from(validity) * u64::from(jitter.unwrap_or_default().min(100)) / 100;
+    // Cache keys are hashes, so the jitter is evenly distributed.
+    let jitter = u64::from_le_bytes(response_db_key) % (max_jitter + 1);
+    validity - u32::try_from(jitter).unwrap_or_default()
+}
+
+/// Get `validity` from cache headers or use the default value (see `ProxyConfig::default_cache_validity_for`).
+fn validity_from_response(response: &Response<Body>, default_validity: u32) -> u32 {
+    // Try to get the value from `Cache-Control: max-age=<seconds>`,
+    // where `seconds` is `u32`.
+    response
+        .headers()
+        .get(header::CACHE_CONTROL)
+        .and_then(|header_value| header_value.to_str().ok())
+        .and_then(CacheControl::from_value)
+        .and_then(|cache_control| cache_control.max_age)
+        .and_then(|duration| u32::try_from(duration.num_seconds()).ok())
+        .unwrap_or(default_validity)
+}
+
+/// `Access-Control-Max-Age` has a higher priority than `Cache-Control: max-age`
+/// for preflight responses.
+fn preflight_validity(response: &Response<Body>, default_validity: u32) -> u32 {
+    response
+        .headers()
+        .get(header::ACCESS_CONTROL_MAX_AGE)
+        .and_then(|max_age| max_age.to_str().ok()?.trim().parse::<u32>().ok())
+        .unwrap_or_else(|| validity_from_response(response, default_validity))
+}
+
+/// Aka "middleware pipeline".
+fn apply_request_middlewares(
+    mut req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    schedule_config_reload: &ScheduleConfigReload,
+    db: &Db,
+    state: &ProxyState,
+    profile: &mut RequestProfile,
+    cors_headers: &mut header::HeaderMap,
+) -> Result<Request<Bytes>, Response<Body>> {
+    let original_uri = OriginalUri::new(&req);
+    req.extensions_mut().insert(original_uri);
+    req = handle_hardening(req)?;
+    profile.lap("validation");
+    req = handle_cache_override(req, proxy_config);
+    // See `ProxyConfig::library_mode`.
+    if proxy_config.admin_endpoints_enabled() {
+        req = handle_config_reload(req, proxy_config, schedule_config_reload)?;
+        req = handle_clear_cache(req, proxy_config, db, state)?;
+        req = handle_purge_cache(req, proxy_config, db)?;
+        req = handle_purge_tag(req, proxy_config, db)?;
+        req = handle_status(req, proxy_config, state)?;
+        req = handle_upstream_health(req, proxy_config, state)?;
+        req = handle_admin_ui(req, proxy_config)?;
+        req = handle_config_dump(req, proxy_config)?;
+        req = handle_cache_inspect(req, proxy_config, db)?;
+        req = handle_cache_entry(req, proxy_config, db, state)?;
+    }
+    req = handle_virtual_endpoints(req, proxy_config)?;
+    profile.lap("admin_endpoints");
+    req = handle_geoip(req, proxy_config, state);
+    req = handle_rules(req, proxy_config)?;
+    req = handle_routes(req, proxy_config, &state.collection_routes.get())?;
+    *cors_headers = response_cors_headers(&req, proxy_config);
+    profile.lap("routing");
+    req = handle_error_budget(req, proxy_config, state);
+    req = handle_preflight(req, proxy_config)?;
+    req = handle_request_script(req, proxy_config)?;
+    req = handle_idempotency(req, proxy_config, db)?;
+    req = handle_cache_key(req, proxy_config);
+    let cache_override = CacheOverride::of(&req);
+    profile.lap("middlewares");
+    if proxy_config.cache_enabled && !RuleFlags::of(&req).skip_cache && !cache_override.refresh {
+        let verbose = is_verbose(&req, proxy_config);
+        // Cache hits are returned as `Err`, measure them too.
+        let req_or_response = handle_cache(req, db, verbose, proxy_config, state);
+        profile.lap("cache_lookup");
+        req = req_or_response?;
+    }
+    if cache_override.only_cache {
+        return Err(error_response(
+            ProxyErrorKind::NotCached,
+            StatusCode::GATEWAY_TIMEOUT,
+            "The response isn't cached.",
+        ));
+    }
+    Ok(req)
+}
+
+/// Answer CORS preflight requests of routes with `ProxyRoute::answer_preflight`
+/// and all `OPTIONS` requests when `ProxyConfig::preflight` is set.
+///
+/// CORS preflights of routes with CORS settings (see `ProxyConfig::cors`) are always answered
+/// according to the settings (allowed origins are set by `apply_cors_headers`).
+///
+/// `ProxyConfig::validate` allows only one of the settings per route, the precedence below
+/// (CORS settings, `preflight`, `answer_preflight`) matters only for unvalidated configs.
+///
+/// # Errors
+///
+/// - Returns `NO_CONTENT` response allowing the configured (or requested) methods and headers
+///   for all origins.
+/// - Returns `NO_CONTENT` response with the header `Allow` to other `OPTIONS` requests.
+fn handle_preflight(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+) -> Result<Request<Bytes>, Response<Body>> {
+    let route_match = match req.extensions().get::<RouteMatch>() {
+        Some(route_match) if req.method() == Method::OPTIONS => route_match,
+        _ => return Ok(req),
+    };
+    let owned_config;
+    let preflight_config = match (cors_config(&req, proxy_config), &proxy_config.preflight) {
+        (Some(cors_config), _) if is_preflight(&req) => {
+            owned_config = PreflightConfig {
+                allowed_methods: cors_config.allowed_methods.clone(),
+                allowed_headers: cors_config.allowed_headers.clone(),
+                max_age: cors_config.max_age,
+            };
+            &owned_config
+        }
+        (_, Some(preflight_config)) => preflight_config,
+        (_, None) if route_match.answer_preflight => {
+            owned_config = PreflightConfig::default();
+            &owned_config
+        }
+        (_, None) => return Ok(req),
+    };
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+    let headers = response.headers_mut();
+
+    if !is_preflight(&req) {
+        // Routes with `answer_preflight` forward other `OPTIONS` requests.
+        if proxy_config.preflight.is_none() {
+            return Ok(req);
+        }
+        let allow = if preflight_config.allowed_methods.is_empty() {
+            DEFAULT_ALLOWED_METHODS.to_owned()
+        } else {
+            preflight_config.allowed_methods.join(", ")
+        };
+        if let Ok(allow) = header::HeaderValue::from_str(&allow) {
+            headers.insert(header::ALLOW, allow);
+        }
+        return Err(response);
+    }
+
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        header::HeaderValue::from_static("*"),
+    );
+    for (requested, allowed, configured) in &[
+        (
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            &preflight_config.allowed_methods,
+        ),
+        (
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            &preflight_config.allowed_headers,
+        ),
+    ] {
+        // The requested values are allowed when the config doesn't list them.
+        let value = if configured.is_empty() {
+            req.headers().get(requested).cloned()
+        } else {
+            header::HeaderValue::from_str(&configured.join(", ")).ok()
+        };
+        if let Some(value) = value {
+            headers.insert(allowed.clone(), value);
+        }
+    }
+    headers.insert(
+        header::ACCESS_CONTROL_MAX_AGE,
+        header::HeaderValue::from(preflight_config.max_age),
+    );
+    Err(response)
+}
+
+/// `Allow` of `OPTIONS` responses when `PreflightConfig::allowed_methods` is empty.
+const DEFAULT_ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+// ------ CORS ------
+
+/// CORS settings of the routed request - see `ProxyRoute::cors` and `ProxyConfig::cors`.
+fn cors_config<'a>(
+    req: &'a Request<Bytes>,
+    proxy_config: &'a ProxyConfig,
+) -> Option<&'a CorsConfig> {
+    req.extensions()
+        .get::<RouteMatch>()
+        .and_then(|route_match| route_match.route.cors.as_ref())
+        .or(proxy_config.cors.as_ref())
+}
+
+/// CORS headers of the response to the routed request (empty when CORS isn't configured).
+///
+/// `Access-Control-Allow-Origin` is missing when the request origin isn't allowed.
+fn response_cors_headers(req: &Request<Bytes>, proxy_config: &ProxyConfig) -> header::HeaderMap {
+    let mut headers = header::HeaderMap::new();
+    let cors_config = match cors_config(req, proxy_config) {
+        Some(cors_config) => cors_config,
+        None => return headers,
+    };
+    if cors_config.allows_all_origins() {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            header::HeaderValue::from_static("*"),
+        );
+        return headers;
+    }
+    // The allowed origin is echoed, so responses differ by the origin.
+    headers.insert(header::VARY, header::HeaderValue::from_static("origin"));
+    if let Some(origin) = req.headers().get(header::ORIGIN) {
+        let is_allowed = origin
+            .to_str()
+            .map(|origin| cors_config.allows_origin(origin))
+            .unwrap_or_default();
+        if is_allowed {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        }
+    }
+    headers
+}
+
+/// Replace CORS headers of proxied and cached responses with `response_cors_headers`.
+fn apply_cors_headers(response: &mut Response<Body>, cors_headers: &header::HeaderMap) {
+    if cors_headers.is_empty() {
+        return;
+    }
+    let headers = response.headers_mut();
+    // Origins not allowed by the proxy can't be allowed by the origin.
+    headers.remove(header::ACCESS_CONTROL_ALLOW_ORIGIN);
+    for (name, value) in cors_headers {
+        if name == header::VARY {
+            headers.append(name.clone(), value.clone());
+        } else {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+/// Request headers of CORS preflights - their responses are cached per values of these headers.
+const PREFLIGHT_REQUEST_HEADERS: [&str; 3] = [
+    "access-control-request-headers",
+    "access-control-request-method",
+    "origin",
+];
+
+fn is_preflight<B>(req: &Request<B>) -> bool {
+    req.method() == Method::OPTIONS
+        && req.headers().contains_key(header::ORIGIN)
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Log the matched route in detail when it has exceeded its error budget recently
+/// (see `ProxyConfig::error_budget`).
+fn handle_error_budget(
+    mut req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    state: &ProxyState,
+) -> Request<Bytes> {
+    if proxy_config.error_budget.is_none() {
+        return req;
+    }
+    if let Some(route_match) = req.extensions_mut().get_mut::<RouteMatch>() {
+        if !route_match.verbose
+            && state
+                .error_budgets
+                .is_escalated(&route_match.route.from, Instant::now())
+        {
+            route_match.verbose = true;
+        }
+    }
+    req
+}
+
+/// Reject ambiguous requests that could be interpreted differently by the proxy and the origin.
+///
+/// # Errors
+///
+/// Returns `BAD_REQUEST` when the request fails the checks in the module `hardening`.
+fn handle_hardening(mut req: Request<Bytes>) -> Result<Request<Bytes>, Response<Body>> {
+    if let Err(error) = hardening::check_request(&req) {
+        warn!(
+            "Rejected suspicious request: {} (URI: '{}')",
+            error,
+            req.uri()
+        );
+        return Err(error_response(
+            ProxyErrorKind::ValidationFailed,
+            StatusCode::BAD_REQUEST,
+            "Invalid request.",
+        ));
+    }
+    hardening::normalize_framing_headers(&mut req);
+    Ok(req)
+}
+
+/// Remove the cache override headers and store `CacheOverride` in the request extensions
+/// if the request contains the valid admin token.
+///
+/// The headers are always removed so they never reach the origin.
+fn handle_cache_override(mut req: Request<Bytes>, proxy_config: &ProxyConfig) -> Request<Bytes> {
+    let bypass = take_cache_bypass(&mut req, proxy_config);
+    let is_admin = is_admin_request(&req, proxy_config);
+
+    let headers = req.headers_mut();
+    headers.remove(ADMIN_TOKEN_HEADER);
+    let refresh = headers.remove(REFRESH_HEADER);
+    let only_cache = headers.remove(ONLY_CACHE_HEADER);
+    let is_true = |value: Option<header::HeaderValue>| match value {
+        Some(value) => is_admin && value.as_bytes().eq_ignore_ascii_case(b"true"),
+        None => false,
+    };
+    let cache_override = CacheOverride {
+        refresh: bypass || is_true(refresh),
+        only_cache: is_true(only_cache),
+    };
+    if cache_override != CacheOverride::default() {
+        req.extensions_mut().insert(cache_override);
+    }
+    req
+}
+
+/// Remove the cache bypass query parameter and return `true` if the bypass is allowed.
+///
+/// See `ProxyConfig::cache_bypass`.
+fn take_cache_bypass(req: &mut Request<Bytes>, proxy_config: &ProxyConfig) -> bool {
+    let cache_bypass = match &proxy_config.cache_bypass {
+        Some(cache_bypass) => cache_bypass,
+        None => return false,
+    };
+    let query = match req.uri().query() {
+        Some(query) => query,
+        None => return false,
+    };
+    let mut has_bypass_param = false;
+    let other_pairs = query
+        .split('&')
+        .filter(|pair| {
+            if pair.split('=').next() == Some(cache_bypass.query_param.as_str()) {
+                has_bypass_param = true;
+                return false;
+            }
+            true
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    if !has_bypass_param {
+        return false;
+    }
+
+    let path_and_query = if other_pairs.is_empty() {
+        req.uri().path().to_owned()
+    } else {
+        format!("{}?{}", req.uri().path(), other_pairs)
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+
+    // The token is accepted only in the header - query parameters are logged.
+    !cache_bypass.require_admin_token || is_admin_request(req, proxy_config)
+}
+
+/// Schedule proxy config reload when the predefined URL path is matched.
+///
+/// The response is completed by `finish_config_reload`.
+fn handle_config_reload(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    schedule_config_reload: &ScheduleConfigReload,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if req.uri().path() == proxy_config.reload_config_url_path {
+        let mut response = Response::new(Body::empty());
+        response
+            .extensions_mut()
+            .insert(PendingConfigReload(schedule_config_reload()));
+        return Err(response);
+    }
+    Ok(req)
+}
+
+/// The config reload started by `handle_config_reload`. It's stored in the response extensions.
+struct PendingConfigReload(ConfigReload);
+
+/// Wait for the config reload and respond with 200 or with the validation errors.
+async fn finish_config_reload(mut response: Response<Body>) -> Response<Body> {
+    let config_reload = match response.extensions_mut().remove::<PendingConfigReload>() {
+        Some(PendingConfigReload(config_reload)) => config_reload,
+        None => return response,
+    };
+    let (status, body) = match config_reload.await {
+        Ok(Ok(epoch)) => (
+            StatusCode::OK,
+            format!("Proxy config reloaded (epoch {}).", epoch),
+        ),
+        Ok(Err(errors)) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "Proxy config is invalid, the running config is kept:\n{}",
+                errors
+                    .iter()
+                    .map(|error| format!("- {}", error))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ),
+        ),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Proxy config reload failed.".to_owned(),
+        ),
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+/// Clear cache and return simple 200 response when the predefined URL path is matched.
+///
+/// The cache is seeded again (see `ProxyConfig::cache_seed_file`).
+fn handle_clear_cache(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+    state: &ProxyState,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if req.uri().path() == proxy_config.clear_cache_url_path {
+        if let Err(error) = cache::clear(db) {
+            error!("cache clearing failed: {}", error);
+            return Err(error_response(
+                ProxyErrorKind::CacheWriteError,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Cache clearing failed.",
+            ));
+        }
+        if proxy_config.cache_seed_file.is_some() {
+            state.cache_seed.schedule();
+        }
+        return Err(Response::new(Body::from("Cache cleared.")));
+    }
+    Ok(req)
+}
+
+/// Remove cached responses by the URI prefix (see `ProxyConfig::purge_cache_url_path`)
+/// when the predefined URL path is matched.
+///
+/// # Errors
+///
+/// - Returns `BAD_REQUEST` response when the query parameter `prefix` is missing or empty.
+/// - Returns `INTERNAL_SERVER_ERROR` response when DB reading or writing fails.
+fn handle_purge_cache(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if Some(req.uri().path()) != proxy_config.purge_cache_url_path.as_deref() {
+        return Ok(req);
+    }
+    let prefix = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| {
+            let mut name_and_value = pair.splitn(2, '=');
+            match (name_and_value.next(), name_and_value.next()) {
+                (Some("prefix"), Some(prefix)) if !prefix.is_empty() => Some(prefix),
+                _ => None,
+            }
+        })
+        .next();
+    let prefix = match prefix {
+        Some(prefix) => prefix,
+        None => {
+            return Err(error_response(
+                ProxyErrorKind::ValidationFailed,
+                StatusCode::BAD_REQUEST,
+                "Missing query parameter 'prefix'.",
+            ));
+        }
+    };
+    match cache::purge(db, prefix) {
+        Ok(purged) => Err(Response::new(Body::from(format!(
+            "Purged {} cached responses.",
+            purged
+        )))),
+        Err(error) => {
+            error!("cache purging failed: {}", error);
+            Err(error_response(
+                ProxyErrorKind::CacheWriteError,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Cache purging failed.",
+            ))
+        }
+    }
+}
+
+/// Body of the purge-tag request - see `ProxyConfig::purge_tag_url_path`.
+#[derive(Deserialize)]
+struct PurgeTagRequest {
+    tag: String,
+}
+
+/// Remove cached responses with the tag from the JSON body (e.g. `{"tag": "catalog"}`)
+/// when the predefined URL path is matched.
+fn handle_purge_tag(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if Some(req.uri().path()) != proxy_config.purge_tag_url_path.as_deref() {
+        return Ok(req);
+    }
+    if req.method() != Method::POST {
+        return Err(error_response(
+            ProxyErrorKind::ValidationFailed,
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Only POST is allowed.",
+        ));
+    }
+    let tag = match serde_json::from_slice::<PurgeTagRequest>(req.body()) {
+        Ok(PurgeTagRequest { tag }) if !tag.is_empty() => tag,
+        _ => {
+            return Err(error_response(
+                ProxyErrorKind::ValidationFailed,
+                StatusCode::BAD_REQUEST,
+                "Expected JSON body with non-empty 'tag'.",
+            ));
+        }
+    };
+    match cache::purge_tag(db, &tag) {
+        Ok(purged) => Err(Response::new(Body::from(format!(
+            "Purged {} cached responses.",
+            purged
+        )))),
+        Err(error) => {
+            error!("cache purging by tag failed: {}", error);
+            Err(error_response(
+                ProxyErrorKind::CacheWriteError,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Cache purging failed.",
+            ))
+        }
+    }
+}
+
+/// Return response with text "Proxy is ready." when the predefined URL path is matched.
+///
+/// Return `SERVICE_UNAVAILABLE` with text "Proxy is draining." when the server is shutting down
+/// or with text "Proxy is warming up." while the cache is seeded (see `ProxyConfig::cache_seed_file`).
+///
+/// Return JSON `DetailedStatus` when the query contains `detailed` (e.g. `/status?detailed`).
+fn handle_status(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    state: &ProxyState,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if req.uri().path() == proxy_config.status_url_path {
+        let detailed = req
+            .uri()
+            .query()
+            .map(|query| {
+                query
+                    .split('&')
+                    .any(|pair| pair.split('=').next() == Some("detailed"))
+            })
+            .unwrap_or_default();
+        if detailed {
+            return Err(detailed_status_response(state));
+        }
+        if state.is_draining() {
+            let mut response = Response::new(Body::from("Proxy is draining."));
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            return Err(response);
+        }
+        if state.cache_seed.is_seeding() {
+            let mut response = Response::new(Body::from("Proxy is warming up."));
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            return Err(response);
+        }
+        return Err(Response::new(Body::from("Proxy is ready.")));
+    }
+    Ok(req)
+}
+
+/// Return JSON with statuses of probed origins by their URIs (see `ProxyConfig::health_checks`)
+/// when the predefined URL path is matched (see `ProxyConfig::upstream_health_url_path`).
+///
+/// Return `SERVICE_UNAVAILABLE` when an origin is unhealthy.
+fn handle_upstream_health(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    state: &ProxyState,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if Some(req.uri().path()) != proxy_config.upstream_health_url_path.as_deref() {
+        return Ok(req);
+    }
+    let statuses = state.upstream_health.snapshot();
+    let mut response = json_response(&statuses);
+    if statuses.values().any(|status| !status.healthy) {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    Err(response)
+}
+
+/// See `handle_status`.
+#[derive(Serialize)]
+struct DetailedStatus {
+    status: &'static str,
+    stats: StatsSnapshot,
+    // Requests waiting for a free slot in the fair queue.
+    queued_requests: usize,
+    // Capabilities learned from origin manifests (`null` for unavailable ones).
+    origins: BTreeMap<String, Option<AddonCapabilities>>,
+    // Error responses per kind (see `ProxyErrorKind`).
+    errors: BTreeMap<&'static str, u64>,
+    // Wall times of request pipeline stages (see `ProxyConfig::profiling`).
+    profile: BTreeMap<&'static str, StageProfile>,
+}
+
+fn detailed_status_response(state: &ProxyState) -> Response<Body> {
+    let status = DetailedStatus {
+        status: if state.is_draining() {
+            "draining"
+        } else if state.cache_seed.is_seeding() {
+            "warming"
+        } else {
+            "ready"
+        },
+        stats: state.stats.snapshot(),
+        queued_requests: state.fair_queue.queued(),
+        origins: state.manifests.snapshot(),
+        errors: state.stats.error_responses(),
+        profile: state.profiler.snapshot(),
+    };
+    let mut response = json_response(&status);
+    if (state.is_draining() || state.cache_seed.is_seeding()) && response.status() == StatusCode::OK
+    {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    response
+}
+
+/// Paths of endpoints called by the admin dashboard - see `handle_admin_ui`.
+#[derive(Serialize)]
+struct AdminUiPaths<'a> {
+    status_url_path: &'a str,
+    reload_config_url_path: &'a str,
+    clear_cache_url_path: &'a str,
+}
+
+/// Return the admin dashboard when the predefined URL path is matched.
+///
+/// The dashboard calls the endpoints defined by `ProxyConfig` `*_url_path` fields.
+/// The page doesn't contain them, they are secret - the page loads them as JSON `AdminUiPaths`
+/// from the same path with the query `paths` and the admin token.
+///
+/// # Errors
+///
+/// - Returns the dashboard page or JSON `AdminUiPaths`.
+/// - Returns `UNAUTHORIZED` when paths are requested without `ProxyConfig::admin_token`.
+fn handle_admin_ui(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if Some(req.uri().path()) == proxy_config.admin_ui_url_path.as_deref() {
+        if req.uri().query() == Some("paths") {
+            if !is_admin_request(&req, proxy_config) {
+                return Err(unauthorized_response());
+            }
+            return Err(json_response(&AdminUiPaths {
+                status_url_path: &proxy_config.status_url_path,
+                reload_config_url_path: &proxy_config.reload_config_url_path,
+                clear_cache_url_path: &proxy_config.clear_cache_url_path,
+            }));
+        }
+        let mut response = Response::new(Body::from(include_str!("../../admin.html")));
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        return Err(response);
+    }
+    Ok(req)
+}
+
+/// Return JSON `ConfigDump` when the predefined URL path is matched.
+///
+/// # Errors
+///
+/// Returns `UNAUTHORIZED` response when the request doesn't contain `ProxyConfig::admin_token`.
+fn handle_config_dump(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if Some(req.uri().path()) == proxy_config.config_dump_url_path.as_deref() {
+        if !is_admin_request(&req, proxy_config) {
+            return Err(unauthorized_response());
+        }
+        return Err(json_response(&ConfigDump {
+            version: &proxy_config.version,
+            config: proxy_config,
+        }));
+    }
+    Ok(req)
+}
+
+/// Return JSON `CacheInspection` when the predefined URL path is matched.
+///
+/// # Errors
+///
+/// - Returns `UNAUTHORIZED` response when the request doesn't contain `ProxyConfig::admin_token`.
+/// - Returns `INTERNAL_SERVER_ERROR` response when DB reading fails.
+fn handle_cache_inspect(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if Some(req.uri().path()) != proxy_config.cache_inspect_url_path.as_deref() {
+        return Ok(req);
+    }
+    if !is_admin_request(&req, proxy_config) {
+        return Err(unauthorized_response());
+    }
+    let limit = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| {
+            let mut name_and_value = pair.splitn(2, '=');
+            match (name_and_value.next(), name_and_value.next()) {
+                (Some("limit"), Some(limit)) => limit.parse::<usize>().ok(),
+                _ => None,
+            }
+        })
+        .next()
+        .unwrap_or(CACHE_INSPECT_DEFAULT_LIMIT);
+
+    let mut inspection = CacheInspection::default();
+    for entry in db.iter() {
+        let (key, value) = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                error!("Cannot read from DB`: {}", error);
+                return Err(error_response(
+                    ProxyErrorKind::CacheReadError,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Cannot read from the cache.",
+                ));
+            }
+        };
+        let value = match bincode::deserialize::<CacheValueForDeserialization>(&value) {
+            Ok(value) => value,
+            Err(_) => {
+                inspection.invalid += 1;
+                continue;
+            }
+        };
+        if inspection.entries.len() == limit {
+            inspection.truncated = true;
+            break;
+        }
+        inspection.entries.push(CacheEntry {
+            fresh: value.is_fresh(),
+            key: hex(&key),
+            method: value.request.method,
+            uri: value.request.uri,
+            status: value.status.as_u16(),
+            size: value.body.len(),
+            timestamp: value.timestamp,
+            // Saturate - timestamps in corrupted values can be arbitrary.
+            expires_at: value.timestamp.saturating_add(i64::from(value.validity)),
+        });
+    }
+    Err(json_response(&inspection))
+}
+
+const CACHE_INSPECT_DEFAULT_LIMIT: usize = 1000;
+
+/// See `handle_cache_inspect`.
+#[derive(Serialize, Default)]
+struct CacheInspection {
+    entries: Vec<CacheEntry>,
+    /// There are more entries than the limit.
+    truncated: bool,
+    /// Entries that cannot be deserialized.
+    invalid: usize,
+}
+
+#[derive(Serialize)]
+struct CacheEntry {
+    /// Hex-encoded key (see `CacheKey::to_db_key`).
+    key: String,
+    method: String,
+    /// The URI sent to the origin.
+    uri: String,
+    status: u16,
+    /// The body size in bytes.
+    size: usize,
+    /// Unix timestamp of caching.
+    timestamp: i64,
+    /// Unix timestamp of the expiration.
+    expires_at: i64,
+    fresh: bool,
+}
+
+/// Return JSON `CacheEntryReport` with all cached responses of the URI
+/// when the predefined URL path is matched (see `ProxyConfig::cache_entry_url_path`).
+///
+/// `DELETE` requests remove the reported responses (see `cache::remove`).
+///
+/// Everything after `uri=` in the query is the proxied URL (e.g.
+/// `/cache-entry?uri=https://example.com/catalog/movie/top.json?skip=100`).
+///
+/// # Errors
+///
+/// - Returns `UNAUTHORIZED` response when the request doesn't contain `ProxyConfig::admin_token`.
+/// - Returns `BAD_REQUEST` response when the query parameter `uri` is missing or invalid.
+/// - Returns `NOT_FOUND` response with the report when the URI isn't cached.
+/// - Returns `INTERNAL_SERVER_ERROR` response when DB reading or writing fails.
+fn handle_cache_entry(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+    state: &ProxyState,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if Some(req.uri().path()) != proxy_config.cache_entry_url_path.as_deref() {
+        return Ok(req);
+    }
+    if !is_admin_request(&req, proxy_config) {
+        return Err(unauthorized_response());
+    }
+    let query = req.uri().query().unwrap_or_default();
+    let url = match query.get("uri=".len()..) {
+        Some(url) if query.starts_with("uri=") && !url.is_empty() => url,
+        _ => {
+            return Err(error_response(
+                ProxyErrorKind::ValidationFailed,
+                StatusCode::BAD_REQUEST,
+                "Missing query parameter 'uri'.",
+            ));
+        }
+    };
+    let route_report = match test_route(url, proxy_config) {
+        Ok(route_report) => route_report,
+        Err(error) => {
+            warn!("invalid cache entry URI: {}", error);
+            return Err(error_response(
+                ProxyErrorKind::ValidationFailed,
+                StatusCode::BAD_REQUEST,
+                "Invalid query parameter 'uri'.",
+            ));
+        }
+    };
+    // Responses are indexed by the URI in the cache key (see `ProxyConfig::cache_key`).
+    let upstream_uri = route_report
+        .upstream_uri
+        .map(|upstream_uri| cache::cached_uri(&upstream_uri, proxy_config.cache_key.as_ref()));
+
+    let mut report = CacheEntryReport {
+        route: route_report.route,
+        rejected_with: route_report.rejected_with.map(|status| status.as_u16()),
+        entries: Vec::new(),
+        upstream_uri,
+    };
+    let remove = req.method() == Method::DELETE;
+    let read_result = report
+        .upstream_uri
+        .as_deref()
+        .map_or(Ok(Vec::new()), |upstream_uri| {
+            cache::keys_of_uri(db, upstream_uri)
+        })
+        .and_then(|keys| {
+            for key in keys {
+                let value = match db.get(key).map_err(|err| err.to_string())? {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let value = bincode::deserialize::<CacheValueForDeserialization>(&value)
+                    .map_err(|err| err.to_string())?;
+                report.entries.push(CacheEntryDetails {
+                    hits: cache::hits(db, key)?.saturating_add(state.cache_hits.pending(key)),
+                    negative: value.is_negative(),
+                    vary: value.request.vary.names(),
+                    entry: CacheEntry {
+                        fresh: value.is_fresh(),
+                        key: hex(&key),
+                        method: value.request.method,
+                        uri: value.request.uri,
+                        status: value.status.as_u16(),
+                        size: value.body.len(),
+                        timestamp: value.timestamp,
+                        expires_at: value.timestamp.saturating_add(i64::from(value.validity)),
+                    },
+                    original_uri: value.metadata.original_uri,
+                    matched_route: value.metadata.route,
+                    reason: value.metadata.reason,
+                    tags: value.metadata.tags,
+                });
+                if remove {
+                    cache::remove(db, key)?;
+                }
+            }
+            Ok(())
+        });
+    if let Err(error) = read_result {
+        error!("Cannot read or remove cache entry: {}", error);
+        return Err(error_response(
+            ProxyErrorKind::CacheReadError,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Cannot read from the cache.",
+        ));
+    }
+    let mut response = json_response(&report);
+    if report.entries.is_empty() && response.status() == StatusCode::OK {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+    }
+    Err(response)
+}
+
+/// See `handle_cache_entry`.
+#[derive(Serialize)]
+struct CacheEntryReport {
+    /// `ProxyRoute::from` of the route matching the URI.
+    route: Option<String>,
+    /// The URI in the cache key.
+    upstream_uri: Option<String>,
+    /// The status of the proxy response when the request isn't routed (e.g. 404).
+    rejected_with: Option<u16>,
+    /// Responses cached for different methods, bodies, users or `Vary` headers.
+    entries: Vec<CacheEntryDetails>,
+}
+
+#[derive(Serialize)]
+struct CacheEntryDetails {
+    #[serde(flatten)]
+    entry: CacheEntry,
+    /// The origin failure (see `ProxyConfig::negative_cache_validity`).
+    negative: bool,
+    /// The URI requested by the client that caused caching.
+    original_uri: String,
+    /// `ProxyRoute::from` of the route matched when the response has been cached.
+    matched_route: Option<String>,
+    reason: CacheReason,
+    /// See `ProxyConfig::cache_tags`.
+    tags: Vec<String>,
+    /// Responses served from the cache (see `CacheHits`).
+    hits: u64,
+    /// Request headers of the cached response variant.
+    vary: Vec<String>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Respond without any origin when a virtual endpoint is matched
+/// (see `ProxyConfig::virtual_endpoints` and `ProxyConfig::builtin_endpoints`).
+fn handle_virtual_endpoints(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+) -> Result<Request<Bytes>, Response<Body>> {
+    let builtin_endpoints: &[_] = if proxy_config.builtin_endpoints_enabled() {
+        &virtual_endpoints::BUILTIN_ENDPOINTS
+    } else {
+        &[]
+    };
+    match virtual_endpoints::respond(&req, &proxy_config.virtual_endpoints)
+        .or_else(|| virtual_endpoints::respond(&req, builtin_endpoints))
+    {
+        Some(response) => Err(response),
+        None => Ok(req),
+    }
+}
+
+/// See `handle_config_dump`.
+#[derive(Serialize)]
+struct ConfigDump<'a> {
+    version: &'a ConfigVersion,
+    // Secrets are redacted.
+    config: &'a ProxyConfig,
+}
+
+fn json_response(value: &impl Serialize) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(json) => {
+            let mut response = Response::new(Body::from(json));
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("application/json"),
+            );
+            response
+        }
+        Err(error) => {
+            error!("cannot serialize JSON response: {}", error);
+            error_response(
+                ProxyErrorKind::Internal,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Cannot serialize response.",
+            )
+        }
+    }
+}
+
+const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// The key of the request in the idempotency journal. It's stored in the request extensions
+/// by `handle_idempotency`, so the response can be journaled.
+#[derive(Clone, Copy)]
+struct IdempotencyJournalKey([u8; 8]);
+
+/// Replay the journaled response to the same POST request with the same idempotency key
+/// (see `ProxyConfig::idempotency`).
+///
+/// # Errors
+///
+/// - Returns the journaled response with the header `Idempotent-Replayed: true`.
+/// - Returns `INTERNAL_SERVER_ERROR` response when DB reading fails.
+fn handle_idempotency(
+    mut req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    db: &Db,
+) -> Result<Request<Bytes>, Response<Body>> {
+    let idempotency_config = match &proxy_config.idempotency {
+        Some(idempotency_config) if req.method() == Method::POST => idempotency_config,
+        _ => return Ok(req),
+    };
+    let journal_key = match req.headers().get(idempotency_config.header.as_str()) {
+        Some(idempotency_key) => idempotency::journal_key(idempotency_key.as_bytes(), &req),
+        None => return Ok(req),
+    };
+    match idempotency::replay(db, journal_key) {
+        Ok(Some(journaled_response)) => {
+            if is_verbose(&req, proxy_config) {
+                info!("replaying journaled response");
+            }
+            let mut response = response_from_cache(journaled_response);
+            response.headers_mut().insert(
+                IDEMPOTENT_REPLAYED_HEADER,
+                header::HeaderValue::from_static("true"),
+            );
+            Err(response)
+        }
+        Ok(None) => {
+            req.extensions_mut()
+                .insert(IdempotencyJournalKey(journal_key));
+            Ok(req)
+        }
+        Err(error) => {
+            error!("cannot read idempotency journal: {}", error);
+            Err(error_response(
+                ProxyErrorKind::CacheReadError,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Cannot read idempotency journal.",
+            ))
+        }
+    }
+}
+
+/// Pass the routed request to the script function `on_request` (see `ProxyConfig::script`).
+///
+/// # Errors
+///
+/// - Returns the response created by the script.
+/// - Returns `INTERNAL_SERVER_ERROR` response when the script fails.
+#[cfg(feature = "scripting")]
+fn handle_request_script(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+) -> Result<Request<Bytes>, Response<Body>> {
+    match proxy_config
+        .script
+        .as_ref()
+        .and_then(|script| script.ast.as_ref())
+    {
+        Some(ast) => scripting::on_request(ast, req),
+        None => Ok(req),
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+fn handle_request_script(
+    req: Request<Bytes>,
+    _: &ProxyConfig,
+) -> Result<Request<Bytes>, Response<Body>> {
+    Ok(req)
+}
+
+/// Pass the origin response to the script function `on_response` (see `ProxyConfig::script`).
+#[cfg(feature = "scripting")]
+fn handle_response_script(response: Response<Body>, proxy_config: &ProxyConfig) -> Response<Body> {
+    match proxy_config
+        .script
+        .as_ref()
+        .and_then(|script| script.ast.as_ref())
+    {
+        Some(ast) => scripting::on_response(ast, response),
+        None => response,
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+fn handle_response_script(response: Response<Body>, _: &ProxyConfig) -> Response<Body> {
+    response
+}
+
+/// Validate requests according to the addon manifest (see `ProxyRoute::negotiate_manifest`)
+/// and reject requests for resources the addon doesn't advertise.
+///
+/// The manifest is fetched only once per origin.
+/// Requests are validated by the standard `validate_request` while the manifest is unavailable.
+///
+/// # Errors
+///
+/// - Returns `NOT_FOUND` when the addon doesn't support the requested resource.
+/// - Returns `BAD_REQUEST` when request validation fails.
+async fn handle_capabilities(
+    req: Request<Bytes>,
+    client: &OnRequestClient,
+    state: &ProxyState,
+) -> Result<Request<Bytes>, Response<Body>> {
+    let route_match = match req.extensions().get::<RouteMatch>() {
+        Some(route_match) if route_match.negotiate_manifest => route_match.clone(),
+        _ => return Ok(req),
+    };
+    let origin = route_match.origin.to_string();
+
+    let now = now_timestamp();
+    if state.manifests.begin_fetch(&origin, now) {
+        match fetch_manifest(&origin, client).await {
+            Ok(capabilities) => state.manifests.learn(&origin, capabilities, now),
+            Err(error) => error!("cannot fetch manifest of '{}': {}", origin, error),
+        }
+    }
+    let invalid_request = || {
+        error_response(
+            ProxyErrorKind::ValidationFailed,
+            StatusCode::BAD_REQUEST,
+            "Invalid request.",
+        )
+    };
+
+    let capabilities = match state.manifests.capabilities(&origin) {
+        Some(capabilities) => capabilities,
+        None => {
+            if route_match.validate
+                && !validations::validate_request(&req, &route_match.path_and_query)
+            {
+                return Err(invalid_request());
+            }
+            return Ok(req);
+        }
+    };
+
+    // /catalog/movie/top.json?x=1 -> /catalog/movie/top.json
+    let path = route_match
+        .path_and_query
+        .split('?')
+        .next()
+        .unwrap_or_default();
+    match ResourceRef::from_str(path) {
+        Ok(resource) => {
+            if capabilities.supports(&resource.resource, &resource.type_name, &resource.id) {
+                return Ok(req);
+            }
+            Err(error_response(
+                ProxyErrorKind::UnsupportedResource,
+                StatusCode::NOT_FOUND,
+                "The addon doesn't support the requested resource.",
+            ))
+        }
+        // Manifest, configuration page, etc.
+        Err(_) => {
+            if route_match.validate
+                && !validations::validate_request_by_manifest(
+                    &req,
+                    path,
+                    &route_match.origin,
+                    &capabilities,
+                )
+            {
+                return Err(invalid_request());
+            }
+            Ok(req)
+        }
+    }
+}
+
+/// Download and parse the origin manifest.
+async fn fetch_manifest(
+    origin: &str,
+    client: &OnRequestClient,
+) -> Result<AddonCapabilities, String> {
+    // http://localhost:8080/ -> http://localhost:8080/manifest.json
+    let uri = format!("{}manifest.json", origin)
+        .parse::<Uri>()
+        .map_err(|err| err.to_string())?;
+    let response = client.get(uri).await.map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("unexpected status {}", response.status()));
+    }
+    let manifest = body_to_bytes(response.into_body())
+        .await
+        .map_err(|err| err.to_string())?;
+    AddonCapabilities::from_manifest(&manifest)
+}
+
+/// Tag the request with the client location (see `ProxyConfig::geoip`),
+/// forward the tags to the origin and count the request per country.
+fn handle_geoip(
+    mut req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    state: &ProxyState,
+) -> Request<Bytes> {
+    if proxy_config.geoip.is_none() {
+        return req;
+    }
+    // Clients mustn't be able to fake their location
+    // (their IP is taken from `X-Forwarded-For` only behind trusted proxies - see `client_ip`).
+    req.headers_mut().remove(GEOIP_COUNTRY_HEADER);
+    req.headers_mut().remove(GEOIP_ASN_HEADER);
+
+    let geo_tag = match lookup_geo_tag(&req, proxy_config) {
+        Some(geo_tag) => geo_tag,
+        None => return req,
+    };
+    if let Some(country) = &geo_tag.country {
+        state.stats.increment_country(country);
+        if let Ok(country) = header::HeaderValue::from_str(country) {
+            req.headers_mut().insert(GEOIP_COUNTRY_HEADER, country);
+        }
+    }
+    if let Some(asn) = geo_tag.asn {
+        req.headers_mut()
+            .insert(GEOIP_ASN_HEADER, header::HeaderValue::from(asn));
+    }
+    req.extensions_mut().insert(geo_tag);
+    req
+}
+
+#[cfg(feature = "geoip")]
+fn lookup_geo_tag(req: &Request<Bytes>, proxy_config: &ProxyConfig) -> Option<GeoTag> {
+    let databases = proxy_config.geoip.as_ref()?.databases.as_ref()?;
+    let ip = client_ip(req, proxy_config)?;
+    let (country, continent) = databases.country_and_continent(ip);
+    Some(GeoTag {
+        country,
+        continent,
+        asn: databases.asn(ip),
+    })
+}
+
+#[cfg(not(feature = "geoip"))]
+fn lookup_geo_tag(_: &Request<Bytes>, _: &ProxyConfig) -> Option<GeoTag> {
+    None
+}
+
+/// Apply `ProxyConfig::rules`.
+///
+/// # Errors
+///
+/// - Returns the response for denied requests.
+/// - Returns `BAD_REQUEST` when request validation fails.
+/// - Returns `INTERNAL_SERVER_ERROR` response if the new address is invalid.
+fn handle_rules(
+    req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if proxy_config.rules.is_empty() {
+        return Ok(req);
+    }
+    rules::apply_rules(req, &proxy_config.rules)
+}
+
+/// Update request's URI to point to another address according to predefined routes.
+///
+/// _Note:_ Requests routed by `ProxyConfig::rules` are skipped.
+/// Routes generated from `ProxyConfig::addon_collection` are matched after the configured ones.
+///
+/// # Errors
+///
+/// - Returns 200 and the content of `landing.html` when the incoming request does not match any routes.
+/// - Returns the empty response with `LibraryModeConfig::route_miss_status` instead
+///   when the landing page and the 404 page are disabled (see `ProxyConfig::library_mode`).
+/// - Returns `FORBIDDEN` when the route isn't available in the client's country.
+/// - Returns `BAD_REQUEST` when request validation fails.
+/// - Returns `INTERNAL_SERVER_ERROR` response if the new address is invalid.
+fn handle_routes(
+    mut req: Request<Bytes>,
+    proxy_config: &ProxyConfig,
+    collection_routes: &IndexedRoutes,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if RuleFlags::of(&req).routed {
+        return Ok(req);
+    }
+    let uri = req.uri();
+    // Try to get the host directly from `req.uri`, then from `host` header and then represent it as relative url.
+    let host = uri
+        .host()
+        .or_else(|| {
+            req.headers()
+                .get("host")
+                .and_then(|value| value.to_str().ok())
+        })
+        .unwrap_or_default();
+
+    // http://example.com/abc/efg?x=1&y=2 -> example.com/abc/efgx=1&y=2
+    let from = [host, uri.path(), uri.query().unwrap_or_default()];
+
+    // Get the most specific route or return 404 / a landing file.
+    // Configured routes win over collection routes with the same `from`.
+    let route = match (
+        proxy_config.find_route(&from),
+        collection_routes.find(&from),
+    ) {
+        (Some(route), Some(collection_route)) if collection_route.from.len() > route.from.len() => {
+            Some(collection_route)
+        }
+        (route, collection_route) => route.or(collection_route),
+    };
+    let route = match route {
+        Some(route) => route,
+        None => {
+            if let Some(library_mode) = &proxy_config.library_mode {
+                if !library_mode.builtin_pages {
+                    return Err(error_response(
+                        ProxyErrorKind::RouteMiss,
+                        library_mode.route_miss_status(),
+                        "",
+                    ));
+                }
+            }
+            if uri.path() == "/" {
+                // Return `landing.html`.
+                return Err(virtual_endpoints::LANDING_PAGE.respond(&req));
+            } else {
+                // Return 404
+                return Err(error_response(
+                    ProxyErrorKind::RouteMiss,
+                    StatusCode::NOT_FOUND,
+                    "404. The requested URL was not found on this server.",
+                ));
+            }
+        }
+    };
+
+    // Geo restrictions and region-specific origins (see `ProxyConfig::geoip`).
+    let (country, continent) = match GeoTag::of(&req) {
+        Some(geo_tag) => (geo_tag.country.as_deref(), geo_tag.continent.as_deref()),
+        None => (None, None),
+    };
+    if !route.allows_country(country) {
+        return Err(error_response(
+            ProxyErrorKind::Denied,
+            StatusCode::FORBIDDEN,
+            "Not available in your country.",
+        ));
+    }
+    let origin = route.target(country, continent).clone();
+
+    // example.com/abc/efgx=1&y=2 -> /abc/efgx=1&y=2  (if matching route's `from` is "example.com")
+    let route_match = RouteMatch::new(
+        Arc::clone(route),
+        origin.clone(),
+        strip_parts_prefix(&from, route.from.len()),
+        proxy_config,
+    );
+    Span::current().record("route", route.from.as_str());
+
+    // Request validation.
+    // Routes with `negotiate_manifest` are validated later by `handle_capabilities`.
+    if !route_match.negotiate_manifest
+        && route_match.validate
+        && !validations::validate_request(&req, &route_match.path_and_query)
+    {
+        return Err(error_response(
+            ProxyErrorKind::ValidationFailed,
+            StatusCode::BAD_REQUEST,
+            "Invalid request.",
+        ));
+    }
+
+    // @TODO: Replace `trim_start_matches` with `strip_prefix` once stable.
+    // /abc/efg?x=1&y=2 -> http://localhost:8000/abc/efgx=1&y=2 (if matching route's `to` is "http://localhost:8000")
+    *req.uri_mut() = match format!(
+        "{}{}",
+        origin,
+        route_match.path_and_query.trim_start_matches('/')
+    )
+    .parse()
+    {
+        Ok(uri) => uri,
+        Err(error) => {
+            error!("Invalid URI in `handle_routes`: {}", error);
+            return Err(error_response(
+                ProxyErrorKind::RoutingFailed,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Cannot route to invalid URI.",
+            ));
+        }
+    };
+
+    // Replace `host` header with the new one from `Request`'s `uri`.
+    if let Some(host) = req.uri().host().and_then(|host| host.parse().ok()) {
+        req.headers_mut().insert("host", host);
+    } else {
+        error!("Missing host in the request uri: {}", req.uri());
+        return Err(error_response(
+            ProxyErrorKind::RoutingFailed,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Cannot route to URI without host.",
+        ));
+    }
+
+    req.extensions_mut().insert(route_match);
+    Ok(req)
+}
+
+/// Concatenated `parts` without the first `prefix_length` bytes.
+///
+/// _Note:_ `prefix_length` has to be at a char boundary (e.g. the length of the matched route's `from`).
+fn strip_parts_prefix(parts: &[&str], prefix_length: usize) -> String {
+    let mut rest = String::new();
+    let mut skipped = 0;
+    for part in parts {
+        let offset = prefix_length.saturating_sub(skipped).min(part.len());
+        rest.push_str(&part[offset..]);
+        skipped += part.len();
+    }
+    rest
+}
+
+/// Return cached response if possible.
+///
+/// Requests with credentials skip the cache - see `is_private_request`.
+///
+/// # Errors
+/// - Returns cached response.
+/// - Returns `INTERNAL_SERVER_ERROR` response when DB reading fails.
+/// - Returns `INTERNAL_SERVER_ERROR` response when deserialization of a cached response fails.
+fn handle_cache(
+    mut req: Request<Bytes>,
+    db: &Db,
+    verbose: bool,
+    proxy_config: &ProxyConfig,
+    state: &ProxyState,
+) -> Result<Request<Bytes>, Response<Body>> {
+    if is_private_request(&req) {
+        return Ok(req);
+    }
+    let request_db_key = request_db_key(&req);
+    // Responses varying by request headers are cached per their values.
+    let vary = match cache::vary_names(db, request_db_key) {
+        Ok(names) => VaryHeaders::new(&names, req.headers()),
+        Err(error) => {
+            error!("Cannot read Vary header names: {}", error);
+            VaryHeaders::default()
+        }
+    };
+    let db_key = vary.to_db_key(request_db_key);
+    req.extensions_mut().insert(CacheDbKey {
+        request: request_db_key,
+        variant: db_key,
+    });
+    match db.get(db_key) {
+        // The cached response has been found.
+        Ok(Some(cached_response)) => {
+            Err(
+                match bincode::deserialize::<CacheValueForDeserialization>(cached_response.as_ref())
+                {
+                    // The origin has failed recently (see `ProxyConfig::negative_cache_validity`).
+                    Ok(cached_response) if cached_response.is_negative() => {
+                        if !cached_response.is_fresh() {
+                            state
+                                .hooks
+                                .cache_event(&CacheEvent::Miss { uri: req.uri() });
+                            return Ok(req);
+                        }
+                        if verbose {
+                            info!("origin failure has been loaded from the cache");
+                        }
+                        Span::current().record("cache_hit", true);
+                        cached_failure_response(cached_response.status)
+                    }
+                    // Return the cached response.
+                    Ok(cached_response) => {
+                        let is_fresh = cached_response.is_fresh();
+                        let only_cache = CacheOverride::of(&req).only_cache;
+                        // The refresh is scheduled by `on_request`.
+                        // (Admins may request also stale responses without contacting the origin
+                        // - see `CacheOverride`.)
+                        let cache_refresh = if is_fresh || !only_cache {
+                            revalidation_refresh(&req, &cached_response, proxy_config)
+                        } else {
+                            None
+                        };
+                        // Is cached response still valid?
+                        // (Stale responses are served while they are refreshed
+                        // - see `ProxyConfig::stale_while_revalidate`.)
+                        if !is_fresh && !only_cache && cache_refresh.is_none() {
+                            state
+                                .hooks
+                                .cache_event(&CacheEvent::Miss { uri: req.uri() });
+                            return Ok(req);
+                        }
+
+                        if verbose {
+                            info!("response has been successfully loaded from the cache");
+                        }
+                        Span::current().record("cache_hit", true);
+                        Stats::increment(&state.stats.cache_hits);
+                        state.cache_hits.record(db_key);
+                        state.hooks.cache_event(&CacheEvent::Hit {
+                            uri: req.uri(),
+                            fresh: is_fresh,
+                        });
+
+                        let mut response = response_from_cache(cached_response);
+                        if let Some(cache_refresh) = cache_refresh {
+                            response.extensions_mut().insert(cache_refresh);
+                        }
+                        response
+                    }
+                    // Deserialization failed.
+                    Err(error) => {
+                        error!("Cannot deserialize a response`: {}", error);
+                        error_response(
+                            ProxyErrorKind::CacheDeserializeError,
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Cannot deserialize a cached response.",
+                        )
+                    }
+                },
+            )
+        }
+
+        // The cached response hasn't been found => just return `req` without any changes.
+        Ok(None) => {
+            state
+                .hooks
+                .cache_event(&CacheEvent::Miss { uri: req.uri() });
+            Ok(req)
+        }
+
+        // DB reading failed.
+        Err(error) => {
+            error!("Cannot read from DB`: {}", error);
+            Err(error_response(
+                ProxyErrorKind::CacheReadError,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Cannot read from the cache.",
+            ))
+        }
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::with_now_getter;
+    use crate::{
+        LogFormat, LogLevel, ProxyRoute, RequestDeadlineConfig, RevalidationConfig, RouteIndex,
+        TrustedProxy,
+    };
+    use proptest::prelude::*;
+    use std::collections::BTreeSet;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::path::PathBuf;
+
+    // ------ handle_status ------
+
+    #[tokio::test]
+    async fn status() {
+        let request = Request::builder()
+            .uri("https://example.com/status")
+            .body(Bytes::new())
+            .unwrap();
+        let config = default_proxy_config();
+        let state = ProxyState::default();
+
+        let response = handle_status(request, &config, &state).unwrap_err();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Proxy is ready.");
+    }
+
+    #[tokio::test]
+    async fn status_draining() {
+        let request = Request::builder()
+            .uri("https://example.com/status")
+            .body(Bytes::new())
+            .unwrap();
+        let config = default_proxy_config();
+        let state = ProxyState::default();
+        state.start_draining();
+
+        let response = handle_status(request, &config, &state).unwrap_err();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Proxy is draining.");
+    }
+
+    #[tokio::test]
+    async fn status_warming_up() {
+        let request = Request::builder()
+            .uri("https://example.com/status")
+            .body(Bytes::new())
+            .unwrap();
+        let config = default_proxy_config();
+        let state = ProxyState::default();
+        state.cache_seed.schedule();
+
+        let response = handle_status(request, &config, &state).unwrap_err();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Proxy is warming up.");
+    }
+
+    #[tokio::test]
+    async fn status_detailed() {
+        let request = Request::builder()
+            .uri("https://example.com/status?detailed")
+            .body(Bytes::new())
+            .unwrap();
+        let config = default_proxy_config();
+        let state = ProxyState::default();
+        let manifest = br#"{ "types": ["movie"], "resources": ["stream"] }"#;
+        state.manifests.learn(
+            "http://localhost:8080/",
+            AddonCapabilities::from_manifest(manifest).unwrap(),
+            0,
+        );
+        let profiling_config = crate::proxy::ProfilingConfig { sample_every: 1 };
+        let mut profile = state.profiler.start(Some(&profiling_config));
+        profile.lap("routing");
+        state.profiler.record(profile);
+
+        let response = handle_status(request, &config, &state).unwrap_err();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        let status = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(status["status"], "ready");
+        assert_eq!(
+            status["origins"]["http://localhost:8080/"]["protocol"],
+            "v3"
+        );
+        assert_eq!(status["profile"]["routing"]["samples"], 1);
+    }
+
+    // ------ handle_upstream_health ------
+
+    #[tokio::test]
+    async fn upstream_health() {
+        let request = Request::builder()
+            .uri("https://example.com/health/upstreams")
+            .body(Bytes::new())
+            .unwrap();
+        let config = default_proxy_config();
+        let state = ProxyState::default();
+        let origin = "http://localhost:8080".parse().unwrap();
+        state
+            .upstream_health
+            .record(&origin, Err("timeout".to_owned()), 1);
+
+        let response = handle_upstream_health(request, &config, &state).unwrap_err();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        let statuses = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(statuses["http://localhost:8080/"]["healthy"], false);
+        assert_eq!(statuses["http://localhost:8080/"]["last_error"], "timeout");
+    }
+
+    // ------ handle_admin_ui ------
+
+    #[tokio::test]
+    async fn admin_ui() {
+        let mut config = default_proxy_config();
+        config.clear_cache_url_path = "/secret-clear-cache".to_owned();
+        config.admin_token = Some("secret".to_owned());
+        let request = |uri: &str, admin_token: Option<&str>| {
+            let mut request = Request::get(uri);
+            if let Some(admin_token) = admin_token {
+                request = request.header(ADMIN_TOKEN_HEADER, admin_token);
+            }
+            request.body(Bytes::new()).unwrap()
+        };
+
+        let response =
+            handle_admin_ui(request("https://example.com/admin/ui", None), &config).unwrap_err();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(!body.contains("/secret-clear-cache"));
+
+        for admin_token in &[None, Some("wrong")] {
+            let request = request("https://example.com/admin/ui?paths", *admin_token);
+            let response = handle_admin_ui(request, &config).unwrap_err();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let request = request("https://example.com/admin/ui?paths", Some("secret"));
+        let response = handle_admin_ui(request, &config).unwrap_err();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        let paths = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(paths["clear_cache_url_path"], "/secret-clear-cache");
+
+        config.admin_ui_url_path = None;
+        let request = Request::builder()
+            .uri("https://example.com/admin/ui")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(handle_admin_ui(request, &config).is_ok());
+    }
+
+    // ------ handle_cache_inspect ------
+
+    #[tokio::test]
+    async fn cache_inspect() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut config = default_proxy_config();
+        config.admin_token = Some("admin-token".to_owned());
+        let headers = header::HeaderMap::new();
+        for (key, path) in &[([1; 8], "/a.json"), ([2; 8], "/b.json")] {
+            let cached_request = CachedRequest {
+                method: "GET".to_owned(),
+                uri: format!("http://example.com{}", path),
+                ..CachedRequest::default()
+            };
+            let value = CacheValueForSerialization::new(
+                &cached_request,
+                StatusCode::OK,
+                &headers,
+                b"body",
+                60,
+            );
+            db.insert(key, bincode::serialize(&value).unwrap()).unwrap();
+        }
+        db.insert([3; 8], "invalid").unwrap();
+        let inspect = |uri: &str| {
+            let request = Request::get(uri)
+                .header(ADMIN_TOKEN_HEADER, "admin-token")
+                .body(Bytes::new())
+                .unwrap();
+            let response = handle_cache_inspect(request, &config, &db).unwrap_err();
+            assert_eq!(response.status(), StatusCode::OK);
+            async {
+                let body = body_to_bytes(response.into_body()).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        let inspection = inspect("http://example.com/admin/cache").await;
+        assert_eq!(inspection["entries"].as_array().unwrap().len(), 2);
+        let entry = &inspection["entries"][0];
+        assert_eq!(entry["key"], "0101010101010101");
+        assert_eq!(entry["uri"], "http://example.com/a.json");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["size"], 4);
+        assert_eq!(
+            entry["expires_at"].as_i64().unwrap(),
+            entry["timestamp"].as_i64().unwrap() + 60
+        );
+        assert_eq!(entry["fresh"], true);
+        assert_eq!(inspection["truncated"], false);
+        assert_eq!(inspection["invalid"], 1);
+
+        let inspection = inspect("http://example.com/admin/cache?limit=1").await;
+        assert_eq!(inspection["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(inspection["truncated"], true);
+
+        let request = Request::get("http://example.com/admin/cache")
+            .body(Bytes::new())
+            .unwrap();
+        let response = handle_cache_inspect(request, &config, &db).unwrap_err();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = Request::get("http://example.com/admin/other")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(handle_cache_inspect(request, &config, &db).is_ok());
+    }
+
+    // ------ handle_cache_entry ------
+
+    #[tokio::test]
+    async fn cache_entry() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut config = default_proxy_config();
+        config.admin_token = Some("admin-token".to_owned());
+        config.routes.push(
+            toml::from_str::<ProxyRoute>(
+                r#"
+                from = "example.com"
+                to = "http://localhost:8080"
+                "#,
+            )
+            .unwrap(),
+        );
+        let cached_request = CachedRequest {
+            method: "GET".to_owned(),
+            uri: "http://localhost:8080/manifest.json".to_owned(),
+            ..CachedRequest::default()
+        };
+        cache_response(
+            Response::new(Body::from("body")),
+            [1; 8],
+            &cached_request,
+            &cache_metadata(),
+            &header::HeaderMap::new(),
+            None,
+            false,
+            &config,
+            &db,
+        )
+        .await
+        .unwrap();
+        let state = ProxyState::default();
+        state.cache_hits.record([1; 8]);
+        let entry_report = |uri: &str| {
+            let request = Request::get(uri)
+                .header(ADMIN_TOKEN_HEADER, "admin-token")
+                .body(Bytes::new())
+                .unwrap();
+            let response = handle_cache_entry(request, &config, &db, &state).unwrap_err();
+            async {
+                let status = response.status();
+                let body = body_to_bytes(response.into_body()).await.unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<serde_json::Value>(&body).ok(),
+                )
+            }
+        };
+
+        let (status, report) =
+            entry_report("http://proxy.com/cache-entry?uri=https://example.com/manifest.json")
+                .await;
+        let report = report.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["route"], "example.com");
+        assert_eq!(
+            report["upstream_uri"],
+            "http://localhost:8080/manifest.json"
+        );
+        let entry = &report["entries"][0];
+        assert_eq!(entry["key"], "0101010101010101");
+        assert_eq!(entry["reason"], "request");
+        assert_eq!(entry["matched_route"], "example.com");
+        assert_eq!(entry["original_uri"], "example.com/manifest.json");
+        assert_eq!(entry["hits"], 1);
+        assert_eq!(entry["negative"], false);
+
+        let (status, report) =
+            entry_report("http://proxy.com/cache-entry?uri=https://other.com/manifest.json").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(report.unwrap()["rejected_with"], 404);
+
+        let (status, _) = entry_report("http://proxy.com/cache-entry").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let request =
+            Request::delete("http://proxy.com/cache-entry?uri=https://example.com/manifest.json")
+                .header(ADMIN_TOKEN_HEADER, "admin-token")
+                .body(Bytes::new())
+                .unwrap();
+        let response = handle_cache_entry(request, &config, &db, &state).unwrap_err();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(db.is_empty());
+        let (status, _) =
+            entry_report("http://proxy.com/cache-entry?uri=https://example.com/manifest.json")
+                .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let request = Request::get("http://proxy.com/cache-entry?uri=https://example.com/a.json")
+            .body(Bytes::new())
+            .unwrap();
+        let response = handle_cache_entry(request, &config, &db, &state).unwrap_err();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // ------ handle_purge_cache ------
+
+    #[tokio::test]
+    async fn purge_cache() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let config = default_proxy_config();
+        for (key, uri) in &[
+            ([1; 8], "http://example.com/catalog/movie/top.json"),
+            ([2; 8], "http://example.com/meta/movie/tt1.json"),
+        ] {
+            db.insert(key, "cached").unwrap();
+            cache::index_uri(&db, uri, *key).unwrap();
+        }
+        let purge = |uri: &str| {
+            let request = Request::get(uri).body(Bytes::new()).unwrap();
+            handle_purge_cache(request, &config, &db).unwrap_err()
+        };
+
+        let response = purge("http://proxy.com/purge-cache?prefix=example.com/catalog");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Purged 1 cached responses.");
+        assert!(!db.contains_key([1; 8]).unwrap());
+        assert!(db.contains_key([2; 8]).unwrap());
+
+        let response = purge("http://proxy.com/purge-cache?prefix=");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(db.contains_key([2; 8]).unwrap());
+    }
+
+    // ------ handle_purge_tag ------
+
+    #[tokio::test]
+    async fn purge_tag() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let config = default_proxy_config();
+        db.insert([1; 8], "cached").unwrap();
+        cache::index_tags(&db, &["catalog".to_owned()], [1; 8]).unwrap();
+        db.insert([2; 8], "cached").unwrap();
+        cache::index_tags(&db, &["meta".to_owned()], [2; 8]).unwrap();
+        let purge = |method: Method, body: &'static str| {
+            let request = Request::builder()
+                .method(method)
+                .uri("http://proxy.com/cache/purge-tag")
+                .body(Bytes::from(body))
+                .unwrap();
+            handle_purge_tag(request, &config, &db).unwrap_err()
+        };
+
+        let response = purge(Method::GET, r#"{"tag": "catalog"}"#);
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let response = purge(Method::POST, r#"{"tag": ""}"#);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = purge(Method::POST, "catalog");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(db.contains_key([1; 8]).unwrap());
+
+        let response = purge(Method::POST, r#"{"tag": "catalog"}"#);
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Purged 1 cached responses.");
+        assert!(!db.contains_key([1; 8]).unwrap());
+        assert!(db.contains_key([2; 8]).unwrap());
+    }
+
+    #[test]
+    fn cache_tags() {
+        let mut config = default_proxy_config();
+        let route = toml::from_str::<ProxyRoute>(
+            r#"
+            from = "example.com"
+            to = "http://localhost:8080"
+            "#,
+        )
+        .unwrap();
+        let route_match = RouteMatch::new(
+            Arc::new(route),
+            "http://localhost:8080".parse().unwrap(),
+            "/catalog/movie/top.json".to_owned(),
+            &config,
+        );
+        assert!(route_match.cache_tags(&config).is_empty());
+
+        config.cache_tags = Some(vec![
+            "{resource}".to_owned(),
+            "{route}:{resource}".to_owned(),
+            "{group}".to_owned(),
+            "{resource}".to_owned(),
+        ]);
+        assert_eq!(
+            route_match.cache_tags(&config),
+            vec!["catalog".to_owned(), "example.com:catalog".to_owned()]
+        );
+    }
+
+    // ------ handle_idempotency ------
+
+    #[tokio::test]
+    async fn idempotent_replay() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut config = default_proxy_config();
+        config.idempotency = Some(toml::from_str("ttl = 60").unwrap());
+        let request = |key: &str| {
+            Request::post("http://example.com/api/orders")
+                .header("idempotency-key", key)
+                .body(Bytes::from("{}"))
+                .unwrap()
+        };
+
+        let first = handle_idempotency(request("abc"), &config, &db).unwrap();
+        let IdempotencyJournalKey(journal_key) = *first.extensions().get().unwrap();
+        let cached_request = request_cache_key(&first).to_cached_request();
+        let response = Response::new(Body::from("created"));
+        let response = journal_response(
+            response,
+            journal_key,
+            &cached_request,
+            config.idempotency.as_ref().unwrap(),
+            false,
+            &config,
+            &db,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            body_to_bytes(response.into_body()).await.unwrap(),
+            "created"
+        );
+
+        let replayed = handle_idempotency(request("abc"), &config, &db).unwrap_err();
+        assert_eq!(replayed.headers()[IDEMPOTENT_REPLAYED_HEADER], "true");
+        assert_eq!(
+            body_to_bytes(replayed.into_body()).await.unwrap(),
+            "created"
+        );
+        // Another key.
+        assert!(handle_idempotency(request("abd"), &config, &db).is_ok());
+    }
+
+    // ------ handle_config_dump ------
+
+    #[tokio::test]
+    async fn config_dump() {
+        let mut config = default_proxy_config();
+        config.version.hash = "abc".to_owned();
+        config.rules = vec![toml::from_str(
+            r#"
+            path = "^/catalog/"
+            set_headers = { "x-api-key" = "secret" }
+            deny = 403
+            "#,
+        )
+        .unwrap()];
+        config.fair_queue = Some(
+            toml::from_str(
+                r#"
+                max_concurrent_requests = 64
+                weights = { "premium-api-key" = 4 }
+                "#,
+            )
+            .unwrap(),
+        );
+        config.admin_token = Some("admin-token".to_owned());
+        config.clear_cache_url_path = "/secret-clear-cache".to_owned();
+        config.purge_cache_url_path = Some("/secret-purge-cache".to_owned());
+        let request = |admin_token: &str| {
+            Request::get("https://example.com/admin/config")
+                .header(ADMIN_TOKEN_HEADER, admin_token)
+                .body(Bytes::new())
+                .unwrap()
+        };
+
+        let response = handle_config_dump(request("wrong"), &config).unwrap_err();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = handle_config_dump(request("admin-token"), &config).unwrap_err();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        let dump = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(dump["version"]["hash"], "abc");
+        assert_eq!(dump["config"]["rules"][0]["path"], "^/catalog/");
+        assert_eq!(dump["config"]["rules"][0]["deny"], 403);
+        assert_eq!(
+            dump["config"]["rules"][0]["set_headers"]["x-api-key"],
+            "<redacted>"
+        );
+        assert_eq!(dump["config"]["fair_queue"]["weights"]["<redacted> #1"], 4);
+        assert_eq!(dump["config"]["clear_cache_url_path"], "<redacted>");
+        assert_eq!(dump["config"]["purge_cache_url_path"], "<redacted>");
+        assert!(!String::from_utf8_lossy(&body).contains("secret"));
+        assert!(!String::from_utf8_lossy(&body).contains("premium"));
+    }
+
+    // ------ handle_cache_override ------
+
+    #[test]
+    fn cache_override_requires_admin_token() {
+        let mut config = default_proxy_config();
+        let request = |admin_token: &str| {
+            Request::builder()
+                .header("X-Proxy-Admin-Token", admin_token)
+                .header("X-Proxy-Refresh", "true")
+                .header("X-Proxy-Only-Cache", "false")
+                .body(Bytes::new())
+                .unwrap()
+        };
+
+        let req = handle_cache_override(request("secret"), &config);
+        assert_eq!(CacheOverride::of(&req), CacheOverride::default());
+        assert!(req.headers().is_empty());
+
+        config.admin_token = Some("secret".to_owned());
+        let req = handle_cache_override(request("wrong"), &config);
+        assert_eq!(CacheOverride::of(&req), CacheOverride::default());
+
+        let req = handle_cache_override(request("secret"), &config);
+        assert_eq!(
+            CacheOverride::of(&req),
+            CacheOverride {
+                refresh: true,
+                only_cache: false
+            }
+        );
+        // The token must not reach the origin.
+        assert!(req.headers().is_empty());
+    }
+
+    #[test]
+    fn cache_bypass_query_param() {
+        let mut config = default_proxy_config();
+        let request = |uri: &str| Request::get(uri).body(Bytes::new()).unwrap();
+        let refresh = CacheOverride {
+            refresh: true,
+            only_cache: false,
+        };
+
+        let req = handle_cache_override(request("http://example.com/a.json?__nocache=1"), &config);
+        assert_eq!(CacheOverride::of(&req), CacheOverride::default());
+        assert_eq!(req.uri(), "http://example.com/a.json?__nocache=1");
+
+        config.cache_bypass = Some(toml::from_str("").unwrap());
+        let req = handle_cache_override(request("http://example.com/a.json?__nocache=1"), &config);
+        assert_eq!(CacheOverride::of(&req), refresh);
+        assert_eq!(req.uri(), "http://example.com/a.json");
+
+        let req = handle_cache_override(
+            request("http://example.com/a.json?x=1&__nocache&y=2"),
+            &config,
+        );
+        assert_eq!(CacheOverride::of(&req), refresh);
+        assert_eq!(req.uri(), "http://example.com/a.json?x=1&y=2");
+
+        config.cache_bypass = Some(toml::from_str("require_admin_token = true").unwrap());
+        config.admin_token = Some("secret".to_owned());
+        let req = handle_cache_override(request("http://example.com/a.json?__nocache=1"), &config);
+        assert_eq!(CacheOverride::of(&req), CacheOverride::default());
+        // The parameter is removed even when it's rejected.
+        assert_eq!(req.uri(), "http://example.com/a.json");
+
+        // The token isn't accepted in the query - it would be logged.
+        let req = handle_cache_override(
+            request("http://example.com/a.json?__nocache=secret"),
+            &config,
+        );
+        assert_eq!(CacheOverride::of(&req), CacheOverride::default());
+
+        let mut req = request("http://example.com/a.json?__nocache=1");
+        req.headers_mut().insert(
+            ADMIN_TOKEN_HEADER,
+            header::HeaderValue::from_static("secret"),
+        );
+        let req = handle_cache_override(req, &config);
+        assert_eq!(CacheOverride::of(&req), refresh);
+    }
+
+    #[tokio::test]
+    async fn only_cache_serves_stale_response() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let state = ProxyState::default();
+        let request = || {
+            Request::builder()
+                .uri("http://example.com/catalog/movie/top.json")
+                .body(Bytes::new())
+                .unwrap()
+        };
+        let headers = header::HeaderMap::new();
+        let cached_request = request_cache_key(&request()).to_cached_request();
+        let mut stale_response =
+            CacheValueForSerialization::new(&cached_request, StatusCode::OK, &headers, b"stale", 0);
+        stale_response.timestamp -= 60;
+        stale_response.monotonic_timestamp -= 60;
+        db.insert(
+            cache_db_key(&request()),
+            bincode::serialize(&stale_response).unwrap(),
+        )
+        .unwrap();
+
+        assert!(handle_cache(request(), &db, false, &default_proxy_config(), &state).is_ok());
+
+        let mut req = request();
+        req.extensions_mut().insert(CacheOverride {
+            refresh: false,
+            only_cache: true,
+        });
+        let response = handle_cache(req, &db, false, &default_proxy_config(), &state).unwrap_err();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "stale");
+    }
+
+    #[test]
+    fn cache_event_hook() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut state = ProxyState::default();
+        state.hooks.on_cache_event = Some(Arc::new({
+            let events = Arc::clone(&events);
+            move |event: &CacheEvent| {
+                let event = match event {
+                    CacheEvent::Hit { uri, fresh } => format!("hit {} {}", uri, fresh),
+                    CacheEvent::Miss { uri } => format!("miss {}", uri),
+                };
+                events.lock().unwrap().push(event);
+            }
+        }));
+        let request = || {
+            Request::builder()
+                .uri("http://example.com/catalog/movie/top.json")
+                .body(Bytes::new())
+                .unwrap()
+        };
+        let config = default_proxy_config();
+
+        assert!(handle_cache(request(), &db, false, &config, &state).is_ok());
+        let headers = header::HeaderMap::new();
+        let cached_request = request_cache_key(&request()).to_cached_request();
+        let cached_response =
+            CacheValueForSerialization::new(&cached_request, StatusCode::OK, &headers, b"ok", 60);
+        db.insert(
+            cache_db_key(&request()),
+            bincode::serialize(&cached_response).unwrap(),
+        )
+        .unwrap();
+        assert!(handle_cache(request(), &db, false, &config, &state).is_err());
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "miss http://example.com/catalog/movie/top.json",
+                "hit http://example.com/catalog/movie/top.json true"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn response_timeout_on_slow_body() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let config = default_proxy_config();
+        // The origin sends the first chunk and then nothing.
+        let chunks = futures_util::StreamExt::chain(
+            futures_util::stream::iter(vec![Ok::<_, hyper::Error>("abc")]),
+            futures_util::stream::pending(),
+        );
+        let body = with_body_deadline(
+            Body::wrap_stream(chunks),
+            Instant::now() + Duration::from_millis(50),
+        );
+
+        let error = cache_response(
+            Response::new(body),
+            [1; 8],
+            &CachedRequest::default(),
+            &cache_metadata(),
+            &header::HeaderMap::new(),
+            None,
+            false,
+            &config,
+            &db,
+        )
+        .await
+        .unwrap_err();
+        assert!(is_caused_by::<BodyDeadlineExceeded>(&error));
+        assert!(db.get([1; 8]).unwrap().is_none());
+
+        let response = handle_origin_timeout([1; 8], false, &config, &db);
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn client_ip_from_remote_addr() {
+        let mut config = default_proxy_config();
+        let mut req = Request::new(Bytes::new());
+        assert_eq!(client_ip(&req, &config), None);
+
+        req.extensions_mut()
+            .insert(RemoteAddr("10.0.0.1:50000".parse().unwrap()));
+        assert_eq!(client_ip(&req, &config), Some("10.0.0.1".parse().unwrap()));
+
+        // Clients can't fake their IP.
+        req.headers_mut().insert(
+            "x-forwarded-for",
+            header::HeaderValue::from_static("198.51.100.1, 203.0.113.7"),
+        );
+        assert_eq!(client_ip(&req, &config), Some("10.0.0.1".parse().unwrap()));
+
+        // The proxy is behind a trusted load balancer - the client has sent `198.51.100.1`
+        // and the load balancers have appended `203.0.113.7` and `10.0.0.2`.
+        config.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+        req.headers_mut().insert(
+            "x-forwarded-for",
+            header::HeaderValue::from_static("198.51.100.1, 203.0.113.7, 10.0.0.2"),
+        );
+        assert_eq!(
+            client_ip(&req, &config),
+            Some("203.0.113.7".parse().unwrap())
+        );
+
+        req.headers_mut().insert(
+            "x-forwarded-for",
+            header::HeaderValue::from_static("invalid, 10.0.0.2"),
+        );
+        assert_eq!(client_ip(&req, &config), Some("10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn fair_queue_client_key_ignores_spoofed_ips() {
+        let config = default_proxy_config();
+        let fair_queue_config =
+            toml::from_str::<FairQueueConfig>("max_concurrent_requests = 1").unwrap();
+        let client_key = |forwarded_for: &'static str| {
+            let mut req = Request::new(Bytes::new());
+            req.extensions_mut()
+                .insert(RemoteAddr("203.0.113.7:50000".parse().unwrap()));
+            req.headers_mut().insert(
+                "x-forwarded-for",
+                header::HeaderValue::from_static(forwarded_for),
+            );
+            fair_queue_client_key(&req, &fair_queue_config, &config)
+        };
+        // An aggressive client can't get a new fair share by rotating fake IPs.
+        assert_eq!(client_key("198.51.100.1"), "203.0.113.7");
+        assert_eq!(client_key("198.51.100.2"), "203.0.113.7");
+    }
+
+    #[test]
+    fn fair_queue_client_key_ignores_unknown_keys() {
+        let config = default_proxy_config();
+        let fair_queue_config = toml::from_str::<FairQueueConfig>(
+            r#"
+            max_concurrent_requests = 1
+            client_key_header = "x-api-key"
+            weights = { premium = 3 }
+            "#,
+        )
+        .unwrap();
+        let client_key = |api_key: &'static str| {
+            let mut req = Request::new(Bytes::new());
+            req.extensions_mut()
+                .insert(RemoteAddr("203.0.113.7:50000".parse().unwrap()));
+            req.headers_mut()
+                .insert("x-api-key", header::HeaderValue::from_static(api_key));
+            fair_queue_client_key(&req, &fair_queue_config, &config)
+        };
+        assert_eq!(client_key("premium"), "premium");
+        // An aggressive client can't get a new fair share by rotating API keys.
+        assert_eq!(client_key("random-key-1"), "203.0.113.7");
+        assert_eq!(client_key("random-key-2"), "203.0.113.7");
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let trusted_proxy = |value: &str| value.parse::<TrustedProxy>();
+        let contains = |trusted_proxy: &str, ip: &str| {
+            trusted_proxy
+                .parse::<TrustedProxy>()
+                .unwrap()
+                .contains(ip.parse().unwrap())
+        };
+        assert!(contains("10.0.0.0/8", "10.255.0.1"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("127.0.0.1", "127.0.0.1"));
+        assert!(!contains("127.0.0.1", "127.0.0.2"));
+        assert!(contains("0.0.0.0/0", "203.0.113.7"));
+        assert!(contains("fd00::/8", "fd12::1"));
+        assert!(!contains("fd00::/8", "10.0.0.1"));
+        assert!(trusted_proxy("10.0.0.0/33").is_err());
+        assert!(trusted_proxy("localhost").is_err());
+        assert_eq!(
+            trusted_proxy("10.0.0.0/8").unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+    }
+
+    #[tokio::test]
+    async fn min_transfer_rate_on_stalled_body() {
+        // The origin sends the first chunk and then stalls.
+        let stalled_body = || {
+            let chunks = futures_util::StreamExt::chain(
+                futures_util::stream::iter(vec![Ok::<_, hyper::Error>("abc")]),
+                futures_util::stream::pending(),
+            );
+            with_min_transfer_rate(Body::wrap_stream(chunks), 10, Duration::from_millis(50))
+        };
+
+        let error = body_to_bytes(stalled_body()).await.unwrap_err();
+        assert!(is_caused_by::<BodyTooSlow>(&error));
+
+        // Fast enough bodies are untouched.
+        let body = with_min_transfer_rate(Body::from("abcdef"), 10, Duration::from_millis(50));
+        assert_eq!(body_to_bytes(body).await.unwrap(), "abcdef");
+    }
+
+    #[tokio::test]
+    async fn stream_too_big_response() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut config = default_proxy_config();
+        config.max_cacheable_body_size = Some(5);
+        let cached_request = CachedRequest::default();
+        let origin_response = |chunks: &'static [&'static str]| {
+            let chunks = chunks.iter().map(|chunk| Ok::<_, hyper::Error>(*chunk));
+            Response::new(Body::wrap_stream(futures_util::stream::iter(chunks)))
+        };
+
+        let (response, body) = cache_response(
+            origin_response(&["abc", "def", "ghi"]),
+            [1; 8],
+            &cached_request,
+            &cache_metadata(),
+            &header::HeaderMap::new(),
+            None,
+            false,
+            &config,
+            &db,
+        )
+        .await
+        .unwrap();
+        assert!(body.is_empty());
+        let response_body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(response_body, "abcdefghi");
+        assert!(db.get([1; 8]).unwrap().is_none());
+
+        let (response, body) = cache_response(
+            origin_response(&["ab", "cde"]),
+            [1; 8],
+            &cached_request,
+            &cache_metadata(),
+            &header::HeaderMap::new(),
+            None,
+            false,
+            &config,
+            &db,
+        )
+        .await
+        .unwrap();
+        assert_eq!(body, "abcde");
+        let response_body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(response_body, "abcde");
+        assert!(db.get([1; 8]).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn negative_cache() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut config = default_proxy_config();
+        config.cache_enabled = true;
+        config.negative_cache_validity = Some(30);
+        let state = ProxyState::default();
+        let request = || {
+            Request::builder()
+                .uri("http://example.com/catalog/movie/top.json")
+                .body(Bytes::new())
+                .unwrap()
+        };
+        let response_db_key = cache_db_key(&request());
+        let cached_request = request_cache_key(&request()).to_cached_request();
+
+        // The origin is unreachable and there isn't a cached response.
+        let failure = handle_origin_fail(
+            response_db_key,
+            false,
+            &config,
+            &db,
+            ProxyErrorKind::UpstreamFailed,
+        );
+        store_failure(
+            &failure,
+            response_db_key,
+            &cached_request,
+            &cache_metadata(),
+            false,
+            &config,
+            &db,
+        );
+
+        let response = handle_cache(request(), &db, false, &config, &state).unwrap_err();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            ProxyErrorKind::of(&response),
+            Some(ProxyErrorKind::CachedFailure)
+        );
+
+        // The cached failure isn't served as a stale response.
+        let response = handle_origin_timeout(response_db_key, false, &config, &db);
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(
+            ProxyErrorKind::of(&response),
+            Some(ProxyErrorKind::UpstreamTimeout)
+        );
+
+        // The request is sent to the origin again when the failure becomes stale.
+        let now = now_timestamp();
+        let request = with_now_getter(Arc::new(move || now + 31), async {
+            handle_cache(request(), &db, false, &config, &state)
+        })
+        .await;
+        assert!(request.is_ok());
+    }
+
+    #[tokio::test]
+    async fn vary_response() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let config = default_proxy_config();
+        let state = ProxyState::default();
+        let request = |path: &str, language: &str| {
+            Request::builder()
+                .uri(format!("http://example.com{}", path))
+                .header(header::ACCEPT_LANGUAGE, language)
+                .body(Bytes::new())
+                .unwrap()
+        };
+        let cache = |req: Request<Bytes>, vary: &'static str, body: &'static str| {
+            let response = Response::builder()
+                .header(header::VARY, vary)
+                .body(Body::from(body))
+                .unwrap();
+            let request_db_key = request_db_key(&req);
+            let cached_request = request_cache_key(&req).to_cached_request();
+            let (config, db) = (&config, &db);
+            async move {
+                cache_response(
+                    response,
+                    request_db_key,
+                    &cached_request,
+                    &cache_metadata(),
+                    req.headers(),
+                    None,
+                    false,
+                    config,
+                    db,
+                )
+                .await
+                .unwrap();
+            }
+        };
+        let cached_body = |req: Request<Bytes>| {
+            let response = handle_cache(req, &db, false, &config, &state).unwrap_err();
+            body_to_bytes(response.into_body())
+        };
+
+        let path = "/catalog/movie/top.json";
+        let req = handle_cache(request(path, "en"), &db, false, &config, &state).unwrap();
+        cache(req, "Accept-Encoding, accept-language", "en").await;
+        assert_eq!(cached_body(request(path, "en")).await.unwrap(), "en");
+
+        // Another language is another variant.
+        let req = handle_cache(request(path, "cs"), &db, false, &config, &state).unwrap();
+        cache(req, "accept-language, Accept-Encoding", "cs").await;
+        assert_eq!(cached_body(request(path, "cs")).await.unwrap(), "cs");
+        assert_eq!(cached_body(request(path, "en")).await.unwrap(), "en");
+
+        // `Vary: *` responses aren't cached.
+        let path = "/catalog/movie/other.json";
+        let req = handle_cache(request(path, "en"), &db, false, &config, &state).unwrap();
+        cache(req, "*", "en").await;
+        assert!(handle_cache(request(path, "en"), &db, false, &config, &state).is_ok());
+    }
+
+    #[tokio::test]
+    async fn preflight_cache() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let config = default_proxy_config();
+        let state = ProxyState::default();
+        let preflight = |origin: &str| {
+            Request::options("http://example.com/catalog/movie/top.json")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                .body(Bytes::new())
+                .unwrap()
+        };
+        let req = handle_cache(
+            preflight("https://web.stremio.com"),
+            &db,
+            false,
+            &config,
+            &state,
+        )
+        .unwrap();
+        let response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(
+                header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                "https://web.stremio.com",
+            )
+            .header(header::ACCESS_CONTROL_MAX_AGE, "7200")
+            .header(header::CACHE_CONTROL, "max-age=60")
+            .body(Body::empty())
+            .unwrap();
+        cache_response(
+            response,
+            request_db_key(&req),
+            &request_cache_key(&req).to_cached_request(),
+            &cache_metadata(),
+            req.headers(),
+            None,
+            false,
+            &config,
+            &db,
+        )
+        .await
+        .unwrap();
+
+        let response = handle_cache(
+            preflight("https://web.stremio.com"),
+            &db,
+            false,
+            &config,
+            &state,
+        )
+        .unwrap_err();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let cached_response = db.iter().next().unwrap().unwrap().1;
+        let cached_response =
+            bincode::deserialize::<CacheValueForDeserialization>(&cached_response).unwrap();
+        assert_eq!(cached_response.validity, 7200);
+
+        // Another origin is another variant.
+        assert!(handle_cache(preflight("https://evil.com"), &db, false, &config, &state).is_ok());
+    }
+
+    #[test]
+    fn answer_preflight() {
+        let mut config = default_proxy_config();
+        config.routes.push(ProxyRoute {
+            from: "example.com".to_owned(),
+            to: "http://localhost:8080".parse().unwrap(),
+            validate: None,
+            negotiate_manifest: None,
+            cache_partition: None,
+            body_replacements: BTreeMap::new(),
+            json_filter: None,
+            verbose: None,
+            allowed_countries: None,
+            blocked_countries: None,
+            to_by_region: BTreeMap::new(),
+            head_revalidation: None,
+            cache_private: None,
+            group: None,
+            signing_profile: None,
+            fix_content_type: None,
+            transcode_to_utf8: None,
+            latency_slo: None,
+            answer_preflight: Some(true),
+            strategy: None,
+            cors: None,
+        });
+        let request = |method: Method| {
+            let request = Request::builder()
+                .method(method)
+                .uri("http://example.com/catalog/movie/top.json")
+                .header(header::ORIGIN, "https://web.stremio.com")
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "x-custom")
+                .body(Bytes::new())
+                .unwrap();
+            handle_routes(request, &config, &IndexedRoutes::default()).unwrap()
+        };
+
+        let response = handle_preflight(request(Method::OPTIONS), &config).unwrap_err();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "x-custom");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "86400");
+
+        assert!(handle_preflight(request(Method::GET), &config).is_ok());
+    }
+
+    #[test]
+    fn configured_preflight() {
+        let mut config = default_proxy_config();
+        config.routes.push(
+            toml::from_str(
+                r#"
+                from = "example.com"
+                to = "http://localhost:8080"
+                "#,
+            )
+            .unwrap(),
+        );
+        let request = |preflight: bool, config: &ProxyConfig| {
+            let mut request = Request::builder()
+                .method(Method::OPTIONS)
+                .uri("http://example.com/catalog/movie/top.json");
+            if preflight {
+                request = request
+                    .header(header::ORIGIN, "https://web.stremio.com")
+                    .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                    .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "x-custom");
+            }
+            let request = request.body(Bytes::new()).unwrap();
+            handle_routes(request, config, &IndexedRoutes::default()).unwrap()
+        };
+        // Forwarded to the origin.
+        assert!(handle_preflight(request(true, &config), &config).is_ok());
+
+        config.preflight = Some(
+            toml::from_str(
+                r#"
+                allowed_methods = ["GET", "HEAD"]
+                allowed_headers = ["content-type"]
+                max_age = 60
+                "#,
+            )
+            .unwrap(),
+        );
+        let response = handle_preflight(request(true, &config), &config).unwrap_err();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, HEAD");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "60");
+
+        let response = handle_preflight(request(false, &config), &config).unwrap_err();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD");
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        config.preflight = Some(toml::from_str("").unwrap());
+        let response = handle_preflight(request(false, &config), &config).unwrap_err();
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, OPTIONS");
+        let response = handle_preflight(request(true, &config), &config).unwrap_err();
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET"
+        );
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_MAX_AGE], "86400");
+    }
+
+    #[test]
+    fn cors() {
+        let mut config = default_proxy_config();
+        for route in &[
+            r#"
+            from = "example.com"
+            to = "http://localhost:8080"
+            "#,
+            r#"
+            from = "private.com"
+            to = "http://localhost:8080"
+            cors = { allowed_origins = ["https://web.stremio.com"], allowed_methods = ["GET"], max_age = 60 }
+            "#,
+        ] {
+            config.routes.push(toml::from_str(route).unwrap());
+        }
+        let request = |uri: &str, origin: &str, config: &ProxyConfig| {
+            let request = Request::builder()
+                .method(Method::OPTIONS)
+                .uri(uri)
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .body(Bytes::new())
+                .unwrap();
+            handle_routes(request, config, &IndexedRoutes::default()).unwrap()
+        };
+        let origin_response = || {
+            Response::builder()
+                .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "https://evil.com")
+                .header(header::VARY, "accept-encoding")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // CORS isn't configured.
+        let req = request("http://example.com/manifest.json", "https://a.com", &config);
+        assert!(response_cors_headers(&req, &config).is_empty());
+        assert!(handle_preflight(req, &config).is_ok());
+
+        config.cors = Some(toml::from_str("").unwrap());
+        let req = request("http://example.com/manifest.json", "https://a.com", &config);
+        let mut response = origin_response();
+        apply_cors_headers(&mut response, &response_cors_headers(&req, &config));
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        let response = handle_preflight(req, &config).unwrap_err();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "POST"
+        );
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_MAX_AGE], "86400");
+
+        // The route settings replace the global ones.
+        let req = request(
+            "http://private.com/manifest.json",
+            "https://web.stremio.com",
+            &config,
+        );
+        let mut response = origin_response();
+        apply_cors_headers(&mut response, &response_cors_headers(&req, &config));
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://web.stremio.com"
+        );
+        let vary = headers.get_all(header::VARY).iter().collect::<Vec<_>>();
+        assert_eq!(vary, vec!["accept-encoding", "origin"]);
+        let response = handle_preflight(req, &config).unwrap_err();
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET"
+        );
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_MAX_AGE], "60");
+
+        let req = request(
+            "http://private.com/manifest.json",
+            "https://evil.com",
+            &config,
+        );
+        let mut response = origin_response();
+        apply_cors_headers(&mut response, &response_cors_headers(&req, &config));
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn head_from_cache() {
+        let request = |method: Method| {
+            Request::builder()
+                .method(method)
+                .uri("http://example.com/catalog/movie/top.json")
+                .body(Bytes::new())
+                .unwrap()
+        };
+        let head_request = request(Method::HEAD);
+        assert_eq!(
+            request_cache_key(&head_request).to_db_key(),
+            request_cache_key(&request(Method::GET)).to_db_key()
+        );
+        assert_ne!(
+            request_cache_key(&request(Method::POST)).to_db_key(),
+            request_cache_key(&request(Method::GET)).to_db_key()
+        );
+
+        let response = without_body(Response::new(Body::from("cached body")));
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "11");
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+
+        // `Content-Length` of the origin response to the HEAD request is kept.
+        let response = Response::builder()
+            .header(header::CONTENT_LENGTH, "100")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            without_body(response).headers()[header::CONTENT_LENGTH],
+            "100"
+        );
+    }
+
+    #[tokio::test]
+    async fn cached_headers() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut config = default_proxy_config();
+        config.max_cached_header_size = 10;
+        let origin_response = || {
+            Response::builder()
+                .header(header::CONTENT_TYPE, "text/plain")
+                .header(header::SET_COOKIE, "id=1")
+                .header(header::CONNECTION, "close")
+                .header("x-huge", "x".repeat(11))
+                .body(Body::from("body"))
+                .unwrap()
+        };
+        let cached_request = CachedRequest::default();
+        let cached_header_names = || {
+            let cached_response = db.get([1; 8]).unwrap().unwrap();
+            let cached_response =
+                bincode::deserialize::<CacheValueForDeserialization>(cached_response.as_ref())
+                    .unwrap();
+            cached_response
+                .headers
+                .keys()
+                .map(header::HeaderName::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        let (response, _) = cache_response(
+            origin_response(),
+            [1; 8],
+            &cached_request,
+            &cache_metadata(),
+            &header::HeaderMap::new(),
+            None,
+            false,
+            &config,
+            &db,
+        )
+        .await
+        .unwrap();
+        // The client still gets all headers.
+        assert_eq!(response.headers().len(), 4);
+        assert_eq!(cached_header_names(), vec!["content-type"]);
+
+        config.cached_headers = Some(vec!["Set-Cookie".to_owned(), "connection".to_owned()]);
+        cache_response(
+            origin_response(),
+            [1; 8],
+            &cached_request,
+            &cache_metadata(),
+            &header::HeaderMap::new(),
+            None,
+            false,
+            &config,
+            &db,
+        )
+        .await
+        .unwrap();
+        assert_eq!(cached_header_names(), vec!["set-cookie"]);
+    }
+
+    #[tokio::test]
+    async fn coalesced_cache_writes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut config = default_proxy_config();
+        config.cache_write_coalescing_window = Some(60);
+        let cached_request = CachedRequest::default();
+        let request_headers = header::HeaderMap::new();
+        let metadata = cache_metadata();
+        let cache_at = |now: i64, body: &'static str| {
+            with_now_getter(
+                Arc::new(move || now),
+                cache_response(
+                    Response::new(Body::from(body)),
+                    [1; 8],
+                    &cached_request,
+                    &metadata,
+                    &request_headers,
+                    None,
+                    false,
+                    &config,
+                    &db,
+                ),
+            )
+        };
+        let cached_timestamp = || {
+            let cached_response = db.get([1; 8]).unwrap().unwrap();
+            bincode::deserialize::<CacheValueForDeserialization>(cached_response.as_ref())
+                .unwrap()
+                .timestamp
+        };
+
+        cache_at(1_000_000, "body").await.unwrap();
+        assert_eq!(cached_timestamp(), 1_000_000);
+
+        // The identical response isn't written again.
+        let (_, body) = cache_at(1_000_010, "body").await.unwrap();
+        assert_eq!(body, "body");
+        assert_eq!(cached_timestamp(), 1_000_000);
+
+        cache_at(1_000_020, "new body").await.unwrap();
+        assert_eq!(cached_timestamp(), 1_000_020);
+
+        // The window is over.
+        cache_at(1_000_100, "new body").await.unwrap();
+        assert_eq!(cached_timestamp(), 1_000_100);
+    }
+
+    #[tokio::test]
+    async fn cached_response_date_and_expires() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let state = ProxyState::default();
+        let request = || {
+            Request::builder()
+                .uri("http://example.com/catalog/movie/top.json")
+                .body(Bytes::new())
+                .unwrap()
+        };
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::DATE,
+            "Thu, 01 Jan 1970 00:00:00 GMT".parse().unwrap(),
+        );
+        let cached_request = request_cache_key(&request()).to_cached_request();
+        // Cached 30 seconds before `now`.
+        let now = 1_600_000_000;
+        let cached_response = with_now_getter(Arc::new(move || now - 30), async {
+            CacheValueForSerialization::new(&cached_request, StatusCode::OK, &headers, b"", 100)
+        })
+        .await;
+        db.insert(
+            cache_db_key(&request()),
+            bincode::serialize(&cached_response).unwrap(),
+        )
+        .unwrap();
+
+        let response = with_now_getter(Arc::new(move || now), async {
+            handle_cache(request(), &db, false, &default_proxy_config(), &state).unwrap_err()
+        })
+        .await;
+        assert_eq!(response.headers()[header::DATE], http_date(now));
+        assert_eq!(
+            response.headers()[header::DATE],
+            "Sun, 13 Sep 2020 12:26:40 GMT"
+        );
+        // 70 seconds of the remaining validity.
+        assert_eq!(response.headers()[header::EXPIRES], http_date(now + 70));
+
+        // Stale responses (served when the origin fails) expire immediately.
+        let response = with_now_getter(Arc::new(move || now + 200), async {
+            handle_origin_fail(
+                cache_db_key(&request()),
+                false,
+                &default_proxy_config(),
+                &db,
+                ProxyErrorKind::UpstreamFailed,
+            )
+        })
+        .await;
+        assert_eq!(response.headers()[header::DATE], http_date(now + 200));
+        assert_eq!(response.headers()[header::EXPIRES], http_date(now + 200));
+    }
+
+    // ------ request_deadline ------
+
+    #[test]
+    fn request_deadline_budget() {
+        let mut proxy_config = default_proxy_config();
+        let request = |deadline: &str| {
+            Request::builder()
+                .header("X-Request-Deadline", deadline)
+                .body(())
+                .unwrap()
+        };
+        let received_at = Instant::now();
+        assert_eq!(
+            request_deadline(&request("500"), &proxy_config, received_at),
+            None
+        );
+
+        proxy_config.request_deadline = Some(RequestDeadlineConfig {
+            header: "x-request-deadline".to_owned(),
+            overhead_ms: 10,
+        });
+        assert_eq!(
+            request_deadline(&request("500"), &proxy_config, received_at),
+            Some(received_at + Duration::from_millis(490))
+        );
+        assert_eq!(
+            request_deadline(&request("5"), &proxy_config, received_at),
+            Some(received_at)
+        );
+        assert_eq!(
+            request_deadline(&request("soon"), &proxy_config, received_at),
+            None
+        );
+
+        let mut origin_request = Request::new(Bytes::new());
+        propagate_deadline(
+            &mut origin_request,
+            Duration::from_millis(300),
+            &proxy_config,
+        );
+        assert_eq!(origin_request.headers()["x-request-deadline"], "300");
+    }
+
+    // ------ cache_db_key ------
+
+    #[test]
+    fn cache_partition() {
+        let request = |uri: &str, token: Option<&str>| {
+            let mut request = Request::builder().uri(uri);
+            if let Some(token) = token {
+                request = request.header("authorization", token);
+            }
+            let mut request = request.body(Bytes::new()).unwrap();
+            let route = toml::from_str::<ProxyRoute>(
+                r#"
+                from = "localhost:5000"
+                to = "http://localhost:8080"
+                cache_partition = { header = "authorization", query_param = "token" }
+                "#,
+            )
+            .unwrap();
+            request.extensions_mut().insert(RouteMatch::new(
+                Arc::new(route),
+                "http://localhost:8080".parse().unwrap(),
+                String::new(),
+                &default_proxy_config(),
+            ));
+            request
+        };
+        let uri = "http://localhost:8080/catalog/movie/top.json";
+
+        assert_eq!(
+            cache_db_key(&request(uri, Some("a"))),
+            cache_db_key(&request(uri, Some("a")))
+        );
+        assert_ne!(
+            cache_db_key(&request(uri, Some("a"))),
+            cache_db_key(&request(uri, Some("b")))
+        );
+        assert_ne!(
+            cache_db_key(&request(uri, Some("a"))),
+            cache_db_key(&request(uri, None))
+        );
+        assert_eq!(
+            cache_partition_user(
+                &request(&format!("{}?x=1&token=c", uri), None),
+                &CachePartitionConfig {
+                    header: Some("authorization".to_owned()),
+                    query_param: Some("token".to_owned()),
+                }
+            ),
+            Some("c")
+        );
+    }
+
+    #[tokio::test]
+    async fn cache_key_strategy() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut config = default_proxy_config();
+        let request = |uri: &str, body: &'static str, config: &ProxyConfig| {
+            let request = Request::builder().uri(uri).body(Bytes::from(body)).unwrap();
+            handle_cache_key(request, config)
+        };
+        let uri = "http://localhost:8080/catalog/movie/top.json";
+        let uri_with_params = format!("{}/?skip=100&utm_source=x", uri);
+        assert_ne!(
+            cache_db_key(&request(uri, "", &config)),
+            cache_db_key(&request(uri, "{}", &config))
+        );
+
+        config.cache_key = Some(crate::proxy::CacheKeyConfig {
+            ignored_query_params: vec!["utm_source".to_owned()],
+            ignored_headers: vec!["User-Agent".to_owned()],
+            normalize_trailing_slash: true,
+            include_body: false,
+        });
+        assert_eq!(
+            cache_db_key(&request(&uri_with_params, "", &config)),
+            cache_db_key(&request(&format!("{}?skip=100", uri), "{}", &config))
+        );
+        assert_ne!(
+            cache_db_key(&request(&uri_with_params, "", &config)),
+            cache_db_key(&request(uri, "", &config))
+        );
+        // The request is sent to the origin unchanged.
+        assert_eq!(
+            request(&uri_with_params, "", &config).uri(),
+            &*uri_with_params
+        );
+
+        let response = Response::builder()
+            .header(header::VARY, "user-agent, accept-language")
+            .body(Body::from("body"))
+            .unwrap();
+        cache_response(
+            response,
+            [1; 8],
+            &CachedRequest::default(),
+            &cache_metadata(),
+            &header::HeaderMap::new(),
+            None,
+            false,
+            &config,
+            &db,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            cache::vary_names(&db, [1; 8]).unwrap(),
+            vec!["accept-language"]
+        );
+    }
+
+    #[test]
+    fn private_requests_and_responses() {
+        let mut config = default_proxy_config();
+        for (from, cache_private) in &[("shared.com", None), ("private.com", Some(true))] {
+            config.routes.push(ProxyRoute {
+                from: (*from).to_owned(),
+                to: "http://localhost:8080".parse().unwrap(),
+                validate: None,
+                negotiate_manifest: None,
+                cache_partition: None,
+                body_replacements: BTreeMap::new(),
+                json_filter: None,
+                verbose: None,
+                allowed_countries: None,
+                blocked_countries: None,
+                to_by_region: BTreeMap::new(),
+                head_revalidation: None,
+                cache_private: *cache_private,
+                group: None,
+                signing_profile: None,
+                fix_content_type: None,
+                transcode_to_utf8: None,
+                latency_slo: None,
+                answer_preflight: None,
+                strategy: None,
+                cors: None,
+            });
+        }
+        let request = |host: &str, credentials: Option<header::HeaderName>| {
+            let mut request = Request::builder().uri(format!("https://{}/manifest.json", host));
+            if let Some(credentials) = credentials {
+                request = request.header(credentials, "secret");
+            }
+            handle_routes(
+                request.body(Bytes::new()).unwrap(),
+                &config,
+                &IndexedRoutes::default(),
+            )
+            .unwrap()
+        };
+
+        assert!(!is_private_request(&request("shared.com", None)));
+        assert!(is_private_request(&request(
+            "shared.com",
+            Some(header::AUTHORIZATION)
+        )));
+        assert!(is_private_request(&request(
+            "shared.com",
+            Some(header::COOKIE)
+        )));
+        assert!(!is_private_request(&request(
+            "private.com",
+            Some(header::COOKIE)
+        )));
+
+        let response = Response::builder()
+            .header(header::SET_COOKIE, "id=1")
+            .body(Body::empty())
+            .unwrap();
+        let route_match = |host| {
+            request(host, None)
+                .extensions()
+                .get::<RouteMatch>()
+                .cloned()
+        };
+        assert!(is_private_response(
+            &response,
+            route_match("shared.com").as_ref()
+        ));
+        assert!(!is_private_response(
+            &response,
+            route_match("private.com").as_ref()
+        ));
+        assert!(!is_private_response(
+            &Response::default(),
+            route_match("shared.com").as_ref()
+        ));
+    }
+
+    // ------ test_route ------
+
+    #[test]
+    fn test_route_report() {
+        let mut config = default_proxy_config();
+        config.routes.push(ProxyRoute {
+            from: "example.com".to_owned(),
+            to: "http://localhost:8080".parse().unwrap(),
+            validate: None,
+            negotiate_manifest: None,
+            cache_partition: None,
+            body_replacements: BTreeMap::new(),
+            json_filter: None,
+            verbose: None,
+            allowed_countries: None,
+            blocked_countries: None,
+            to_by_region: BTreeMap::new(),
+            head_revalidation: None,
+            cache_private: None,
+            group: None,
+            signing_profile: None,
+            fix_content_type: None,
+            transcode_to_utf8: None,
+            latency_slo: None,
+            answer_preflight: None,
+            strategy: None,
+            cors: None,
+        });
+
+        let report = test_route("http://example.com/catalog/movie/top.json", &config).unwrap();
+        assert_eq!(report.route.as_deref(), Some("example.com"));
+        assert_eq!(
+            report.upstream_uri.unwrap(),
+            "http://localhost:8080/catalog/movie/top.json"
+        );
+        assert_eq!(report.validation_passed, Some(true));
+        assert_eq!(report.cache_key.unwrap().len(), 16);
+
+        let report = test_route("http://example.com/unknown", &config).unwrap();
+        assert_eq!(report.validation_passed, Some(false));
+        assert_eq!(report.rejected_with, Some(StatusCode::BAD_REQUEST));
 
-/// Clear cache and return simple 200 response when the predefined URL path is matched.
-fn handle_clear_cache(
-    req: Request<Bytes>,
-    proxy_config: &ProxyConfig,
-    db: &Db,
-) -> Result<Request<Bytes>, Response<Body>> {
-    if req.uri().path() == proxy_config.clear_cache_url_path {
-        if let Err(error) = db.clear() {
-            eprintln!("cache clearing failed: {}", error);
-            return Err(Response::new(Body::from("Cache clearing failed.")));
-        }
-        return Err(Response::new(Body::from("Cache cleared.")));
+        let report = test_route("http://other.com/manifest.json", &config).unwrap();
+        assert!(report.route.is_none());
+        assert_eq!(report.rejected_with, Some(StatusCode::NOT_FOUND));
     }
-    Ok(req)
-}
 
-/// Return response with text "Proxy is ready." when the predefined URL path is matched.
-fn handle_status(
-    req: Request<Bytes>,
-    proxy_config: &ProxyConfig,
-) -> Result<Request<Bytes>, Response<Body>> {
-    if req.uri().path() == proxy_config.status_url_path {
-        return Err(Response::new(Body::from("Proxy is ready.")));
-    }
-    Ok(req)
-}
+    // ------ config problems ------
 
-/// Update request's URI to point to another address according to predefined routes.
-///
-/// # Errors
-///
-/// - Returns 200 and the content of `landing.html` when the incoming request does not match any routes.
-/// - Returns `BAD_REQUEST` when request validation fails.
-/// - Returns `INTERNAL_SERVER_ERROR` response if the new address is invalid.
-fn handle_routes(
-    mut req: Request<Bytes>,
-    proxy_config: &ProxyConfig,
-) -> Result<Request<Bytes>, Response<Body>> {
-    let uri = req.uri();
-    // Try to get the host directly from `req.uri`, then from `host` header and then represent it as relative url.
-    let host = uri
-        .host()
-        .or_else(|| {
-            req.headers()
-                .get("host")
-                .and_then(|value| value.to_str().ok())
-        })
-        .unwrap_or_default();
+    #[test]
+    fn route_groups() {
+        let mut config = toml::from_str::<ProxyConfig>(
+            r#"
+            reload_config_url_path = "/reload-proxy-config"
+            clear_cache_url_path = "/clear-cache"
+            status_url_path = "/status"
+            db_directory = "proxy_db"
+            ip = "0.0.0.0"
+            default_port = 5000
+            cache_enabled = true
+            default_cache_validity = 600
+            cache_stale_threshold_on_fail = 172_800
+            timeout = 20
+            shutdown_grace_period = 10
+            verbose = false
 
-    // http://example.com/abc/efg?x=1&y=2 -> example.com/abc/efg?x=1&y=2
-    let from = format!("{}{}{}", host, uri.path(), uri.query().unwrap_or_default());
+            [route_groups.catalogs]
+            head_revalidation = true
+            validate = false
+            body_replacements = { "a" = "b", "c" = "d" }
 
-    // Get the first matching route or return 404 / a landing file.
-    let route = proxy_config
-        .routes
-        .iter()
-        .find(|route| from.starts_with(&route.from));
-    let route = match route {
-        Some(route) => route,
-        None => {
-            if uri.path() == "/" {
-                // Return `landing.html`.
-                let response =
-                    Response::new(Body::from(include_bytes!("../../landing.html").as_ref()));
-                return Err(response);
-            } else {
-                // Return 404
-                let mut response = Response::new(Body::from(
-                    "404. The requested URL was not found on this server.",
-                ));
-                *response.status_mut() = StatusCode::NOT_FOUND;
-                return Err(response);
-            }
-        }
-    };
+            [route_groups.eu_catalogs]
+            group = "catalogs"
+            allowed_countries = ["DE"]
 
-    // @TODO: Replace `trim_start_matches` with `strip_prefix` once stable.
-    // example.com/abc/efg?x=1&y=2 -> /abc/efg?x=1&y=2  (if matching route's `from` is "example.com")
-    let routed_path_and_query = from.trim_start_matches(&route.from);
+            [[routes]]
+            from = "catalog.com"
+            to = "http://localhost:8080"
+            group = "eu_catalogs"
+            validate = true
+            body_replacements = { "a" = "x" }
 
-    // Request validation.
-    if route.validate != Some(false) && !validations::validate_request(&req, routed_path_and_query)
-    {
-        let mut response = Response::new(Body::from("Invalid request."));
-        *response.status_mut() = StatusCode::BAD_REQUEST;
-        return Err(response);
+            [[routes]]
+            from = "other.com"
+            to = "http://localhost:8081"
+            "#,
+        )
+        .unwrap();
+        config.apply_route_groups().unwrap();
+
+        let route = &config.routes[0];
+        assert_eq!(route.head_revalidation, Some(true));
+        assert_eq!(route.validate, Some(true));
+        assert_eq!(route.allowed_countries, Some(vec!["DE".to_owned()]));
+        assert_eq!(route.body_replacements["a"], "x");
+        assert_eq!(route.body_replacements["c"], "d");
+        assert_eq!(config.routes[1].head_revalidation, None);
+
+        config.routes[1].group = Some("missing".to_owned());
+        assert_eq!(
+            config.apply_route_groups().unwrap_err(),
+            "route 'other.com' references unknown route group 'missing'"
+        );
+
+        config.routes[1].group = None;
+        config.route_groups.get_mut("catalogs").unwrap().group = Some("eu_catalogs".to_owned());
+        assert_eq!(
+            config.apply_route_groups().unwrap_err(),
+            "route group 'eu_catalogs' is part of a cycle"
+        );
     }
 
-    // @TODO: Replace `trim_start_matches` with `strip_prefix` once stable.
-    // /abc/efg?x=1&y=2 -> http://localhost:8000/abc/efgx=1&y=2 (if matching route's `to` is "http://localhost:8000")
-    *req.uri_mut() = match format!(
-        "{}{}",
-        route.to,
-        routed_path_and_query.trim_start_matches('/')
-    )
-    .parse()
-    {
-        Ok(uri) => uri,
-        Err(error) => {
-            eprintln!("Invalid URI in `handle_routes`: {}", error);
-            let mut response = Response::new(Body::from("Cannot route to invalid URI."));
-            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return Err(response);
+    #[test]
+    fn config_problems() {
+        let mut config = default_proxy_config();
+        assert!(config.problems().is_empty());
+
+        for from in &["example.com", "example.com/catalog", "example.com"] {
+            config.routes.push(ProxyRoute {
+                from: (*from).to_owned(),
+                to: "http://localhost:8081".parse().unwrap(),
+                validate: None,
+                negotiate_manifest: None,
+                cache_partition: None,
+                body_replacements: BTreeMap::new(),
+                json_filter: None,
+                verbose: None,
+                allowed_countries: None,
+                blocked_countries: None,
+                to_by_region: BTreeMap::new(),
+                head_revalidation: None,
+                cache_private: None,
+                group: None,
+                signing_profile: None,
+                fix_content_type: None,
+                transcode_to_utf8: None,
+                latency_slo: None,
+                answer_preflight: None,
+                strategy: None,
+                cors: None,
+            });
         }
-    };
+        config.config_dump_url_path = Some("/status".to_owned());
+        assert_eq!(
+            config.problems(),
+            vec![
+                "route #3 'example.com' is unreachable because a previous route has the same 'from'",
+                "'status_url_path' and 'config_dump_url_path' have the same value '/status'",
+            ]
+        );
+    }
 
-    // Replace `host` header with the new one from `Request`'s `uri`.
-    if let Some(host) = req.uri().host().and_then(|host| host.parse().ok()) {
-        req.headers_mut().insert("host", host);
-    } else {
-        eprintln!("Missing host in the request uri: {}", req.uri());
-        let mut response = Response::new(Body::from("Cannot route to URI without host."));
-        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-        return Err(response);
+    #[test]
+    fn config_validation() {
+        let mut config = default_proxy_config();
+        assert!(config.validate().is_ok());
+
+        config.timeout = 0;
+        config.response_timeout = Some(30);
+        config.routes.push(ProxyRoute {
+            from: "example.com".to_owned(),
+            to: "ftp://localhost:8081".parse().unwrap(),
+            validate: None,
+            negotiate_manifest: None,
+            cache_partition: None,
+            body_replacements: BTreeMap::new(),
+            json_filter: None,
+            verbose: None,
+            allowed_countries: None,
+            blocked_countries: None,
+            to_by_region: BTreeMap::new(),
+            head_revalidation: None,
+            cache_private: None,
+            group: None,
+            signing_profile: None,
+            fix_content_type: None,
+            transcode_to_utf8: None,
+            latency_slo: None,
+            answer_preflight: None,
+            strategy: None,
+            cors: None,
+        });
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec![
+                "route 'example.com' has an unsupported scheme in 'ftp://localhost:8081/' (expected http or https)",
+                "'timeout' has to be greater than 0",
+            ]
+        );
     }
 
-    Ok(req)
-}
+    #[test]
+    fn overlapping_preflight_settings_rejected() {
+        let mut config = default_proxy_config();
+        config.routes.push(
+            toml::from_str::<ProxyRoute>(
+                r#"
+                from = "example.com"
+                to = "http://localhost:8080"
+                answer_preflight = true
+                "#,
+            )
+            .unwrap(),
+        );
+        assert!(config.validate().is_ok());
 
-/// Return cached response if possible.
-///
-/// # Errors
-/// - Returns cached response.
-/// - Returns `INTERNAL_SERVER_ERROR` response when DB reading fails.
-/// - Returns `INTERNAL_SERVER_ERROR` response when deserialization of a cached response fails.
-fn handle_cache(
-    req: Request<Bytes>,
-    db: &Db,
-    verbose: bool,
-) -> Result<Request<Bytes>, Response<Body>> {
-    let cache_key = CacheKey {
-        method: req.method(),
-        uri: req.uri(),
-        body: req.body(),
-    };
+        config.preflight = Some(PreflightConfig::default());
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec!["route 'example.com' has 'answer_preflight' together with 'preflight' - remove one of them"]
+        );
 
-    match db.get(cache_key.to_db_key()) {
-        // The cached response has been found.
-        Ok(Some(cached_response)) => {
-            Err(
-                match bincode::deserialize::<CacheValueForDeserialization>(cached_response.as_ref())
-                {
-                    // Return the cached response.
-                    Ok(cached_response) => {
-                        // Is cached response still valid?
-                        if now_timestamp()
-                            > cached_response.timestamp + i64::from(cached_response.validity)
-                        {
-                            return Ok(req);
-                        }
+        config.routes[0].answer_preflight = None;
+        config.routes[0].cors = Some(toml::from_str("").unwrap());
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec!["'preflight' can't be combined with 'cors' - set 'allowed_methods', 'allowed_headers' and 'max_age' in 'cors' instead"]
+        );
+    }
 
-                        if verbose {
-                            println!("response has been successfully loaded from the cache");
-                        }
+    // ------ validity ------
 
-                        let mut response = Response::new(Body::from(cached_response.body));
-                        *response.status_mut() = cached_response.status;
-                        *response.headers_mut() = cached_response.headers;
-                        response
-                    }
-                    // Deserialization failed.
-                    Err(error) => {
-                        eprintln!("Cannot deserialize a response`: {}", error);
-                        let mut response =
-                            Response::new(Body::from("Cannot deserialize a cached response."));
-                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                        response
-                    }
-                },
-            )
-        }
+    #[test]
+    fn resource_ttls() {
+        let mut config = default_proxy_config();
+        assert_eq!(config.default_cache_validity_for(Some("stream")), Some(600));
 
-        // The cached response hasn't been found => just return `req` without any changes.
-        Ok(None) => Ok(req),
+        config.resource_ttls = Some(toml::from_str("subtitles = 0").unwrap());
+        assert_eq!(config.default_cache_validity_for(Some("stream")), Some(300));
+        assert_eq!(
+            config.default_cache_validity_for(Some("meta")),
+            Some(86_400)
+        );
+        assert_eq!(
+            config.default_cache_validity_for(Some("catalog")),
+            Some(600)
+        );
+        assert_eq!(config.default_cache_validity_for(Some("subtitles")), None);
+        assert_eq!(config.default_cache_validity_for(None), Some(600));
+    }
 
-        // DB reading failed.
-        Err(error) => {
-            eprintln!("Cannot read from DB`: {}", error);
-            let mut response = Response::new(Body::from("Cannot read from the cache."));
-            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            Err(response)
-        }
+    #[test]
+    fn validity_from_max_age() {
+        let response = Response::builder()
+            .header("cache-control", "max-age=60")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(validity_from_response(&response, 600), 60);
+        assert_eq!(validity_from_response(&Response::default(), 600), 600);
     }
-}
 
-// ------ ------- TESTS ------ ------
+    #[test]
+    fn ttl_jitter() {
+        assert_eq!(with_ttl_jitter(600, [7; 8], None), 600);
+        let validities = (0..100_u8)
+            .map(|key| with_ttl_jitter(600, [key; 8], Some(10)))
+            .collect::<Vec<_>>();
+        assert!(validities
+            .iter()
+            .all(|validity| (540..=600).contains(validity)));
+        // Validities are spread.
+        assert!(validities.iter().collect::<BTreeSet<_>>().len() > 10);
+        // The jitter is stable.
+        assert_eq!(
+            with_ttl_jitter(600, [42; 8], Some(10)),
+            with_ttl_jitter(600, [42; 8], Some(10))
+        );
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::ProxyRoute;
-    use std::net::{IpAddr, Ipv4Addr};
-    use std::path::PathBuf;
+    #[test]
+    fn head_validators() {
+        let headers = |validators: &[(header::HeaderName, &'static str)]| {
+            validators
+                .iter()
+                .map(|(name, value)| (name.clone(), header::HeaderValue::from_static(value)))
+                .collect::<header::HeaderMap>()
+        };
+        let last_modified = || (header::LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT");
+        let cached_headers = headers(&[last_modified(), (header::CONTENT_LENGTH, "42")]);
 
-    // ------ handle_status ------
+        assert!(is_unchanged(
+            &cached_headers,
+            &headers(&[last_modified(), (header::CONTENT_LENGTH, "42")])
+        ));
+        assert!(!is_unchanged(
+            &cached_headers,
+            &headers(&[last_modified(), (header::CONTENT_LENGTH, "43")])
+        ));
+        assert!(!is_unchanged(&cached_headers, &headers(&[last_modified()])));
+        // Transformed bodies are cached without `Content-Length`.
+        assert!(is_unchanged(
+            &headers(&[last_modified()]),
+            &headers(&[last_modified(), (header::CONTENT_LENGTH, "43")])
+        ));
+        // There is nothing to compare.
+        assert!(!is_unchanged(&headers(&[]), &headers(&[])));
+    }
 
-    #[tokio::test]
-    async fn status() {
-        let request = Request::builder()
-            .uri("https://example.com/status")
+    #[test]
+    fn revalidation_near_expiry() {
+        let mut proxy_config = default_proxy_config();
+        proxy_config.revalidation = Some(RevalidationConfig {
+            remaining_validity: 10,
+            queue_size: 10,
+            interval_ms: 0,
+        });
+        let mut request = Request::builder()
+            .uri("http://localhost:8080/catalog/movie/top.json")
             .body(Bytes::new())
             .unwrap();
-        let config = default_proxy_config();
+        let route = toml::from_str::<ProxyRoute>(
+            r#"
+            from = "example.com"
+            to = "http://localhost:8080"
+            "#,
+        )
+        .unwrap();
+        request.extensions_mut().insert(RouteMatch::new(
+            Arc::new(route),
+            "http://localhost:8080".parse().unwrap(),
+            "/catalog/movie/top.json".to_owned(),
+            &proxy_config,
+        ));
+        let cached_request = request_cache_key(&request).to_cached_request();
+        let cached_response = |age: i64| {
+            let headers = header::HeaderMap::new();
+            let mut cached_response = CacheValueForSerialization::new(
+                &cached_request,
+                StatusCode::OK,
+                &headers,
+                b"",
+                100,
+            );
+            cached_response.timestamp -= age;
+            cached_response.monotonic_timestamp -= age;
+            bincode::deserialize::<CacheValueForDeserialization>(
+                &bincode::serialize(&cached_response).unwrap(),
+            )
+            .unwrap()
+        };
+
+        assert!(revalidation_refresh(&request, &cached_response(50), &proxy_config).is_none());
+        let cache_refresh =
+            revalidation_refresh(&request, &cached_response(95), &proxy_config).unwrap();
+        assert_eq!(cache_refresh.response_db_key, cache_db_key(&request));
+        assert_eq!(cache_refresh.uri, *request.uri());
+    }
+
+    #[test]
+    fn stale_while_revalidate() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let state = ProxyState::default();
+        let mut proxy_config = default_proxy_config();
+        proxy_config.stale_while_revalidate = Some(30);
+        let route = Arc::new(
+            toml::from_str::<ProxyRoute>(
+                r#"
+                from = "example.com"
+                to = "http://localhost:8080"
+                "#,
+            )
+            .unwrap(),
+        );
+        let request = || {
+            let mut request = Request::builder()
+                .uri("http://localhost:8080/catalog/movie/top.json")
+                .body(Bytes::new())
+                .unwrap();
+            request.extensions_mut().insert(RouteMatch::new(
+                Arc::clone(&route),
+                "http://localhost:8080".parse().unwrap(),
+                "/catalog/movie/top.json".to_owned(),
+                &default_proxy_config(),
+            ));
+            request
+        };
+        let cache = |age: i64| {
+            let headers = header::HeaderMap::new();
+            let cached_request = request_cache_key(&request()).to_cached_request();
+            let mut cached_response = CacheValueForSerialization::new(
+                &cached_request,
+                StatusCode::OK,
+                &headers,
+                b"stale",
+                100,
+            );
+            cached_response.timestamp -= age;
+            cached_response.monotonic_timestamp -= age;
+            db.insert(
+                cache_db_key(&request()),
+                bincode::serialize(&cached_response).unwrap(),
+            )
+            .unwrap();
+        };
 
-        let response = handle_status(request, &config).unwrap_err();
+        // The stale response is served and refreshed.
+        cache(120);
+        let response = handle_cache(request(), &db, false, &proxy_config, &state).unwrap_err();
         assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.extensions().get::<CacheRefresh>().is_some());
 
-        let body = body_to_bytes(response.into_body()).await.unwrap();
-        assert_eq!(body, "Proxy is ready.");
+        // Too old.
+        cache(140);
+        assert!(handle_cache(request(), &db, false, &proxy_config, &state).is_ok());
+
+        // Fresh responses aren't refreshed without `ProxyConfig::revalidation`.
+        cache(90);
+        let response = handle_cache(request(), &db, false, &proxy_config, &state).unwrap_err();
+        assert!(response.extensions().get::<CacheRefresh>().is_none());
+
+        // Other requests don't get stale responses.
+        proxy_config.stale_while_revalidate = None;
+        cache(120);
+        assert!(handle_cache(request(), &db, false, &proxy_config, &state).is_ok());
     }
 
     // ------ handle_routes ------
@@ -519,7 +5936,7 @@ mod tests {
             .unwrap();
         let config = default_proxy_config();
 
-        let response = handle_routes(request, &config).unwrap_err();
+        let response = handle_routes(request, &config, &IndexedRoutes::default()).unwrap_err();
         assert_eq!(response.status(), StatusCode::OK);
 
         let body = body_to_bytes(response.into_body()).await.unwrap();
@@ -534,7 +5951,7 @@ mod tests {
             .unwrap();
         let config = default_proxy_config();
 
-        let response = handle_routes(request, &config).unwrap_err();
+        let response = handle_routes(request, &config, &IndexedRoutes::default()).unwrap_err();
         assert_eq!(response.status(), StatusCode::OK);
 
         let body = body_to_bytes(response.into_body()).await.unwrap();
@@ -549,8 +5966,12 @@ mod tests {
             .unwrap();
         let config = default_proxy_config();
 
-        let response = handle_routes(request, &config).unwrap_err();
+        let response = handle_routes(request, &config, &IndexedRoutes::default()).unwrap_err();
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            ProxyErrorKind::of(&response),
+            Some(ProxyErrorKind::RouteMiss)
+        );
 
         let body = body_to_bytes(response.into_body()).await.unwrap();
         assert_eq!(body, "404. The requested URL was not found on this server.");
@@ -567,12 +5988,91 @@ mod tests {
             from: "example.com".to_owned(),
             to: "http://localhost:8080".parse().unwrap(),
             validate: None,
+            negotiate_manifest: None,
+            cache_partition: None,
+            body_replacements: BTreeMap::new(),
+            json_filter: None,
+            verbose: None,
+            allowed_countries: None,
+            blocked_countries: None,
+            to_by_region: BTreeMap::new(),
+            head_revalidation: None,
+            cache_private: None,
+            group: None,
+            signing_profile: None,
+            fix_content_type: None,
+            transcode_to_utf8: None,
+            latency_slo: None,
+            answer_preflight: None,
+            strategy: None,
+            cors: None,
         });
 
-        let request = handle_routes(request, &config).unwrap();
+        let request = handle_routes(request, &config, &IndexedRoutes::default()).unwrap();
         assert_eq!(request.uri(), "http://localhost:8080/manifest.json");
     }
 
+    proptest! {
+        #[test]
+        fn handle_routes_never_panics(
+            host in "[a-z]{1,8}(\\.[a-z]{2,4})?(:[0-9]{1,5})?",
+            path in "(/[a-zA-Z0-9._~%-]{0,10}){1,4}",
+            query in proptest::option::of("[a-z0-9=&%]{0,16}"),
+            route_from in "[a-z]{1,8}(\\.[a-z]{2,4})?(/[a-z]{0,6})?",
+            validate in any::<bool>(),
+        ) {
+            let path_and_query = match &query {
+                Some(query) => format!("{}?{}", path, query),
+                None => path.clone(),
+            };
+            let request = Request::builder()
+                .uri(path_and_query.as_str())
+                .header("host", host.as_str())
+                .body(Bytes::new());
+            prop_assume!(request.is_ok());
+            let mut config = default_proxy_config();
+            for from in &[route_from, host] {
+                config.routes.push(ProxyRoute {
+                    from: from.clone(),
+                    to: "http://localhost:8080/addon/".parse().unwrap(),
+                    validate: Some(validate),
+                    negotiate_manifest: None,
+                    cache_partition: None,
+                    body_replacements: BTreeMap::new(),
+                    json_filter: None,
+                    verbose: None,
+                    allowed_countries: None,
+                    blocked_countries: None,
+                    to_by_region: BTreeMap::new(),
+                    head_revalidation: None,
+                    cache_private: None,
+                    group: None,
+                    signing_profile: None,
+                    fix_content_type: None,
+                    transcode_to_utf8: None,
+                    latency_slo: None,
+                    answer_preflight: None,
+                    strategy: None,
+                    cors: None,
+                });
+            }
+
+            match handle_routes(request.unwrap(), &config, &IndexedRoutes::default()) {
+                Ok(request) => {
+                    let host = request.uri().host();
+                    prop_assert_eq!(host, Some("localhost"));
+                    prop_assert!(request.uri().to_string().parse::<Uri>().is_ok());
+                    prop_assert_eq!(request.headers()["host"].to_str().ok(), host);
+                    prop_assert!(request.extensions().get::<RouteMatch>().is_some());
+                }
+                Err(response) => prop_assert!(matches!(
+                    response.status(),
+                    StatusCode::OK | StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND
+                )),
+            }
+        }
+    }
+
     #[tokio::test]
     async fn handle_routes_top() {
         let request = Request::builder()
@@ -584,9 +6084,27 @@ mod tests {
             from: "example.com".to_owned(),
             to: "http://localhost:8080".parse().unwrap(),
             validate: None,
+            negotiate_manifest: None,
+            cache_partition: None,
+            body_replacements: BTreeMap::new(),
+            json_filter: None,
+            verbose: None,
+            allowed_countries: None,
+            blocked_countries: None,
+            to_by_region: BTreeMap::new(),
+            head_revalidation: None,
+            cache_private: None,
+            group: None,
+            signing_profile: None,
+            fix_content_type: None,
+            transcode_to_utf8: None,
+            latency_slo: None,
+            answer_preflight: None,
+            strategy: None,
+            cors: None,
         });
 
-        let request = handle_routes(request, &config).unwrap();
+        let request = handle_routes(request, &config, &IndexedRoutes::default()).unwrap();
         assert_eq!(
             request.uri(),
             "http://localhost:8080/catalog/movie/top.json"
@@ -604,9 +6122,27 @@ mod tests {
             from: "example.com".to_owned(),
             to: "http://localhost:8080".parse().unwrap(),
             validate: None,
+            negotiate_manifest: None,
+            cache_partition: None,
+            body_replacements: BTreeMap::new(),
+            json_filter: None,
+            verbose: None,
+            allowed_countries: None,
+            blocked_countries: None,
+            to_by_region: BTreeMap::new(),
+            head_revalidation: None,
+            cache_private: None,
+            group: None,
+            signing_profile: None,
+            fix_content_type: None,
+            transcode_to_utf8: None,
+            latency_slo: None,
+            answer_preflight: None,
+            strategy: None,
+            cors: None,
         });
 
-        let response = handle_routes(request, &config).unwrap_err();
+        let response = handle_routes(request, &config, &IndexedRoutes::default()).unwrap_err();
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 
         let body = body_to_bytes(response.into_body()).await.unwrap();
@@ -624,10 +6160,254 @@ mod tests {
             from: "example.com".to_owned(),
             to: "http://localhost:8080".parse().unwrap(),
             validate: Some(false),
+            negotiate_manifest: None,
+            cache_partition: None,
+            body_replacements: BTreeMap::new(),
+            json_filter: None,
+            verbose: None,
+            allowed_countries: None,
+            blocked_countries: None,
+            to_by_region: BTreeMap::new(),
+            head_revalidation: None,
+            cache_private: None,
+            group: None,
+            signing_profile: None,
+            fix_content_type: None,
+            transcode_to_utf8: None,
+            latency_slo: None,
+            answer_preflight: None,
+            strategy: None,
+            cors: None,
         });
 
-        let request = handle_routes(request, &config).unwrap();
+        let request = handle_routes(request, &config, &IndexedRoutes::default()).unwrap();
+        assert_eq!(request.uri(), "http://localhost:8080/invalid");
+    }
+
+    #[tokio::test]
+    async fn handle_routes_library_mode() {
+        let mut config = default_proxy_config();
+        config.routes.push(
+            toml::from_str(
+                r#"
+                from = "example.com"
+                to = "http://localhost:8080"
+                "#,
+            )
+            .unwrap(),
+        );
+        config.library_mode = Some(toml::from_str("route_miss_status = 502").unwrap());
+        assert!(!config.admin_endpoints_enabled());
+        assert!(!config.builtin_endpoints_enabled());
+        let route = |uri: &str, config: &ProxyConfig| {
+            let request = Request::get(uri).body(Bytes::new()).unwrap();
+            handle_routes(request, config, &IndexedRoutes::default())
+        };
+
+        for uri in &["https://unknown.com/", "https://unknown.com/unknown"] {
+            let response = route(uri, &config).unwrap_err();
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+            assert_eq!(
+                ProxyErrorKind::of(&response),
+                Some(ProxyErrorKind::RouteMiss)
+            );
+            let body = body_to_bytes(response.into_body()).await.unwrap();
+            assert!(body.is_empty());
+        }
+        // Stremio-specific validation is disabled.
+        let request = route("https://example.com/invalid", &config).unwrap();
         assert_eq!(request.uri(), "http://localhost:8080/invalid");
+
+        config.library_mode = Some(toml::from_str("builtin_pages = true").unwrap());
+        let response = route("https://unknown.com/unknown", &config).unwrap_err();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "404. The requested URL was not found on this server.");
+    }
+
+    #[test]
+    fn handle_routes_country() {
+        let request = |country: Option<&str>| {
+            let mut request = Request::builder()
+                .uri("https://licensed.com/manifest.json")
+                .body(Bytes::new())
+                .unwrap();
+            request.extensions_mut().insert(GeoTag {
+                country: country.map(str::to_owned),
+                continent: None,
+                asn: None,
+            });
+            request
+        };
+        let mut config = default_proxy_config();
+        config.routes.push(ProxyRoute {
+            from: "licensed.com".to_owned(),
+            to: "http://localhost:8080".parse().unwrap(),
+            validate: None,
+            negotiate_manifest: None,
+            cache_partition: None,
+            body_replacements: BTreeMap::new(),
+            json_filter: None,
+            verbose: None,
+            allowed_countries: Some(vec!["CZ".to_owned(), "SK".to_owned()]),
+            blocked_countries: Some(vec!["SK".to_owned()]),
+            to_by_region: BTreeMap::new(),
+            head_revalidation: None,
+            cache_private: None,
+            group: None,
+            signing_profile: None,
+            fix_content_type: None,
+            transcode_to_utf8: None,
+            latency_slo: None,
+            answer_preflight: None,
+            strategy: None,
+            cors: None,
+        });
+
+        assert!(handle_routes(request(Some("cz")), &config, &IndexedRoutes::default()).is_ok());
+        for country in &[Some("SK"), Some("US"), None] {
+            let response =
+                handle_routes(request(*country), &config, &IndexedRoutes::default()).unwrap_err();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[test]
+    fn handle_routes_region() {
+        let request = |country: Option<&str>, continent: Option<&str>| {
+            let mut request = Request::builder()
+                .uri("https://regional.com/manifest.json")
+                .body(Bytes::new())
+                .unwrap();
+            request.extensions_mut().insert(GeoTag {
+                country: country.map(str::to_owned),
+                continent: continent.map(str::to_owned),
+                asn: None,
+            });
+            request
+        };
+        let mut to_by_region = BTreeMap::new();
+        to_by_region.insert("EU".to_owned(), "http://eu.example.com".parse().unwrap());
+        to_by_region.insert("CA".to_owned(), "http://us.example.com".parse().unwrap());
+        let mut config = default_proxy_config();
+        config.routes.push(ProxyRoute {
+            from: "regional.com".to_owned(),
+            to: "http://localhost:8080".parse().unwrap(),
+            validate: None,
+            negotiate_manifest: None,
+            cache_partition: None,
+            body_replacements: BTreeMap::new(),
+            json_filter: None,
+            verbose: None,
+            allowed_countries: None,
+            blocked_countries: None,
+            to_by_region,
+            head_revalidation: None,
+            cache_private: None,
+            group: None,
+            signing_profile: None,
+            fix_content_type: None,
+            transcode_to_utf8: None,
+            latency_slo: None,
+            answer_preflight: None,
+            strategy: None,
+            cors: None,
+        });
+
+        let origin_of = |country, continent| {
+            handle_routes(
+                request(country, continent),
+                &config,
+                &IndexedRoutes::default(),
+            )
+            .unwrap()
+            .uri()
+            .to_string()
+        };
+        assert_eq!(
+            origin_of(Some("CZ"), Some("EU")),
+            "http://eu.example.com/manifest.json"
+        );
+        // Country codes are matched before continent codes.
+        assert_eq!(
+            origin_of(Some("CA"), Some("NA")),
+            "http://us.example.com/manifest.json"
+        );
+        assert_eq!(
+            origin_of(Some("BR"), Some("SA")),
+            "http://localhost:8080/manifest.json"
+        );
+        assert_eq!(origin_of(None, None), "http://localhost:8080/manifest.json");
+    }
+
+    #[test]
+    fn verbose_route() {
+        let request = || {
+            Request::builder()
+                .uri("https://verbose.com/manifest.json")
+                .body(Bytes::new())
+                .unwrap()
+        };
+        let mut config = default_proxy_config();
+        config.routes.push(ProxyRoute {
+            from: "verbose.com".to_owned(),
+            to: "http://localhost:8080".parse().unwrap(),
+            validate: None,
+            negotiate_manifest: None,
+            cache_partition: None,
+            body_replacements: BTreeMap::new(),
+            json_filter: None,
+            verbose: Some(true),
+            allowed_countries: None,
+            blocked_countries: None,
+            to_by_region: BTreeMap::new(),
+            head_revalidation: None,
+            cache_private: None,
+            group: None,
+            signing_profile: None,
+            fix_content_type: None,
+            transcode_to_utf8: None,
+            latency_slo: None,
+            answer_preflight: None,
+            strategy: None,
+            cors: None,
+        });
+
+        assert!(!is_verbose(&request(), &config));
+        let request = handle_routes(request(), &config, &IndexedRoutes::default()).unwrap();
+        assert!(is_verbose(&request, &config));
+    }
+
+    #[test]
+    fn redacted_headers() {
+        let mut config = default_proxy_config();
+        config.redact_headers = vec!["Authorization".to_owned()];
+        let request = Request::builder()
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .header(header::ACCEPT, "application/json")
+            .header(ADMIN_TOKEN_HEADER, "admin-token")
+            .body(())
+            .unwrap();
+        let headers = format!(
+            "{:?}",
+            LoggableHeaders {
+                headers: request.headers(),
+                proxy_config: &config
+            }
+        );
+        assert_eq!(
+            headers,
+            r#"{"authorization": "<redacted>", "accept": "application/json", "x-proxy-admin-token": "<redacted>"}"#
+        );
+    }
+
+    fn cache_metadata() -> CacheMetadata {
+        CacheMetadata {
+            original_uri: "example.com/manifest.json".to_owned(),
+            route: Some("example.com".to_owned()),
+            reason: CacheReason::Request,
+            tags: Vec::new(),
+        }
     }
 
     fn default_proxy_config() -> ProxyConfig {
@@ -635,15 +6415,72 @@ mod tests {
             reload_config_url_path: "/reload-proxy-config".to_owned(),
             clear_cache_url_path: "/clear-cache".to_owned(),
             status_url_path: "/status".to_owned(),
+            admin_ui_url_path: Some("/admin/ui".to_owned()),
+            config_dump_url_path: Some("/admin/config".to_owned()),
+            cache_inspect_url_path: Some("/admin/cache".to_owned()),
+            cache_entry_url_path: Some("/cache-entry".to_owned()),
+            purge_cache_url_path: Some("/purge-cache".to_owned()),
+            purge_tag_url_path: Some("/cache/purge-tag".to_owned()),
+            upstream_health_url_path: Some("/health/upstreams".to_owned()),
+            admin_token: None,
+            cache_bypass: None,
+            response_signing: None,
             db_directory: PathBuf::from("proxy_db"),
+            cache_seed_file: None,
             ip: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
             default_port: 5000,
+            trusted_proxies: Vec::new(),
             cache_enabled: false,
             default_cache_validity: 600,            // 10 * 60
             cache_stale_threshold_on_fail: 172_800, // 48 * 60 * 60
+            negative_cache_validity: None,
             timeout: 20,
+            response_timeout: None,
+            min_transfer_rate: None,
+            dns_failover: None,
+            happy_eyeballs: None,
+            compression: None,
+            profiling: None,
+            shutdown_grace_period: 10,
+            shutdown_drain_delay: 0,
             routes: Vec::new(),
+            route_groups: BTreeMap::new(),
+            signing_profiles: BTreeMap::new(),
+            addon_collection: None,
             verbose: false,
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Text,
+            redact_headers: Vec::new(),
+            fair_queue: None,
+            script: None,
+            geoip: None,
+            rules: Vec::new(),
+            prefetch: None,
+            cache_ttl_jitter: None,
+            cache_write_coalescing_window: None,
+            cache_sweep_interval: None,
+            cache_tags: None,
+            cache_key: None,
+            cached_headers: None,
+            max_cached_header_size: 4096,
+            max_cacheable_body_size: None,
+            response_header_limits: None,
+            error_budget: None,
+            latency_slo_alerts: None,
+            idempotency: None,
+            health_checks: None,
+            revalidation: None,
+            stale_while_revalidate: None,
+            resource_ttls: None,
+            metrics: None,
+            request_deadline: None,
+            virtual_endpoints: Vec::new(),
+            builtin_endpoints: None,
+            preflight: None,
+            cors: None,
+            library_mode: None,
+            version: ConfigVersion::default(),
+            route_index: RouteIndex::default(),
         }
     }
 }
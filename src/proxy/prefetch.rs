@@ -0,0 +1,115 @@
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct CatalogPage {
+    metas: Vec<IgnoredAny>,
+}
+
+/// The number of items in the catalog response.
+///
+/// Returns `None` when the body isn't a valid catalog response.
+pub fn catalog_page_size(body: &[u8]) -> Option<usize> {
+    serde_json::from_slice::<CatalogPage>(body)
+        .ok()
+        .map(|page| page.metas.len())
+}
+
+/// Paths of the next `depth` catalog pages.
+///
+/// Returns an empty `Vec` for non-catalog paths, empty pages and search results.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// assert_eq!(
+///     next_catalog_page_paths("/catalog/movie/top/skip=100.json", 100, 2),
+///     vec!["/catalog/movie/top/skip=200.json", "/catalog/movie/top/skip=300.json"]
+/// );
+/// ```
+pub fn next_catalog_page_paths(path: &str, page_size: usize, depth: u32) -> Vec<String> {
+    if page_size == 0 || !path.starts_with("/catalog/") || !path.ends_with(".json") {
+        return Vec::new();
+    }
+    // /catalog/movie/top/skip=100.json -> ["catalog", "movie", "top", "skip=100"]
+    let segments = path[1..path.len() - ".json".len()]
+        .split('/')
+        .collect::<Vec<_>>();
+    let (catalog_path, extra) = match segments.as_slice() {
+        [_, type_name, id] => (format!("/catalog/{}/{}", type_name, id), ""),
+        [_, type_name, id, extra] => (format!("/catalog/{}/{}", type_name, id), *extra),
+        _ => return Vec::new(),
+    };
+
+    let mut skip = 0;
+    let mut other_extra = Vec::new();
+    for pair in extra.split('&').filter(|pair| !pair.is_empty()) {
+        let mut name_and_value = pair.splitn(2, '=');
+        match (name_and_value.next(), name_and_value.next()) {
+            (Some("skip"), Some(value)) => match value.parse::<usize>() {
+                Ok(value) => skip = value,
+                Err(_) => return Vec::new(),
+            },
+            // Search results aren't paginated.
+            (Some("search"), _) => return Vec::new(),
+            _ => other_extra.push(pair),
+        }
+    }
+
+    (1..=depth as usize)
+        .map(|page| {
+            let skip = format!("skip={}", skip + page * page_size);
+            let extra = other_extra
+                .iter()
+                .copied()
+                .chain(Some(skip.as_str()))
+                .collect::<Vec<_>>()
+                .join("&");
+            format!("{}/{}.json", catalog_path, extra)
+        })
+        .collect()
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_page() {
+        assert_eq!(
+            next_catalog_page_paths("/catalog/movie/top.json", 100, 2),
+            vec![
+                "/catalog/movie/top/skip=100.json",
+                "/catalog/movie/top/skip=200.json"
+            ]
+        );
+    }
+
+    #[test]
+    fn page_with_extra() {
+        assert_eq!(
+            next_catalog_page_paths("/catalog/movie/top/genre=Action&skip=50.json", 50, 1),
+            vec!["/catalog/movie/top/genre=Action&skip=100.json"]
+        );
+    }
+
+    #[test]
+    fn not_paginated() {
+        assert!(
+            next_catalog_page_paths("/catalog/movie/top/search=matrix.json", 100, 2).is_empty()
+        );
+        assert!(next_catalog_page_paths("/catalog/movie/top.json", 0, 2).is_empty());
+        assert!(next_catalog_page_paths("/meta/movie/tt1.json", 100, 2).is_empty());
+    }
+
+    #[test]
+    fn page_size() {
+        assert_eq!(
+            catalog_page_size(br#"{ "metas": [{ "id": "tt1" }, { "id": "tt2" }] }"#),
+            Some(2)
+        );
+        assert_eq!(catalog_page_size(b"<html></html>"), None);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::ProfilingConfig;
+
+// ------ Profiler ------
+
+/// Wall times of request pipeline stages aggregated from sampled requests
+/// (see `ProxyConfig::profiling`).
+#[derive(Default)]
+pub struct Profiler {
+    requests: AtomicU64,
+    stages: Mutex<BTreeMap<&'static str, StageTimes>>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct StageTimes {
+    samples: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Profiler {
+    /// Start profiling of a new request.
+    ///
+    /// Only every `sample_every`-th request is measured, the profile of the others is empty.
+    pub fn start(&self, config: Option<&ProfilingConfig>) -> RequestProfile {
+        let sampled = match config {
+            Some(config) => {
+                let sample_every = u64::from(config.sample_every.max(1));
+                // The position of the request in the current sampling period.
+                let position = self.requests.fetch_add(1, Ordering::Relaxed) % sample_every;
+                position == 0
+            }
+            None => false,
+        };
+        let laps = if sampled {
+            Some(Laps {
+                last: Instant::now(),
+                stages: Vec::new(),
+            })
+        } else {
+            None
+        };
+        RequestProfile { laps }
+    }
+
+    /// Add stage times of the finished request.
+    pub fn record(&self, profile: RequestProfile) {
+        let laps = match profile.laps {
+            Some(laps) => laps,
+            None => return,
+        };
+        // A stage may be split into multiple laps (e.g. validations before and after routing).
+        let mut request_stages = BTreeMap::<&'static str, Duration>::new();
+        for (stage, duration) in laps.stages {
+            *request_stages.entry(stage).or_default() += duration;
+        }
+        let mut stages = self.stages.lock().expect("lock profiler stages");
+        for (stage, duration) in request_stages {
+            let times = stages.entry(stage).or_default();
+            times.samples += 1;
+            times.total += duration;
+            times.max = times.max.max(duration);
+        }
+    }
+
+    /// Aggregated times per stage name.
+    pub fn snapshot(&self) -> BTreeMap<&'static str, StageProfile> {
+        let stages = self.stages.lock().expect("lock profiler stages");
+        stages
+            .iter()
+            .map(|(stage, times)| {
+                let mean = times.total / u32::try_from(times.samples).unwrap_or(u32::MAX);
+                let profile = StageProfile {
+                    samples: times.samples,
+                    mean_us: micros(mean),
+                    max_us: micros(times.max),
+                    total_us: micros(times.total),
+                };
+                (*stage, profile)
+            })
+            .collect()
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    u64::try_from(duration.as_micros()).unwrap_or(u64::MAX)
+}
+
+/// Wall times of one pipeline stage (see `Profiler::snapshot`).
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct StageProfile {
+    /// The number of sampled requests that have passed through the stage.
+    pub samples: u64,
+    pub mean_us: u64,
+    pub max_us: u64,
+    pub total_us: u64,
+}
+
+// ------ RequestProfile ------
+
+/// Wall times of pipeline stages of one request. It's empty when the request isn't sampled.
+pub struct RequestProfile {
+    laps: Option<Laps>,
+}
+
+struct Laps {
+    last: Instant,
+    stages: Vec<(&'static str, Duration)>,
+}
+
+impl RequestProfile {
+    /// Attribute the time since the previous lap (or since the start) to the `stage`.
+    pub fn lap(&mut self, stage: &'static str) {
+        if let Some(laps) = &mut self.laps {
+            let now = Instant::now();
+            laps.stages.push((stage, now.duration_since(laps.last)));
+            laps.last = now;
+        }
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(stages: &[(&'static str, u64)]) -> RequestProfile {
+        RequestProfile {
+            laps: Some(Laps {
+                last: Instant::now(),
+                stages: stages
+                    .iter()
+                    .map(|(stage, micros)| (*stage, Duration::from_micros(*micros)))
+                    .collect(),
+            }),
+        }
+    }
+
+    #[test]
+    fn sample_every() {
+        let profiler = Profiler::default();
+        let config = ProfilingConfig { sample_every: 3 };
+        let sampled = (0..7)
+            .map(|_| profiler.start(Some(&config)).laps.is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(sampled, vec![true, false, false, true, false, false, true]);
+        assert!(profiler.start(None).laps.is_none());
+    }
+
+    #[test]
+    fn aggregate_stages() {
+        let profiler = Profiler::default();
+        profiler.record(profile(&[
+            ("validation", 10),
+            ("routing", 30),
+            ("validation", 20),
+        ]));
+        profiler.record(profile(&[("validation", 60), ("upstream", 1000)]));
+        // Not sampled.
+        profiler.record(RequestProfile { laps: None });
+
+        let snapshot = profiler.snapshot();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(
+            snapshot["validation"],
+            StageProfile {
+                samples: 2,
+                mean_us: 45,
+                max_us: 60,
+                total_us: 90,
+            }
+        );
+        assert_eq!(snapshot["routing"].samples, 1);
+        assert_eq!(snapshot["upstream"].max_us, 1000);
+    }
+
+    #[test]
+    fn lap() {
+        let profiler = Profiler::default();
+        let mut profile = profiler.start(Some(&ProfilingConfig { sample_every: 1 }));
+        profile.lap("routing");
+        profile.lap("upstream");
+        let stages = profile.laps.as_ref().map(|laps| {
+            laps.stages
+                .iter()
+                .map(|(stage, _)| *stage)
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(stages, Some(vec!["routing", "upstream"]));
+    }
+}
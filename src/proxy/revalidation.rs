@@ -0,0 +1,138 @@
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time;
+
+use crate::helpers;
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// ------ RevalidationQueue ------
+
+/// Bounded queue of background cache refreshes.
+///
+/// Jobs are processed one by one with a pause between them, so cached responses
+/// that expire at the same time don't stampede the origin.
+///
+/// See `ProxyConfig::revalidation`.
+#[derive(Default)]
+pub struct RevalidationQueue {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    jobs: VecDeque<([u8; 8], Job)>,
+    // Keys of cached responses in `jobs` and the one being refreshed.
+    queued_keys: HashSet<[u8; 8]>,
+    worker_running: bool,
+}
+
+impl RevalidationQueue {
+    /// Queue the refresh of the cached response with the given key.
+    ///
+    /// Returns `false` when the refresh is already queued or when the queue is full.
+    ///
+    /// _Note:_ It has to be called inside the Tokio runtime.
+    pub fn push(
+        &self,
+        key: [u8; 8],
+        job: impl Future<Output = ()> + Send + 'static,
+        capacity: usize,
+        interval: Duration,
+    ) -> bool {
+        let mut inner = self.inner.lock().expect("lock revalidation queue");
+        if inner.jobs.len() >= capacity || !inner.queued_keys.insert(key) {
+            return false;
+        }
+        // Jobs keep the now getter of the task that has queued them.
+        let now_getter = helpers::scoped_now_getter();
+        let job = helpers::with_optional_now_getter(now_getter.clone(), job);
+        inner.jobs.push_back((key, Box::pin(job)));
+        if !inner.worker_running {
+            inner.worker_running = true;
+            helpers::spawn_with_now_getter(
+                now_getter,
+                run_worker(Arc::clone(&self.inner), interval),
+            );
+        }
+        true
+    }
+
+    /// The number of jobs waiting for processing.
+    pub fn len(&self) -> usize {
+        self.inner
+            .lock()
+            .expect("lock revalidation queue")
+            .jobs
+            .len()
+    }
+
+    /// See `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Process jobs until the queue is empty.
+async fn run_worker(inner: Arc<Mutex<Inner>>, interval: Duration) {
+    loop {
+        time::delay_for(interval).await;
+        let (key, job) = {
+            let mut inner = inner.lock().expect("lock revalidation queue");
+            match inner.jobs.pop_front() {
+                Some(key_and_job) => key_and_job,
+                None => {
+                    inner.worker_running = false;
+                    return;
+                }
+            }
+        };
+        job.await;
+        inner
+            .lock()
+            .expect("lock revalidation queue")
+            .queued_keys
+            .remove(&key);
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn bounded_and_deduplicated() {
+        let queue = RevalidationQueue::default();
+        let done = Arc::new(AtomicUsize::new(0));
+        let job = || {
+            let done = Arc::clone(&done);
+            async move {
+                done.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        let interval = Duration::from_millis(100);
+
+        assert!(queue.push([1; 8], job(), 2, interval));
+        assert!(!queue.push([1; 8], job(), 2, interval));
+        assert!(queue.push([2; 8], job(), 2, interval));
+        assert!(!queue.push([3; 8], job(), 2, interval));
+        assert_eq!(queue.len(), 2);
+
+        // Jobs are staggered by the interval.
+        time::delay_for(Duration::from_millis(150)).await;
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+        time::delay_for(Duration::from_millis(150)).await;
+        assert_eq!(done.load(Ordering::SeqCst), 2);
+        assert!(queue.is_empty());
+
+        // The key may be queued again after its refresh.
+        assert!(queue.push([1; 8], job(), 2, interval));
+    }
+}
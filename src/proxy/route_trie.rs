@@ -0,0 +1,167 @@
+use std::sync::Arc;
+
+use once_cell::sync::OnceCell;
+
+use super::ProxyRoute;
+
+// ------ RouteTrie ------
+
+/// Byte-wise prefix trie of route `from`s for the longest-prefix matching.
+#[derive(Debug, Clone)]
+pub struct RouteTrie {
+    // The first node is the root.
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Node {
+    children: Vec<(u8, usize)>,
+    // The index of the first route with `from` ending in this node.
+    route: Option<usize>,
+}
+
+impl Default for RouteTrie {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+        }
+    }
+}
+
+impl RouteTrie {
+    /// Index routes by their `from`s.
+    ///
+    /// The first route wins when more routes have the same `from`.
+    pub fn new(routes: &[ProxyRoute]) -> Self {
+        let mut trie = Self::default();
+        for (index, route) in routes.iter().enumerate() {
+            let mut node = 0;
+            for byte in route.from.bytes() {
+                node = match trie.nodes[node]
+                    .children
+                    .iter()
+                    .find(|(child_byte, _)| *child_byte == byte)
+                {
+                    Some((_, child)) => *child,
+                    None => {
+                        trie.nodes.push(Node::default());
+                        let child = trie.nodes.len() - 1;
+                        trie.nodes[node].children.push((byte, child));
+                        child
+                    }
+                };
+            }
+            trie.nodes[node].route.get_or_insert(index);
+        }
+        trie
+    }
+
+    /// The index of the route with the longest `from` that is a prefix of concatenated `parts`.
+    pub fn longest_match(&self, parts: &[&str]) -> Option<usize> {
+        let mut node = 0;
+        let mut matched = self.nodes[node].route;
+        for byte in parts.iter().flat_map(|part| part.bytes()) {
+            node = match self.nodes[node]
+                .children
+                .iter()
+                .find(|(child_byte, _)| *child_byte == byte)
+            {
+                Some((_, child)) => *child,
+                None => break,
+            };
+            if let Some(route) = self.nodes[node].route {
+                matched = Some(route);
+            }
+        }
+        matched
+    }
+}
+
+// ------ IndexedRoutes ------
+
+/// Routes with their `RouteTrie`.
+///
+/// Routes are shared, so matched routes can be stored in request extensions without cloning.
+#[derive(Debug, Default)]
+pub struct IndexedRoutes {
+    routes: Vec<Arc<ProxyRoute>>,
+    trie: RouteTrie,
+}
+
+impl IndexedRoutes {
+    pub fn new(routes: Vec<ProxyRoute>) -> Self {
+        let trie = RouteTrie::new(&routes);
+        Self {
+            routes: routes.into_iter().map(Arc::new).collect(),
+            trie,
+        }
+    }
+
+    /// The route with the longest `from` that is a prefix of concatenated `parts`.
+    pub fn find(&self, parts: &[&str]) -> Option<&Arc<ProxyRoute>> {
+        self.trie
+            .longest_match(parts)
+            .map(|index| &self.routes[index])
+    }
+}
+
+// ------ RouteIndex ------
+
+/// `IndexedRoutes` of `ProxyConfig::routes` built on the first use.
+///
+/// Clones are empty, so routes of the cloned config may be modified before they are matched.
+#[derive(Debug, Default)]
+pub struct RouteIndex(OnceCell<IndexedRoutes>);
+
+impl Clone for RouteIndex {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl RouteIndex {
+    pub fn get_or_build(&self, routes: &[ProxyRoute]) -> &IndexedRoutes {
+        self.0.get_or_init(|| IndexedRoutes::new(routes.to_vec()))
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix() {
+        let routes = [
+            "example.com",
+            "example.com/catalog/",
+            "other.com",
+            "example.com",
+        ]
+        .iter()
+        .map(|from| {
+            toml::from_str::<ProxyRoute>(&format!(
+                r#"
+                    from = "{}"
+                    to = "http://localhost:8080"
+                    "#,
+                from
+            ))
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+        let trie = RouteTrie::new(&routes);
+
+        assert_eq!(trie.longest_match(&["example.com/catalog/movie"]), Some(1));
+        assert_eq!(trie.longest_match(&["example.com/catalog"]), Some(0));
+        assert_eq!(trie.longest_match(&["example.com"]), Some(0));
+        assert_eq!(trie.longest_match(&["other.com/manifest.json"]), Some(2));
+        assert_eq!(trie.longest_match(&["example.org"]), None);
+        assert_eq!(
+            trie.longest_match(&["example.com", "/catalog/", "movie"]),
+            Some(1)
+        );
+        assert_eq!(RouteTrie::default().longest_match(&["example.com"]), None);
+    }
+}
@@ -0,0 +1,238 @@
+use hyper::body::Bytes;
+use hyper::{Body, Request, Response};
+
+use http::header::{HeaderName, HeaderValue};
+use http::{StatusCode, Uri};
+
+use crate::proxy::error_kind::{error_response, ProxyErrorKind};
+use crate::proxy::{validations, ProxyRule};
+
+// ------ RuleFlags ------
+
+/// Decisions of applied rules for the next middlewares.
+///
+/// They are stored in the request extensions.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RuleFlags {
+    /// The request has been routed by `route_to`.
+    pub routed: bool,
+    /// Don't load the response from the cache and don't cache it.
+    pub skip_cache: bool,
+}
+
+impl RuleFlags {
+    /// Get flags stored in the request extensions.
+    pub fn of<T>(req: &Request<T>) -> Self {
+        req.extensions().get::<Self>().copied().unwrap_or_default()
+    }
+}
+
+// ------ apply_rules ------
+
+/// Apply matching rules in order.
+///
+/// # Errors
+///
+/// - Returns a response with the `deny` status code.
+/// - Returns `BAD_REQUEST` when the validation of the request routed by `route_to` fails.
+/// - Returns `INTERNAL_SERVER_ERROR` response if the rewritten address is invalid.
+pub fn apply_rules(
+    mut req: Request<Bytes>,
+    rules: &[ProxyRule],
+) -> Result<Request<Bytes>, Response<Body>> {
+    let mut flags = RuleFlags::of(&req);
+    for rule in rules {
+        if !is_matching(rule, &req) {
+            continue;
+        }
+
+        if let Some(status) = rule.deny {
+            return Err(error_response(
+                ProxyErrorKind::Denied,
+                status,
+                "Denied by a proxy rule.",
+            ));
+        }
+
+        for (name, value) in &rule.set_headers {
+            // Names and values are checked on the config load.
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                req.headers_mut().insert(name, value);
+            }
+        }
+
+        if let Some(rewrite_path) = &rule.rewrite_path {
+            let path = req.uri().path();
+            let new_path = match &rule.path {
+                Some(path_regex) => path_regex.replace(path, rewrite_path.as_str()).into_owned(),
+                None => rewrite_path.clone(),
+            };
+            let path_and_query = match req.uri().query() {
+                Some(query) => format!("{}?{}", new_path, query),
+                None => new_path,
+            };
+            let mut parts = req.uri().clone().into_parts();
+            parts.path_and_query = Some(path_and_query.parse().map_err(|_| invalid_uri())?);
+            *req.uri_mut() = Uri::from_parts(parts).map_err(|_| invalid_uri())?;
+        }
+
+        if let Some(route_to) = &rule.route_to {
+            let path_and_query = req
+                .uri()
+                .path_and_query()
+                .map(|path_and_query| path_and_query.as_str().to_owned())
+                .unwrap_or_default();
+            if rule.validate != Some(false) && !validations::validate_request(&req, &path_and_query)
+            {
+                return Err(error_response(
+                    ProxyErrorKind::ValidationFailed,
+                    StatusCode::BAD_REQUEST,
+                    "Invalid request.",
+                ));
+            }
+            // /abc/efg?x=1&y=2 -> http://localhost:8000/abc/efg?x=1&y=2
+            *req.uri_mut() = format!("{}{}", route_to, path_and_query.trim_start_matches('/'))
+                .parse()
+                .map_err(|_| invalid_uri())?;
+            flags.routed = true;
+        }
+
+        if rule.skip_cache {
+            flags.skip_cache = true;
+        }
+    }
+    req.extensions_mut().insert(flags);
+    Ok(req)
+}
+
+fn is_matching(rule: &ProxyRule, req: &Request<Bytes>) -> bool {
+    if let Some(path_regex) = &rule.path {
+        if !path_regex.is_match(req.uri().path()) {
+            return false;
+        }
+    }
+    if let Some(method) = &rule.method {
+        if !req.method().as_str().eq_ignore_ascii_case(method) {
+            return false;
+        }
+    }
+    rule.headers.iter().all(|(name, value)| {
+        req.headers()
+            .get_all(name.as_str())
+            .iter()
+            .any(|header_value| header_value == value.as_str())
+    })
+}
+
+fn invalid_uri() -> Response<Body> {
+    error_response(
+        ProxyErrorKind::RoutingFailed,
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Invalid URI created by a proxy rule.",
+    )
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(toml: &str) -> ProxyRule {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn request(uri: &str) -> Request<Bytes> {
+        Request::builder().uri(uri).body(Bytes::new()).unwrap()
+    }
+
+    #[test]
+    fn deny() {
+        let rules = vec![rule(
+            r#"
+            path = "^/admin"
+            deny = 403
+            "#,
+        )];
+
+        let response = apply_rules(request("https://example.com/admin/x"), &rules).unwrap_err();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(apply_rules(request("https://example.com/manifest.json"), &rules).is_ok());
+    }
+
+    #[test]
+    fn conditions() {
+        let rules = vec![rule(
+            r#"
+            method = "post"
+            headers = { "x-api-key" = "blocked" }
+            deny = 429
+            "#,
+        )];
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("https://example.com/manifest.json")
+            .header("x-api-key", "blocked")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(apply_rules(req, &rules).is_err());
+
+        let req = Request::builder()
+            .uri("https://example.com/manifest.json")
+            .header("x-api-key", "blocked")
+            .body(Bytes::new())
+            .unwrap();
+        assert!(apply_rules(req, &rules).is_ok());
+    }
+
+    #[test]
+    fn rewrite_path_and_set_headers() {
+        let rules = vec![rule(
+            r#"
+            path = "^/v1/(.*)"
+            rewrite_path = "/$1"
+            set_headers = { "x-api-version" = "1" }
+            "#,
+        )];
+
+        let req = apply_rules(request("https://example.com/v1/manifest.json?x=1"), &rules).unwrap();
+        assert_eq!(req.uri(), "https://example.com/manifest.json?x=1");
+        assert_eq!(req.headers()["x-api-version"], "1");
+        assert!(!RuleFlags::of(&req).routed);
+    }
+
+    #[test]
+    fn route_to_and_skip_cache() {
+        let rules = vec![
+            rule(
+                r#"
+                path = "^/catalog/"
+                route_to = "http://localhost:8081"
+                "#,
+            ),
+            rule("skip_cache = true"),
+        ];
+
+        let req = apply_rules(
+            request("https://example.com/catalog/movie/top.json"),
+            &rules,
+        )
+        .unwrap();
+        assert_eq!(req.uri(), "http://localhost:8081/catalog/movie/top.json");
+        let flags = RuleFlags::of(&req);
+        assert!(flags.routed);
+        assert!(flags.skip_cache);
+    }
+
+    #[test]
+    fn route_to_invalid_request() {
+        let rules = vec![rule(r#"route_to = "http://localhost:8081""#)];
+
+        let response = apply_rules(request("https://example.com/invalid"), &rules).unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
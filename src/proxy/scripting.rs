@@ -0,0 +1,353 @@
+use std::convert::TryFrom;
+use std::path::Path;
+
+use hyper::body::Bytes;
+use hyper::{Body, Request, Response};
+
+use http::header::{HeaderName, HeaderValue};
+use http::{HeaderMap, Method, StatusCode, Uri};
+
+use once_cell::sync::Lazy;
+use rhai::{Dynamic, Engine, EvalAltResult, ImmutableString, Map, Scope, AST};
+use tracing::error;
+
+use crate::proxy::error_kind::{error_response, ProxyErrorKind};
+
+/// Protects the proxy against infinite loops in scripts.
+const MAX_OPERATIONS: u64 = 100_000;
+
+static ENGINE: Lazy<Engine> = Lazy::new(|| {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine
+});
+
+/// Compile the script file.
+///
+/// # Errors
+///
+/// Returns error when the file cannot be read or when the script is invalid.
+pub fn compile(path: &Path) -> Result<AST, String> {
+    ENGINE
+        .compile_file(path.to_path_buf())
+        .map_err(|err| format!("cannot compile script '{}': {}", path.display(), err))
+}
+
+/// Call the script function `on_request`.
+///
+/// # Errors
+///
+/// - Returns the response created by the script.
+/// - Returns `INTERNAL_SERVER_ERROR` response when the script fails or its result is invalid.
+pub fn on_request(ast: &AST, mut req: Request<Bytes>) -> Result<Request<Bytes>, Response<Body>> {
+    let mut request = Map::new();
+    request.insert("method".into(), Dynamic::from(req.method().to_string()));
+    request.insert("uri".into(), Dynamic::from(req.uri().to_string()));
+    request.insert("headers".into(), headers_to_map(req.headers()));
+
+    let result = match call(ast, "on_request", request) {
+        Some(Ok(result)) => result,
+        Some(Err(error)) => return Err(script_error_response(&error)),
+        None => return Ok(req),
+    };
+
+    // The script returned a response.
+    if result.contains_key("status") {
+        return Err(map_to_response(result).unwrap_or_else(|error| script_error_response(&error)));
+    }
+
+    // The script returned a (modified) request.
+    match apply_request_map(&mut req, &result) {
+        Ok(()) => Ok(req),
+        Err(error) => Err(script_error_response(&error)),
+    }
+}
+
+/// Call the script function `on_response`.
+///
+/// _Note:_ Script errors are only logged and the original response is returned.
+pub fn on_response(ast: &AST, mut response: Response<Body>) -> Response<Body> {
+    let mut response_map = Map::new();
+    response_map.insert(
+        "status".into(),
+        Dynamic::from(i64::from(response.status().as_u16())),
+    );
+    response_map.insert("headers".into(), headers_to_map(response.headers()));
+
+    let result = match call(ast, "on_response", response_map) {
+        Some(Ok(result)) => result,
+        Some(Err(error)) => {
+            error!("script function on_response failed: {}", error);
+            return response;
+        }
+        None => return response,
+    };
+
+    let status = match status_field(&result) {
+        Ok(status) => status,
+        Err(error) => {
+            error!("script function on_response failed: {}", error);
+            return response;
+        }
+    };
+    let mut headers = response.headers().clone();
+    let headers_result = match map_field(&result, "headers") {
+        Ok(Some(new_headers)) => apply_headers(&mut headers, new_headers),
+        Ok(None) => Ok(()),
+        Err(error) => Err(error),
+    };
+    if let Err(error) = headers_result {
+        error!("script function on_response failed: {}", error);
+        return response;
+    }
+    if let Some(status) = status {
+        *response.status_mut() = status;
+    }
+    *response.headers_mut() = headers;
+    response
+}
+
+/// Call the script function with one map argument.
+///
+/// Returns `None` when the function isn't defined in the script.
+fn call(ast: &AST, function: &str, arg: Map) -> Option<Result<Map, String>> {
+    let result =
+        ENGINE.call_fn::<_, Dynamic>(&mut Scope::new(), ast, function, (Dynamic::from(arg),));
+    match result {
+        Ok(result) => Some(
+            result
+                .try_cast::<Map>()
+                .ok_or_else(|| format!("{} has to return a map", function)),
+        ),
+        Err(error) => match *error {
+            EvalAltResult::ErrorFunctionNotFound(ref name, _) if name.starts_with(function) => None,
+            error => Some(Err(error.to_string())),
+        },
+    }
+}
+
+fn apply_request_map(req: &mut Request<Bytes>, map: &Map) -> Result<(), String> {
+    if let Some(method) = string_field(map, "method")? {
+        *req.method_mut() = Method::from_bytes(method.as_bytes())
+            .map_err(|_| format!("invalid method '{}'", method))?;
+    }
+    if let Some(uri) = string_field(map, "uri")? {
+        *req.uri_mut() = uri
+            .parse::<Uri>()
+            .map_err(|_| format!("invalid uri '{}'", uri))?;
+    }
+    if let Some(headers) = map_field(map, "headers")? {
+        apply_headers(req.headers_mut(), headers)?;
+    }
+    Ok(())
+}
+
+/// Header names are lowercased, only the first value of multi-value headers is used.
+fn headers_to_map(headers: &HeaderMap) -> Dynamic {
+    let mut map = Map::new();
+    for name in headers.keys() {
+        if let Some(value) = headers.get(name).and_then(|value| value.to_str().ok()) {
+            map.insert(name.as_str().into(), Dynamic::from(value.to_owned()));
+        }
+    }
+    Dynamic::from(map)
+}
+
+/// Set the changed headers and remove the headers missing in the map.
+///
+/// Unchanged headers are kept untouched to preserve multi-value headers.
+fn apply_headers(headers: &mut HeaderMap, new_headers: Map) -> Result<(), String> {
+    let removed_names = headers
+        .keys()
+        .filter(|name| !new_headers.contains_key(name.as_str()))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in removed_names {
+        headers.remove(name);
+    }
+
+    for (name, value) in new_headers {
+        let value = value
+            .try_cast::<ImmutableString>()
+            .ok_or_else(|| format!("header '{}' has to be a string", name))?;
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid header name '{}'", name))?;
+        let value = HeaderValue::from_str(&value)
+            .map_err(|_| format!("invalid value of header '{}'", name))?;
+        let unchanged = headers
+            .get(&name)
+            .map(|old_value| old_value == value)
+            .unwrap_or_default();
+        if !unchanged {
+            headers.insert(name, value);
+        }
+    }
+    Ok(())
+}
+
+fn map_to_response(mut map: Map) -> Result<Response<Body>, String> {
+    let mut response = Response::new(Body::empty());
+    if let Some(status) = status_field(&map)? {
+        *response.status_mut() = status;
+    }
+    if let Some(body) = string_field(&map, "body")? {
+        *response.body_mut() = Body::from(body.to_string());
+    }
+    if let Some(headers) = map.remove("headers") {
+        let headers = headers
+            .try_cast::<Map>()
+            .ok_or("field 'headers' has to be a map")?;
+        apply_headers(response.headers_mut(), headers)?;
+    }
+    Ok(response)
+}
+
+fn status_field(map: &Map) -> Result<Option<StatusCode>, String> {
+    match map.get("status") {
+        Some(status) => status
+            .clone()
+            .try_cast::<i64>()
+            .and_then(|status| u16::try_from(status).ok())
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .map(Some)
+            .ok_or_else(|| "field 'status' has to be a valid status code".to_owned()),
+        None => Ok(None),
+    }
+}
+
+fn string_field(map: &Map, field: &str) -> Result<Option<ImmutableString>, String> {
+    match map.get(field) {
+        Some(value) => value
+            .clone()
+            .try_cast::<ImmutableString>()
+            .map(Some)
+            .ok_or_else(|| format!("field '{}' has to be a string", field)),
+        None => Ok(None),
+    }
+}
+
+fn map_field(map: &Map, field: &str) -> Result<Option<Map>, String> {
+    match map.get(field) {
+        Some(value) => value
+            .clone()
+            .try_cast::<Map>()
+            .map(Some)
+            .ok_or_else(|| format!("field '{}' has to be a map", field)),
+        None => Ok(None),
+    }
+}
+
+fn script_error_response(error: &str) -> Response<Body> {
+    error!("script function on_request failed: {}", error);
+    error_response(
+        ProxyErrorKind::ScriptError,
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Script error.",
+    )
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyper_helpers::body_to_bytes;
+
+    fn ast(script: &str) -> AST {
+        ENGINE.compile(script).unwrap()
+    }
+
+    #[test]
+    fn on_request_set_header() {
+        let ast = ast(r#"
+            fn on_request(request) {
+                request.headers["x-proxy"] = "addon_proxy";
+                request
+            }
+        "#);
+        let request = Request::builder()
+            .uri("http://localhost:8080/manifest.json")
+            .header("accept", "application/json")
+            .body(Bytes::new())
+            .unwrap();
+
+        let request = on_request(&ast, request).unwrap();
+        assert_eq!(request.headers()["x-proxy"], "addon_proxy");
+        assert_eq!(request.headers()["accept"], "application/json");
+    }
+
+    #[test]
+    fn on_request_pick_route() {
+        let ast = ast(r#"
+            fn on_request(request) {
+                request.uri = "http://localhost:9090/manifest.json";
+                request
+            }
+        "#);
+        let request = Request::builder()
+            .uri("http://localhost:8080/manifest.json")
+            .body(Bytes::new())
+            .unwrap();
+
+        let request = on_request(&ast, request).unwrap();
+        assert_eq!(request.uri(), "http://localhost:9090/manifest.json");
+    }
+
+    #[tokio::test]
+    async fn on_request_short_circuit() {
+        let ast = ast(r#"
+            fn on_request(request) {
+                #{ status: 403, body: "Denied." }
+            }
+        "#);
+        let request = Request::builder()
+            .uri("http://localhost:8080/manifest.json")
+            .body(Bytes::new())
+            .unwrap();
+
+        let response = on_request(&ast, request).unwrap_err();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Denied.");
+    }
+
+    #[test]
+    fn on_request_not_defined() {
+        let ast = ast("fn on_response(response) { response }");
+        let request = Request::builder()
+            .uri("http://localhost:8080/manifest.json")
+            .body(Bytes::new())
+            .unwrap();
+
+        assert!(on_request(&ast, request).is_ok());
+    }
+
+    #[test]
+    fn on_request_infinite_loop() {
+        let ast = ast("fn on_request(request) { loop {} }");
+        let request = Request::builder()
+            .uri("http://localhost:8080/manifest.json")
+            .body(Bytes::new())
+            .unwrap();
+
+        let response = on_request(&ast, request).unwrap_err();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn on_response_remove_header() {
+        let ast = ast(r#"
+            fn on_response(response) {
+                response.headers.remove("server");
+                response
+            }
+        "#);
+        let response = Response::builder()
+            .header("server", "origin")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = on_response(&ast, response);
+        assert!(response.headers().get("server").is_none());
+    }
+}
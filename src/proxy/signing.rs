@@ -0,0 +1,178 @@
+use std::fmt::Write;
+
+use hmac::{Hmac, Mac, NewMac};
+use hyper::{header, Body, Request, Response};
+use sha2::Sha256;
+
+use crate::helpers::{http_date, now_timestamp};
+use crate::hyper_helpers::{body_to_bytes, StreamedBody};
+use crate::proxy::{ResponseSigningConfig, SigningProfile};
+
+/// HMAC-SHA256 of the message as a lowercase hex string.
+pub fn hmac_sha256_hex(key: &[u8], message: &[u8]) -> String {
+    // HMAC accepts keys of any length.
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC key");
+    mac.update(message);
+    let mut hex = String::with_capacity(64);
+    for byte in mac.finalize().into_bytes() {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// Compare the secret (e.g. the admin token) with the received value.
+///
+/// HMACs of both values are compared instead of the values themselves,
+/// so the comparison time doesn't reveal how many leading bytes match.
+pub fn secret_matches(secret: &[u8], received: &[u8]) -> bool {
+    hmac_sha256_hex(secret, secret) == hmac_sha256_hex(secret, received)
+}
+
+// ------ sign_request ------
+
+/// Add the date header and the header with HMAC-SHA256 of `<METHOD>\n<path and query>\n<date>`.
+///
+/// See `ProxyConfig::signing_profiles`.
+pub fn sign_request<B>(req: &mut Request<B>, signing_profile: &SigningProfile) {
+    let date = http_date(now_timestamp());
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let string_to_sign = format!("{}\n{}\n{}", req.method(), path_and_query, date);
+    let signature = hmac_sha256_hex(signing_profile.key.as_bytes(), string_to_sign.as_bytes());
+
+    // Header names are validated by `ProxyConfig::load`.
+    for (name, value) in &[
+        (&signing_profile.date_header, date),
+        (&signing_profile.signature_header, signature),
+    ] {
+        if let (Ok(name), Ok(value)) = (
+            header::HeaderName::from_bytes(name.as_bytes()),
+            header::HeaderValue::from_str(value),
+        ) {
+            req.headers_mut().insert(name, value);
+        }
+    }
+}
+
+// ------ sign_response ------
+
+/// Add the header `<signing_config.header>: t=<timestamp>,sha256=<signature>`
+/// where the signature is HMAC-SHA256 of `<timestamp>.<body>`.
+///
+/// The whole body is buffered to compute the signature.
+/// Responses with `StreamedBody` aren't signed - buffering would defeat streaming
+/// (e.g. bodies bigger than `ProxyConfig::max_cacheable_body_size`).
+///
+/// See `ProxyConfig::response_signing`.
+///
+/// # Errors
+///
+/// Returns `hyper::Error` when the body cannot be read.
+pub async fn sign_response(
+    response: Response<Body>,
+    signing_config: &ResponseSigningConfig,
+) -> Result<Response<Body>, hyper::Error> {
+    if response.extensions().get::<StreamedBody>().is_some() {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = body_to_bytes(body).await?;
+
+    let timestamp = now_timestamp();
+    let mut message = format!("{}.", timestamp).into_bytes();
+    message.extend_from_slice(&body);
+    let signature = hmac_sha256_hex(signing_config.key.as_bytes(), &message);
+
+    // The header name is validated by `ProxyConfig::load`.
+    if let (Ok(name), Ok(value)) = (
+        header::HeaderName::from_bytes(signing_config.header.as_bytes()),
+        header::HeaderValue::from_str(&format!("t={},sha256={}", timestamp, signature)),
+    ) {
+        parts.headers.insert(name, value);
+    }
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::with_now_getter;
+    use std::sync::Arc;
+
+    #[test]
+    fn rfc_4231_test_case_2() {
+        assert_eq!(
+            hmac_sha256_hex(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn secrets() {
+        assert!(secret_matches(b"admin-token", b"admin-token"));
+        assert!(!secret_matches(b"admin-token", b"admin-tokem"));
+        assert!(!secret_matches(b"admin-token", b"admin"));
+        assert!(!secret_matches(b"admin-token", b""));
+    }
+
+    #[tokio::test]
+    async fn signed_request() {
+        let signing_profile = toml::from_str::<SigningProfile>(r#"key = "secret""#).unwrap();
+        let mut request = Request::post("http://localhost:8080/catalog/movie/top.json?x=1")
+            .body(())
+            .unwrap();
+        with_now_getter(Arc::new(|| 1_600_000_000), async {
+            sign_request(&mut request, &signing_profile)
+        })
+        .await;
+
+        assert_eq!(
+            request.headers()["x-signature-date"],
+            "Sun, 13 Sep 2020 12:26:40 GMT"
+        );
+        assert_eq!(
+            request.headers()["x-signature"],
+            hmac_sha256_hex(
+                b"secret",
+                b"POST\n/catalog/movie/top.json?x=1\nSun, 13 Sep 2020 12:26:40 GMT"
+            )
+            .as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn signed_response() {
+        let signing_config = toml::from_str::<ResponseSigningConfig>(r#"key = "secret""#).unwrap();
+        let response = with_now_getter(
+            Arc::new(|| 1_600_000_000),
+            sign_response(Response::new(Body::from("{}")), &signing_config),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            response.headers()["x-proxy-signature"],
+            format!(
+                "t=1600000000,sha256={}",
+                hmac_sha256_hex(b"secret", b"1600000000.{}")
+            )
+            .as_str()
+        );
+        assert_eq!(body_to_bytes(response.into_body()).await.unwrap(), "{}");
+    }
+
+    #[tokio::test]
+    async fn streamed_response_isnt_signed() {
+        let signing_config = toml::from_str::<ResponseSigningConfig>(r#"key = "secret""#).unwrap();
+        let mut response = Response::new(Body::from("{}"));
+        response.extensions_mut().insert(StreamedBody);
+        let response = sign_response(response, &signing_config).await.unwrap();
+
+        assert!(!response.headers().contains_key("x-proxy-signature"));
+        assert_eq!(body_to_bytes(response.into_body()).await.unwrap(), "{}");
+    }
+}
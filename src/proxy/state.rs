@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{
+    CacheHits, CacheSeed, CollectionRoutes, ErrorBudgets, FairQueue, LatencySlos, LoadBalancer,
+    ManifestRegistry, Profiler, ProxyHooks, RevalidationQueue, Stats, UpstreamHealth,
+};
+
+// ------ ProxyState ------
+
+/// Runtime state shared by all `on_request` calls of a running `Proxy`.
+///
+/// Unlike `ProxyConfig`, the state is created only once on the server start
+/// and it survives config reloads.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Default)]
+pub struct ProxyState {
+    /// Limits and fairly distributes concurrent requests to origins.
+    pub fair_queue: FairQueue,
+    /// Proxy counters.
+    pub stats: Stats,
+    /// Hits of cached responses not saved to the DB yet.
+    pub cache_hits: CacheHits,
+    /// Capabilities learned from origin manifests (see `ProxyRoute::negotiate_manifest`).
+    pub manifests: ManifestRegistry,
+    /// Background refreshes of cached responses (see `ProxyConfig::revalidation`).
+    pub revalidation: RevalidationQueue,
+    /// Origin failures per route (see `ProxyConfig::error_budget`).
+    pub error_budgets: ErrorBudgets,
+    /// Origin latencies per route (see `ProxyConfig::latency_slo_alerts`).
+    pub latency_slos: LatencySlos,
+    /// Requests in progress per origin replica (see `ProxyRoute::strategy`).
+    pub load_balancer: LoadBalancer,
+    /// Results of origin probes (see `ProxyConfig::health_checks`).
+    pub upstream_health: UpstreamHealth,
+    /// Callbacks set by the embedder (see `Proxy::hooks`).
+    pub hooks: ProxyHooks,
+    /// Routes generated from `ProxyConfig::addon_collection`.
+    pub collection_routes: CollectionRoutes,
+    /// Warming of the cache (see `ProxyConfig::cache_seed_file`).
+    pub cache_seed: CacheSeed,
+    /// Wall times of request pipeline stages (see `ProxyConfig::profiling`).
+    pub profiler: Profiler,
+    /// `true` when the server is shutting down and it shouldn't receive new traffic.
+    draining: AtomicBool,
+}
+
+impl ProxyState {
+    /// Mark the proxy as draining - the status endpoint starts to report it
+    /// so load balancers stop sending new traffic.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// See `start_draining`.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+}
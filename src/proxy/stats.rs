@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Db, ProxyErrorKind};
+
+const STATS_TREE: &str = "stats";
+const STATS_KEY: &str = "counters";
+
+// ------ Stats ------
+
+/// Proxy counters.
+///
+/// Counters are persisted on the server stop and restored on the server start.
+#[derive(Default)]
+pub struct Stats {
+    /// All requests accepted by the proxy server.
+    pub requests: AtomicU64,
+    /// Requests that are being processed at the moment (it's not persisted).
+    pub in_flight: AtomicU64,
+    /// Responses loaded from the cache.
+    pub cache_hits: AtomicU64,
+    /// Requests sent to origins.
+    pub origin_requests: AtomicU64,
+    /// Failed requests to origins (including invalid responses).
+    pub origin_failures: AtomicU64,
+    /// Requests whose processing panicked.
+    pub panics: AtomicU64,
+    /// Requests per client country (see `ProxyConfig::geoip`, it's not persisted).
+    countries: Mutex<BTreeMap<String, u64>>,
+    /// Error responses created by the proxy per kind (it's not persisted).
+    errors: Mutex<BTreeMap<ProxyErrorKind, u64>>,
+}
+
+impl Stats {
+    /// Increment the counter.
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Increment `requests` and `in_flight` counters.
+    ///
+    /// `in_flight` is decremented when the returned guard is dropped.
+    pub fn track_request(&self) -> InFlightGuard<'_> {
+        Self::increment(&self.requests);
+        Self::increment(&self.in_flight);
+        InFlightGuard { stats: self }
+    }
+
+    /// Count the request from the given country (ISO 3166-1 alpha-2 code).
+    pub fn increment_country(&self, country: &str) {
+        let mut countries = self.countries.lock().expect("lock stats countries");
+        match countries.get_mut(country) {
+            Some(requests) => *requests += 1,
+            None => {
+                countries.insert(country.to_owned(), 1);
+            }
+        }
+    }
+
+    /// Requests per client country.
+    pub fn country_requests(&self) -> BTreeMap<String, u64> {
+        self.countries.lock().expect("lock stats countries").clone()
+    }
+
+    /// Count the error response of the given kind.
+    pub fn increment_error(&self, kind: ProxyErrorKind) {
+        let mut errors = self.errors.lock().expect("lock stats errors");
+        *errors.entry(kind).or_default() += 1;
+    }
+
+    /// Error responses per kind (see `ProxyErrorKind::as_str`).
+    pub fn error_responses(&self) -> BTreeMap<&'static str, u64> {
+        self.errors
+            .lock()
+            .expect("lock stats errors")
+            .iter()
+            .map(|(kind, count)| (kind.as_str(), *count))
+            .collect()
+    }
+
+    /// Get the current counter values.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            origin_requests: self.origin_requests.load(Ordering::Relaxed),
+            origin_failures: self.origin_failures.load(Ordering::Relaxed),
+            panics: self.panics.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Add persisted counters (see `persist`) to the current ones.
+    ///
+    /// # Errors
+    ///
+    /// Returns error when DB reading or deserialization fails.
+    pub fn restore(&self, db: &Db) -> Result<(), String> {
+        let stored = db
+            .open_tree(STATS_TREE)
+            .and_then(|tree| tree.get(STATS_KEY))
+            .map_err(|err| err.to_string())?;
+        if let Some(stored) = stored {
+            let snapshot = bincode::deserialize::<StatsSnapshot>(stored.as_ref())
+                .map_err(|err| err.to_string())?;
+            self.requests
+                .fetch_add(snapshot.requests, Ordering::Relaxed);
+            self.cache_hits
+                .fetch_add(snapshot.cache_hits, Ordering::Relaxed);
+            self.origin_requests
+                .fetch_add(snapshot.origin_requests, Ordering::Relaxed);
+            self.origin_failures
+                .fetch_add(snapshot.origin_failures, Ordering::Relaxed);
+            self.panics.fetch_add(snapshot.panics, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Save the current counters to a standalone DB tree, so they aren't removed by cache clearing.
+    ///
+    /// # Errors
+    ///
+    /// Returns error when serialization or DB writing fails.
+    pub fn persist(&self, db: &Db) -> Result<(), String> {
+        let snapshot = bincode::serialize(&self.snapshot()).map_err(|err| err.to_string())?;
+        db.open_tree(STATS_TREE)
+            .and_then(|tree| tree.insert(STATS_KEY, snapshot))
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+// ------ InFlightGuard ------
+
+/// See `Stats::track_request`.
+pub struct InFlightGuard<'a> {
+    stats: &'a Stats,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// ------ StatsSnapshot ------
+
+/// Counter values at the given moment. See `Stats` for field descriptions.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub requests: u64,
+    pub in_flight: u64,
+    pub cache_hits: u64,
+    pub origin_requests: u64,
+    pub origin_failures: u64,
+    pub panics: u64,
+}
+
+impl StatsSnapshot {
+    /// Monotonic counters (all fields except `in_flight`) with their names.
+    pub fn counters(&self) -> [(&'static str, u64); 5] {
+        [
+            ("requests", self.requests),
+            ("cache_hits", self.cache_hits),
+            ("origin_requests", self.origin_requests),
+            ("origin_failures", self.origin_failures),
+            ("panics", self.panics),
+        ]
+    }
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_request() {
+        let stats = Stats::default();
+        let guard = stats.track_request();
+        assert_eq!(stats.snapshot().in_flight, 1);
+        drop(guard);
+        assert_eq!(stats.snapshot().in_flight, 0);
+        assert_eq!(stats.snapshot().requests, 1);
+    }
+
+    #[test]
+    fn error_responses() {
+        let stats = Stats::default();
+        stats.increment_error(ProxyErrorKind::UpstreamTimeout);
+        stats.increment_error(ProxyErrorKind::RouteMiss);
+        stats.increment_error(ProxyErrorKind::UpstreamTimeout);
+        let errors = stats.error_responses().into_iter().collect::<Vec<_>>();
+        assert_eq!(errors, [("route_miss", 1), ("upstream_timeout", 2)]);
+    }
+
+    #[test]
+    fn persist_and_restore() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let stats = Stats::default();
+        Stats::increment(&stats.cache_hits);
+        Stats::increment(&stats.cache_hits);
+        stats.persist(&db).unwrap();
+        // Cache clearing doesn't remove stats.
+        db.clear().unwrap();
+
+        let restored_stats = Stats::default();
+        Stats::increment(&restored_stats.cache_hits);
+        restored_stats.restore(&db).unwrap();
+        assert_eq!(restored_stats.snapshot().cache_hits, 3);
+    }
+}
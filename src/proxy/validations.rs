@@ -1,7 +1,11 @@
+use http::Uri;
 use hyper::body::Bytes;
 use hyper::{Body, Request, Response};
 use std::str::FromStr;
 use stremio_core::types::addons::ResourceRef;
+use tracing::warn;
+
+use crate::proxy::{AddonCapabilities, AddonProtocol};
 
 // The proxy returns BAD_REQUEST when the request is invalid
 // and doesn't allow to pass it to the origin.
@@ -14,15 +18,50 @@ pub fn validate_request(_: &Request<Bytes>, path: &str) -> bool {
     }
 
     if let Err(error) = ResourceRef::from_str(path) {
-        eprintln!(
-            "Request validation error! (Path: '{}', Error: '{:#?}')",
-            path, error
-        );
+        warn!(path, ?error, "request validation failed");
         return false;
     }
     true
 }
 
+/// Validate non-resource paths (resources are checked by `AddonCapabilities::supports`)
+/// of the addon with a known manifest.
+///
+/// Paths allowed by `validate_request` for all addons (e.g. `/public*` and `/images*`)
+/// are allowed in addition to the paths from the manifest.
+///
+/// Falls back to `validate_request` for addons with the legacy protocol.
+pub fn validate_request_by_manifest(
+    req: &Request<Bytes>,
+    path: &str,
+    origin: &Uri,
+    capabilities: &AddonCapabilities,
+) -> bool {
+    if capabilities.protocol == AddonProtocol::Legacy {
+        return validate_request(req, path);
+    }
+    match path {
+        "/manifest.json" | "/" | "" => return true,
+        "/configure" if capabilities.configurable => return true,
+        public if public.starts_with("/public") => return true,
+        images if images.starts_with("/images") => return true,
+        _ => (),
+    }
+    // Logo and background served by the addon.
+    capabilities.assets.iter().any(|asset| {
+        let asset = match asset.parse::<Uri>() {
+            Ok(asset) => asset,
+            Err(_) => return false,
+        };
+        let same_origin = match asset.host() {
+            Some(host) => Some(host) == origin.host(),
+            // Relative URL.
+            None => true,
+        };
+        same_origin && asset.path() == path
+    })
+}
+
 /// The proxy doesn't allow to cache an invalid response
 /// and tries to return its previous valid cached version.
 pub fn validate_response(response: &Response<Body>) -> bool {
@@ -87,6 +126,62 @@ mod tests {
         assert!(!validate_request(&request, path));
     }
 
+    // ------ validate_request_by_manifest ------
+
+    fn capabilities() -> AddonCapabilities {
+        let manifest = br#"{
+            "types": ["movie"],
+            "resources": ["stream"],
+            "logo": "http://localhost:8080/static/logo.png",
+            "background": "https://cdn.example.com/background.jpg",
+            "behaviorHints": { "configurable": true }
+        }"#;
+        AddonCapabilities::from_manifest(manifest).unwrap()
+    }
+
+    #[test]
+    fn validate_request_by_manifest_extra_endpoints() {
+        let request = Request::default();
+        let origin = "http://localhost:8080".parse().unwrap();
+        let capabilities = capabilities();
+        assert!(validate_request_by_manifest(
+            &request,
+            "/configure",
+            &origin,
+            &capabilities
+        ));
+        assert!(validate_request_by_manifest(
+            &request,
+            "/static/logo.png",
+            &origin,
+            &capabilities
+        ));
+        assert!(!validate_request_by_manifest(
+            &request,
+            "/background.jpg",
+            &origin,
+            &capabilities
+        ));
+        assert!(validate_request_by_manifest(
+            &request,
+            "/public/file.pdf",
+            &origin,
+            &capabilities
+        ));
+        assert!(validate_request_by_manifest(
+            &request,
+            "/images/poster.png",
+            &origin,
+            &capabilities
+        ));
+        assert!(!validate_request_by_manifest(
+            &request,
+            "/unknown",
+            &origin,
+            &capabilities
+        ));
+    }
+
     // ------ validate_response ------
 
     #[test]
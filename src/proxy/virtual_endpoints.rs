@@ -0,0 +1,274 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use http::{Request, StatusCode, Uri};
+use hyper::body::Bytes;
+use hyper::{header, Body, Response};
+use once_cell::sync::Lazy;
+
+use crate::proxy::VirtualEndpoint;
+
+/// Responses for crawlers and browsers - see `ProxyConfig::builtin_endpoints`.
+pub static BUILTIN_ENDPOINTS: Lazy<Vec<VirtualEndpoint>> = Lazy::new(|| {
+    let headers = |content_type: Option<&str>| {
+        let mut headers = HashMap::new();
+        headers.insert("cache-control".to_owned(), "max-age=86400".to_owned());
+        if let Some(content_type) = content_type {
+            headers.insert("content-type".to_owned(), content_type.to_owned());
+        }
+        headers
+    };
+    vec![
+        VirtualEndpoint {
+            path: "/robots.txt".to_owned(),
+            status: None,
+            headers: headers(Some("text/plain")),
+            body: "User-agent: *\nDisallow: /\n".to_owned(),
+        },
+        VirtualEndpoint {
+            path: "/favicon.ico".to_owned(),
+            status: Some(StatusCode::NO_CONTENT),
+            headers: headers(None),
+            body: String::new(),
+        },
+    ]
+});
+
+// ------ StaticFile ------
+
+/// The page returned for requests to `/` without any route.
+pub static LANDING_PAGE: Lazy<StaticFile> = Lazy::new(|| {
+    StaticFile::new(
+        include_bytes!("../../landing.html"),
+        "text/html; charset=utf-8",
+    )
+});
+
+/// Content compiled into the proxy.
+///
+/// Clients may revalidate it by `If-None-Match` with the precomputed `ETag`.
+pub struct StaticFile {
+    content: Bytes,
+    content_type: &'static str,
+    etag: String,
+}
+
+impl StaticFile {
+    pub fn new(content: &'static [u8], content_type: &'static str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        Self {
+            content: Bytes::from_static(content),
+            content_type,
+            etag: format!("\"{:016x}\"", hasher.finish()),
+        }
+    }
+
+    /// Respond with the content or with `NOT_MODIFIED` when the client has the same version.
+    pub fn respond<B>(&self, req: &Request<B>) -> Response<Body> {
+        let is_not_modified = req
+            .headers()
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|etag| etag.trim().trim_start_matches("W/"))
+            .any(|etag| etag == self.etag || etag == "*");
+
+        let mut response = if is_not_modified {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            response
+        } else {
+            // `Bytes` from static content are cloned without copying.
+            let mut response = Response::new(Body::from(self.content.clone()));
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static(self.content_type),
+            );
+            response
+        };
+        let headers = response.headers_mut();
+        // The content changes only with a new proxy version.
+        headers.insert(
+            header::CACHE_CONTROL,
+            header::HeaderValue::from_static("public, max-age=3600"),
+        );
+        if let Ok(etag) = header::HeaderValue::from_str(&self.etag) {
+            headers.insert(header::ETAG, etag);
+        }
+        response
+    }
+}
+
+// ------ respond ------
+
+/// Render the response of the first virtual endpoint matching the request path.
+///
+/// See `ProxyConfig::virtual_endpoints`.
+pub fn respond(req: &Request<Bytes>, endpoints: &[VirtualEndpoint]) -> Option<Response<Body>> {
+    let endpoint = endpoints
+        .iter()
+        .find(|endpoint| endpoint.path == req.uri().path())?;
+
+    let mut response = Response::new(Body::from(render(&endpoint.body, req.uri())));
+    *response.status_mut() = endpoint.status.unwrap_or(StatusCode::OK);
+    for (name, value) in &endpoint.headers {
+        // Headers are validated by `ProxyConfig::load`.
+        if let (Ok(name), Ok(value)) = (
+            header::HeaderName::from_bytes(name.as_bytes()),
+            header::HeaderValue::from_str(value),
+        ) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    Some(response)
+}
+
+// ------ render ------
+
+/// Replace variables in double braces (see `VirtualEndpoint`).
+///
+/// Unknown variables are replaced with empty strings.
+fn render(template: &str, uri: &Uri) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let variable_and_rest = &rest[start + 2..];
+        match variable_and_rest.find("}}") {
+            Some(end) => {
+                output.push_str(variable(variable_and_rest[..end].trim(), uri));
+                rest = &variable_and_rest[end + 2..];
+            }
+            // Not a variable.
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+fn variable<'a>(name: &str, uri: &'a Uri) -> &'a str {
+    if name == "path" {
+        return uri.path();
+    }
+    // @TODO: Replace `trim_start_matches` with `strip_prefix` once stable.
+    if name.starts_with("query.") {
+        let query_param = name.trim_start_matches("query.");
+        return uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| {
+                let mut name_and_value = pair.splitn(2, '=');
+                match (name_and_value.next(), name_and_value.next()) {
+                    (Some(name), value) if name == query_param => Some(value.unwrap_or_default()),
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or_default();
+    }
+    ""
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyper_helpers::body_to_bytes;
+
+    #[test]
+    fn render_variables() {
+        let uri = "http://example.com/echo?name=Stremio&empty"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            render(
+                "{{ path }}: Hello {{query.name}}{{query.empty}}{{query.missing}}{{unknown}}!",
+                &uri
+            ),
+            "/echo: Hello Stremio!"
+        );
+        assert_eq!(render("{ \"a\": 1 } {{path", &uri), "{ \"a\": 1 } {{path");
+    }
+
+    #[tokio::test]
+    async fn respond_matching_endpoint() {
+        let endpoints = vec![toml::from_str::<VirtualEndpoint>(
+            r#"
+            path = "/maintenance"
+            status = 503
+            headers = { "content-type" = "text/plain", "retry-after" = "60" }
+            body = "Back in a minute ({{query.lang}})."
+            "#,
+        )
+        .unwrap()];
+        let request = |uri: &str| Request::get(uri).body(Bytes::new()).unwrap();
+
+        assert!(respond(&request("http://example.com/manifest.json"), &endpoints).is_none());
+
+        let response = respond(
+            &request("http://example.com/maintenance?lang=en"),
+            &endpoints,
+        )
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "60");
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Back in a minute (en).");
+    }
+
+    #[tokio::test]
+    async fn static_file_revalidation() {
+        let file = StaticFile::new(b"<h1>Hello</h1>", "text/html");
+        let request = |if_none_match: Option<&str>| {
+            let mut request = Request::get("http://example.com/");
+            if let Some(if_none_match) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, if_none_match);
+            }
+            request.body(Bytes::new()).unwrap()
+        };
+
+        let response = file.respond(&request(None));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "public, max-age=3600"
+        );
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "<h1>Hello</h1>");
+
+        let if_none_match = format!("\"other\", W/{}", etag);
+        let response = file.respond(&request(Some(&if_none_match)));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+
+        let response = file.respond(&request(Some("\"other\"")));
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn builtin_robots_txt() {
+        let request = Request::get("http://example.com/robots.txt")
+            .body(Bytes::new())
+            .unwrap();
+        let response = respond(&request, &BUILTIN_ENDPOINTS).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "User-agent: *\nDisallow: /\n");
+    }
+}
@@ -0,0 +1,423 @@
+//! Helpers for tests and benchmarks.
+
+use std::convert::Infallible;
+use std::io;
+use std::net::{Ipv4Addr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures_util::future::{self, Either};
+use hyper::body::Bytes;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, HeaderMap, Method, Request, Response, Server, StatusCode, Uri};
+use tokio::sync::oneshot;
+
+// ------ MockOrigin ------
+
+/// Programmable origin (addon) server.
+///
+/// It runs in its own thread with its own runtime, so it can be shared by tests
+/// with different runtimes. The server is stopped and all its connections are closed on drop.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let origin = MockOrigin::start(5005).unwrap();
+/// let resource = origin.resource("/catalog/movie/{id}");
+/// resource
+///     .header("Cache-Control", "max-age=300")
+///     .body(r#"{ "metas": [] }"#)
+///     .respond_in_sequence(vec![MockResponse::new().status(StatusCode::BAD_GATEWAY)]);
+/// // ... send requests through the proxy ...
+/// assert_eq!(resource.request_count(), 2);
+/// resource.verify();
+/// ```
+pub struct MockOrigin {
+    port: u16,
+    resources: Arc<Mutex<Vec<MockResource>>>,
+    shutdown_sender: Option<oneshot::Sender<()>>,
+    server_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MockOrigin {
+    /// Start the server on `127.0.0.1:<port>` (`0` means a random free port).
+    ///
+    /// # Errors
+    ///
+    /// Returns error when the port cannot be bound.
+    pub fn start(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+
+        let resources = Arc::new(Mutex::new(Vec::<MockResource>::new()));
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+
+        let server_resources = Arc::clone(&resources);
+        let server_thread = thread::spawn(move || {
+            let mut rt = tokio::runtime::Builder::new()
+                .enable_all()
+                .basic_scheduler()
+                .build()
+                .expect("rt build");
+
+            rt.block_on(async move {
+                let make_service = make_service_fn(move |_| {
+                    let resources = Arc::clone(&server_resources);
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |req| {
+                            handle_request(req, Arc::clone(&resources))
+                        }))
+                    }
+                });
+                let server = Server::from_tcp(listener)
+                    .expect("mock origin from listener")
+                    .serve(make_service);
+                // Dropping the runtime after the shutdown closes open connections.
+                if let Either::Left((Err(error), _)) =
+                    future::select(server, shutdown_receiver).await
+                {
+                    eprintln!("mock origin error: {}", error);
+                }
+            })
+        });
+
+        Ok(Self {
+            port,
+            resources,
+            shutdown_sender: Some(shutdown_sender),
+            server_thread: Some(server_thread),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// E.g. `http://127.0.0.1:5005`.
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// Create a resource responding with an empty `200` by default.
+    ///
+    /// Path segments in braces are wildcards (e.g. `/catalog/movie/{id}`).
+    /// Requests to unknown paths get `404`.
+    pub fn resource(&self, path: &str) -> MockResource {
+        let resource = MockResource {
+            path: path.to_owned(),
+            data: Arc::default(),
+        };
+        self.resources
+            .lock()
+            .expect("lock mock resources")
+            .push(resource.clone());
+        resource
+    }
+}
+
+impl Drop for MockOrigin {
+    fn drop(&mut self) {
+        if let Some(shutdown_sender) = self.shutdown_sender.take() {
+            let _ = shutdown_sender.send(());
+        }
+        if let Some(server_thread) = self.server_thread.take() {
+            let _ = server_thread.join();
+        }
+    }
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    resources: Arc<Mutex<Vec<MockResource>>>,
+) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let request = CapturedRequest {
+        method: parts.method,
+        uri: parts.uri,
+        headers: parts.headers,
+        body: hyper::body::to_bytes(body).await.unwrap_or_default(),
+    };
+
+    let resource = resources
+        .lock()
+        .expect("lock mock resources")
+        .iter()
+        .find(|resource| resource.matches(request.uri.path()))
+        .cloned();
+    let response = match resource {
+        Some(resource) => resource.respond(request),
+        None => MockResponse::new().status(StatusCode::NOT_FOUND),
+    };
+
+    if let Some(delay) = response.delay {
+        tokio::time::delay_for(delay).await;
+    }
+    let mut http_response = Response::new(Body::from(response.body));
+    *http_response.status_mut() = response.status;
+    *http_response.headers_mut() = response.headers;
+    Ok(http_response)
+}
+
+// ------ MockResource ------
+
+/// See `MockOrigin::resource`.
+///
+/// Setters change the default response and they can be chained.
+#[derive(Clone)]
+pub struct MockResource {
+    path: String,
+    data: Arc<Mutex<ResourceData>>,
+}
+
+#[derive(Default)]
+struct ResourceData {
+    default_response: MockResponse,
+    // Responses for the next requests.
+    response_sequence: Vec<MockResponse>,
+    requests: Vec<CapturedRequest>,
+    expectations: Vec<Expectation>,
+    failed_expectations: Vec<String>,
+}
+
+struct Expectation {
+    description: String,
+    predicate: Box<dyn Fn(&CapturedRequest) -> bool + Send>,
+}
+
+impl MockResource {
+    pub fn status(&self, status: StatusCode) -> &Self {
+        self.update(|data| data.default_response.status = status)
+    }
+
+    /// # Panics
+    ///
+    /// Panics when the name or the value is invalid.
+    pub fn header(&self, name: &str, value: &str) -> &Self {
+        self.update(|data| {
+            let response = std::mem::take(&mut data.default_response);
+            data.default_response = response.header(name, value);
+        })
+    }
+
+    pub fn body(&self, body: impl Into<Bytes>) -> &Self {
+        let body = body.into();
+        self.update(|data| data.default_response.body = body)
+    }
+
+    pub fn delay(&self, delay: Duration) -> &Self {
+        self.update(|data| data.default_response.delay = Some(delay))
+    }
+
+    /// Use the responses for the next requests in order and then the default response again.
+    pub fn respond_in_sequence(&self, responses: Vec<MockResponse>) -> &Self {
+        self.update(|data| data.response_sequence.extend(responses))
+    }
+
+    /// All next requests have to satisfy the predicate - see `verify`.
+    pub fn expect(
+        &self,
+        description: &str,
+        predicate: impl Fn(&CapturedRequest) -> bool + Send + 'static,
+    ) -> &Self {
+        let expectation = Expectation {
+            description: description.to_owned(),
+            predicate: Box::new(predicate),
+        };
+        self.update(|data| data.expectations.push(expectation))
+    }
+
+    /// The number of received requests.
+    pub fn request_count(&self) -> usize {
+        self.data.lock().expect("lock mock resource").requests.len()
+    }
+
+    /// Received requests in order.
+    pub fn requests(&self) -> Vec<CapturedRequest> {
+        self.data
+            .lock()
+            .expect("lock mock resource")
+            .requests
+            .clone()
+    }
+
+    /// # Panics
+    ///
+    /// Panics when a request hasn't satisfied an expectation (see `expect`).
+    pub fn verify(&self) {
+        let data = self.data.lock().expect("lock mock resource");
+        assert!(
+            data.failed_expectations.is_empty(),
+            "unexpected requests to '{}':\n{}",
+            self.path,
+            data.failed_expectations.join("\n")
+        );
+    }
+
+    fn update(&self, f: impl FnOnce(&mut ResourceData)) -> &Self {
+        f(&mut self.data.lock().expect("lock mock resource"));
+        self
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        let pattern_segments = self.path.split('/');
+        let path_segments = path.split('/');
+        pattern_segments.clone().count() == path_segments.clone().count()
+            && pattern_segments
+                .zip(path_segments)
+                .all(|(pattern, segment)| {
+                    (pattern.starts_with('{') && pattern.ends_with('}')) || pattern == segment
+                })
+    }
+
+    fn respond(&self, request: CapturedRequest) -> MockResponse {
+        let mut data = self.data.lock().expect("lock mock resource");
+        let failures = data
+            .expectations
+            .iter()
+            .filter(|expectation| !(expectation.predicate)(&request))
+            .map(|expectation| {
+                format!(
+                    "- {} {} doesn't satisfy '{}'",
+                    request.method, request.uri, expectation.description
+                )
+            })
+            .collect::<Vec<_>>();
+        data.failed_expectations.extend(failures);
+        data.requests.push(request);
+        if data.response_sequence.is_empty() {
+            data.default_response.clone()
+        } else {
+            data.response_sequence.remove(0)
+        }
+    }
+}
+
+// ------ MockResponse ------
+
+/// See `MockResource::respond_in_sequence`.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    delay: Option<Duration>,
+}
+
+impl Default for MockResponse {
+    fn default() -> Self {
+        Self {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+            delay: None,
+        }
+    }
+}
+
+impl MockResponse {
+    /// Empty `200` response.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// # Panics
+    ///
+    /// Panics when the name or the value is invalid.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.append(
+            hyper::header::HeaderName::from_bytes(name.as_bytes()).expect("valid header name"),
+            value.parse().expect("valid header value"),
+        );
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Wait before sending the response (e.g. to test timeouts).
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+}
+
+// ------ CapturedRequest ------
+
+/// Request received by `MockOrigin`.
+#[derive(Debug, Clone)]
+pub struct CapturedRequest {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+// ------ ------- TESTS ------ ------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Client;
+
+    #[tokio::test]
+    async fn sequence_and_capture() {
+        let origin = MockOrigin::start(0).unwrap();
+        let resource = origin.resource("/catalog/movie/{id}");
+        resource
+            .body("default")
+            .respond_in_sequence(vec![MockResponse::new()
+                .status(StatusCode::BAD_GATEWAY)
+                .body("failed")])
+            .expect("has x-test header", |request| {
+                request.headers.contains_key("x-test")
+            });
+        let client = Client::new();
+        let send_request = || async {
+            let request = Request::get(format!("{}/catalog/movie/top.json", origin.url()))
+                .header("x-test", "1")
+                .body(Body::empty())
+                .unwrap();
+            let response = client.request(request).await.unwrap();
+            let status = response.status();
+            (status, hyper::body::to_bytes(response).await.unwrap())
+        };
+
+        assert_eq!(
+            send_request().await,
+            (StatusCode::BAD_GATEWAY, "failed".into())
+        );
+        assert_eq!(send_request().await, (StatusCode::OK, "default".into()));
+        assert_eq!(resource.request_count(), 2);
+        assert_eq!(resource.requests()[0].uri, "/catalog/movie/top.json");
+        resource.verify();
+
+        let response = client
+            .get(format!("{}/unknown", origin.url()).parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "unexpected requests")]
+    async fn failed_expectation() {
+        let origin = MockOrigin::start(0).unwrap();
+        let resource = origin.resource("/manifest.json");
+        resource.expect("is POST", |request| request.method == Method::POST);
+
+        Client::new()
+            .get(format!("{}/manifest.json", origin.url()).parse().unwrap())
+            .await
+            .unwrap();
+        resource.verify();
+    }
+}
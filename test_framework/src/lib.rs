@@ -1,4 +1,5 @@
 use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
 use syn::{parse_macro_input, parse_quote};
 use quote::quote;
 use std::mem;
@@ -23,6 +24,11 @@ use std::mem;
 ///
 ///     fn after_all() {)
 ///
+///     // Optional, called after `after_all`.
+///     fn report(results: &[TestResult]) {
+///         print_report(results);
+///     }
+///
 ///     // ------ TESTS ------
 ///
 ///     #[test]
@@ -60,103 +66,138 @@ use std::mem;
 ///
 ///     #[test]
 ///     fn it_works() {
-///         run_test_sync(|| {assert_eq!(2 + 2, 4);});
+///         run_test_sync("it_works", false, || {assert_eq!(2 + 2, 4);});
 ///     }
 ///
 ///     #[tokio::test]
 ///     fn it_works_async() {
-///         run_test_async(async {
+///         run_test_async("it_works_async", false, async {
 ///             let two = futures::future::ready(2).await;
 ///             assert_eq!(two + 2, 4);
 ///         }).await;
 ///     }
-///     const TEST_COUNT: usize = 1;
-///     static REMAINING_TESTS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(TEST_COUNT);
+///     const TEST_COUNT: usize = 2;
+///     const IGNORED_TEST_COUNT: usize = 0;
+///     // `TEST_COUNT`, `IGNORED_TEST_COUNT` or their sum according to `--ignored` / `--include-ignored`.
+///     fn test_count_to_run() -> usize { /* ... */ }
+///     static REMAINING_TESTS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
 ///     static BEFORE_ALL_CALL: std::sync::Once = std::sync::Once::new();
-///     fn run_test_sync(test: impl FnOnce() + std::panic::UnwindSafe) {
+///     fn run_test_sync(name: &'static str, should_panic: bool, test: impl FnOnce() + std::panic::UnwindSafe) {
 ///         BEFORE_ALL_CALL.call_once(|| {
+///             REMAINING_TESTS.store(test_count_to_run(), std::sync::atomic::Ordering::SeqCst);
 ///             before_all();
 ///         });
 ///         before_each();  
 ///
+///         let start = std::time::Instant::now();
 ///         let result = std::panic::catch_unwind(|| {
 ///             test()
 ///         });    
+///         let duration = start.elapsed();
 ///
 ///         after_each();   
+///         // Only when `report` is defined.
+///         record_test_result(TestResult { name, passed: result.is_ok() != should_panic, duration });
 ///         if REMAINING_TESTS.fetch_sub(1, std::sync::atomic::Ordering::Relaxed) == 1 {
 ///             after_all();
+///             // Only when `report` is defined.
+///             report(&take_test_results());
 ///         }
 ///         
-///         assert!(result.is_ok())
+///         if let Err(panic) = result {
+///             std::panic::resume_unwind(panic);
+///         }
 ///     }    
-///     async fn run_test_async(test: impl futures::future::Future)
+///     async fn run_test_async(name: &'static str, should_panic: bool, test: impl futures::future::Future)
 ///     {
-///         BEFORE_ALL_CALL.call_once(|| {
-///             before_all();
-///         });
-///         before_each();  
+///         // The same as `run_test_sync`, but the test is awaited.
+///     }    
 ///
-///         use futures::future::FutureExt;
-///         let result = std::panic::AssertUnwindSafe(test).catch_unwind().await; 
+///     // Only when `report` is defined:
 ///
-///         after_each();   
-///         if REMAINING_TESTS.fetch_sub(1, std::sync::atomic::Ordering::Relaxed) == 1 {
-///             after_all();
-///         }
-///            
-///         assert!(result.is_ok())
-///     }    
+///     pub struct TestResult {
+///         pub name: &'static str,
+///         pub passed: bool,
+///         pub duration: std::time::Duration,
+///     }
+///     fn record_test_result(result: TestResult) { /* ... */ }
+///     fn take_test_results() -> Vec<TestResult> { /* ... */ }
+///     fn print_report(results: &[TestResult]) { /* ... */ }
 /// }
 ///```
+///
+/// # Nested modules and conditional compilation
+///
+/// Tests in nested inline modules are wrapped as well, callbacks are defined only in the annotated module.
+/// Tests (or their modules) with `#[cfg(...)]` attributes are counted only when they are compiled,
+/// so `after_all` is called after the last compiled test.
+///
+/// # `#[should_panic]` and `#[ignore]`
+///
+/// The original panic is resumed after `after_each` so `#[should_panic(expected = "...")]` works.
+/// Ignored tests are counted only when they are run (`cargo test -- --ignored` or `--include-ignored`).
+///
+/// # Report
+///
+/// Define `fn report(results: &[TestResult])` in the module to get the name, the result
+/// and the duration of each test once all tests are done.
+/// The generated `print_report` prints them with a summary - useful with `cargo test -- --nocapture` in CI:
+///
+/// ```text
+/// test_callbacks report for 'integration':
+///   it_works ........................ ok      0.001 s
+///   it_works_async .................. FAILED  0.012 s
+///   1 passed; 1 failed; 0.013 s total
+/// ```
 #[proc_macro_attribute]
 pub fn test_callbacks(_: TokenStream, tokens: TokenStream) -> TokenStream {
     let mut item_mod = parse_macro_input!(tokens as syn::ItemMod);
 
+    let module_name = item_mod.ident.to_string();
+
     if let Some((_, items)) = &mut item_mod.content {
-        let mut test_count: usize = 0;
+        // Results are collected only for the user-defined `report` callback.
+        let has_report = items.iter().any(|item| {
+            matches!(item, syn::Item::Fn(item_fn) if item_fn.sig.ident == "report")
+        });
 
-        for item in items.iter_mut() {
-            if let syn::Item::Fn(item_fn) = item {
-                // Functions with an attribute that contains "test" are considered as tests.
-                let is_test = item_fn.attrs.iter().any(|attr| {
-                    attr.path.segments.iter().any(|segment| {
-                        segment.ident == "test"
-                    })
-                });
-                if is_test {
-                    test_count += 1;
-
-                    // Wrap original test block into async or sync wrapper.
-
-                    let stmts = mem::take(&mut item_fn.block.stmts);
-                    let nested_block = syn::Block {
-                        brace_token: item_fn.block.brace_token,
-                        stmts
-                    };
-                    let wrapped_stmts = if item_fn.sig.asyncness.is_some() {
-                        parse_quote! {
-                            run_test_async(async #nested_block).await;
-                        }
-                    } else {
-                        parse_quote! {
-                            run_test_sync(|| #nested_block);
-                        }
-                    };
-                    item_fn.block.stmts = vec![wrapped_stmts];
-                }
-            }
-        }
+        let mut counted_tests = Vec::new();
+        wrap_tests(items, &[], &[], &mut counted_tests);
 
-        // ------ Inject `TEST_COUNT`, `REMAINING_TESTS` and `BEFORE_ALL_CALL` ------
+        // ------ Inject `TEST_COUNT`, `IGNORED_TEST_COUNT`, `test_count_to_run`, `REMAINING_TESTS` and `BEFORE_ALL_CALL` ------
+
+        let (counted_ignored_tests, counted_tests): (Vec<_>, Vec<_>) =
+            counted_tests.into_iter().partition(|counted_test| counted_test.ignored);
+        let test_count = count_tests(items, counted_tests, "TEST");
+        let ignored_test_count = count_tests(items, counted_ignored_tests, "IGNORED_TEST");
 
         let item_test_count = parse_quote! {
             const TEST_COUNT: usize = #test_count;
         };
         items.push(syn::Item::Const(item_test_count));
 
+        let item_ignored_test_count = parse_quote! {
+            const IGNORED_TEST_COUNT: usize = #ignored_test_count;
+        };
+        items.push(syn::Item::Const(item_ignored_test_count));
+
+        // Ignored tests are run only with `cargo test -- --ignored` or `--include-ignored`.
+        let item_fn_test_count_to_run = parse_quote! {
+            fn test_count_to_run() -> usize {
+                let args = std::env::args().collect::<Vec<_>>();
+                if args.iter().any(|arg| arg == "--include-ignored") {
+                    TEST_COUNT + IGNORED_TEST_COUNT
+                } else if args.iter().any(|arg| arg == "--ignored") {
+                    IGNORED_TEST_COUNT
+                } else {
+                    TEST_COUNT
+                }
+            }
+        };
+        items.push(syn::Item::Fn(item_fn_test_count_to_run));
+
         let item_remaining_tests = parse_quote! {
-            static REMAINING_TESTS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(TEST_COUNT);
+            static REMAINING_TESTS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
         };
         items.push(syn::Item::Static(item_remaining_tests));
 
@@ -165,46 +206,74 @@ pub fn test_callbacks(_: TokenStream, tokens: TokenStream) -> TokenStream {
         };
         items.push(syn::Item::Static(item_before_all_call));
 
+        // ------ Inject `TestResult`, `record_test_result`, `take_test_results` and `print_report` ------
+
+        let (record_test_result, call_report) = if has_report {
+            items.extend(report_items(&module_name));
+            (
+                quote! { record_test_result(TestResult { name, passed: result.is_ok() != should_panic, duration }); },
+                quote! { report(&take_test_results()); },
+            )
+        } else {
+            (quote! { let _ = (name, should_panic, duration); }, quote! {})
+        };
+
         // ------ Inject `run_test_sync` and `run_test_async` ------
 
         let item_fn_run_test_sync = parse_quote! {
-            fn run_test_sync(test: impl FnOnce() + std::panic::UnwindSafe) {
+            fn run_test_sync(name: &'static str, should_panic: bool, test: impl FnOnce() + std::panic::UnwindSafe) {
                 BEFORE_ALL_CALL.call_once(|| {
+                    REMAINING_TESTS.store(test_count_to_run(), std::sync::atomic::Ordering::SeqCst);
                     before_all();
                 });
                 before_each();  
 
+                let start = std::time::Instant::now();
                 let result = std::panic::catch_unwind(|| {
                     test()
                 });    
+                let duration = start.elapsed();
 
                 after_each();   
+                #record_test_result
                 if REMAINING_TESTS.fetch_sub(1, std::sync::atomic::Ordering::Relaxed) == 1 {
                     after_all();
+                    #call_report
                 }
                 
-                assert!(result.is_ok())
+                // Resume the original panic so `#[should_panic(expected = "...")]` can check its message.
+                if let Err(panic) = result {
+                    std::panic::resume_unwind(panic);
+                }
             }    
         };    
         items.push(syn::Item::Fn(item_fn_run_test_sync));
 
         let item_fn_run_test_async = parse_quote! {
-            async fn run_test_async(test: impl futures::future::Future)
+            async fn run_test_async(name: &'static str, should_panic: bool, test: impl futures::future::Future)
             {
                 BEFORE_ALL_CALL.call_once(|| {
+                    REMAINING_TESTS.store(test_count_to_run(), std::sync::atomic::Ordering::SeqCst);
                     before_all();
                 });
                 before_each();  
         
                 use futures::future::FutureExt;
+                let start = std::time::Instant::now();
                 let result = std::panic::AssertUnwindSafe(test).catch_unwind().await; 
+                let duration = start.elapsed();
         
                 after_each();   
+                #record_test_result
                 if REMAINING_TESTS.fetch_sub(1, std::sync::atomic::Ordering::Relaxed) == 1 {
                     after_all();
+                    #call_report
                 }
                 
-                assert!(result.is_ok())
+                // Resume the original panic so `#[should_panic(expected = "...")]` can check its message.
+                if let Err(panic) = result {
+                    std::panic::resume_unwind(panic);
+                }
             }    
         };    
         items.push(syn::Item::Fn(item_fn_run_test_async));
@@ -213,3 +282,173 @@ pub fn test_callbacks(_: TokenStream, tokens: TokenStream) -> TokenStream {
     let output = quote!{ #item_mod };
     output.into()
 }
+
+/// Wrap tests in `items` and in nested modules into `run_test_sync` or `run_test_async`.
+///
+/// `cfgs` are predicates of `#[cfg(...)]` attributes of the parent modules.
+/// Each found test is pushed to `counted_tests`.
+fn wrap_tests(
+    items: &mut [syn::Item],
+    module_path: &[String],
+    cfgs: &[TokenStream2],
+    counted_tests: &mut Vec<CountedTest>,
+) {
+    // Wrappers are defined in the root module.
+    let supers = module_path.iter().map(|_| quote! { super:: }).collect::<Vec<_>>();
+
+    for item in items.iter_mut() {
+        match item {
+            syn::Item::Fn(item_fn) => {
+                // Functions with an attribute that contains "test" are considered as tests.
+                let is_test = item_fn.attrs.iter().any(|attr| {
+                    attr.path.segments.iter().any(|segment| {
+                        segment.ident == "test"
+                    })
+                });
+                if !is_test {
+                    continue;
+                }
+                let has_attr = |name| item_fn.attrs.iter().any(|attr| attr.path.is_ident(name));
+                let should_panic = has_attr("should_panic");
+                counted_tests.push(CountedTest {
+                    cfgs: with_cfgs(cfgs, &item_fn.attrs),
+                    ignored: has_attr("ignore"),
+                });
+
+                let mut test_path = module_path.to_vec();
+                test_path.push(item_fn.sig.ident.to_string());
+                let test_name = test_path.join("::");
+
+                // Wrap original test block into async or sync wrapper.
+
+                let stmts = mem::take(&mut item_fn.block.stmts);
+                let nested_block = syn::Block {
+                    brace_token: item_fn.block.brace_token,
+                    stmts
+                };
+                let wrapped_stmts = if item_fn.sig.asyncness.is_some() {
+                    parse_quote! {
+                        #(#supers)* run_test_async(#test_name, #should_panic, async #nested_block).await;
+                    }
+                } else {
+                    parse_quote! {
+                        #(#supers)* run_test_sync(#test_name, #should_panic, || #nested_block);
+                    }
+                };
+                item_fn.block.stmts = vec![wrapped_stmts];
+            }
+            syn::Item::Mod(item_mod) => {
+                // Modules in other files (`mod name;`) are skipped.
+                if let Some((_, items)) = &mut item_mod.content {
+                    let mut module_path = module_path.to_vec();
+                    module_path.push(item_mod.ident.to_string());
+                    let cfgs = with_cfgs(cfgs, &item_mod.attrs);
+                    wrap_tests(items, &module_path, &cfgs, counted_tests);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+struct CountedTest {
+    // Predicates of `#[cfg(...)]` attributes of the test and its modules.
+    cfgs: Vec<TokenStream2>,
+    // `#[ignore]`
+    ignored: bool,
+}
+
+/// Create an expression with the number of compiled tests.
+///
+/// Conditionally compiled tests are counted by injected constants
+/// (`<prefix>_COUNTER_<index>`) defined only when they are compiled.
+fn count_tests(items: &mut Vec<syn::Item>, counted_tests: Vec<CountedTest>, prefix: &str) -> TokenStream2 {
+    let unconditional_test_count = counted_tests.iter().filter(|counted_test| counted_test.cfgs.is_empty()).count();
+    let mut test_counters = Vec::new();
+    if unconditional_test_count > 0 {
+        test_counters.push(quote! { #unconditional_test_count });
+    }
+    for (index, counted_test) in counted_tests.into_iter().enumerate() {
+        let cfgs = counted_test.cfgs;
+        if cfgs.is_empty() {
+            continue;
+        }
+        let counter = quote::format_ident!("{}_COUNTER_{}", prefix, index);
+        items.push(parse_quote! {
+            #[cfg(all(#(#cfgs),*))]
+            const #counter: usize = 1;
+        });
+        items.push(parse_quote! {
+            #[cfg(not(all(#(#cfgs),*)))]
+            const #counter: usize = 0;
+        });
+        test_counters.push(quote! { #counter });
+    }
+    if test_counters.is_empty() {
+        test_counters.push(quote! { 0 });
+    }
+    quote! { #(#test_counters)+* }
+}
+
+/// Append predicates of `#[cfg(...)]` attributes to `cfgs`.
+fn with_cfgs(cfgs: &[TokenStream2], attrs: &[syn::Attribute]) -> Vec<TokenStream2> {
+    let mut cfgs = cfgs.to_vec();
+    cfgs.extend(
+        attrs
+            .iter()
+            .filter(|attr| attr.path.is_ident("cfg"))
+            .filter_map(|attr| attr.parse_args::<TokenStream2>().ok()),
+    );
+    cfgs
+}
+
+/// Items for the user-defined callback `report`.
+fn report_items(module_name: &str) -> Vec<syn::Item> {
+    vec![
+        parse_quote! {
+            /// The result of one test - see `report`.
+            #[derive(Debug, Clone)]
+            pub struct TestResult {
+                pub name: &'static str,
+                pub passed: bool,
+                pub duration: std::time::Duration,
+            }
+        },
+        parse_quote! {
+            static RESULTS: std::sync::Mutex<Vec<TestResult>> = std::sync::Mutex::new(Vec::new());
+        },
+        parse_quote! {
+            fn record_test_result(result: TestResult) {
+                RESULTS.lock().unwrap_or_else(std::sync::PoisonError::into_inner).push(result);
+            }
+        },
+        parse_quote! {
+            fn take_test_results() -> Vec<TestResult> {
+                std::mem::take(&mut *RESULTS.lock().unwrap_or_else(std::sync::PoisonError::into_inner))
+            }
+        },
+        parse_quote! {
+            /// Print test durations and a summary.
+            #[allow(dead_code)]
+            fn print_report(results: &[TestResult]) {
+                println!("test_callbacks report for '{}':", #module_name);
+                for result in results {
+                    println!(
+                        "  {:.<32} {:<7} {:.3} s",
+                        format!("{} ", result.name),
+                        if result.passed { "ok" } else { "FAILED" },
+                        result.duration.as_secs_f64()
+                    );
+                }
+                let passed = results.iter().filter(|result| result.passed).count();
+                let total_time: std::time::Duration = results.iter().map(|result| result.duration).sum();
+                println!(
+                    "  {} passed; {} failed; {:.3} s total",
+                    passed,
+                    results.len() - passed,
+                    total_time.as_secs_f64()
+                );
+            }
+        },
+    ]
+}
@@ -9,8 +9,8 @@ mod caching {
     use std::sync::Mutex;
 
     use chrono::Utc;
-    use http_test_server::TestServer;
 
+    use ::addon_proxy::testing::MockOrigin;
     use ::addon_proxy::{default_client, helpers::set_now_getter, on_request, Proxy};
     use hyper::client::HttpConnector;
     use hyper::{Client, StatusCode, Uri};
@@ -33,6 +33,10 @@ mod caching {
         PROXY_STOPPER.lock().unwrap().take().unwrap()();
     }
 
+    fn report(results: &[TestResult]) {
+        print_report(results);
+    }
+
     // ------ TESTS ------
 
     // Caching: the proxy must cache responses by respecting the HTTP cache headers that come from the origin
@@ -44,6 +48,7 @@ mod caching {
         test_no_headers(&client).await;
         test_max_age_header(&client).await;
         test_stale_response(&client).await;
+        test_head_revalidation(&client).await;
     }
 
     // "If no cache headers are returned at all, assume 10 minutes cache validity."
@@ -53,7 +58,7 @@ mod caching {
         set_now_getter(|| Utc::now().timestamp());
 
         let mock_server = start_mock_server();
-        let resource = mock_server.create_resource("/catalog/movie/top.json");
+        let resource = mock_server.resource("/catalog/movie/top.json");
         resource.body(include_str!("../test_data/top.json"));
 
         let path = "/origin/catalog/movie/top.json";
@@ -84,7 +89,7 @@ mod caching {
         set_now_getter(|| Utc::now().timestamp());
 
         let mock_server = start_mock_server();
-        let resource = mock_server.create_resource("/catalog/movie/top.json");
+        let resource = mock_server.resource("/catalog/movie/top.json");
         resource
             // 300s = 5 min
             .header("Cache-Control", "max-age=300")
@@ -119,7 +124,7 @@ mod caching {
         set_now_getter(|| Utc::now().timestamp());
 
         let mock_server = start_mock_server();
-        let resource = mock_server.create_resource("/catalog/movie/top.json");
+        let resource = mock_server.resource("/catalog/movie/top.json");
         resource.body(include_str!("../test_data/top.json"));
 
         let path = "/origin/catalog/movie/top.json";
@@ -151,6 +156,49 @@ mod caching {
         );
     }
 
+    // Routes with `head_revalidation` download expired responses again only when they have changed.
+    async fn test_head_revalidation(client: &Client<HttpConnector>) {
+        // ------ ARRANGE ------
+        clear_cache().await;
+        set_now_getter(|| Utc::now().timestamp());
+
+        let mock_server = start_mock_server();
+        let resource = mock_server.resource("/catalog/movie/top.json");
+        resource
+            .header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+            .body(include_str!("../test_data/top.json"));
+
+        let path = "/head-revalidated/catalog/movie/top.json";
+        let send_request = || async { client.get(url_from_path(path)).await.unwrap() };
+
+        // ------ ACT ------
+
+        send_request().await; // This request should be loaded from the origin.
+
+        // Jump in time: +11 min. Jump has to be longer than `default_cache_validity` in proxy config.
+        set_now_getter(|| Utc::now().timestamp() + (11 * 60));
+
+        // The response hasn't changed - it should be revalidated by a HEAD request.
+        assert_eq!(send_request().await.status(), StatusCode::OK);
+        send_request().await;
+
+        // Jump in time: +22 min.
+        set_now_getter(|| Utc::now().timestamp() + (22 * 60));
+        resource.body("{\"metas\":[]}");
+
+        // The response has changed - it should be loaded from the origin after a HEAD request.
+        send_request().await;
+
+        // ------ ASSERT ------
+
+        let methods = resource
+            .requests()
+            .into_iter()
+            .map(|request| request.method.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(methods, vec!["GET", "HEAD", "HEAD", "GET"]);
+    }
+
     // ------ SETUP HELPERS ------
 
     fn start_proxy(config_path: &'static str) -> impl FnOnce() {
@@ -166,7 +214,7 @@ mod caching {
                             .send(controller)
                             .expect("send proxy controller")
                     })
-                    .set_on_server_stop(move || {
+                    .set_on_server_stop(move |_summary| {
                         stop_signal_sender.send(()).expect("send stop signal")
                     })
                     .start()
@@ -190,8 +238,8 @@ mod caching {
     }
 
     #[must_use = "Mock server is stopped on drop"]
-    fn start_mock_server() -> TestServer {
-        TestServer::new_with_port(5005).unwrap()
+    fn start_mock_server() -> MockOrigin {
+        MockOrigin::start(5005).unwrap()
     }
 
     fn url_from_path(path: &str) -> Uri {
@@ -0,0 +1,208 @@
+use test_framework::test_callbacks;
+
+#[test_callbacks]
+#[cfg(test)]
+mod concurrent_caching {
+    use once_cell::sync::Lazy;
+
+    use std::fs;
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use futures::future::join_all;
+
+    use ::addon_proxy::testing::MockOrigin;
+    use ::addon_proxy::{default_client, on_request, Proxy};
+    use hyper::body::Bytes;
+    use hyper::client::HttpConnector;
+    use hyper::{Client, StatusCode, Uri};
+
+    static PROXY_STOPPER: Lazy<Mutex<Option<Box<dyn FnOnce() + Send>>>> =
+        Lazy::new(|| Mutex::new(None));
+
+    const CONCURRENT_CLIENTS: usize = 20;
+    const ORIGIN_DELAY: Duration = Duration::from_millis(300);
+
+    // ------ SETUP ------
+
+    fn before_all() {
+        let _ = fs::remove_dir_all("test_data/concurrent_proxy_db");
+        let proxy_stopper = start_proxy("test_data/proxy_cfg_concurrent.toml");
+        *PROXY_STOPPER.lock().unwrap() = Some(Box::new(proxy_stopper));
+    }
+
+    fn before_each() {}
+
+    fn after_each() {}
+
+    fn after_all() {
+        PROXY_STOPPER.lock().unwrap().take().unwrap()();
+        let _ = fs::remove_dir_all("test_data/concurrent_proxy_db");
+    }
+
+    fn report(results: &[TestResult]) {
+        print_report(results);
+    }
+
+    // ------ TESTS ------
+
+    #[tokio::test]
+    async fn concurrent_caching_test_suite() {
+        let client = Client::new();
+        // Run tests sequentially because they share the mock origin port and the cache.
+        test_cold_cache_consistent_bodies(&client).await;
+        test_clear_cache_during_in_flight_writes(&client).await;
+    }
+
+    // Concurrent requests to a cold cache get the same body and the next requests are cached.
+    async fn test_cold_cache_consistent_bodies(client: &Client<HttpConnector>) {
+        // ------ ARRANGE ------
+        clear_cache(client).await;
+
+        let mock_server = start_mock_server();
+        let resource = mock_server.resource("/catalog/movie/top.json");
+        resource
+            .body(include_str!("../test_data/top.json"))
+            .delay(ORIGIN_DELAY);
+
+        let path = "/origin/catalog/movie/top.json";
+
+        // ------ ACT ------
+
+        let bodies = send_concurrent_requests(client, path).await;
+        let origin_requests = resource.request_count();
+        send_request(client, path).await;
+
+        // ------ ASSERT ------
+
+        assert!(bodies
+            .iter()
+            .all(|body| body == include_str!("../test_data/top.json")));
+        // The last request should be loaded from the cache.
+        assert_eq!(resource.request_count(), origin_requests);
+    }
+
+    // The origin should receive exactly one of concurrent identical requests.
+    #[tokio::test]
+    #[ignore = "requires single-flight (request coalescing) in the proxy"]
+    async fn cold_cache_single_origin_request() {
+        let client = Client::new();
+        clear_cache(&client).await;
+
+        let mock_server = start_mock_server();
+        let resource = mock_server.resource("/catalog/movie/top.json");
+        resource
+            .body(include_str!("../test_data/top.json"))
+            .delay(ORIGIN_DELAY);
+
+        send_concurrent_requests(&client, "/origin/catalog/movie/top.json").await;
+
+        assert_eq!(resource.request_count(), 1);
+    }
+
+    // Clearing the cache while responses are being fetched mustn't break the cache:
+    // the in-flight responses are still returned to clients and the cache stays consistent.
+    async fn test_clear_cache_during_in_flight_writes(client: &Client<HttpConnector>) {
+        // ------ ARRANGE ------
+        clear_cache(client).await;
+
+        let mock_server = start_mock_server();
+        let resource = mock_server.resource("/catalog/movie/top.json");
+        resource
+            .body(include_str!("../test_data/top.json"))
+            .delay(ORIGIN_DELAY);
+
+        let path = "/origin/catalog/movie/top.json";
+
+        // ------ ACT ------
+
+        let (bodies, _) = futures::join!(send_concurrent_requests(client, path), async {
+            tokio::time::delay_for(ORIGIN_DELAY / 2).await;
+            clear_cache(client).await;
+        });
+        let origin_requests = resource.request_count();
+
+        let cached_body = send_request(client, path).await;
+        let origin_requests_after_write = resource.request_count();
+
+        clear_cache(client).await;
+        let fresh_body = send_request(client, path).await;
+
+        // ------ ASSERT ------
+
+        assert!(bodies
+            .iter()
+            .all(|body| body == include_str!("../test_data/top.json")));
+        // The responses written after the clearing are valid cache entries.
+        assert_eq!(cached_body, include_str!("../test_data/top.json"));
+        assert_eq!(origin_requests_after_write, origin_requests);
+        // The next clearing removes them.
+        assert_eq!(fresh_body, include_str!("../test_data/top.json"));
+        assert_eq!(resource.request_count(), origin_requests + 1);
+    }
+
+    // ------ REQUEST HELPERS ------
+
+    async fn send_concurrent_requests(client: &Client<HttpConnector>, path: &str) -> Vec<Bytes> {
+        join_all((0..CONCURRENT_CLIENTS).map(|_| send_request(client, path))).await
+    }
+
+    async fn send_request(client: &Client<HttpConnector>, path: &str) -> Bytes {
+        let response = client.get(url_from_path(path)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        hyper::body::to_bytes(response.into_body()).await.unwrap()
+    }
+
+    // ------ SETUP HELPERS ------
+
+    fn start_proxy(config_path: &'static str) -> impl FnOnce() {
+        let (controller_sender, controller_receiver) = mpsc::channel();
+        let (stop_signal_sender, stop_signal_receiver) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            let proxy = async {
+                Proxy::new(default_client, on_request)
+                    .set_config_path(config_path)
+                    .set_on_server_start(move |controller| {
+                        controller_sender
+                            .send(controller)
+                            .expect("send proxy controller")
+                    })
+                    .set_on_server_stop(move |_summary| {
+                        stop_signal_sender.send(()).expect("send stop signal")
+                    })
+                    .start()
+                    .await
+            };
+
+            let mut rt = tokio::runtime::Builder::new()
+                .enable_all()
+                .basic_scheduler()
+                .build()
+                .expect("rt build");
+
+            rt.block_on(proxy)
+        });
+
+        let controller = controller_receiver.recv().expect("receive proxy ctrl");
+        move || {
+            controller.stop();
+            stop_signal_receiver.recv().expect("receive stop signal");
+        }
+    }
+
+    #[must_use = "Mock server is stopped on drop"]
+    fn start_mock_server() -> MockOrigin {
+        MockOrigin::start(5025).unwrap()
+    }
+
+    fn url_from_path(path: &str) -> Uri {
+        let proxy_url = "http://127.0.0.1:5020";
+        format!("{}{}", proxy_url, path).parse().unwrap()
+    }
+
+    async fn clear_cache(client: &Client<HttpConnector>) {
+        client.get(url_from_path("/clear-cache")).await.unwrap();
+    }
+}
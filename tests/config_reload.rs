@@ -0,0 +1,247 @@
+use test_framework::test_callbacks;
+
+#[test_callbacks]
+#[cfg(test)]
+mod config_reload {
+    use once_cell::sync::Lazy;
+
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+    use std::{env, fs};
+
+    use futures::future::join_all;
+
+    use ::addon_proxy::{default_client, on_request, Proxy};
+    use hyper::{body, Client, Request, StatusCode, Uri};
+    use tokio::time;
+
+    static PROXY_STOPPER: Lazy<Mutex<Option<Box<dyn FnOnce() + Send>>>> =
+        Lazy::new(|| Mutex::new(None));
+    static CONFIG_PATH: Lazy<PathBuf> =
+        Lazy::new(|| env::temp_dir().join("addon_proxy_config_reload_test.toml"));
+
+    const ADMIN_TOKEN: &str = "secret";
+    const CONCURRENT_CLIENTS: usize = 16;
+
+    // ------ SETUP ------
+
+    fn before_all() {
+        write_config("first");
+        let proxy_stopper = start_proxy(CONFIG_PATH.clone());
+        *PROXY_STOPPER.lock().unwrap() = Some(Box::new(proxy_stopper));
+    }
+
+    fn before_each() {}
+
+    fn after_each() {}
+
+    fn after_all() {
+        PROXY_STOPPER.lock().unwrap().take().unwrap()();
+        fs::remove_file(&*CONFIG_PATH).ok();
+    }
+
+    fn report(results: &[TestResult]) {
+        print_report(results);
+    }
+
+    // ------ TESTS ------
+
+    #[tokio::test]
+    async fn config_reload_test_suite() {
+        // Run tests sequentially because they share the config file.
+        test_reload().await;
+        test_reload_under_load().await;
+    }
+
+    async fn test_reload() {
+        // Requests without reloads get the current config.
+        for _ in 0..3 {
+            assert_eq!(config_name().await, "first");
+        }
+
+        // The config file change is ignored until the reload.
+        write_config("second");
+        assert_eq!(config_name().await, "first");
+        reload_config().await;
+        wait_for_config_name("second").await;
+        for _ in 0..3 {
+            assert_eq!(config_name().await, "second");
+        }
+
+        // An invalid config is not loaded.
+        let (epoch, _) = config_decision().await;
+        fs::write(&*CONFIG_PATH, "invalid config").unwrap();
+        let (status, _) = try_reload_config().await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(config_decision().await, (epoch, "second".to_owned()));
+
+        // Neither a config that fails the validation.
+        let duplicate_route =
+            "[[routes]]\nfrom = \"127.0.0.1:5000/origin\"\nto = \"http://127.0.0.1:5006\"\n";
+        fs::write(
+            &*CONFIG_PATH,
+            format!("{}{}", config_text("third"), duplicate_route),
+        )
+        .unwrap();
+        let (status, body) = try_reload_config().await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body.contains("is unreachable because a previous route has the same 'from'"));
+        assert_eq!(config_decision().await, (epoch, "second".to_owned()));
+    }
+
+    // Each request is handled by one config, even when configs are reloaded
+    // while many requests are in flight.
+    async fn test_reload_under_load() {
+        // ------ ARRANGE ------
+        let stop = AtomicBool::new(false);
+        let client = || async {
+            let mut decisions = Vec::new();
+            while !stop.load(Ordering::SeqCst) {
+                decisions.push(config_decision().await);
+            }
+            decisions
+        };
+
+        // ------ ACT ------
+
+        let (decisions, _) =
+            futures::join!(join_all((0..CONCURRENT_CLIENTS).map(|_| client())), async {
+                for index in 0..10 {
+                    let name = format!("load-{}", index);
+                    write_config(&name);
+                    reload_config().await;
+                    wait_for_config_name(&name).await;
+                }
+                stop.store(true, Ordering::SeqCst);
+            });
+
+        // ------ ASSERT ------
+
+        let mut names_by_epoch = HashMap::new();
+        for client_decisions in &decisions {
+            // Clients never get an older config after a newer one.
+            assert!(client_decisions
+                .windows(2)
+                .all(|decisions| decisions[0].0 <= decisions[1].0));
+            for (epoch, name) in client_decisions {
+                // Decisions tagged by the same epoch are made by the same config.
+                let epoch_name = names_by_epoch.entry(*epoch).or_insert(name);
+                assert_eq!(epoch_name, &name, "mixed configs in epoch {}", epoch);
+            }
+        }
+        assert!(names_by_epoch.len() > 1);
+    }
+
+    // ------ HELPERS ------
+
+    /// The body of the virtual endpoint `/config-name`.
+    async fn config_name() -> String {
+        config_decision().await.1
+    }
+
+    /// The debug header `X-Proxy-Config-Epoch` and the body of the virtual endpoint `/config-name`.
+    async fn config_decision() -> (u64, String) {
+        let req = Request::get(url_from_path("/config-name"))
+            .header("x-proxy-admin-token", ADMIN_TOKEN)
+            .body(body::Body::empty())
+            .unwrap();
+        let res = Client::new().request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let epoch = res.headers()["x-proxy-config-epoch"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        (epoch, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn reload_config() {
+        let (status, body) = try_reload_config().await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    /// The status and the body of the reload response.
+    async fn try_reload_config() -> (StatusCode, String) {
+        let res = Client::new()
+            .get(url_from_path("/reload-proxy-config"))
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// The config is reloaded in a standalone task.
+    async fn wait_for_config_name(name: &str) {
+        let start = Instant::now();
+        while config_name().await != name {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "config '{}' hasn't been loaded",
+                name
+            );
+            time::delay_for(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Write the test config with the virtual endpoint `/config-name` responding with `name`.
+    fn write_config(name: &str) {
+        fs::write(&*CONFIG_PATH, config_text(name)).unwrap();
+    }
+
+    fn config_text(name: &str) -> String {
+        format!(
+            "admin_token = \"{}\"\n{}\n[[virtual_endpoints]]\npath = \"/config-name\"\nbody = \"{}\"\n",
+            ADMIN_TOKEN,
+            include_str!("../test_data/proxy_cfg_no_cache.toml"),
+            name
+        )
+    }
+
+    fn url_from_path(path: &str) -> Uri {
+        format!("http://127.0.0.1:5000{}", path).parse().unwrap()
+    }
+
+    // ------ SETUP HELPERS ------
+
+    fn start_proxy(config_path: PathBuf) -> impl FnOnce() {
+        let (controller_sender, controller_receiver) = mpsc::channel();
+        let (stop_signal_sender, stop_signal_receiver) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            let proxy = async {
+                Proxy::new(default_client, on_request)
+                    .set_config_path(config_path)
+                    .set_on_server_start(move |controller| {
+                        controller_sender
+                            .send(controller)
+                            .expect("send proxy controller")
+                    })
+                    .set_on_server_stop(move |_summary| {
+                        stop_signal_sender.send(()).expect("send stop signal")
+                    })
+                    .start()
+                    .await
+            };
+
+            let mut rt = tokio::runtime::Builder::new()
+                .enable_all()
+                .basic_scheduler()
+                .build()
+                .expect("rt build");
+
+            rt.block_on(proxy)
+        });
+
+        let controller = controller_receiver.recv().expect("receive proxy ctrl");
+        move || {
+            controller.stop();
+            stop_signal_receiver.recv().expect("receive stop signal");
+        }
+    }
+}
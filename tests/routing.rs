@@ -7,15 +7,17 @@ mod routing {
 
     use std::sync::mpsc;
     use std::sync::Mutex;
+    use std::time::{Duration, Instant};
 
-    use http_test_server::TestServer;
-
-    use ::addon_proxy::{default_client, on_request, Proxy};
-    use hyper::{Client, StatusCode, Uri};
+    use ::addon_proxy::testing::MockOrigin;
+    use ::addon_proxy::{default_client, on_request, Proxy, ShutdownReason};
+    use hyper::{Body, Client, Request, StatusCode, Uri};
 
     static PROXY_STOPPER: Lazy<Mutex<Option<Box<dyn FnOnce() + Send>>>> =
         Lazy::new(|| Mutex::new(None));
-    static MOCK_SERVER: Lazy<Mutex<Option<TestServer>>> = Lazy::new(|| Mutex::new(None));
+    static MOCK_SERVER: Lazy<Mutex<Option<MockOrigin>>> = Lazy::new(|| Mutex::new(None));
+
+    const SLOW_ORIGIN_DELAY: Duration = Duration::from_secs(1);
 
     // ------ SETUP ------
 
@@ -34,6 +36,10 @@ mod routing {
         MOCK_SERVER.lock().unwrap().take().unwrap();
     }
 
+    fn report(results: &[TestResult]) {
+        print_report(results);
+    }
+
     // ------ TESTS ------
 
     #[tokio::test]
@@ -57,6 +63,25 @@ mod routing {
         assert_eq!(res.status(), StatusCode::OK,);
     }
 
+    #[tokio::test]
+    async fn request_deadline_exceeded() {
+        let request = |deadline: &str| {
+            Request::get(url_from_path("/origin/catalog/movie/slow.json"))
+                .header("x-request-deadline", deadline)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let client = Client::new();
+
+        let start = Instant::now();
+        let res = client.request(request("200")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(start.elapsed() < SLOW_ORIGIN_DELAY);
+
+        let res = client.request(request("5000")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
     // ------ SETUP HELPERS ------
 
     fn start_proxy(config_path: &'static str) -> impl FnOnce() {
@@ -72,8 +97,8 @@ mod routing {
                             .send(controller)
                             .expect("send proxy controller")
                     })
-                    .set_on_server_stop(move || {
-                        stop_signal_sender.send(()).expect("send stop signal")
+                    .set_on_server_stop(move |summary| {
+                        stop_signal_sender.send(summary).expect("send stop signal")
                     })
                     .start()
                     .await
@@ -91,24 +116,32 @@ mod routing {
         let controller = controller_receiver.recv().expect("receive proxy ctrl");
         move || {
             controller.stop();
-            stop_signal_receiver.recv().expect("receive stop signal");
+            let summary = stop_signal_receiver.recv().expect("receive stop signal");
+            assert_eq!(summary.reason, ShutdownReason::Requested);
+            assert_eq!(summary.pending_requests, 0);
         }
     }
 
     #[must_use = "Mock server is stopped on drop"]
-    fn start_mock_server() -> TestServer {
-        let mock_server = TestServer::new_with_port(5005).unwrap();
+    fn start_mock_server() -> MockOrigin {
+        let mock_server = MockOrigin::start(5005).unwrap();
 
         mock_server
-            .create_resource("/manifest.json")
+            .resource("/manifest.json")
             .header("Content-Type", "application/json")
             .body(include_str!("../test_data/manifest.json"));
 
         mock_server
-            .create_resource("/catalog/movie/top.json")
+            .resource("/catalog/movie/top.json")
             .header("Content-Type", "application/json")
             .body(include_str!("../test_data/top.json"));
 
+        mock_server
+            .resource("/catalog/movie/slow.json")
+            .header("Content-Type", "application/json")
+            .body(include_str!("../test_data/top.json"))
+            .delay(SLOW_ORIGIN_DELAY);
+
         mock_server
     }
 
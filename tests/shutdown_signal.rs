@@ -0,0 +1,75 @@
+use test_framework::test_callbacks;
+
+#[test_callbacks]
+#[cfg(all(test, unix))]
+mod shutdown_signal {
+    use std::process::{self, Command};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use ::addon_proxy::{default_client, on_request, Proxy, ShutdownReason};
+
+    // ------ SETUP ------
+
+    fn before_all() {}
+
+    fn before_each() {}
+
+    fn after_each() {}
+
+    fn after_all() {}
+
+    fn report(results: &[TestResult]) {
+        print_report(results);
+    }
+
+    // ------ TESTS ------
+
+    #[test]
+    fn sigterm_stops_proxy_gracefully() {
+        let (controller_sender, controller_receiver) = mpsc::channel();
+        let (summary_sender, summary_receiver) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            let proxy = async {
+                Proxy::new(default_client, on_request)
+                    .set_config_path("test_data/proxy_cfg_no_cache.toml")
+                    .set_handle_signals(true)
+                    .set_on_server_start(move |controller| {
+                        controller_sender
+                            .send(controller)
+                            .expect("send proxy controller")
+                    })
+                    .set_on_server_stop(move |summary| {
+                        summary_sender.send(summary).expect("send shutdown summary")
+                    })
+                    .start()
+                    .await
+            };
+
+            let mut rt = tokio::runtime::Builder::new()
+                .enable_all()
+                .basic_scheduler()
+                .build()
+                .expect("rt build");
+
+            rt.block_on(proxy)
+        });
+
+        // Signal handlers are registered before the server is started.
+        // (The controller is kept, otherwise the server would be stopped when it's dropped.)
+        let _controller = controller_receiver.recv().expect("receive proxy ctrl");
+        let status = Command::new("kill")
+            .arg("-TERM")
+            .arg(process::id().to_string())
+            .status()
+            .expect("run kill");
+        assert!(status.success());
+
+        let summary = summary_receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("receive shutdown summary");
+        assert_eq!(summary.reason, ShutdownReason::Requested);
+        assert_eq!(summary.pending_requests, 0);
+    }
+}
@@ -0,0 +1,235 @@
+//! Drives the proxy for minutes and checks that memory and file descriptors don't leak.
+//!
+//! Run it on Linux by
+//! ```bash
+//! SOAK_TEST_SECONDS=300 cargo test --release --features soak-test --test soak -- --nocapture
+//! ```
+#![cfg(all(feature = "soak-test", target_os = "linux"))]
+
+use test_framework::test_callbacks;
+
+#[test_callbacks]
+#[cfg(test)]
+mod soak {
+    use once_cell::sync::Lazy;
+
+    use std::env;
+    use std::fs;
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use futures::future::join_all;
+
+    use ::addon_proxy::testing::MockOrigin;
+    use ::addon_proxy::{default_client, on_request, Proxy};
+    use hyper::client::HttpConnector;
+    use hyper::{Client, StatusCode, Uri};
+
+    static PROXY_STOPPER: Lazy<Mutex<Option<Box<dyn FnOnce() + Send>>>> =
+        Lazy::new(|| Mutex::new(None));
+
+    const DEFAULT_SOAK_SECONDS: u64 = 300;
+    const CONCURRENT_CLIENTS: usize = 20;
+    const CONFIG_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+    const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+    // Allowed growth after the warm-up.
+    const MAX_RSS_GROWTH_KB: u64 = 50 * 1024;
+    const MAX_FD_GROWTH: usize = 2 * CONCURRENT_CLIENTS;
+
+    // ------ SETUP ------
+
+    fn before_all() {
+        let _ = fs::remove_dir_all("test_data/soak_proxy_db");
+        let proxy_stopper = start_proxy("test_data/proxy_cfg_soak.toml");
+        *PROXY_STOPPER.lock().unwrap() = Some(Box::new(proxy_stopper));
+    }
+
+    fn before_each() {}
+
+    fn after_each() {}
+
+    fn after_all() {
+        PROXY_STOPPER.lock().unwrap().take().unwrap()();
+        let _ = fs::remove_dir_all("test_data/soak_proxy_db");
+    }
+
+    fn report(results: &[TestResult]) {
+        print_report(results);
+    }
+
+    // ------ TESTS ------
+
+    #[tokio::test]
+    async fn soak() {
+        // ------ ARRANGE ------
+
+        let soak_duration = Duration::from_secs(
+            env::var("SOAK_TEST_SECONDS")
+                .ok()
+                .and_then(|seconds| seconds.parse().ok())
+                .unwrap_or(DEFAULT_SOAK_SECONDS),
+        );
+        let warm_up = soak_duration / 10;
+        let mock_server = start_mock_server();
+        let client = Client::new();
+
+        // ------ ACT ------
+
+        let start = Instant::now();
+        let mut baseline = None;
+        let mut last_config_reload = Instant::now();
+        let mut peak = (0, 0);
+        let mut round = 0;
+
+        while start.elapsed() < soak_duration {
+            let next_sample = Instant::now() + SAMPLE_INTERVAL;
+            while Instant::now() < next_sample {
+                join_all((0..CONCURRENT_CLIENTS).map(|client_index| {
+                    send_mixed_request(&client, round * CONCURRENT_CLIENTS + client_index)
+                }))
+                .await;
+                round += 1;
+
+                if last_config_reload.elapsed() > CONFIG_RELOAD_INTERVAL {
+                    client
+                        .get(url_from_path("/reload-proxy-config"))
+                        .await
+                        .unwrap();
+                    last_config_reload = Instant::now();
+                }
+            }
+
+            let sample = (rss_kb(), open_fds());
+            println!(
+                "{:>4} s: RSS {} kB, open fds {}",
+                start.elapsed().as_secs(),
+                sample.0,
+                sample.1
+            );
+            if start.elapsed() < warm_up {
+                continue;
+            }
+            if baseline.is_none() {
+                baseline = Some(sample);
+            }
+            peak = (peak.0.max(sample.0), peak.1.max(sample.1));
+        }
+        drop(mock_server);
+
+        // ------ ASSERT ------
+
+        let (baseline_rss, baseline_fds) = baseline.expect("soak test is too short");
+        assert!(
+            peak.0 <= baseline_rss + MAX_RSS_GROWTH_KB,
+            "RSS grew from {} kB to {} kB",
+            baseline_rss,
+            peak.0
+        );
+        assert!(
+            peak.1 <= baseline_fds + MAX_FD_GROWTH,
+            "open fds grew from {} to {}",
+            baseline_fds,
+            peak.1
+        );
+    }
+
+    // ------ TRAFFIC HELPERS ------
+
+    /// Cycle cacheable responses, responses stale immediately, origin failures and invalid requests.
+    async fn send_mixed_request(client: &Client<HttpConnector>, index: usize) {
+        let path = match index % 4 {
+            // A bounded set of URLs, so the cache doesn't grow forever.
+            0 => format!("/origin/catalog/movie/top/skip={}.json", index % 100 * 100),
+            1 => "/origin/meta/movie/tt1.json".to_owned(),
+            2 => "/origin/stream/movie/tt1.json".to_owned(),
+            _ => "/origin/unknown".to_owned(),
+        };
+        let response = client.get(url_from_path(&path)).await.unwrap();
+        // Read response body until the end.
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+    }
+
+    // ------ PROCESS HELPERS ------
+
+    fn rss_kb() -> u64 {
+        fs::read_to_string("/proc/self/status")
+            .unwrap()
+            .lines()
+            .find(|line| line.starts_with("VmRSS:"))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|rss| rss.parse().ok())
+            .expect("VmRSS in /proc/self/status")
+    }
+
+    fn open_fds() -> usize {
+        fs::read_dir("/proc/self/fd").unwrap().count()
+    }
+
+    // ------ SETUP HELPERS ------
+
+    fn start_proxy(config_path: &'static str) -> impl FnOnce() {
+        let (controller_sender, controller_receiver) = mpsc::channel();
+        let (stop_signal_sender, stop_signal_receiver) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            let proxy = async {
+                Proxy::new(default_client, on_request)
+                    .set_config_path(config_path)
+                    .set_on_server_start(move |controller| {
+                        controller_sender
+                            .send(controller)
+                            .expect("send proxy controller")
+                    })
+                    .set_on_server_stop(move |_summary| {
+                        stop_signal_sender.send(()).expect("send stop signal")
+                    })
+                    .start()
+                    .await
+            };
+
+            let mut rt = tokio::runtime::Builder::new()
+                .enable_all()
+                .basic_scheduler()
+                .build()
+                .expect("rt build");
+
+            rt.block_on(proxy)
+        });
+
+        let controller = controller_receiver.recv().expect("receive proxy ctrl");
+        move || {
+            controller.stop();
+            stop_signal_receiver.recv().expect("receive stop signal");
+        }
+    }
+
+    #[must_use = "Mock server is stopped on drop"]
+    fn start_mock_server() -> MockOrigin {
+        let mock_server = MockOrigin::start(5015).unwrap();
+
+        mock_server
+            .resource("/catalog/movie/top/{skip}")
+            .header("Content-Type", "application/json")
+            .body(include_str!("../test_data/top.json"));
+
+        // Stale immediately.
+        mock_server
+            .resource("/meta/movie/tt1.json")
+            .header("Content-Type", "application/json")
+            .header("Cache-Control", "max-age=0")
+            .body(r#"{ "meta": null }"#);
+
+        // Origin failure - the response isn't cached.
+        mock_server
+            .resource("/stream/movie/tt1.json")
+            .status(StatusCode::INTERNAL_SERVER_ERROR);
+
+        mock_server
+    }
+
+    fn url_from_path(path: &str) -> Uri {
+        let proxy_url = "http://127.0.0.1:5010";
+        format!("{}{}", proxy_url, path).parse().unwrap()
+    }
+}